@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::units::parse_duration;
+
+/// One end of an `--newer-than`/`--older-than` window: a fixed time, or an
+/// age measured back from whenever a file is checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeBound {
+    Ago(Duration),
+    At(SystemTime),
+}
+
+impl TimeBound {
+    pub fn resolve(&self, now: SystemTime) -> SystemTime {
+        match self {
+            TimeBound::Ago(age) => now.checked_sub(*age).unwrap_or(UNIX_EPOCH),
+            TimeBound::At(time) => *time,
+        }
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn field(value: &str, name: &str, range: std::ops::RangeInclusive<u32>) -> Result<u32> {
+    let parsed: u32 = value.parse().with_context(|| format!("Bad {} {:?}", name, value))?;
+    if !range.contains(&parsed) {
+        anyhow::bail!("{} out of range: {:?}", name, value);
+    }
+    Ok(parsed)
+}
+
+#[cfg(unix)]
+fn local_offset(days: i64, seconds: i64) -> i64 {
+    // Asks mktime for the same wall-clock time, which applies the local zone
+    // and whether daylight saving was in effect on that date.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = 70;
+    tm.tm_mday = 1 + days as i32;
+    tm.tm_sec = seconds as i32;
+    tm.tm_isdst = -1;
+    let local = unsafe { libc::mktime(&mut tm) };
+    match local {
+        -1 => 0,
+        local => local - (days * 86_400 + seconds),
+    }
+}
+
+/// No time zone lookup on other platforms; timestamps there are in UTC.
+#[cfg(not(unix))]
+fn local_offset(_days: i64, _seconds: i64) -> i64 {
+    0
+}
+
+/// `YYYY-MM-DD`, optionally followed by `THH:MM[:SS]` (or a space instead of
+/// the `T`). Local time unless suffixed with `Z` or ` UTC`.
+fn parse_timestamp(value: &str) -> Result<SystemTime> {
+    let (value, utc) = match value.strip_suffix('Z').or_else(|| value.strip_suffix("UTC")) {
+        Some(value) => (value.trim_end(), true),
+        None => (value, false),
+    };
+    let (date, time) = match value.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+
+    let mut parts = date.splitn(3, '-');
+    let (Some(year), Some(month), Some(day)) = (parts.next(), parts.next(), parts.next()) else {
+        anyhow::bail!("Expected YYYY-MM-DD, got {:?}", date);
+    };
+    let year = field(year, "year", 1970..=9999)?;
+    let days = days_from_civil(year as i64, field(month, "month", 1..=12)?, field(day, "day", 1..=31)?);
+
+    let mut seconds = 0;
+    if let Some(time) = time {
+        let mut parts = time.splitn(3, ':');
+        let (Some(hours), Some(minutes)) = (parts.next(), parts.next()) else {
+            anyhow::bail!("Expected HH:MM[:SS], got {:?}", time);
+        };
+        seconds = field(hours, "hour", 0..=23)? * 3600 + field(minutes, "minute", 0..=59)? * 60;
+        if let Some(secs) = parts.next() {
+            seconds += field(secs, "second", 0..=60)?;
+        }
+    }
+
+    let mut since_epoch = days * 86_400 + seconds as i64;
+    if !utc {
+        since_epoch += local_offset(days, seconds as i64);
+    }
+    Ok(UNIX_EPOCH + Duration::from_secs(since_epoch.max(0) as u64))
+}
+
+impl FromStr for TimeBound {
+    type Err = anyhow::Error;
+
+    /// An age such as `24h` or `7d`, `@<unix seconds>`, or a timestamp such as
+    /// `2025-01-31`, `2025-01-31T18:00` or `2025-01-31T18:00:00Z`.
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if let Some(seconds) = value.strip_prefix('@') {
+            let seconds: u64 = seconds.parse().with_context(|| format!("Bad Unix time {:?}", value))?;
+            return Ok(TimeBound::At(UNIX_EPOCH + Duration::from_secs(seconds)));
+        }
+        if value.contains('-') {
+            return parse_timestamp(value).map(TimeBound::At);
+        }
+        parse_duration(value)
+            .map(TimeBound::Ago)
+            .with_context(|| format!("Expected an age such as 24h or a timestamp such as 2025-01-31T18:00, got {:?}", value))
+    }
+}
+
+/// `--newer-than`/`--older-than`: which files, by source modification time,
+/// a sync copies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AgeFilter {
+    pub newer_than: Option<TimeBound>,
+    pub older_than: Option<TimeBound>,
+}
+
+impl AgeFilter {
+    pub fn is_empty(&self) -> bool {
+        self.newer_than.is_none() && self.older_than.is_none()
+    }
+
+    /// Whether a file last modified at `modified` is inside the window.
+    pub fn admits(&self, modified: SystemTime) -> bool {
+        let now = SystemTime::now();
+        self.newer_than.is_none_or(|bound| modified > bound.resolve(now))
+            && self.older_than.is_none_or(|bound| modified < bound.resolve(now))
+    }
+}
@@ -0,0 +1,128 @@
+use serde_json::json;
+use std::{
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::report::{ErrorEvent, Sink};
+
+const MAX_BATCH: usize = 20;
+
+/// The sending end of a `spawn_coalescing` thread.
+struct Coalescing {
+    sender: Sender<ErrorEvent>,
+    thread: JoinHandle<()>,
+}
+
+impl Coalescing {
+    fn send(&self, event: &ErrorEvent) {
+        let _ = self.sender.send(event.clone());
+    }
+
+    /// Delivers whatever is waiting straight away and stops the thread.
+    fn finish(self) {
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+}
+
+/// Forwards error events to `deliver` from a background thread, at most once
+/// per `window`, so an error storm becomes a handful of batched deliveries.
+fn spawn_coalescing<F>(window: Duration, deliver: F) -> Coalescing
+where
+    F: Fn(&[ErrorEvent], usize) + Send + 'static,
+{
+    let (sender, receiver) = channel::<ErrorEvent>();
+
+    let thread = thread::spawn(move || {
+        while let Ok(first) = receiver.recv() {
+            let deadline = Instant::now() + window;
+            let mut batch = vec![first];
+            let mut coalesced = 0;
+
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match receiver.recv_timeout(remaining) {
+                    Ok(event) if batch.len() < MAX_BATCH => batch.push(event),
+                    Ok(_) => coalesced += 1,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+
+            deliver(&batch, coalesced);
+        }
+    });
+
+    Coalescing { sender, thread }
+}
+
+pub struct WebhookSink {
+    coalescing: Coalescing,
+}
+
+impl WebhookSink {
+    pub fn new(url: String, window: Duration) -> Self {
+        let coalescing = spawn_coalescing(window, move |batch, coalesced| {
+            let payload = json!({ "errors": batch, "coalesced": coalesced });
+            let result = ureq::post(&url)
+                .header("Content-Type", "application/json")
+                .send(payload.to_string());
+
+            // Not routed through report::error, a dead endpoint would feed itself.
+            if let Err(error) = result {
+                eprintln!("Failed to deliver webhook to {}: {}", url, error);
+            }
+        });
+        WebhookSink { coalescing }
+    }
+}
+
+impl Sink for WebhookSink {
+    fn error(&self, event: &ErrorEvent) {
+        self.coalescing.send(event);
+    }
+
+    fn finish(self: Box<Self>) {
+        self.coalescing.finish();
+    }
+}
+
+#[cfg(feature = "desktop-notify")]
+pub struct DesktopSink {
+    coalescing: Coalescing,
+}
+
+#[cfg(feature = "desktop-notify")]
+impl DesktopSink {
+    pub fn new(window: Duration) -> Self {
+        let coalescing = spawn_coalescing(window, |batch, coalesced| {
+            let total = batch.len() + coalesced;
+            let body = match total {
+                1 => batch[0].message.clone(),
+                _ => format!("{} (and {} more errors)", batch[0].message, total - 1),
+            };
+
+            if let Err(error) = notify_rust::Notification::new()
+                .summary("rustsync error")
+                .body(&body)
+                .show()
+            {
+                eprintln!("Failed to show desktop notification: {}", error);
+            }
+        });
+        DesktopSink { coalescing }
+    }
+}
+
+#[cfg(feature = "desktop-notify")]
+impl Sink for DesktopSink {
+    fn error(&self, event: &ErrorEvent) {
+        self.coalescing.send(event);
+    }
+
+    fn finish(self: Box<Self>) {
+        self.coalescing.finish();
+    }
+}
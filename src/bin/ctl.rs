@@ -0,0 +1,22 @@
+use clap::Parser;
+use std::path::PathBuf;
+use anyhow::Result;
+
+use rustsync::control::send;
+
+#[derive(Parser)]
+#[command(name = "rustsyncctl", about = "Control a running filesync")]
+struct Args {
+    /// Path given to filesync --control-socket
+    #[arg(short = 'S', long = "socket")]
+    socket: PathBuf,
+
+    /// pause, resume, status, resync, confirm-deletes or retry-deadletter
+    command: String,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    println!("{}", send(&args.socket, &args.command)?);
+    Ok(())
+}
@@ -3,25 +3,62 @@ use libp2p::identity;
 use std::path::PathBuf;
 use anyhow::Result;
 
-use rustsync::keys::{save_keypair, load_keypair, default_rustsync_dir, test_rustsync_dir};
+use rustsync::keys::{
+    add_peer, default_rustsync_dir, fingerprint, list_peers, load_keypair, remove_peer, save_keypair, test_rustsync_dir,
+};
 
 #[derive(Parser)]
 #[command(name = "key-gen", about = "Generate rustsync peer keys")]
 struct Args {
-    #[arg(short = 'O', long = "output", default_value_t = default_rustsync_dir())]
-    output: String,
+    /// Key directory [default: ~/.rustsync]
+    #[arg(short = 'O', long = "output")]
+    output: Option<PathBuf>,
+
+    /// Print the peer IDs in the allowlist and exit
+    #[arg(long, conflicts_with_all = ["add_peer", "remove_peer"])]
+    list_peers: bool,
+
+    /// Add a peer ID to the allowlist and exit
+    #[arg(long, value_name = "PEER_ID", conflicts_with = "remove_peer")]
+    add_peer: Option<String>,
+
+    /// Remove a peer ID from the allowlist and exit
+    #[arg(long, value_name = "PEER_ID")]
+    remove_peer: Option<String>,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let dir = PathBuf::from(&args.output);
+    let dir = args.output.map_or_else(default_rustsync_dir, Ok)?;
     test_rustsync_dir(&dir)?;
 
+    if args.list_peers {
+        for peer in list_peers(&dir)? {
+            println!("{peer}");
+        }
+        return Ok(());
+    }
+    if let Some(peer_id) = &args.add_peer {
+        match add_peer(&dir, peer_id)? {
+            true => println!("Added {peer_id}"),
+            false => println!("{peer_id} is already allowed"),
+        }
+        return Ok(());
+    }
+    if let Some(peer_id) = &args.remove_peer {
+        match remove_peer(&dir, peer_id)? {
+            true => println!("Removed {peer_id}"),
+            false => println!("{peer_id} was not in the allowlist"),
+        }
+        return Ok(());
+    }
+
     println!("Generating new Ed25519 keypair...");
     let keypair = identity::Keypair::generate_ed25519();
 
     let peer_id = save_keypair(&dir, &keypair)?;
     println!("Peer ID: {peer_id}");
+    println!("Fingerprint: {}", fingerprint(&keypair));
 
     // Sanity check
     let loaded = load_keypair(&dir, &peer_id)?;
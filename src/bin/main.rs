@@ -1,323 +1,1715 @@
+use anyhow::Context;
 use clap::Parser;
-use notify::{
-    event::{DataChange, MetadataKind, ModifyKind, RenameMode},
-    EventKind, RecommendedWatcher, RecursiveMode, Watcher,
-};
 use std::{
-    ffi::CString,
+    collections::{BTreeSet, HashMap},
     fs,
-    io,
-    os::unix::ffi::OsStrExt,
+    net::SocketAddr,
     path::{Path, PathBuf},
-    sync::mpsc::channel,
+    sync::{
+        atomic::Ordering,
+        mpsc::{channel, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rustsync::{
+    age::{AgeFilter, TimeBound},
+    alert::WebhookSink,
+    bundle::apply_bundle,
+    cas::{self, CasStore},
+    compress::Compression,
+    conflict::{ConflictLog, ConflictPolicy},
+    control::{self, Command, ControlRequest},
+    copy::{self, Fsync, Reflink},
+    daemon::{daemonize, shutdown_flag, PidFile},
+    deadletter::DeadLetters,
+    debounce::DebounceRule,
+    deploy::AtomicDeploy,
+    diff::DiffPrinter,
+    echo::SelfWrites,
+    encrypt::Encryption,
+    fanout::FanOut,
+    hash::{self, ChecksumAlgorithm},
+    hashcache::{self, HashCache},
+    health::{self, Health},
+    hooks::{Hook, HookRunner},
+    idmap::{IdMap, IdRange},
+    inflight,
+    journal::{read_records, Journal},
+    keys::default_rustsync_dir,
+    listing::{entries, write_list, ListFormat},
+    manifest::{Difference, Manifest},
+    merkle::{self, MerkleTree},
+    metrics::{self, SummaryFormat},
+    mirror::{
+        apply_event, apply_planned, blocked_deletes, confirm_deletes, drain_queued_copies, flush_deletes, flush_held,
+        flush_merkle, flush_transactions, handle_event, handle_watch_error, has_queued_copies, is_ignored, is_paused,
+        merkle_root, pause, queue_depth, queued_copies, recent_operations, remounted, resume, resume_pending,
+        retry_dead_letters, run_queued_copy, unmounted, Atime, Changes, DanglingSymlinks, LockedFiles, Mirror, Options,
+        Preserve, CONTROL_DIR,
+    },
+    mounts::{MountChange, MountWatcher},
+    priority::{CopyOrder, PriorityRule},
+    probe::preflight,
+    reconcile::{limit_deletes, metadata_sync, plan, reconcile, reconcile_under},
+    relpath::is_case_insensitive,
+    remote::{Backend, SshTarget},
+    report::{self, ErrorKind, LogLevel},
+    route::Route,
+    safety::{check_roots, parse_percent, DeleteLimit},
+    schedule::ActiveWindow,
+    snapshot::take_snapshot,
+    space::MinFreeSpace,
+    trace::EventTrace,
+    transaction::TransactionGlob,
+    transform::Transforms,
+    trickle::{Trickle, TrickleLimit},
+    units::{parse_duration, parse_size},
+    watch::{watch, WatchHandle, WatcherBackend},
 };
-use filetime::FileTime;
 
-fn cross_platform_symlink(path: &Path, sym_path: &Path) -> io::Result<()> {
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs as unix_fs;
-        unix_fs::symlink(path, sym_path)
+#[derive(Parser)]
+struct Args {
+    #[arg(required_unless_present_any = ["replay", "clear_hash_cache", "from_manifest"])]
+    watch_root: Option<PathBuf>,
+    #[arg(required_unless_present_any = ["replay", "clear_hash_cache", "list", "cas_checkout", "snapshot_dir", "from_manifest"])]
+    output_root: Option<PathBuf>,
+
+    /// Additional destination to mirror into alongside OUTPUT_ROOT (repeatable; live syncs only)
+    #[arg(long = "dest", value_name = "DIR", conflicts_with_all = ["once", "dry_run", "dry_run_diff", "metadata_sync", "rebuild"])]
+    destinations: Vec<PathBuf>,
+
+    /// Mirror into OUTPUT_ROOT (and each --dest) at WATCH_ROOT's path below this ancestor directory, e.g. / for full paths
+    #[arg(long, value_name = "BASE")]
+    relative_to: Option<PathBuf>,
+
+    /// Mirror paths matching a glob into another directory, e.g. '*.jpg=>/photos' (repeatable, first match wins)
+    #[arg(long, value_name = "GLOB=>DIR")]
+    route: Vec<Route>,
+
+    /// Hash function used for manifests and verification
+    #[arg(long, value_enum, default_value_t = ChecksumAlgorithm::default())]
+    checksum_algorithm: ChecksumAlgorithm,
+
+    /// Keep a Merkle root over OUTPUT_ROOT's files in OUTPUT_ROOT/.rustsync/merkle-root, updated as changes are mirrored
+    #[arg(long, conflicts_with = "atomic_deploy")]
+    merkle: bool,
+
+    /// Recompute OUTPUT_ROOT's Merkle root from scratch, compare it with the stored one and exit (1 if they differ)
+    #[arg(long)]
+    verify_merkle: bool,
+
+    /// Hash every file for --manifest/--check instead of reusing hashes of unchanged files
+    #[arg(long)]
+    no_hash_cache: bool,
+
+    /// Delete all cached hashes and exit
+    #[arg(long)]
+    clear_hash_cache: bool,
+
+    /// Check that the roots, destinations, globs and key file are usable, print a summary and exit (1 on any problem)
+    #[arg(long)]
+    check_config: bool,
+
+    /// Memory map files at least this large for SHA-2 hashing and copying (bytes or a suffix such as 64M)
+    #[arg(long, value_parser = parse_size, default_value = "64M")]
+    mmap_threshold: u64,
+
+    /// Cap the memory copies and hashes in progress hold at once, counting a file read whole or memory mapped by its size and a streamed copy by its buffers (bytes or a suffix such as 512M)
+    #[arg(long, alias = "limit-inflight-bytes", value_name = "SIZE", value_parser = parse_size)]
+    max_inflight_bytes: Option<u64>,
+
+    /// Hash this many files at once for --manifest, --check, --merkle and --verify-merkle (default: one per CPU)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: Option<u32>,
+
+    /// Write a manifest of the watch root to this file and exit
+    #[arg(long, conflicts_with = "check")]
+    manifest: Option<PathBuf>,
+
+    /// Print the files a sync would consider, after --exclude-vcs and --newer-than/--older-than, and exit (OUTPUT_ROOT is optional)
+    #[arg(long, conflicts_with_all = ["manifest", "check"])]
+    list: bool,
+
+    /// With --list, print entries like rsync --list-only, as CSV or as JSON lines
+    #[arg(long, value_enum, default_value_t = ListFormat::default(), requires = "list")]
+    list_format: ListFormat,
+
+    /// Copy the files in --from-bundle into OUTPUT_ROOT, the only root given, checking each against this manifest of the source, and list entries neither has, then exit (1 if any)
+    #[arg(long, value_name = "FILE", requires = "from_bundle", conflicts_with_all = ["manifest", "check", "list", "rebuild", "replay", "cas", "destinations", "compress_dest", "encrypt_dest", "journal"])]
+    from_manifest: Option<PathBuf>,
+
+    /// Directory of source files laid out as under WATCH_ROOT, for --from-manifest
+    #[arg(long, value_name = "DIR", requires = "from_manifest")]
+    from_bundle: Option<PathBuf>,
+
+    /// Verify the output root against a previously written manifest and exit
+    #[arg(long)]
+    check: Option<PathBuf>,
+
+    /// Keep OUTPUT_ROOT as a content-addressable store: deduplicated objects/<hash prefix>/<hash> plus an index of paths
+    #[arg(long)]
+    cas: bool,
+
+    /// Write the tree indexed by the --cas store at WATCH_ROOT out to this directory and exit
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["list", "manifest", "check", "replay"])]
+    cas_checkout: Option<PathBuf>,
+
+    /// Instead of mirroring, write a full snapshot to DIR/snapshots/<time> at startup and every --interval, hard linking unchanged files to the previous one
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["output_root", "list", "manifest", "check", "replay", "cas", "cas_checkout"])]
+    snapshot_dir: Option<PathBuf>,
+
+    /// With --snapshot-dir, the number of snapshots kept; older ones are removed
+    #[arg(long, default_value_t = 10, requires = "snapshot_dir")]
+    keep_snapshots: usize,
+
+    /// Metadata categories to mirror (owner and non-user xattrs need privileges)
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = Options::default().preserve)]
+    preserve: Vec<Preserve>,
+
+    /// Mirror the immutable and append-only chattr flags (Linux, needs root or CAP_LINUX_IMMUTABLE)
+    #[arg(long)]
+    preserve_flags: bool,
+
+    /// Don't set a mirrored entry's times back when it's newer than its source, as clock skew makes it; differing contents are still copied
+    #[arg(long)]
+    only_newer: bool,
+
+    /// How much newer than the source the mirror must be for --only-newer to keep it, allowing for clock skew
+    #[arg(long, value_parser = parse_duration, default_value = "1s", requires = "only_newer")]
+    only_newer_tolerance: Duration,
+
+    /// Only mirror N levels below the watch root's direct contents (0 mirrors the direct contents alone)
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// What to do with a source symlink whose target doesn't exist
+    #[arg(long, value_enum, default_value_t = DanglingSymlinks::Keep)]
+    dangling_symlinks: DanglingSymlinks,
+
+    /// Skip files another process holds locked (ETXTBSY, or a sharing violation on Windows) until their next change instead of reporting an error
+    #[arg(long)]
+    skip_locked: bool,
+
+    /// Retry copies of files another process holds locked with backoff, for about a minute, before reporting an error (with --once, report it straight away)
+    #[arg(long, conflicts_with = "skip_locked")]
+    wait_locked: bool,
+
+    /// Before copying over a file in OUTPUT_ROOT, check it's still what rustsync last wrote, log it to OUTPUT_ROOT/.rustsync/conflicts.log if not, and overwrite it, skip it or back it up first
+    #[arg(long, value_enum, value_name = "POLICY", conflicts_with_all = ["compress_dest", "encrypt_dest"])]
+    on_conflict: Option<ConflictPolicy>,
+
+    /// Retry an operation that fails with backoff and, after this many retries, set it aside in OUTPUT_ROOT/.rustsync/deadletter and stop applying changes to its path
+    #[arg(long, value_name = "N")]
+    dead_letter_after: Option<u32>,
+
+    /// Apply the operations set aside by --dead-letter-after again at startup
+    #[arg(long, requires = "dead_letter_after")]
+    retry_deadletter: bool,
+
+    /// Drop events for paths a rustsync mirroring OUTPUT_ROOT back to WATCH_ROOT wrote within this long, for two-way syncs
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    echo_window: Option<Duration>,
+
+    /// Hold live deletes until none has arrived for this long and apply them together, skipping those under a deleted directory (0 applies each at once)
+    #[arg(long, value_parser = parse_duration, default_value = "200ms")]
+    delete_batch_window: Duration,
+
+    /// Access times on the mirror: copy the source's, leave what the copy set, or set the current time
+    #[arg(long, value_enum, default_value_t = Atime::Preserve)]
+    atime: Atime,
+
+    /// Shift mirrored owners into the current user's /etc/subuid and /etc/subgid ranges, for rootless container storage
+    #[arg(long)]
+    map_root_uid_via_subuid: bool,
+
+    /// With --map-root-uid-via-subuid, shift UIDs and GIDs into this range instead of reading /etc/subuid and /etc/subgid
+    #[arg(long, value_name = "START[:COUNT]", requires = "map_root_uid_via_subuid")]
+    subuid_range: Option<IdRange>,
+
+    /// Don't carry setuid (or setgid on files) over to the mirror
+    #[arg(long)]
+    no_setuid: bool,
+
+    /// Store files zstd-compressed as <name>.zst on the destination
+    #[arg(long)]
+    compress_dest: bool,
+
+    /// With --compress-dest, zstd level
+    #[arg(long, default_value_t = Compression::default().level)]
+    compress_level: i32,
+
+    /// With --compress-dest, store files smaller than this as-is (bytes or a suffix such as 64K)
+    #[arg(long, value_parser = parse_size, default_value = "4K")]
+    compress_min_size: u64,
+
+    /// With --compress-dest, extensions stored as-is because they're already compressed
+    #[arg(long, value_delimiter = ',', default_values_t = Compression::default().skip_extensions)]
+    compress_skip: Vec<String>,
+
+    /// Store files encrypted as <name>.enc on the destination (passphrase from --encrypt-key-file or $RUSTSYNC_PASSPHRASE)
+    #[arg(long, conflicts_with_all = ["compress_dest", "destinations"])]
+    encrypt_dest: bool,
+
+    /// With --encrypt-dest, read the passphrase from this file
+    #[arg(long, requires = "encrypt_dest")]
+    encrypt_key_file: Option<PathBuf>,
+
+    /// With --encrypt-dest, encrypt file and directory names too
+    #[arg(long, requires = "encrypt_dest")]
+    encrypt_names: bool,
+
+    /// Rewrite matching files on their way to the mirror, as <glob>=<transform> (noop, strip-trailing-whitespace, redact-secrets)
+    #[arg(long = "transform", visible_alias = "filter", value_name = "GLOB=TRANSFORM")]
+    transforms: Vec<String>,
+
+    /// Ignore metadata changes and mirror only file contents
+    #[arg(long, conflicts_with = "metadata_only")]
+    sync_content_only: bool,
+
+    /// Ignore edits to existing files and mirror only their metadata (new files are still copied)
+    #[arg(long)]
+    metadata_only: bool,
+
+    /// Order in which waiting file copies run: as their events arrive, or smallest first
+    #[arg(long, value_enum, default_value_t = CopyOrder::default())]
+    copy_order: CopyOrder,
+
+    /// Copy paths matching a glob before others, e.g. '*.md=10' (repeatable, first match wins, default 0)
+    #[arg(long = "priority", value_name = "GLOB=PRIORITY")]
+    priorities: Vec<PriorityRule>,
+
+    /// Also skip what git ignores: .gitignore files, .git/info/exclude, the global excludes file and .git itself
+    #[arg(long)]
+    exclude_vcs: bool,
+
+    /// On a case-insensitive destination, mirror paths that clash by case with one already there with this added to the name (e.g. '~case'), instead of skipping them
+    #[arg(long, value_name = "SUFFIX")]
+    case_conflict_suffix: Option<String>,
+
+    /// Hold changes to paths matching a glob until none has changed for --transaction-settle, then apply them together (repeatable, one group per glob)
+    #[arg(long = "transaction-glob", value_name = "GLOB")]
+    transaction_globs: Vec<TransactionGlob>,
+
+    /// Quiet period before a --transaction-glob group is applied (e.g. 500ms, 2s)
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    transaction_settle: Duration,
+
+    /// In full syncs, mirror files hard linked to each other in the source as hard links instead of separate copies
+    #[arg(long)]
+    preserve_hardlinks_within_batch: bool,
+
+    /// When a directory whose mirror is a symlink in the destination is deleted, empty it through the link and keep the link
+    #[arg(long)]
+    keep_dest_links: bool,
+
+    /// Copy a changed file only once its size and modification time are the same twice this far apart (e.g. 500ms, 2s)
+    #[arg(long, value_parser = parse_duration)]
+    stable_time: Option<Duration>,
+
+    /// How many times --stable-time checks a file that keeps changing before copying it anyway
+    #[arg(long, default_value_t = 10, requires = "stable_time")]
+    stable_checks: u32,
+
+    /// Copy a changed file matching a glob only once it has had no events for the duration (e.g. '*.mp4=2s,*.toml=50ms'); the first matching glob wins
+    #[arg(long, value_name = "GLOB=DURATION", value_delimiter = ',')]
+    debounce: Vec<DebounceRule>,
+
+    /// Debounce window for files no --debounce glob matches (0 copies them straight away)
+    #[arg(long, value_parser = parse_duration, default_value = "0s")]
+    debounce_default: Duration,
+
+    /// Only copy files modified after this: an age such as 24h, or a timestamp such as 2025-01-31T18:00 (local time, or append Z for UTC)
+    #[arg(long, value_name = "AGE|TIME")]
+    newer_than: Option<TimeBound>,
+
+    /// Only copy files modified before this: an age such as 30d, or a timestamp as for --newer-than
+    #[arg(long, value_name = "AGE|TIME")]
+    older_than: Option<TimeBound>,
+
+    /// Stop deleting once a sync, or a burst of delete events, would delete more than this many entries
+    #[arg(long)]
+    max_deletes: Option<u64>,
+
+    /// Stop deleting once a sync, or a burst of delete events, would delete more than this percentage of the mirror
+    #[arg(long, value_parser = parse_percent)]
+    max_delete_percent: Option<f64>,
+
+    /// Window over which live delete events count towards --max-deletes/--max-delete-percent
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    delete_window: Duration,
+
+    /// Carry out deletes over --max-deletes/--max-delete-percent instead of holding them
+    #[arg(long)]
+    force: bool,
+
+    /// Copy files once their writer closes them instead of on every write (Linux only)
+    #[arg(long)]
+    sync_on_close: bool,
+
+    /// Print what the run did (events, operations, bytes, errors, uptime) when shutting down
+    #[arg(long)]
+    summary_on_exit: bool,
+
+    /// Also print the run summary this often (e.g. 1h)
+    #[arg(long, value_parser = parse_duration)]
+    summary_interval: Option<Duration>,
+
+    /// Print run summaries as text or as a single JSON line
+    #[arg(long, value_enum, default_value_t = SummaryFormat::Text)]
+    summary_format: SummaryFormat,
+
+    /// debug also logs events that were deliberately ignored
+    #[arg(long, value_enum, default_value_t = LogLevel::default())]
+    log_level: LogLevel,
+
+    /// Print each kind of error once per this window, summarizing repeats (for other paths) when it ends; 0 prints all
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    log_throttle: Duration,
+
+    /// Log every raw watcher event to stderr, marked with whether it's acted on or ignored
+    #[arg(long, conflicts_with = "once")]
+    trace_events: bool,
+
+    /// Only trace events on paths matching this glob, relative to the watch root (repeatable)
+    #[arg(long = "trace-glob", value_name = "GLOB", requires = "trace_events")]
+    trace_globs: Vec<String>,
+
+    /// Pause copies while the destination has less free space than this (bytes, 10G, or 5%)
+    #[arg(long)]
+    min_free_space: Option<MinFreeSpace>,
+
+    /// Clone file extents instead of copying bytes where the filesystem supports it
+    #[arg(long, value_enum, default_value_t = Reflink::default())]
+    reflink: Reflink,
+
+    /// Write temp files here instead of beside their destinations (must be on the same filesystem to be used)
+    #[arg(long, value_name = "DIR")]
+    temp_dir: Option<PathBuf>,
+
+    /// Flush copies to disk: data syncs file contents, full also syncs metadata and directory entries (slower)
+    #[arg(long, value_enum, default_value_t = Fsync::default())]
+    fsync: Fsync,
+
+    /// Quiet period before a directory's metadata changes are applied
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    dir_metadata_window: Duration,
+
+    /// Mirror into OUTPUT_ROOT.staging and swap it into place once changes settle
+    #[arg(long)]
+    atomic_deploy: bool,
+
+    /// Quiet period before an --atomic-deploy swap (e.g. 500ms, 5s)
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    settle_time: Duration,
+
+    /// Run a full scan-and-reconcile sync at startup and then every interval (e.g. 15m, 1h)
+    #[arg(long, value_parser = parse_duration)]
+    interval: Option<Duration>,
+
+    /// Only start --interval syncs inside this daily window, e.g. 22:00-06:00 (local time, or append UTC)
+    #[arg(long, requires = "interval")]
+    active_window: Option<ActiveWindow>,
+
+    /// Find changes by rescanning the tree this often instead of with native events (for network filesystems and containers), or set the interval of --watcher-backend poll
+    #[arg(long, value_parser = parse_duration, conflicts_with = "no_watch")]
+    poll_interval: Option<Duration>,
+
+    /// Which of notify's watchers to use; one the platform doesn't have is an error
+    #[arg(long, value_enum, default_value_t = WatcherBackend::Auto, conflicts_with = "no_watch")]
+    watcher_backend: WatcherBackend,
+
+    /// Watch and sync filesystems mounted under the watch root after startup, and don't mirror unmounts as deletes
+    #[arg(long, conflicts_with = "no_watch")]
+    follow_new_mounts: bool,
+
+    /// Spread the startup sync over time, copying at most --trickle-files/--trickle-bytes per minute while live changes sync as usual
+    #[arg(long, conflicts_with_all = ["once", "interval", "dry_run", "dry_run_diff", "metadata_sync"])]
+    trickle: bool,
+
+    /// With --trickle, files to copy per minute
+    #[arg(long, requires = "trickle", default_value_t = 60)]
+    trickle_files: u64,
+
+    /// With --trickle, bytes to copy per minute (e.g. 500M)
+    #[arg(long, requires = "trickle", value_parser = parse_size)]
+    trickle_bytes: Option<u64>,
+
+    /// With --interval, don't watch for live changes at all
+    #[arg(long, requires = "interval")]
+    no_watch: bool,
+
+    /// Sync once and exit: 0 if nothing changed, 2 if files changed, 1 on errors
+    #[arg(long, conflicts_with_all = ["interval", "atomic_deploy", "daemonize"])]
+    once: bool,
+
+    /// Empty OUTPUT_ROOT, after confirmation (or with --force), copy WATCH_ROOT afresh, verify every file and write a new manifest and Merkle root, then exit (1 on failures)
+    #[arg(long, conflicts_with_all = ["once", "interval", "atomic_deploy", "daemonize", "dry_run", "manifest", "check", "verify_merkle", "replay"])]
+    rebuild: bool,
+
+    /// Report files and directories whose names aren't valid UTF-8 as errors and leave them out of the mirror
+    #[arg(long)]
+    require_utf8: bool,
+
+    /// Treat a destination that can't set timestamps as an error instead of warning once and syncing without times
+    #[arg(long)]
+    require_times: bool,
+
+    /// Exit at startup when a destination lacks something the options need (chown for --preserve owner, say) instead of warning and doing without
+    #[arg(long)]
+    strict: bool,
+
+    /// Print the operations a sync would apply and exit without changing anything
+    #[arg(long, conflicts_with_all = ["once", "interval"])]
+    dry_run: bool,
+
+    /// Like --dry-run, plus unified diffs of overwritten text files and size changes of binary ones
+    #[arg(long, conflicts_with_all = ["once", "interval"])]
+    dry_run_diff: bool,
+
+    /// Copy only metadata (owner, permissions, times) onto mirrored files whose contents already match, then exit
+    #[arg(long, conflicts_with_all = ["once", "interval", "dry_run", "dry_run_diff", "compress_dest", "encrypt_dest"])]
+    metadata_sync: bool,
+
+    /// With --dry-run-diff, treat files larger than this as binary
+    #[arg(long, value_parser = parse_size, default_value = "1M")]
+    diff_max_size: u64,
+
+    /// With --dry-run-diff, stop printing diffs after this many lines
+    #[arg(long, default_value_t = 2000)]
+    diff_max_lines: usize,
+
+    /// Append every applied operation to this journal file
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// Print the operations recorded in a journal and exit
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// With --replay, re-apply the operations from WATCH_ROOT onto OUTPUT_ROOT
+    #[arg(long, requires = "replay")]
+    apply: bool,
+
+    /// With --replay, skip corrupt journal records instead of stopping
+    #[arg(long, requires = "replay")]
+    skip_corrupt: bool,
+
+    /// Run a command when a synced path matches a glob, e.g. 'conf/*.toml=systemctl reload app' (repeatable)
+    #[arg(long, value_name = "GLOB=COMMAND")]
+    on_change: Vec<Hook>,
+
+    /// Quiet period before an --on-change command runs
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    hook_debounce: Duration,
+
+    /// POST a JSON payload describing sync errors to this URL
+    #[arg(long)]
+    notify_webhook: Option<String>,
+
+    /// With an OUTPUT_ROOT like user@host:/path, authenticate with this private key instead of the SSH agent
+    #[cfg(feature = "ssh")]
+    #[arg(long, value_name = "FILE")]
+    ssh_key: Option<PathBuf>,
+
+    /// With an OUTPUT_ROOT like user@host:/path, the SSH port
+    #[cfg(feature = "ssh")]
+    #[arg(long, default_value_t = 22)]
+    ssh_port: u16,
+
+    /// Show desktop notifications for sync errors
+    #[cfg(feature = "desktop-notify")]
+    #[arg(long)]
+    desktop_notify: bool,
+
+    /// Seconds over which error notifications are coalesced into one delivery
+    #[arg(long, default_value_t = 10)]
+    notify_interval: u64,
+
+    /// Accept pause/resume/status/resync commands on this Unix socket (see rustsyncctl)
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+
+    /// Serve /healthz and /readyz probes over HTTP on this address (e.g. 0.0.0.0:8080)
+    #[arg(long, value_name = "ADDR")]
+    health_addr: Option<SocketAddr>,
+
+    /// Serve a status and control dashboard over HTTP on this address, without authentication (e.g. 127.0.0.1:8384)
+    #[cfg(feature = "web-ui")]
+    #[arg(long, value_name = "ADDR")]
+    web_addr: Option<SocketAddr>,
+
+    /// Operations buffered while paused before falling back to a full resync on resume
+    #[arg(long, default_value_t = Options::default().max_queue)]
+    max_queue: usize,
+
+    /// Fork into the background (Unix only)
+    #[arg(long)]
+    daemonize: bool,
+
+    /// Write the process id here and refuse to start if it names a live process
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+
+    /// Where stdout/stderr go when daemonized [default: ~/.rustsync/filesync.log]
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+}
+
+fn open_hash_cache(root: &Path, algorithm: ChecksumAlgorithm, enabled: bool) -> Option<HashCache> {
+    if !enabled {
+        return None;
     }
+    HashCache::open(root, algorithm)
+        .inspect_err(|error| eprintln!("Hash cache disabled: {:#}", error))
+        .ok()
+}
 
-    #[cfg(windows)]
-    {
-        use std::os::windows::fs as windows_fs;
-        if path.is_dir() {
-            windows_fs::symlink_dir(path, sym_path)
-        } else {
-            windows_fs::symlink_file(path, sym_path)
+fn save_hash_cache(cache: Option<HashCache>) {
+    if let Some(cache) = cache {
+        let (reused, hashed) = cache.stats();
+        println!("Hash cache: {} reused, {} hashed", reused, hashed);
+        if let Err(error) = cache.save() {
+            eprintln!("{:#}", error);
         }
     }
 }
 
-fn change_root(watch_root: &Path, output_root: &Path, path: &Path) -> Option<PathBuf> {
-    path.strip_prefix(watch_root).ok().map(|relative| output_root.join(relative))
+fn write_manifest(
+    watch_root: &Path,
+    manifest_path: &Path,
+    algorithm: ChecksumAlgorithm,
+    transforms: &Transforms,
+    hash_cache: bool,
+) -> anyhow::Result<()> {
+    let mut cache = open_hash_cache(watch_root, algorithm, hash_cache);
+    let mut manifest = Manifest::build_cached(watch_root, algorithm, cache.as_mut())?;
+    save_hash_cache(cache);
+    // The mirror holds what transforms write, not the source.
+    manifest.apply_transforms(watch_root, transforms)?;
+    manifest.save(manifest_path)?;
+    println!("Wrote {} entries to {:?}", manifest.entries.len(), manifest_path);
+    Ok(())
 }
 
-fn handle_watch_error(error: &notify::Error) {
-    eprintln!("Watch error: {:?}", error);
+fn list(watch_root: PathBuf, args: &Args) -> anyhow::Result<()> {
+    // Without OUTPUT_ROOT, the control directory stands in for it: it's never
+    // listed anyway.
+    let output_root = match &args.output_root {
+        Some(output_root) if remote_target(output_root).is_none() => fs::canonicalize(output_root)?,
+        _ => watch_root.join(CONTROL_DIR),
+    };
+    let options = Options {
+        exclude_vcs: args.exclude_vcs,
+        age: AgeFilter {
+            newer_than: args.newer_than,
+            older_than: args.older_than,
+        },
+        ..Options::default()
+    };
+    let mirror = Mirror::new(watch_root, output_root, options);
+    let listed = entries(&mirror);
+    write_list(&listed, args.list_format, &mut std::io::stdout().lock())?;
+    Ok(())
 }
 
-fn handle_not_under_watch_error(watch_root: &Path, path: &Path) {
-    eprintln!("Path {:?} is not under watch root {:?}", path, watch_root);
-}
+fn snapshot(watch_root: PathBuf, snapshot_dir: &Path, args: &Args) -> anyhow::Result<()> {
+    fs::create_dir_all(snapshot_dir).with_context(|| format!("Failed to create {:?}", snapshot_dir))?;
+    let snapshot_dir = fs::canonicalize(snapshot_dir)?;
+    check_roots(&watch_root, &[&snapshot_dir])?;
+    let options = Options {
+        exclude_vcs: args.exclude_vcs,
+        reflink: args.reflink,
+        ..Options::default()
+    };
+    let mirror = Mirror::new(watch_root, snapshot_dir, options);
+    let shutdown = shutdown_flag();
 
-fn handle_get_metadata_error(path: &Path, error: &io::Error) {
-    eprintln!("Failed to get metadata for {:?}: {:?}", path, error);
+    loop {
+        if args.active_window.as_ref().is_none_or(|window| window.is_open()) {
+            let mut cache = open_hash_cache(&mirror.watch_root, args.checksum_algorithm, !args.no_hash_cache);
+            let summary = take_snapshot(&mirror, args.checksum_algorithm, cache.as_mut(), args.keep_snapshots)?;
+            save_hash_cache(cache);
+            println!("Snapshot complete: {}", summary);
+        }
+        let Some(interval) = args.interval else {
+            return Ok(());
+        };
+        let due = Instant::now() + interval;
+        while Instant::now() < due {
+            if shutdown.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
 }
 
-fn handle_create_dir_error(path: &Path, error: &io::Error) {
-    eprintln!("Failed to create dir {:?}: {:?}", path, error);
+/// Sets up `--temp-dir`, removing what a crashed run left there, and warns
+/// about destinations it can't serve.
+fn use_temp_dir(watch_root: &Path, temp_dir: &Path, destinations: &[&Path]) -> anyhow::Result<()> {
+    let temp_dir = fs::canonicalize(temp_dir).with_context(|| format!("Failed to open --temp-dir {:?}", temp_dir))?;
+    if temp_dir.starts_with(watch_root) {
+        anyhow::bail!("--temp-dir {:?} is inside the watch root, so its temp files would be mirrored", temp_dir);
+    }
+    copy::set_temp_dir(&temp_dir)?;
+    let removed = copy::clean_temp_dir(&temp_dir).with_context(|| format!("Failed to clean --temp-dir {:?}", temp_dir))?;
+    if removed > 0 {
+        println!("Removed {} stale temp files from {:?}", removed, temp_dir);
+    }
+    for destination in destinations.iter().filter(|destination| !copy::temp_dir_reaches(destination)) {
+        eprintln!(
+            "Warning: --temp-dir {:?} is on a different filesystem from {:?}, whose temp files stay beside their destinations",
+            temp_dir, destination
+        );
+    }
+    Ok(())
 }
 
-fn handle_event_unknown(event: &notify::Event, path: &Path) {
-    eprintln!("Unknown[unsupported]: {:?} {:?}", path, event);
+/// `--relative-to`: where WATCH_ROOT goes under each destination, its path
+/// below `base`, which has to be an ancestor of it.
+fn relative_prefix(watch_root: &Path, base: &Path) -> anyhow::Result<PathBuf> {
+    let base = fs::canonicalize(base).with_context(|| format!("Failed to open --relative-to {:?}", base))?;
+    report::set_path_base(base.clone());
+    match watch_root.strip_prefix(&base) {
+        Ok(prefix) => Ok(prefix.to_path_buf()),
+        Err(_) => anyhow::bail!("--relative-to {:?} is not an ancestor of WATCH_ROOT {:?}", base, watch_root),
+    }
 }
 
-fn handle_event_other(_watch_root: &Path, _output_root: &Path, path: &Path) {
-    eprintln!("Other[unsupported]: {:?}", path);
+/// The host and path of an scp-style OUTPUT_ROOT, unless a local path by
+/// that name exists.
+fn remote_target(output_root: &Path) -> Option<SshTarget> {
+    match output_root.exists() {
+        true => None,
+        false => output_root.to_str().and_then(SshTarget::parse),
+    }
 }
 
-fn handle_event_modify_other(_watch_root: &Path, _output_root: &Path, path: &Path) {
-    eprintln!("Modify[unsupported][other]: {:?}", path);
+/// Fails on options that need OUTPUT_ROOT to be a plain local directory,
+/// naming what it is instead.
+fn check_backend_args(args: &Args, destination: &str) -> anyhow::Result<()> {
+    let local_only = [
+        ("--dest", !args.destinations.is_empty()),
+        ("--route", !args.route.is_empty()),
+        ("--compress-dest", args.compress_dest),
+        ("--encrypt-dest", args.encrypt_dest),
+        ("--merkle", args.merkle),
+        ("--verify-merkle", args.verify_merkle),
+        ("--check", args.check.is_some()),
+        ("--atomic-deploy", args.atomic_deploy),
+        ("--transaction-glob", !args.transaction_globs.is_empty()),
+        ("--metadata-sync", args.metadata_sync),
+        ("--min-free-space", args.min_free_space.is_some()),
+        ("--preserve-hardlinks-within-batch", args.preserve_hardlinks_within_batch),
+        ("--keep-dest-links", args.keep_dest_links),
+        ("--preserve-flags", args.preserve_flags),
+        ("--only-newer", args.only_newer),
+        ("--on-conflict", args.on_conflict.is_some()),
+        ("--dead-letter-after", args.dead_letter_after.is_some()),
+        ("--rebuild", args.rebuild),
+        ("--replay --apply", args.apply),
+    ];
+    let set: Vec<&str> = local_only.iter().filter(|(_, set)| *set).map(|(name, _)| *name).collect();
+    if !set.is_empty() {
+        anyhow::bail!("Not supported with {}: {}", destination, set.join(", "));
+    }
+    Ok(())
 }
 
-fn handle_event_create_other(_watch_root: &Path, _output_root: &Path, path: &Path) {
-    eprintln!("Created[unsupported][other]: {:?}", path);
+#[cfg(feature = "ssh")]
+fn connect_remote(target: SshTarget, args: &Args) -> anyhow::Result<Box<dyn Backend>> {
+    println!("Connecting to {}", target);
+    let backend = rustsync::remote::SshBackend::connect(target, args.ssh_port, args.ssh_key.clone())?;
+    Ok(Box::new(backend))
 }
 
-fn handle_event_create_hardlink(_watch_root: &Path, _output_root: &Path, path: &Path) {
-    eprintln!("Created[unsupported][hardlink]: {:?}", path);
+#[cfg(not(feature = "ssh"))]
+fn connect_remote(target: SshTarget, _args: &Args) -> anyhow::Result<Box<dyn Backend>> {
+    anyhow::bail!("{} is a remote destination, which needs rustsync built with --features ssh", target)
 }
 
-fn handle_event_delete(watch_root: &Path, output_root: &Path, path: &Path) {
-    println!("Deleted: {:?}", path);
-
-    let mirrored_path = match change_root(watch_root, output_root, path) {
-        Some(path) => path,
-        None => return handle_not_under_watch_error(watch_root, path),
+/// Validates what a run with these arguments would use, without watching or
+/// writing anything, and returns the number of problems found.
+fn check_config(args: &Args) -> usize {
+    let mut problems = Vec::new();
+    let mut directory = |label: &str, path: &Path| match fs::canonicalize(path) {
+        Ok(path) if path.is_dir() => {
+            println!("ok: {} {:?}", label, path);
+            Some(path)
+        }
+        Ok(path) => {
+            problems.push(format!("{} {:?} is not a directory", label, path));
+            None
+        }
+        Err(error) => {
+            problems.push(format!("{} {:?}: {}", label, path, error));
+            None
+        }
     };
 
-    let result = if mirrored_path.is_dir() {
-        fs::remove_dir_all(&mirrored_path)
-    } else {
-        fs::remove_file(&mirrored_path)
+    let watch_root = args.watch_root.as_deref().and_then(|path| directory("watch root", path));
+    let mut destinations = Vec::new();
+    match args.output_root.as_deref().and_then(remote_target) {
+        Some(target) => println!("note: output root {} is remote and isn't checked", target),
+        None => destinations.extend(args.output_root.as_deref().and_then(|path| directory("output root", path))),
+    }
+    for path in &args.destinations {
+        destinations.extend(directory("--dest", path));
+    }
+    let relative_to_applies = destinations.len();
+    for route in &args.route {
+        destinations.extend(directory("--route destination", &route.destination));
+    }
+    if let (Some(base), Some(watch_root)) = (&args.relative_to, &watch_root) {
+        match relative_prefix(watch_root, base) {
+            Ok(prefix) => {
+                println!("ok: --relative-to {:?}, mirroring into {:?} below OUTPUT_ROOT and each --dest", base, prefix);
+                for destination in &mut destinations[..relative_to_applies] {
+                    *destination = destination.join(&prefix);
+                }
+            }
+            Err(error) => problems.push(format!("{:#}", error)),
+        }
+    }
+
+    if let Some(watch_root) = &watch_root {
+        for destination in &destinations {
+            if destination != watch_root && destination.starts_with(watch_root) {
+                println!("note: destination {:?} is inside the watch root and won't be mirrored", destination);
+            }
+        }
+        let destinations: Vec<&Path> = destinations.iter().map(PathBuf::as_path).collect();
+        if let Err(error) = check_roots(watch_root, &destinations) {
+            problems.push(format!("{:#}", error));
+        }
+    }
+    for (i, destination) in destinations.iter().enumerate() {
+        for other in &destinations[i + 1..] {
+            if destination.starts_with(other) || other.starts_with(destination) {
+                problems.push(format!("Destinations {:?} and {:?} overlap", destination, other));
+            }
+        }
+    }
+
+    // Routes, priorities and transaction globs were compiled while parsing the arguments.
+    let mut transforms = Transforms::default();
+    let mut valid_transforms = 0;
+    for spec in &args.transforms {
+        match transforms.add_spec(spec) {
+            Ok(()) => valid_transforms += 1,
+            Err(error) => problems.push(format!("--transform {:?}: {:#}", spec, error)),
+        }
+    }
+    println!(
+        "ok: {} routes, {} priority rules, {} transforms, {} transaction groups",
+        args.route.len(),
+        args.priorities.len(),
+        valid_transforms,
+        args.transaction_globs.len()
+    );
+
+    if args.encrypt_dest {
+        match read_passphrase(args.encrypt_key_file.as_deref()) {
+            Ok(_) => println!("ok: --encrypt-dest passphrase"),
+            Err(error) => problems.push(format!("{:#}", error)),
+        }
+        #[cfg(unix)]
+        if let Some(key_file) = &args.encrypt_key_file {
+            use std::os::unix::fs::PermissionsExt;
+            if let Ok(metadata) = fs::metadata(key_file) {
+                if metadata.permissions().mode() & 0o077 != 0 {
+                    problems.push(format!("Key file {:?} must not be accessible by group or others", key_file));
+                }
+            }
+        }
+    }
+
+    let files = [("--journal", &args.journal), ("--control-socket", &args.control_socket), ("--pid-file", &args.pid_file)];
+    for (label, path) in files {
+        let parent = path.as_deref().and_then(Path::parent).filter(|parent| !parent.as_os_str().is_empty());
+        if let Some(parent) = parent {
+            if !parent.is_dir() {
+                problems.push(format!("{} directory {:?} doesn't exist", label, parent));
+            }
+        }
+    }
+
+    for problem in &problems {
+        println!("problem: {}", problem);
+    }
+    match problems.len() {
+        0 => println!("Config OK"),
+        count => println!("Config has {} problem(s)", count),
+    }
+    problems.len()
+}
+
+/// The --encrypt-dest passphrase, from `key_file` or $RUSTSYNC_PASSPHRASE.
+fn read_passphrase(key_file: Option<&Path>) -> anyhow::Result<Vec<u8>> {
+    let passphrase = match key_file {
+        Some(key_file) => fs::read(key_file).with_context(|| format!("Failed to read {:?}", key_file))?,
+        None => std::env::var("RUSTSYNC_PASSPHRASE")
+            .context("--encrypt-dest needs --encrypt-key-file or $RUSTSYNC_PASSPHRASE")?
+            .into_bytes(),
     };
+    let passphrase = passphrase.trim_ascii_end().to_vec();
+    if passphrase.is_empty() {
+        anyhow::bail!("The --encrypt-dest passphrase is empty");
+    }
+    Ok(passphrase)
+}
 
-    if let Err(error) = result {
-        eprintln!("Failed to delete {:?}: {}", mirrored_path, error);
+fn print_run_summary(started: Instant, format: SummaryFormat) {
+    let summary = metrics::run_summary(started.elapsed());
+    match format {
+        SummaryFormat::Text => println!("{}", summary),
+        SummaryFormat::Json => println!("{}", serde_json::to_string(&summary).unwrap_or_default()),
     }
 }
 
-fn handle_event_rename(watch_root: &Path, output_root: &Path, path: &Path, new_path: &Path) {
-    println!("Renamed: {:?} -> {:?}", path, new_path);
+/// Recomputes the Merkle root of everything in `output_root`, hashing every
+/// file afresh, and compares it with the one `--merkle` stored.
+fn verify_merkle(output_root: &Path) -> anyhow::Result<bool> {
+    let (algorithm, stored) = merkle::load_root(output_root)?;
+    let manifest = Manifest::build(output_root, algorithm)?;
+    let mut tree = MerkleTree::new(&manifest);
+    let computed = tree.root();
 
-    let mirrored_path = match change_root(watch_root, output_root, path) {
-        Some(path) => path,
-        None => return handle_not_under_watch_error(watch_root, path),
-    };
+    if computed == stored {
+        println!("Merkle root OK over {} files: {}", tree.len(), stored);
+        return Ok(true);
+    }
+    println!("Merkle root mismatch: stored {}, computed {}", stored, computed);
+    println!("OUTPUT_ROOT was changed outside rustsync");
+    Ok(false)
+}
+
+fn check_manifest(
+    output_root: &Path,
+    manifest_path: &Path,
+    algorithm: ChecksumAlgorithm,
+    compressed: bool,
+    encryption: Option<&Encryption>,
+    hash_cache: bool,
+) -> anyhow::Result<bool> {
+    let expected = Manifest::load(manifest_path)?;
+    if expected.algorithm != algorithm {
+        anyhow::bail!(
+            "Manifest {:?} was built with {} but --checksum-algorithm is {}",
+            manifest_path,
+            expected.algorithm,
+            algorithm
+        );
+    }
 
-    let mirrored_new_path = match change_root(watch_root, output_root, new_path) {
-        Some(path) => path,
-        None => return handle_not_under_watch_error(watch_root, new_path),
+    let mut cache = open_hash_cache(output_root, algorithm, hash_cache);
+    let actual = match (compressed, encryption) {
+        (_, Some(encryption)) => Manifest::build_decrypted(output_root, algorithm, encryption, cache.as_mut())?,
+        (true, None) => Manifest::build_decompressed(output_root, algorithm, cache.as_mut())?,
+        (false, None) => Manifest::build_cached(output_root, algorithm, cache.as_mut())?,
     };
+    save_hash_cache(cache);
+    let differences = expected.compare(&actual)?;
 
-    if let Err(error) = fs::rename(&mirrored_path, &mirrored_new_path) {
-        eprintln!("Failed to rename {:?} -> {:?}: {}", mirrored_path, mirrored_new_path, error);
+    if is_case_insensitive(output_root).unwrap_or(false) {
+        println!("Destination is case-insensitive");
+        let mut seen: HashMap<String, &Path> = HashMap::new();
+        let mut conflicts = BTreeSet::new();
+        for relative in expected.entries.keys() {
+            for name in relative.ancestors().filter(|name| !name.as_os_str().is_empty()) {
+                let previous = *seen.entry(name.to_string_lossy().to_lowercase()).or_insert(name);
+                if previous != name {
+                    conflicts.insert((previous, name));
+                }
+            }
+        }
+        for (previous, name) in conflicts {
+            println!("Case conflict: {:?} and {:?} can't both exist on it", previous, name);
+        }
+    }
+
+    for difference in &differences {
+        match difference {
+            Difference::Missing(path) => println!("Missing: {:?}", path),
+            Difference::Extra(path) => println!("Extra: {:?}", path),
+            Difference::Mismatch(path) => println!("Mismatch: {:?}", path),
+        }
     }
+
+    println!("Checked {} entries, {} differences", expected.entries.len(), differences.len());
+    Ok(differences.is_empty())
 }
 
-fn handle_event_metadata(watch_root: &Path, output_root: &Path, path: &Path) {
-    println!("Modify[metadata]: {:?}", path);
+fn replay_journal(journal_path: &Path, mirror: Option<&Mirror>, skip_corrupt: bool) -> anyhow::Result<()> {
+    for record in read_records(journal_path)? {
+        let record = match record {
+            Ok(record) => record,
+            Err(error) if skip_corrupt => {
+                eprintln!("Skipping corrupt record: {:#}", error);
+                continue;
+            }
+            Err(error) => return Err(error),
+        };
 
-    let mirrored_path = match change_root(watch_root, output_root, path) {
-        Some(path) => path,
-        None => return handle_not_under_watch_error(watch_root, path),
-    };
+        match mirror {
+            Some(mirror) => apply_event(mirror, &record.operation),
+            None => println!("{} {}", record.timestamp, record.operation),
+        }
+    }
+    Ok(())
+}
+
+/// Watches and syncs a newly mounted filesystem, or stops a vanished one's
+/// contents from being deleted from the mirror.
+fn follow_mount(mirror: &Mirror, watcher: &mut WatchHandle, change: MountChange) {
+    match change {
+        MountChange::Mounted(path) => {
+            println!("Mounted: {:?}", path);
+            let relative = path.strip_prefix(&mirror.watch_root).unwrap_or(&path);
+            remounted(mirror, relative);
+            if let Err(error) = watcher.add(&path) {
+                eprintln!("Failed to watch new mount {:?}: {}", path, error);
+            }
+            println!("Mount sync complete: {}", reconcile_under(mirror, relative));
+        }
+        MountChange::Unmounted(path) => {
+            println!("Unmounted: {:?}, keeping its mirrored contents", path);
+            unmounted(mirror, path.strip_prefix(&mirror.watch_root).unwrap_or(&path));
+        }
+    }
+}
 
-    let metadata = match fs::metadata(path) {
-        Ok(metadata) => metadata,
-        Err(error) => return eprintln!("Failed to read metadata for {:?}: {}", path, error),
+fn handle_control(mirror: &Mirror, fan_out: Option<&FanOut>, request: ControlRequest) {
+    let response = match request.command {
+        Command::Pause => {
+            pause(mirror);
+            println!("Paused by {}", request.origin);
+            serde_json::json!({ "paused": true })
+        }
+        Command::Resume => {
+            resume(mirror);
+            println!("Resumed by {}", request.origin);
+            serde_json::json!({ "paused": false })
+        }
+        Command::Resync => {
+            let summary = reconcile(mirror);
+            println!("Sync complete: {}", summary);
+            if let Some(fan_out) = fan_out {
+                fan_out.reconcile();
+            }
+            serde_json::json!({ "resync": summary.to_string() })
+        }
+        Command::ConfirmDeletes => {
+            let deleted = confirm_deletes(mirror);
+            println!("Deletes confirmed by {}: {} deleted", request.origin, deleted);
+            serde_json::json!({ "deleted": deleted })
+        }
+        Command::RetryDeadLetters => {
+            let retried = retry_dead_letters(mirror);
+            println!("Dead letters retried by {}: {}", request.origin, retried);
+            serde_json::json!({ "retried": retried })
+        }
+        Command::Status => serde_json::json!({
+            "paused": is_paused(mirror),
+            "queue_depth": queue_depth(mirror),
+            "copy_queue": queued_copies(mirror),
+            "blocked_deletes": blocked_deletes(mirror),
+            "errors": report::error_counts(),
+            "metrics": metrics::snapshot(),
+            "merkle_root": merkle_root(mirror),
+            "dead_letters": mirror.dead_letters.as_ref().map(DeadLetters::list).unwrap_or_default(),
+            "recent": recent_operations(mirror),
+        }),
     };
+    let _ = request.reply.send(response.to_string());
+}
+
+fn sync_once(mirror: &Mirror) -> i32 {
+    let summary = reconcile(mirror);
+    flush_merkle(mirror);
+    report::flush_throttled(true);
+    let errors: u64 = report::error_counts().values().sum();
 
-    if let Err(error) = fs::set_permissions(&mirrored_path, metadata.permissions()) {
-        eprintln!("Failed to set permissions for {:?}: {}", mirrored_path, error);
+    println!(
+        "summary files_copied={} bytes_copied={} created={} deleted={} metadata_updated={} errors={} elapsed_ms={}",
+        summary.files_copied,
+        summary.bytes_copied,
+        summary.created,
+        summary.deleted,
+        summary.metadata_updated,
+        errors,
+        summary.elapsed.as_millis()
+    );
+
+    match (errors, summary.changes()) {
+        (0, 0) => 0,
+        (0, _) => 2,
+        _ => 1,
     }
+}
 
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::MetadataExt;
+/// Control files holding per-file state from earlier runs, which a rebuild
+/// starts without.
+const PER_FILE_STATE: &[&str] = &["written", "deadletter", "self-writes", "partial"];
 
-        let atime = FileTime::from_unix_time(metadata.atime(), metadata.atime_nsec() as u32);
-        let mtime = FileTime::from_unix_time(metadata.mtime(), metadata.mtime_nsec() as u32);
+/// `--rebuild`: empties OUTPUT_ROOT but for its control directory, and that
+/// of per-file state, syncs it afresh, then checks every file against a new
+/// manifest of the source, saved as `.rustsync/manifest` along with a new
+/// Merkle root. A marker in the control directory while it runs lets an
+/// interrupted rebuild resume without emptying the mirror again. Returns
+/// whether every file verified.
+fn rebuild(mirror: &Mirror, algorithm: ChecksumAlgorithm, force: bool) -> anyhow::Result<bool> {
+    let control = mirror.output_root.join(CONTROL_DIR);
+    let marker = control.join("rebuild");
 
-        if let Err(error) = filetime::set_file_times(&mirrored_path, atime, mtime) {
-            eprintln!("Failed to set timestamps for {:?}: {}", mirrored_path, error);
+    if marker.exists() {
+        println!("Resuming the rebuild of {:?}", mirror.output_root);
+    } else {
+        // An unmounted or missing source would leave an empty mirror.
+        let mut sources = fs::read_dir(&mirror.watch_root).with_context(|| format!("Failed to read {:?}", mirror.watch_root))?;
+        if !sources.any(|entry| entry.is_ok_and(|entry| entry.file_name() != CONTROL_DIR)) {
+            anyhow::bail!("Watch root {:?} is empty; is it mounted? Not rebuilding from it", mirror.watch_root);
         }
-
-        let c_path = match CString::new(mirrored_path.as_os_str().as_bytes()) {
-            Ok(path) => path,
-            Err(error) => {
-                eprintln!("Failed to convert path for chown {:?}: {}", mirrored_path, error);
-                return;
+        if !force {
+            print!("This deletes everything in {:?} and copies {:?} again. Type 'rebuild' to go ahead: ", mirror.output_root, mirror.watch_root);
+            std::io::Write::flush(&mut std::io::stdout())?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if answer.trim() != "rebuild" {
+                anyhow::bail!("Rebuild cancelled");
             }
-        };
+        }
+        fs::create_dir_all(&control)?;
+        fs::write(&marker, format!("{}\n", report::unix_timestamp()))?;
 
-        unsafe {
-            if libc::chown(c_path.as_ptr(), metadata.uid(), metadata.gid()) != 0 {
-                eprintln!("Failed to set owner/group for {:?}", mirrored_path);
+        let mut removed = 0;
+        for entry in fs::read_dir(&mirror.output_root)? {
+            let path = entry?.path();
+            if path.file_name() == Some(CONTROL_DIR.as_ref()) {
+                continue;
             }
+            match path.is_dir() && !path.is_symlink() {
+                true => fs::remove_dir_all(&path),
+                false => fs::remove_file(&path),
+            }
+            .with_context(|| format!("Failed to remove {:?}", path))?;
+            removed += 1;
         }
+        let _ = fs::remove_file(merkle::root_path(&mirror.output_root));
+        for name in PER_FILE_STATE {
+            let path = control.join(name);
+            match path.is_dir() {
+                true => fs::remove_dir_all(&path),
+                false => fs::remove_file(&path),
+            }
+            .or_else(|error| match error.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(error),
+            })
+            .with_context(|| format!("Failed to remove {:?}", path))?;
+        }
+        println!("Emptied {:?} ({} entries removed)", mirror.output_root, removed);
+    }
+
+    println!("Sync complete: {}", reconcile(mirror));
+    report::flush_throttled(true);
+
+    // Fresh hashes on both sides: the point is not to trust earlier state.
+    let mut expected = Manifest::build(&mirror.watch_root, algorithm)?;
+    expected.apply_transforms(&mirror.watch_root, &mirror.options.transforms)?;
+    expected.entries.retain(|relative, _| !is_ignored(mirror, &mirror.watch_root.join(relative)));
+    let manifest_path = control.join("manifest");
+    expected.save(&manifest_path)?;
+    let verified = check_manifest(
+        &mirror.output_root,
+        &manifest_path,
+        algorithm,
+        mirror.options.compress.is_some(),
+        mirror.options.encrypt.as_ref(),
+        false,
+    )?;
+
+    let mut tree = MerkleTree::new(&Manifest::build(&mirror.output_root, algorithm)?);
+    let root = tree.root().to_string();
+    merkle::save_root(&mirror.output_root, algorithm, &root)?;
+    println!("Wrote {:?} and Merkle root {}", manifest_path, root);
+
+    let errors: u64 = report::error_counts().values().sum();
+    if !verified || errors > 0 {
+        println!("Rebuild incomplete: {} errors; run --rebuild again to retry what's missing", errors);
+        return Ok(false);
     }
+    fs::remove_file(&marker)?;
+    println!("Rebuild complete");
+    Ok(true)
+}
 
-    #[cfg(windows)]
-    {
-        use std::os::windows::fs::MetadataExt;
+fn dry_run(mirror: &Mirror, diffs: Option<DiffPrinter>) {
+    let (operations, summary) = plan(mirror);
 
-        let atime =
-            FileTime::from_seconds_since_1970(metadata.last_access_time() / 10_000_000, 0);
-        let mtime =
-            FileTime::from_seconds_since_1970(metadata.last_write_time() / 10_000_000, 0);
+    for operation in &operations {
+        println!("{}", operation);
+    }
 
-        if let Err(error) = filetime::set_file_times(&mirrored_path, atime, mtime) {
-            eprintln!("Failed to set timestamps for {:?}: {}", mirrored_path, error);
+    if let Some(mut diffs) = diffs {
+        for operation in &operations {
+            diffs.operation(mirror, operation);
         }
+        diffs.finish();
     }
+
+    println!("Dry run: {}", summary);
 }
 
-fn handle_event_create_symlink(watch_root: &Path, output_root: &Path, path: &Path) {
-    println!("Created[symlink]: {:?}", path);
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    report::set_log_level(args.log_level);
+    report::set_log_throttle(args.log_throttle);
+    hash::set_mmap_threshold(args.mmap_threshold);
+    if let Some(jobs) = args.jobs {
+        hash::set_jobs(jobs as usize);
+    }
+    if let Some(bytes) = args.max_inflight_bytes {
+        inflight::set_limit(bytes);
+    }
 
-    let mirrored_path = match change_root(watch_root, output_root, path) {
-        Some(path) => path,
-        None => return handle_not_under_watch_error(watch_root, path),
-    };
+    if args.clear_hash_cache {
+        hashcache::clear()?;
+        println!("Cleared hash cache");
+        return Ok(());
+    }
 
-    let original_target = match fs::read_link(path) {
-        Ok(target) => target,
-        Err(error) => {
-            eprintln!("Failed to read symlink {:?}: {}", path, error);
-            return;
+    if args.check_config {
+        std::process::exit(match check_config(&args) {
+            0 => 0,
+            _ => 1,
+        });
+    }
+
+    if let (Some(journal_path), false) = (&args.replay, args.apply) {
+        return replay_journal(journal_path, None, args.skip_corrupt);
+    }
+
+    if let Some(destination) = &args.cas_checkout {
+        let store = args.watch_root.as_deref().context("The store to check out is required")?;
+        let files = cas::checkout(store, destination)?;
+        println!("Checked out {} files to {:?}", files, destination);
+        return Ok(());
+    }
+
+    if let (Some(manifest_path), Some(bundle)) = (&args.from_manifest, &args.from_bundle) {
+        // There's no source to watch, so the one root given is OUTPUT_ROOT.
+        let output_root = match (&args.watch_root, &args.output_root) {
+            (Some(output_root), None) => output_root,
+            _ => anyhow::bail!("--from-manifest takes OUTPUT_ROOT alone"),
+        };
+        let manifest = Manifest::load(manifest_path)?;
+        let summary = apply_bundle(&manifest, bundle, output_root)?;
+        for relative in &summary.missing {
+            println!("Missing from bundle: {:?}", relative);
         }
+        println!("Bundle applied: {}", summary);
+        std::process::exit(match summary.is_complete() {
+            true => 0,
+            false => 1,
+        });
+    }
+
+    let watch_root = fs::canonicalize(args.watch_root.as_deref().context("WATCH_ROOT is required")?)?;
+
+    if args.list {
+        return list(watch_root, &args);
+    }
+    if let Some(snapshot_dir) = &args.snapshot_dir {
+        return snapshot(watch_root, snapshot_dir, &args);
+    }
+    let given_output = args.output_root.clone().context("OUTPUT_ROOT is required")?;
+    let mut output_path = given_output.clone();
+    let mut remote = remote_target(&output_path);
+    let prefix = match &args.relative_to {
+        Some(base) => Some(relative_prefix(&watch_root, base)?),
+        None => None,
+    };
+    if let Some(prefix) = &prefix {
+        output_path = output_path.join(prefix);
+        remote = remote.and_then(|_| remote_target(&output_path));
+        for destination in &mut args.destinations {
+            *destination = destination.join(prefix);
+        }
+    }
+    // --relative-to's directories are only created once the sync starts, so
+    // until then the prefix goes on the resolved OUTPUT_ROOT.
+    let output_root = match (&remote, &prefix) {
+        (Some(target), _) => PathBuf::from(target.to_string()),
+        (None, Some(prefix)) => fs::canonicalize(&given_output)?.join(prefix),
+        (None, None) => fs::canonicalize(&output_path)?,
+    };
+    let backend: Option<Box<dyn Backend>> = match remote.clone() {
+        Some(_) if args.cas => anyhow::bail!("--cas needs a local OUTPUT_ROOT"),
+        Some(target) => {
+            check_backend_args(&args, "a remote OUTPUT_ROOT")?;
+            Some(connect_remote(target, &args)?)
+        }
+        None if args.cas => {
+            check_backend_args(&args, "--cas")?;
+            Some(Box::new(CasStore::open(&output_root, args.checksum_algorithm)?))
+        }
+        None => None,
     };
 
-    let mirrored_target =
-        change_root(watch_root, output_root, &original_target).unwrap_or(original_target);
+    let encryption = match args.encrypt_dest {
+        true => Some(Encryption::new(
+            &read_passphrase(args.encrypt_key_file.as_deref())?,
+            &output_root,
+            args.encrypt_names,
+            args.checksum_algorithm,
+        )?),
+        false => None,
+    };
 
-    if let Err(error) = cross_platform_symlink(&mirrored_target, &mirrored_path) {
-        eprintln!("Failed to create symlink {:?} -> {:?}: {}", mirrored_path, mirrored_target, error);
+    let mut transforms = Transforms::default();
+    for spec in &args.transforms {
+        transforms.add_spec(spec)?;
+    }
+
+    if let Some(manifest_path) = &args.manifest {
+        return write_manifest(&watch_root, manifest_path, args.checksum_algorithm, &transforms, !args.no_hash_cache);
+    }
+
+    if args.verify_merkle {
+        if !verify_merkle(&output_root)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(manifest_path) = &args.check {
+        if !check_manifest(
+            &output_root,
+            manifest_path,
+            args.checksum_algorithm,
+            args.compress_dest,
+            encryption.as_ref(),
+            !args.no_hash_cache,
+        )? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if prefix.is_some() && !(args.dry_run || args.dry_run_diff) {
+        let local_paths = remote.is_none().then_some(&output_path).into_iter().chain(&args.destinations);
+        for path in local_paths {
+            fs::create_dir_all(path).with_context(|| format!("Failed to create {:?}", path))?;
+        }
     }
-}
 
-fn sync_file_to_mirror(watch_root: &Path, output_root: &Path, path: &Path, event_label: &str) {
-    println!("{}: {:?}", event_label, path);
+    let mut deploy = match args.atomic_deploy {
+        true => Some(AtomicDeploy::new(&output_path, args.settle_time, &args.preserve, args.preserve_flags)?),
+        false => None,
+    };
+
+    let output_root = match &deploy {
+        Some(deploy) => fs::canonicalize(&deploy.staging)?,
+        None => output_root,
+    };
+
+    let routes = args
+        .route
+        .into_iter()
+        .map(|mut route| {
+            route.destination = fs::canonicalize(&route.destination)
+                .with_context(|| format!("Failed to open --route destination {:?}", route.destination))?;
+            Ok(route)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut destinations: Vec<&Path> = remote.is_none().then_some(output_root.as_path()).into_iter().collect();
+    destinations.extend(routes.iter().map(|route| route.destination.as_path()));
+    destinations.extend(args.destinations.iter().map(PathBuf::as_path));
+    check_roots(&watch_root, &destinations)?;
+    if let Some(temp_dir) = &args.temp_dir {
+        use_temp_dir(&watch_root, temp_dir, &destinations)?;
+    }
 
-    let mirrored_path = match change_root(watch_root, output_root, path) {
-        Some(path) => path,
-        None => return handle_not_under_watch_error(watch_root, path),
+    let mut options = Options {
+        preserve: args.preserve,
+        min_free_space: args.min_free_space,
+        reflink: args.reflink,
+        directory_metadata_window: args.dir_metadata_window,
+        max_queue: args.max_queue,
+        fsync: args.fsync,
+        strip_setuid: args.no_setuid,
+        routes,
+        sync_on_close: args.sync_on_close,
+        changes: match (args.sync_content_only, args.metadata_only) {
+            (true, _) => Changes::Content,
+            (_, true) => Changes::Metadata,
+            _ => Changes::All,
+        },
+        transforms,
+        delete_limit: DeleteLimit {
+            max_deletes: args.max_deletes,
+            max_percent: args.max_delete_percent,
+            window: args.delete_window,
+            force: args.force,
+        },
+        copy_order: args.copy_order,
+        priorities: args.priorities,
+        exclude_vcs: args.exclude_vcs,
+        case_suffix: args.case_conflict_suffix,
+        transaction_globs: args.transaction_globs,
+        transaction_settle: args.transaction_settle,
+        preserve_hardlinks: args.preserve_hardlinks_within_batch,
+        keep_dest_links: args.keep_dest_links,
+        stable_time: args.stable_time,
+        stable_checks: args.stable_checks,
+        debounce: args.debounce,
+        debounce_default: args.debounce_default,
+        dangling_symlinks: args.dangling_symlinks,
+        locked: match (args.skip_locked, args.wait_locked) {
+            (true, _) => LockedFiles::Skip,
+            // --once has no later pass to retry in, so the lock is an error.
+            (_, true) if !args.once => LockedFiles::Wait,
+            _ => LockedFiles::Error,
+        },
+        max_depth: args.max_depth,
+        atime: args.atime,
+        delete_batch_window: args.delete_batch_window,
+        require_utf8: args.require_utf8,
+        preserve_flags: args.preserve_flags,
+        only_newer: args.only_newer.then_some(args.only_newer_tolerance),
+        atomic_deploy: args.atomic_deploy,
+        age: AgeFilter {
+            newer_than: args.newer_than,
+            older_than: args.older_than,
+        },
+        require_times: args.require_times,
+        id_map: match (args.map_root_uid_via_subuid, args.subuid_range) {
+            (false, _) => None,
+            (true, Some(range)) => Some(IdMap::new(range, range)),
+            (true, None) => Some(IdMap::for_current_user()?),
+        },
+        compress: args.compress_dest.then(|| Compression {
+            level: args.compress_level,
+            min_size: args.compress_min_size,
+            skip_extensions: args.compress_skip.iter().map(|extension| extension.to_lowercase()).collect(),
+            algorithm: args.checksum_algorithm,
+        }),
+        encrypt: encryption,
     };
+    if !(args.dry_run || args.dry_run_diff) {
+        let mut destinations: Vec<PathBuf> = remote.is_none().then(|| output_root.clone()).into_iter().collect();
+        destinations.extend(options.routes.iter().map(|route| route.destination.clone()));
+        destinations.extend(args.destinations.iter().cloned());
+        preflight(&destinations, &mut options, args.strict)?;
+    }
+    let mut mirror = Mirror::new(watch_root, output_root, options.clone());
+    mirror.backend = backend;
 
-    if let Some(parent) = mirrored_path.parent() {
-        if let Err(error) = fs::create_dir_all(parent) {
-            eprintln!("Failed to create parent dirs for {:?}: {}", mirrored_path, error);
-            return;
+    if let Some(journal_path) = &args.replay {
+        return replay_journal(journal_path, Some(&mirror), args.skip_corrupt);
+    }
+
+    if let Some(journal_path) = &args.journal {
+        mirror.journal = Some(Journal::open(journal_path)?);
+    }
+
+    if args.rebuild {
+        std::process::exit(match rebuild(&mirror, args.checksum_algorithm, args.force)? {
+            true => 0,
+            false => 1,
+        });
+    }
+
+    // A dry run writes nothing, the conflict index included.
+    if let (Some(policy), false) = (args.on_conflict, args.dry_run || args.dry_run_diff) {
+        mirror.conflicts = Some(ConflictLog::open(&mirror.output_root, policy)?);
+    }
+
+    if let (Some(after), false) = (args.dead_letter_after, args.dry_run || args.dry_run_diff) {
+        mirror.dead_letters = Some(DeadLetters::open(&mirror.output_root, after)?);
+        if args.retry_deadletter {
+            println!("Retrying {} dead-lettered operations", retry_dead_letters(&mirror));
         }
     }
+    if let (Some(window), false) = (args.echo_window, args.dry_run || args.dry_run_diff) {
+        mirror.self_writes = Some(Arc::new(SelfWrites::shared(window, &mirror.output_root, &mirror.watch_root)));
+    }
 
-    if let Err(error) = fs::copy(path, &mirrored_path) {
-        eprintln!("Failed to copy file {:?} -> {:?}: {}", path, mirrored_path, error);
+    if args.trace_events {
+        mirror.trace = Some(EventTrace::new(&args.trace_globs)?);
     }
-}
 
-fn handle_event_create_regularfile(watch_root: &Path, output_root: &Path, path: &Path) {
-    sync_file_to_mirror(watch_root, output_root, path, "Created[file]");
-}
+    if args.merkle {
+        let mut cache = open_hash_cache(&mirror.output_root, args.checksum_algorithm, !args.no_hash_cache);
+        let manifest = Manifest::build_cached(&mirror.output_root, args.checksum_algorithm, cache.as_mut())?;
+        save_hash_cache(cache);
+        let mut tree = MerkleTree::new(&manifest);
+        let root = tree.root().to_string();
+        // Nothing should write to the mirror while rustsync isn't running.
+        let root_path = merkle::root_path(&mirror.output_root);
+        match merkle::load_root(&mirror.output_root) {
+            Ok((algorithm, stored)) if algorithm == tree.algorithm && stored != root => report::error(
+                ErrorKind::Merkle,
+                &root_path,
+                format!(
+                    "OUTPUT_ROOT changed while rustsync wasn't running: its Merkle root is {}, not the stored {}",
+                    root, stored
+                ),
+            ),
+            Err(error) if root_path.exists() => report::error(ErrorKind::Merkle, &root_path, format!("{:#}", error)),
+            _ => {}
+        }
+        merkle::save_root(&mirror.output_root, tree.algorithm, &root)?;
+        println!("Merkle root over {} files: {}", tree.len(), root);
+        mirror.merkle = Some(Mutex::new(tree));
+    }
 
-fn handle_event_data(watch_root: &Path, output_root: &Path, path: &Path) {
-    sync_file_to_mirror(watch_root, output_root, path, "Modified[file]");
-}
+    if args.dry_run || args.dry_run_diff {
+        let diffs = args.dry_run_diff.then(|| DiffPrinter::new(args.diff_max_size, args.diff_max_lines));
+        dry_run(&mirror, diffs);
+        return Ok(());
+    }
+
+    // Hooks and notifications run on threads of their own, so they start
+    // only once no fork (--daemonize) or signal mask (shutdown_flag) is still
+    // to come, and whatever they have waiting goes out before rustsync exits.
+    let start_background = |mirror: &mut Mirror| {
+        if !args.on_change.is_empty() {
+            mirror.hooks = Some(HookRunner::new(args.on_change.clone(), args.hook_debounce));
+        }
 
-fn handle_event_create_dir(watch_root: &Path, output_root: &Path, path: &Path) {
-    println!("Created[dir]: {:?}", path);
+        let notify_window = Duration::from_secs(args.notify_interval);
+        if let Some(url) = &args.notify_webhook {
+            report::add_sink(Box::new(WebhookSink::new(url.clone(), notify_window)));
+        }
 
-    let mirrored_path = match change_root(watch_root, output_root, path) {
-        Some(path) => path,
-        None => return handle_not_under_watch_error(watch_root, path),
+        #[cfg(feature = "desktop-notify")]
+        if args.desktop_notify {
+            report::add_sink(Box::new(rustsync::alert::DesktopSink::new(notify_window)));
+        }
+    };
+    let finish_background = |mirror: &mut Mirror| {
+        if let Some(hooks) = mirror.hooks.take() {
+            hooks.finish();
+        }
+        report::finish_sinks();
     };
 
-    if let Err(error) = fs::create_dir(&mirrored_path) {
-        handle_create_dir_error(&mirrored_path, &error);
+    if args.once {
+        start_background(&mut mirror);
+        let code = sync_once(&mirror);
+        finish_background(&mut mirror);
+        std::process::exit(code);
     }
-}
 
-fn handle_event_create_file(watch_root: &Path, output_root: &Path, path: &Path) {
-    let metadata = match fs::metadata(path) {
-        Ok(metadata) => metadata,
-        Err(error) => return handle_get_metadata_error(path, &error),
+    if args.metadata_sync {
+        start_background(&mut mirror);
+        println!("Metadata sync: {}", metadata_sync(&mirror));
+        report::flush_throttled(true);
+        finish_background(&mut mirror);
+        return Ok(());
+    }
+
+    if let Some(pid_path) = &args.pid_file {
+        PidFile::check(pid_path)?;
+    }
+
+    if args.daemonize {
+        let log_file = match &args.log_file {
+            Some(log_file) => log_file.clone(),
+            None => default_rustsync_dir()?.join("filesync.log"),
+        };
+        daemonize(&log_file)?;
+    }
+
+    let _pid_file = match &args.pid_file {
+        Some(pid_path) => Some(PidFile::acquire(pid_path)?),
+        None => None,
     };
 
-    let link_count = {
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::MetadataExt;
-            metadata.nlink()
+    let shutdown = shutdown_flag();
+    start_background(&mut mirror);
+
+    let fan_out = match args.destinations.is_empty() {
+        true => None,
+        false => {
+            let destinations = args
+                .destinations
+                .iter()
+                .map(|destination| {
+                    fs::canonicalize(destination).with_context(|| format!("Failed to open --dest {:?}", destination))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Some(FanOut::new(mirror.watch_root.clone(), destinations, options))
         }
-        #[cfg(windows)]
-        {
-            use std::os::windows::fs::MetadataExt;
-            metadata.number_of_links()
+    };
+
+    let control = match &args.control_socket {
+        Some(socket_path) => Some(control::listen(socket_path)?),
+        None => None,
+    };
+
+    #[cfg(feature = "web-ui")]
+    let web = match args.web_addr {
+        Some(address) => {
+            let (bound, requests) = rustsync::web::serve(address)?;
+            println!("Web UI on http://{}", bound);
+            Some(requests)
         }
+        None => None,
     };
+    #[cfg(not(feature = "web-ui"))]
+    let web: Option<std::sync::mpsc::Receiver<ControlRequest>> = None;
 
-    if link_count > 1 {
-        handle_event_create_hardlink(watch_root, output_root, path);
-    } else {
-        handle_event_create_regularfile(watch_root, output_root, path);
+    let health = match args.health_addr {
+        Some(address) => {
+            let health = Arc::new(Health::default());
+            println!("Health probes on http://{}", health::serve(address, health.clone())?);
+            Some(health)
+        }
+        None => None,
+    };
+
+    let (sender, receiver) = channel();
+    let mut watcher = match args.no_watch {
+        true => None,
+        false => Some(watch(&mirror.watch_root, sender.clone(), args.watcher_backend, args.poll_interval)?),
+    };
+    let mut mounts = args.follow_new_mounts.then(|| MountWatcher::new(&mirror.watch_root));
+    let mut trickle = args.trickle.then(|| {
+        let (mut operations, mut summary) = plan(&mirror);
+        limit_deletes(&mirror, &mut operations, &mut summary);
+        let limit = TrickleLimit {
+            files: Some(args.trickle_files),
+            bytes: args.trickle_bytes,
+        };
+        let trickle = Trickle::new(&mirror.watch_root, operations, limit);
+        println!("Trickling startup sync: {}", trickle);
+        trickle
+    });
+
+    let mut next_reconcile = args.interval.map(|_| Instant::now());
+    // Ready once the watcher is up and the startup sync, if any, is done.
+    let mut startup_synced = next_reconcile.is_none();
+    let started = Instant::now();
+    let mut next_summary = args.summary_interval.map(|interval| started + interval);
+    let mut window_open = None;
+
+    println!("Outputting to {:?}", mirror.output_root);
+    for output_root in fan_out.iter().flat_map(|fan_out| fan_out.output_roots()) {
+        println!("Outputting to {:?}", output_root);
     }
-}
+    println!("(Ctrl+C to quit)");
 
-fn handle_event(watch_root: &Path, output_root: &Path, event: &notify::Event) {
-    let event_kind = &event.kind;
-    let paths = &event.paths;
-    let path = &paths[0];
-
-    match event_kind {
-        EventKind::Other => handle_event_other(watch_root, output_root, path),
-        EventKind::Remove(_) => handle_event_delete(watch_root, output_root, path),
-        EventKind::Modify(modify_kind) => match modify_kind {
-            ModifyKind::Other => handle_event_modify_other(watch_root, output_root, path),
-            ModifyKind::Name(RenameMode::Both) => {
-                handle_event_rename(watch_root, output_root, path, &paths[1])
-            }
-            ModifyKind::Metadata(MetadataKind::Any) => {
-                handle_event_metadata(watch_root, output_root, path)
-            }
-            ModifyKind::Data(DataChange::Any) => handle_event_data(watch_root, output_root, path),
-            _ => {}
-        },
-        EventKind::Create(_) => {
-            if path.is_symlink() {
-                handle_event_create_symlink(watch_root, output_root, path);
-            } else if path.is_file() {
-                handle_event_create_file(watch_root, output_root, path);
-            } else if path.is_dir() {
-                handle_event_create_dir(watch_root, output_root, path);
-            } else {
-                handle_event_create_other(watch_root, output_root, path);
+    while !shutdown.load(Ordering::SeqCst) {
+        // Waiting copies run one a turn, and without waiting for events.
+        let timeout = match has_queued_copies(&mirror) {
+            true => Duration::ZERO,
+            false => Duration::from_millis(200),
+        };
+        match receiver.recv_timeout(timeout) {
+            Ok(Ok(event)) => {
+                handle_event(&mirror, &event);
+                if let Some(fan_out) = &fan_out {
+                    fan_out.handle_event(&event);
+                }
+                if let Some(deploy) = &mut deploy {
+                    deploy.changed();
+                }
             }
+            Ok(Err(error)) => handle_watch_error(&error),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
         }
-        EventKind::Access(_) => {}
-        _ => handle_event_unknown(event, path),
-    }
-}
+        run_queued_copy(&mirror);
 
-#[derive(Parser)]
-struct Args {
-    watch_root: PathBuf,
-    output_root: PathBuf,
-}
+        for requests in [&control, &web].into_iter().flatten() {
+            while let Ok(request) = requests.try_recv() {
+                handle_control(&mirror, fan_out.as_ref(), request);
+            }
+        }
 
-fn main() -> notify::Result<()> {
-    let args = Args::parse();
-    let watch_root = fs::canonicalize(PathBuf::from(args.watch_root))?;
-    let output_root = fs::canonicalize(PathBuf::from(args.output_root))?;
+        resume_pending(&mirror);
 
-    let (sender, receiver) = channel();
-    let mut watcher: RecommendedWatcher = Watcher::new(sender, notify::Config::default())?;
+        if let Some(window) = &args.active_window {
+            let open = window.is_open();
+            if window_open != Some(open) {
+                match open {
+                    true => println!("Active window {} open, full syncs enabled", window),
+                    false => println!("Active window {} closed, full syncs deferred", window),
+                }
+                window_open = Some(open);
+            }
+        }
 
-    watcher.watch(&watch_root, RecursiveMode::Recursive)?;
+        if let (Some(mounts), Some(watcher)) = (&mut mounts, &mut watcher) {
+            for change in mounts.poll() {
+                follow_mount(&mirror, watcher, change);
+            }
+        }
+        if let Some(pending) = &mut trickle {
+            let due = pending.due();
+            if !due.is_empty() {
+                for operation in due {
+                    apply_planned(&mirror, operation);
+                }
+                println!("Trickle: {}", pending);
+            }
+            if pending.is_done() {
+                println!("Trickle complete");
+                trickle = None;
+            }
+        }
+        if let (Some(due), Some(interval)) = (next_reconcile, args.interval) {
+            if due <= Instant::now() && window_open != Some(false) {
+                println!("Sync complete: {}", reconcile(&mirror));
+                if let Some(fan_out) = &fan_out {
+                    fan_out.reconcile();
+                }
+                next_reconcile = Some(Instant::now() + interval);
+                startup_synced = true;
+            }
+        }
+        if let Some(health) = &health {
+            health.beat();
+            if startup_synced && trickle.is_none() {
+                health.set_ready();
+            }
+        }
+        flush_held(&mirror);
+        report::flush_throttled(false);
 
-    println!("Watching {:?}", watch_root);
-    println!("Outputting to {:?}", output_root);
-    println!("(Ctrl+C to quit)");
+        if let (Some(due), Some(interval)) = (next_summary, args.summary_interval) {
+            if due <= Instant::now() {
+                print_run_summary(started, args.summary_format);
+                next_summary = Some(due + interval);
+            }
+        }
 
-    for result in receiver {
-        match result {
-            Ok(event) => handle_event(&watch_root, &output_root, &event),
-            Err(error) => handle_watch_error(&error),
+        if let Some(deploy) = &mut deploy {
+            if let Err(error) = deploy.poll() {
+                eprintln!("Atomic deploy failed: {:?}", error);
+            }
         }
     }
 
+    println!("Shutting down");
+    flush_transactions(&mirror, true);
+    drain_queued_copies(&mirror);
+    flush_deletes(&mirror, true);
+    report::flush_throttled(true);
+    finish_background(&mut mirror);
+    if args.summary_on_exit {
+        print_run_summary(started, args.summary_format);
+    }
+
+    if let Some(socket_path) = &args.control_socket {
+        let _ = fs::remove_file(socket_path);
+    }
+
     Ok(())
 }
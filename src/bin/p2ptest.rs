@@ -1,24 +1,77 @@
 use clap::Parser;
-use std::path::PathBuf;
+use libp2p::{Multiaddr, PeerId};
+use std::{path::PathBuf, time::Duration};
 use anyhow::Result;
 
-use rustsync::keys::{load_keypair, default_rustsync_dir, test_rustsync_dir};
+use rustsync::{
+    hash::ChecksumAlgorithm,
+    keys::{load_keypair, load_keypair_pinned, default_rustsync_dir, test_rustsync_dir},
+    p2p::{Node, NodeConfig, Role},
+    units::parse_duration,
+};
 
 #[derive(Parser)]
 #[command(name = "p2ptest", about = "Tests p2p functionality")]
 struct Args {
-    #[arg(short = 'I', long = "input", default_value_t = default_rustsync_dir())]
-    input: String,
+    /// Key directory [default: ~/.rustsync]
+    #[arg(short = 'I', long = "input")]
+    input: Option<PathBuf>,
 
     peer_id: String,
+
+    /// Refuse to start unless the key's SHA-256 fingerprint (printed by key-gen) matches
+    #[arg(long)]
+    fingerprint: Option<String>,
+
+    /// Directory served to and filled from peers
+    #[arg(long, default_value = ".")]
+    root: PathBuf,
+
+    /// Address to listen on, e.g. /ip4/0.0.0.0/udp/4001/quic-v1
+    #[arg(long)]
+    listen: Vec<Multiaddr>,
+
+    /// Peer to keep connected to, e.g. /ip4/10.0.0.2/udp/4001/quic-v1/p2p/<peer id>
+    #[arg(long)]
+    dial: Vec<Multiaddr>,
+
+    /// QUIC keepalive interval
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    keepalive: Duration,
+
+    /// Longest wait between redial attempts
+    #[arg(long, value_parser = parse_duration, default_value = "60s")]
+    max_backoff: Duration,
+
+    #[arg(long, value_enum, default_value_t = ChecksumAlgorithm::default())]
+    checksum_algorithm: ChecksumAlgorithm,
+
+    /// source pushes to peers, replica pulls and accepts pushes, readonly only serves and verifies
+    #[arg(long, value_enum, default_value_t = Role::default())]
+    role: Role,
+
+    /// Peer a replica accepts changes from (repeatable, default any peer)
+    #[arg(long)]
+    source_peer: Vec<PeerId>,
+
+    /// Don't write a receipt of each peer session to ROOT/.rustsync/receipts
+    #[arg(long)]
+    no_receipts: bool,
+
+    /// Print the files each sync with a peer would send or fetch, with sizes and a byte total, without transferring or writing anything
+    #[arg(long)]
+    dry_run: bool,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let dir = PathBuf::from(&args.input);
+    let dir = args.input.map_or_else(default_rustsync_dir, Ok)?;
     test_rustsync_dir(&dir)?;
 
-    let loaded = load_keypair(&dir, &args.peer_id)?;
+    let loaded = match &args.fingerprint {
+        Some(expected) => load_keypair_pinned(&dir, &args.peer_id, expected)?,
+        None => load_keypair(&dir, &args.peer_id)?,
+    };
     assert_eq!(
         loaded.public().to_peer_id().to_string(),
         args.peer_id
@@ -26,5 +79,23 @@ fn main() -> Result<()> {
 
     println!("Keypair loaded successfully for peer: {}", args.peer_id);
 
-    Ok(())
+    if args.listen.is_empty() && args.dial.is_empty() {
+        return Ok(());
+    }
+
+    let config = NodeConfig {
+        root: args.root,
+        algorithm: args.checksum_algorithm,
+        listen: args.listen,
+        dial: args.dial,
+        keepalive: args.keepalive,
+        max_backoff: args.max_backoff,
+        role: args.role,
+        source_peers: args.source_peer,
+        receipts: !args.no_receipts && !args.dry_run,
+        dry_run: args.dry_run,
+    };
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(Node::new(loaded, config)?.run())
 }
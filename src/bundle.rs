@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use std::{
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    copy::{copy_file, staging_path, while_writable, Reflink},
+    hash::hash_file,
+    manifest::Manifest,
+    report::{self, ErrorKind},
+};
+
+#[derive(Debug, Default)]
+pub struct BundleSummary {
+    pub copied: u64,
+    pub unchanged: u64,
+    /// Entries the bundle lacks that the destination doesn't already hold.
+    pub missing: Vec<PathBuf>,
+    /// Bundle files whose hash isn't the manifest's, which are left out.
+    pub mismatched: Vec<PathBuf>,
+}
+
+impl BundleSummary {
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl fmt::Display for BundleSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "copied={} unchanged={} missing={} mismatched={}",
+            self.copied,
+            self.unchanged,
+            self.missing.len(),
+            self.mismatched.len()
+        )
+    }
+}
+
+/// Whether `destination` already holds `hash`.
+fn holds(destination: &Path, hash: &str, manifest: &Manifest) -> bool {
+    destination.is_file() && hash_file(destination, manifest.algorithm).is_ok_and(|actual| actual == hash)
+}
+
+/// `--from-manifest`/`--from-bundle`: brings `output_root` up to date from
+/// `bundle`, a directory of some of the source's files laid out as in the
+/// source, without the source itself. Each bundle file is copied to a temp
+/// file and only renamed into place once the copy matches `manifest`;
+/// entries the bundle lacks are only reported, and nothing is deleted.
+pub fn apply_bundle(manifest: &Manifest, bundle: &Path, output_root: &Path) -> Result<BundleSummary> {
+    let mut summary = BundleSummary::default();
+
+    for (relative, hash) in &manifest.entries {
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            anyhow::bail!("Manifest entry {:?} is not a plain relative path", relative);
+        }
+        let source = bundle.join(relative);
+        let destination = output_root.join(relative);
+
+        if !source.is_file() {
+            match holds(&destination, hash, manifest) {
+                true => summary.unchanged += 1,
+                false => summary.missing.push(relative.clone()),
+            }
+            continue;
+        }
+
+        if holds(&destination, hash, manifest) {
+            summary.unchanged += 1;
+            continue;
+        }
+
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}", parent))?;
+        }
+        let temp = staging_path(&destination);
+        copy_file(&source, &temp, Reflink::Auto)
+            .with_context(|| format!("Failed to copy {:?} -> {:?}", source, temp))?;
+        let remove_temp = |_: &anyhow::Error| {
+            let _ = fs::remove_file(&temp);
+        };
+        let actual = hash_file(&temp, manifest.algorithm).inspect_err(remove_temp)?;
+        if actual != *hash {
+            let _ = fs::remove_file(&temp);
+            report::error(
+                ErrorKind::Copy,
+                &source,
+                format!("{:?} doesn't match the manifest: expected {}, got {}", source, hash, actual),
+            );
+            summary.mismatched.push(relative.clone());
+            continue;
+        }
+        while_writable(&destination, || fs::rename(&temp, &destination))
+            .with_context(|| format!("Failed to move {:?} into place at {:?}", temp, destination))
+            .inspect_err(remove_temp)?;
+        println!("Copied: {:?}", relative);
+        summary.copied += 1;
+    }
+
+    Ok(summary)
+}
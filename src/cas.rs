@@ -0,0 +1,339 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    copy::{copy_file, set_unix_mode, temp_path, unix_mode, Reflink},
+    hash::{hash_file, ChecksumAlgorithm},
+    metrics,
+    mirror::{cross_platform_symlink, Preserve},
+    remote::Backend,
+};
+
+/// What the index records for a path in the store.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Entry {
+    File { hash: String, size: u64, mtime: i64, mode: u32 },
+    Dir { mode: u32 },
+    Symlink {
+        #[serde(with = "crate::pathbytes")]
+        target: PathBuf,
+    },
+}
+
+/// A line of the index, which is only ever appended to while syncing.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Change {
+    Put {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+        entry: Entry,
+    },
+    Delete {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+    },
+    Rename {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+        #[serde(with = "crate::pathbytes")]
+        new_path: PathBuf,
+    },
+}
+
+fn apply(entries: &mut BTreeMap<PathBuf, Entry>, change: Change) {
+    match change {
+        Change::Put { path, entry } => {
+            entries.insert(path, entry);
+        }
+        Change::Delete { path } => entries.retain(|entry, _| !entry.starts_with(&path)),
+        Change::Rename { path, new_path } => {
+            let moved: Vec<PathBuf> = entries.keys().filter(|entry| entry.starts_with(&path)).cloned().collect();
+            for old in moved {
+                let entry = entries.remove(&old).unwrap();
+                let renamed = new_path.join(old.strip_prefix(&path).unwrap());
+                entries.insert(renamed.components().collect(), entry);
+            }
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, as the index records modification times.
+fn unix_mtime(metadata: &fs::Metadata) -> i64 {
+    filetime::FileTime::from_last_modification_time(metadata).unix_seconds()
+}
+
+fn index_path(root: &Path) -> PathBuf {
+    root.join("index")
+}
+
+/// Reads the index, replaying its changes in order.
+pub fn load_index(root: &Path) -> Result<BTreeMap<PathBuf, Entry>> {
+    let mut entries = BTreeMap::new();
+    let file = match File::open(index_path(root)) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+        Err(error) => return Err(error).with_context(|| format!("Failed to open {:?}", index_path(root))),
+    };
+    let mut lines = BufReader::new(file).lines().enumerate().peekable();
+    while let Some((number, line)) = lines.next() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(change) => apply(&mut entries, change),
+            // A crash can leave the last line half written; anything else is damage.
+            Err(error) if lines.peek().is_none() => {
+                eprintln!("Skipping torn last CAS index line {}: {}", number + 1, error)
+            }
+            Err(error) => bail!("Malformed CAS index line {} in {:?}: {}", number + 1, index_path(root), error),
+        }
+    }
+    Ok(entries)
+}
+
+/// `--cas`: the destination as a content-addressable store. Files are stored
+/// once per distinct content under `objects/<first two hex digits>/<hash>`,
+/// and `index` maps each mirrored path to its object (or records it as a
+/// directory or symlink).
+pub struct CasStore {
+    root: PathBuf,
+    algorithm: ChecksumAlgorithm,
+    entries: Mutex<BTreeMap<PathBuf, Entry>>,
+    index: Mutex<File>,
+    incoming: AtomicU64,
+}
+
+impl CasStore {
+    /// Opens the store at `root`, creating it if needed, and compacts its
+    /// index down to one line per path.
+    pub fn open(root: &Path, algorithm: ChecksumAlgorithm) -> Result<Self> {
+        fs::create_dir_all(root.join("objects")).with_context(|| format!("Failed to create a store in {:?}", root))?;
+        let entries = load_index(root)?;
+
+        let compacted = temp_path(&index_path(root));
+        let mut file = File::create(&compacted)?;
+        for (path, entry) in &entries {
+            let change = Change::Put { path: path.clone(), entry: entry.clone() };
+            writeln!(file, "{}", serde_json::to_string(&change)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&compacted, index_path(root))?;
+        let index = fs::OpenOptions::new().append(true).open(index_path(root))?;
+
+        Ok(CasStore {
+            root: root.to_path_buf(),
+            algorithm,
+            entries: Mutex::new(entries),
+            index: Mutex::new(index),
+            incoming: AtomicU64::new(0),
+        })
+    }
+
+    pub fn object_path(&self, hash: &str) -> PathBuf {
+        self.root.join("objects").join(&hash[..2]).join(hash)
+    }
+
+    pub fn entry(&self, relative: &Path) -> Option<Entry> {
+        self.entries.lock().unwrap().get(relative).cloned()
+    }
+
+    fn record(&self, change: Change) -> Result<()> {
+        let line = serde_json::to_string(&change)?;
+        let mut entries = self.entries.lock().unwrap();
+        writeln!(self.index.lock().unwrap(), "{}", line).context("Failed to append to the CAS index")?;
+        apply(&mut entries, change);
+        Ok(())
+    }
+
+    /// Copies `source` into the store and returns its hash. The copy is
+    /// hashed rather than the source, so a file written to meanwhile can't
+    /// be stored under the wrong hash, and dropped if that content is
+    /// already stored.
+    fn store(&self, source: &Path) -> Result<String> {
+        let incoming = self.root.join("objects").join(format!(
+            ".incoming-{}-{}",
+            std::process::id(),
+            self.incoming.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = (|| {
+            copy_file(source, &incoming, Reflink::Never)
+                .with_context(|| format!("Failed to copy {:?} into the store", source))?;
+            let hash = hash_file(&incoming, self.algorithm)?;
+            let object = self.object_path(&hash);
+            if object.exists() {
+                metrics::add("cas_deduplicated", 1);
+                return Ok(hash);
+            }
+            fs::create_dir_all(object.parent().unwrap())?;
+            set_unix_mode(&incoming, 0o444)?;
+            File::open(&incoming)?.sync_all()?;
+            fs::rename(&incoming, &object)?;
+            metrics::add("cas_objects", 1);
+            Ok(hash)
+        })();
+        let _ = fs::remove_file(&incoming);
+        result
+    }
+}
+
+impl Backend for CasStore {
+    fn matches(&self, relative: &Path, source: &fs::Metadata) -> bool {
+        match (self.entry(relative), source.file_type()) {
+            (Some(Entry::File { size, mtime, .. }), kind) if kind.is_file() => {
+                size == source.len() && mtime == unix_mtime(source)
+            }
+            (Some(Entry::Dir { .. }), kind) => kind.is_dir(),
+            (Some(Entry::Symlink { .. }), kind) => kind.is_symlink(),
+            _ => false,
+        }
+    }
+
+    fn write(&self, relative: &Path, source: &Path, metadata: &fs::Metadata) -> Result<()> {
+        let hash = self.store(source)?;
+        let entry = Entry::File {
+            hash,
+            size: metadata.len(),
+            mtime: unix_mtime(metadata),
+            mode: unix_mode(metadata) & 0o7777,
+        };
+        self.record(Change::Put { path: relative.to_path_buf(), entry })
+    }
+
+    fn mkdir(&self, relative: &Path) -> Result<()> {
+        if matches!(self.entry(relative), Some(Entry::Dir { .. })) {
+            return Ok(());
+        }
+        let entry = Entry::Dir { mode: 0o755 };
+        self.record(Change::Put { path: relative.to_path_buf(), entry })
+    }
+
+    fn symlink(&self, relative: &Path, target: &Path) -> Result<()> {
+        let entry = Entry::Symlink { target: target.to_path_buf() };
+        self.record(Change::Put { path: relative.to_path_buf(), entry })
+    }
+
+    /// Objects are kept: other paths, or older backups of the index, may
+    /// still refer to them.
+    fn delete(&self, relative: &Path) -> Result<()> {
+        self.record(Change::Delete { path: relative.to_path_buf() })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.record(Change::Rename {
+            path: from.to_path_buf(),
+            new_path: to.to_path_buf(),
+        })
+    }
+
+    fn set_metadata(&self, relative: &Path, metadata: &fs::Metadata, preserve: &[Preserve]) -> Result<()> {
+        let Some(recorded) = self.entry(relative) else {
+            return Ok(());
+        };
+        let mut entry = recorded.clone();
+        match &mut entry {
+            Entry::File { mode, mtime, .. } => {
+                if preserve.contains(&Preserve::Perms) {
+                    *mode = unix_mode(metadata) & 0o7777;
+                }
+                if preserve.contains(&Preserve::Times) {
+                    *mtime = unix_mtime(metadata);
+                }
+            }
+            Entry::Dir { mode } if preserve.contains(&Preserve::Perms) => *mode = unix_mode(metadata) & 0o7777,
+            _ => return Ok(()),
+        }
+        if entry == recorded {
+            return Ok(());
+        }
+        self.record(Change::Put { path: relative.to_path_buf(), entry })
+    }
+
+    fn listed(&self) -> Option<Vec<PathBuf>> {
+        Some(self.entries.lock().unwrap().keys().cloned().collect())
+    }
+}
+
+/// Where the object an index entry names by `hash` is stored, if that's a
+/// hash at all rather than a damaged entry.
+fn indexed_object(root: &Path, hash: &str) -> Result<PathBuf> {
+    if hash.len() <= 2 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        bail!("Invalid object hash {:?} in the CAS index", hash);
+    }
+    Ok(root.join("objects").join(&hash[..2]).join(hash))
+}
+
+/// `--cas-checkout`: materializes the tree the store at `root` indexes into
+/// `destination`. Returns the number of files written.
+pub fn checkout(root: &Path, destination: &Path) -> Result<u64> {
+    let entries = load_index(root)?;
+    let mut files = 0;
+    let mut directories = Vec::new();
+
+    // Checked up front, so a damaged index writes nothing, let alone outside
+    // `destination`.
+    for (relative, entry) in &entries {
+        let plain = relative.components().all(|component| matches!(component, Component::Normal(_)));
+        if !plain || relative.as_os_str().is_empty() {
+            bail!("Invalid path {:?} in the CAS index", relative);
+        }
+        if let Some(ancestor) = relative.ancestors().skip(1).find(|ancestor| {
+            matches!(entries.get(*ancestor), Some(Entry::File { .. } | Entry::Symlink { .. }))
+        }) {
+            bail!("Invalid path {:?} in the CAS index: {:?} is not a directory", relative, ancestor);
+        }
+        if let Entry::File { hash, .. } = entry {
+            indexed_object(root, hash)?;
+        }
+    }
+
+    fs::create_dir_all(destination)?;
+    let mut symlinks = Vec::new();
+    for (relative, entry) in &entries {
+        let path = destination.join(relative);
+        // Whatever was already in `destination` mustn't lead out of it either.
+        for ancestor in relative.ancestors().skip(1).filter(|ancestor| !ancestor.as_os_str().is_empty()) {
+            let ancestor = destination.join(ancestor);
+            if fs::symlink_metadata(&ancestor).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+                bail!("Refusing to check out {:?} through the symlink {:?}", path, ancestor);
+            }
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match entry {
+            Entry::Dir { mode } => {
+                fs::create_dir_all(&path)?;
+                directories.push((path, *mode));
+            }
+            // Last, so nothing is written through them.
+            Entry::Symlink { target } => symlinks.push((path, target)),
+            Entry::File { hash, mtime, mode, .. } => {
+                let object = indexed_object(root, hash)?;
+                let _ = fs::remove_file(&path);
+                fs::copy(&object, &path).with_context(|| format!("Failed to check out {:?} from {:?}", path, object))?;
+                set_unix_mode(&path, *mode)?;
+                filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(*mtime, 0))?;
+                files += 1;
+            }
+        }
+    }
+    for (path, target) in symlinks {
+        let _ = fs::remove_file(&path);
+        cross_platform_symlink(target, &path).with_context(|| format!("Failed to create symlink {:?}", path))?;
+    }
+    // Last, so read-only directories don't stop their contents being written.
+    for (path, mode) in directories {
+        set_unix_mode(&path, mode)?;
+    }
+    Ok(files)
+}
@@ -0,0 +1,39 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The time the holds between events (debouncing, coalescing, rename pairing,
+/// delete batches and the like) are measured against. A manual clock only
+/// moves when `advance`d, so scripted tests don't depend on how long each step
+/// takes.
+#[derive(Clone, Default)]
+pub struct Clock {
+    manual: Option<Arc<Mutex<Instant>>>,
+}
+
+impl Clock {
+    pub fn manual() -> Self {
+        Clock {
+            manual: Some(Arc::new(Mutex::new(Instant::now()))),
+        }
+    }
+
+    pub fn is_manual(&self) -> bool {
+        self.manual.is_some()
+    }
+
+    pub fn now(&self) -> Instant {
+        match &self.manual {
+            Some(now) => *now.lock().unwrap(),
+            None => Instant::now(),
+        }
+    }
+
+    /// Moves a manual clock forward. The real one can't be moved.
+    pub fn advance(&self, by: Duration) {
+        if let Some(now) = &self.manual {
+            *now.lock().unwrap() += by;
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::Hash,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Collapses repeated keys until each has been quiet for `window`.
+pub struct Coalescer<K> {
+    window: Duration,
+    last_seen: HashMap<K, Instant>,
+}
+
+impl<K: Hash + Eq + Clone> Coalescer<K> {
+    pub fn new(window: Duration) -> Self {
+        Coalescer {
+            window,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    pub fn touch(&mut self, key: K, now: Instant) {
+        self.last_seen.insert(key, now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.last_seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last_seen.is_empty()
+    }
+
+    /// Removes and returns every key that has been quiet for the whole window
+    /// by `now`.
+    pub fn take_settled(&mut self, now: Instant) -> Vec<K> {
+        let window = self.window;
+        let settled: Vec<K> = self
+            .last_seen
+            .iter()
+            .filter(|(_, seen)| now.saturating_duration_since(**seen) >= window)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &settled {
+            self.last_seen.remove(key);
+        }
+        settled
+    }
+
+    /// Removes and returns every key, settled or not.
+    pub fn take_all(&mut self) -> Vec<K> {
+        self.last_seen.drain().map(|(key, _)| key).collect()
+    }
+}
+
+/// Deletes collected until none has arrived for `window`, so a burst, such
+/// as the per-file events of a directory removed in one go, is applied
+/// together.
+pub struct DeleteBatch {
+    window: Duration,
+    paths: BTreeSet<PathBuf>,
+    last_seen: Option<Instant>,
+}
+
+impl DeleteBatch {
+    pub fn new(window: Duration) -> Self {
+        DeleteBatch {
+            window,
+            paths: BTreeSet::new(),
+            last_seen: None,
+        }
+    }
+
+    pub fn push(&mut self, relative: PathBuf, now: Instant) {
+        self.paths.insert(relative);
+        self.last_seen = Some(now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn is_settled(&self, now: Instant) -> bool {
+        self.last_seen.is_some_and(|seen| now.saturating_duration_since(seen) >= self.window)
+    }
+
+    /// Takes out the whole batch: the deletes left once those under another
+    /// delete in it are dropped, and the deepest directory holding them all.
+    pub fn take(&mut self) -> (Vec<PathBuf>, PathBuf) {
+        self.last_seen = None;
+        let paths = std::mem::take(&mut self.paths);
+        let mut common: Option<PathBuf> = None;
+        let mut deletes: Vec<PathBuf> = Vec::new();
+        // Sorted, so a directory comes before everything under it.
+        for path in paths {
+            let parent = path.parent().unwrap_or(Path::new(""));
+            common = Some(match common {
+                None => parent.to_path_buf(),
+                Some(common) => common.ancestors().find(|ancestor| parent.starts_with(ancestor)).unwrap().to_path_buf(),
+            });
+            if !deletes.last().is_some_and(|last| path.starts_with(last)) {
+                deletes.push(path);
+            }
+        }
+        (deletes, common.unwrap_or_default())
+    }
+}
+
+/// Lets one holder at a time work on each key. While a key is held, the first
+/// other claim waits its turn and any further ones are turned away, since the
+/// waiter will see whatever they would have.
+pub struct InFlight<K> {
+    /// Held keys, and whether a claim is already waiting on each.
+    held: Mutex<HashMap<K, bool>>,
+    released: Condvar,
+}
+
+impl<K: Hash + Eq + Clone> Default for InFlight<K> {
+    fn default() -> Self {
+        InFlight {
+            held: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> InFlight<K> {
+    /// Holds `key` until the guard drops, or None if a claim is already
+    /// waiting on it.
+    pub fn claim(&self, key: K) -> Option<InFlightGuard<'_, K>> {
+        let mut held = self.held.lock().unwrap();
+        match held.get_mut(&key) {
+            None => {}
+            Some(true) => return None,
+            Some(waiting) => {
+                *waiting = true;
+                held = self.released.wait_while(held, |held| held.contains_key(&key)).unwrap();
+            }
+        }
+
+        held.insert(key.clone(), false);
+        Some(InFlightGuard { owner: self, key })
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.lock().unwrap().is_empty()
+    }
+}
+
+pub struct InFlightGuard<'a, K: Hash + Eq + Clone> {
+    owner: &'a InFlight<K>,
+    key: K,
+}
+
+impl<K: Hash + Eq + Clone> Drop for InFlightGuard<'_, K> {
+    fn drop(&mut self) {
+        self.owner.held.lock().unwrap().remove(&self.key);
+        self.owner.released.notify_all();
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    copy::{open_source, staging_path},
+    hash::{hash_file, ChecksumAlgorithm},
+};
+
+/// Skippable frame magic (0x184D2A50..=0x184D2A5F) that carries the header.
+/// zstd decoders ignore skippable frames, so the files stay plain `.zst`.
+const HEADER_MAGIC: u32 = 0x184D2A5E;
+const HEADER_LIMIT: u32 = 64 * 1024;
+
+/// Settings for `--compress-dest`.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    pub level: i32,
+    pub min_size: u64,
+    /// Lowercase extensions, without the dot, that are stored as-is.
+    pub skip_extensions: Vec<String>,
+    pub algorithm: ChecksumAlgorithm,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            level: 3,
+            min_size: 4096,
+            skip_extensions: ["zst", "gz", "xz", "bz2", "zip", "7z", "jpg", "jpeg", "png", "mp3", "mp4", "mkv"]
+                .iter()
+                .map(|extension| extension.to_string())
+                .collect(),
+            algorithm: ChecksumAlgorithm::default(),
+        }
+    }
+}
+
+impl Compression {
+    pub fn applies_to(&self, path: &Path, size: u64) -> bool {
+        if size < self.min_size {
+            return false;
+        }
+
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        !self.skip_extensions.contains(&extension)
+    }
+}
+
+/// Original size and hash of a compressed file, stored in front of its data.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub size: u64,
+    pub algorithm: ChecksumAlgorithm,
+    pub hash: String,
+}
+
+pub fn compressed_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.file_name().unwrap_or_default());
+    name.push(".zst");
+    path.with_file_name(name)
+}
+
+/// Strips `.zst` from a file written by `compress_file`, or returns `None`
+/// for anything else (including `.zst` files that came from the source).
+pub fn original_path(path: &Path) -> Option<PathBuf> {
+    if path.extension()? != "zst" {
+        return None;
+    }
+    read_header(path).ok()??;
+    Some(path.with_extension(""))
+}
+
+/// Compresses `source` into `destination` via a temp file, headed by the
+/// original size and hash.
+pub fn compress_file(source: &Path, destination: &Path, compression: &Compression) -> Result<()> {
+    let header = Header {
+        size: fs::metadata(source)
+            .with_context(|| format!("Failed to read metadata for {:?}", source))?
+            .len(),
+        algorithm: compression.algorithm,
+        hash: hash_file(source, compression.algorithm)?,
+    };
+
+    let temp = staging_path(destination);
+    let result = write_compressed(source, &temp, &header, compression.level)
+        .and_then(|()| fs::rename(&temp, destination).with_context(|| format!("Failed to rename {:?}", temp)));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn write_compressed(source: &Path, temp: &Path, header: &Header, level: i32) -> Result<()> {
+    let mut input = open_source(source).with_context(|| format!("Failed to open {:?}", source))?;
+    let mut output = File::create(temp).with_context(|| format!("Failed to create {:?}", temp))?;
+
+    let payload = serde_json::to_vec(header)?;
+    output.write_all(&HEADER_MAGIC.to_le_bytes())?;
+    output.write_all(&(payload.len() as u32).to_le_bytes())?;
+    output.write_all(&payload)?;
+
+    let mut encoder = zstd::Encoder::new(output, level)?;
+    encoder.set_pledged_src_size(Some(header.size))?;
+    io::copy(&mut input, &mut encoder).with_context(|| format!("Failed to compress {:?}", source))?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Reads the header written by `compress_file`, or `None` if the file
+/// doesn't start with one.
+pub fn read_header(path: &Path) -> io::Result<Option<Header>> {
+    let mut file = File::open(path)?;
+    let mut prefix = [0u8; 8];
+    if file.read_exact(&mut prefix).is_err() {
+        return Ok(None);
+    }
+
+    let magic = u32::from_le_bytes(prefix[..4].try_into().unwrap());
+    let length = u32::from_le_bytes(prefix[4..].try_into().unwrap());
+    if magic != HEADER_MAGIC || length > HEADER_LIMIT {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0u8; length as usize];
+    file.read_exact(&mut payload)?;
+    Ok(serde_json::from_slice(&payload).ok())
+}
+
+/// Streams the original contents of a compressed file.
+pub fn decompress(path: &Path) -> Result<impl Read> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    zstd::Decoder::new(BufReader::new(file)).with_context(|| format!("Failed to decompress {:?}", path))
+}
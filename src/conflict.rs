@@ -0,0 +1,289 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    hash::{hash_file, ChecksumAlgorithm},
+    metrics,
+    mirror::CONTROL_DIR,
+    report::{self, unix_timestamp, ErrorKind},
+};
+
+/// What happens to a destination file changed since rustsync last wrote it,
+/// when the source is copied over it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Copy over it
+    Overwrite,
+    /// Leave the destination's version in place
+    Skip,
+    /// Move it under OUTPUT_ROOT/.rustsync/conflicts, then copy
+    Backup,
+}
+
+/// A destination file as rustsync left it, or as it was found.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime_ns: u64,
+    pub hash: String,
+}
+
+fn stamp(metadata: &fs::Metadata) -> (u64, u64) {
+    let mtime_ns = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64);
+    (metadata.len(), mtime_ns)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Written {
+    #[serde(with = "crate::pathbytes")]
+    path: PathBuf,
+    #[serde(flatten)]
+    state: FileState,
+}
+
+#[derive(Serialize)]
+struct Conflict<'a> {
+    timestamp: u64,
+    #[serde(serialize_with = "crate::pathbytes::serialize")]
+    path: &'a Path,
+    expected: &'a FileState,
+    actual: &'a FileState,
+    resolution: ConflictPolicy,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "crate::pathbytes::option::serialize")]
+    backup: Option<PathBuf>,
+}
+
+/// `--on-conflict`: remembers what rustsync wrote to each destination file,
+/// in `OUTPUT_ROOT/.rustsync/written`, and before copying over one checks it's
+/// still that. Each one that isn't is recorded in
+/// `OUTPUT_ROOT/.rustsync/conflicts.log` and resolved by the policy.
+pub struct ConflictLog {
+    policy: ConflictPolicy,
+    control: PathBuf,
+    written: Mutex<HashMap<PathBuf, FileState>>,
+    index: Mutex<Index>,
+    log: Mutex<File>,
+    /// Hashes of what copies just wrote, by destination, taken by `wrote`.
+    copied: Mutex<HashMap<PathBuf, (u64, String)>>,
+}
+
+/// The `written` index open for appending, and how many lines it holds.
+struct Index {
+    file: File,
+    lines: usize,
+}
+
+/// Lines the index may hold beyond one per file before it's compacted again.
+const SLACK: usize = 1024;
+
+fn append(file: &mut File, record: &impl Serialize) -> Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {:?}", path))
+}
+
+/// Rewrites the index at `path` with one line per file in `written`.
+fn compact(path: &Path, written: &HashMap<PathBuf, FileState>) -> Result<()> {
+    let mut compacted = String::new();
+    for (path, state) in written {
+        compacted.push_str(&serde_json::to_string(&Written { path: path.clone(), state: state.clone() })?);
+        compacted.push('\n');
+    }
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, compacted).with_context(|| format!("Failed to write {:?}", temp))?;
+    fs::rename(&temp, path).with_context(|| format!("Failed to write {:?}", path))
+}
+
+impl ConflictLog {
+    /// Loads what earlier runs wrote, compacting the index to one line per
+    /// file, and opens the conflict log for appending. The index is compacted
+    /// again whenever it's grown `SLACK` lines past that.
+    pub fn open(output_root: &Path, policy: ConflictPolicy) -> Result<Self> {
+        let control = output_root.join(CONTROL_DIR);
+        fs::create_dir_all(&control).with_context(|| format!("Failed to create {:?}", control))?;
+
+        let index_path = control.join("written");
+        let mut written = HashMap::new();
+        if let Ok(contents) = fs::read_to_string(&index_path) {
+            // A line cut short by a crash is dropped; later lines win.
+            for record in contents.lines().filter_map(|line| serde_json::from_str::<Written>(line).ok()) {
+                written.insert(record.path, record.state);
+            }
+        }
+        compact(&index_path, &written)?;
+
+        Ok(ConflictLog {
+            policy,
+            index: Mutex::new(Index {
+                file: open_append(&index_path)?,
+                lines: written.len(),
+            }),
+            log: Mutex::new(open_append(&control.join("conflicts.log"))?),
+            control,
+            written: Mutex::new(written),
+            copied: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn policy(&self) -> ConflictPolicy {
+        self.policy
+    }
+
+    fn remember(&self, destination: &Path, state: FileState) {
+        let record = Written {
+            path: destination.to_path_buf(),
+            state,
+        };
+        let mut index = self.index.lock().unwrap();
+        if let Err(error) = append(&mut index.file, &record) {
+            report::error(ErrorKind::Journal, destination, format!("Failed to record write of {:?}: {:#}", destination, error));
+        }
+        index.lines += 1;
+        let mut written = self.written.lock().unwrap();
+        written.insert(record.path, record.state);
+        if index.lines > written.len() + SLACK {
+            let path = self.control.join("written");
+            match compact(&path, &written).and_then(|()| open_append(&path)) {
+                Ok(file) => *index = Index { file, lines: written.len() },
+                Err(error) => report::error(ErrorKind::Journal, &path, format!("Failed to compact {:?}: {:#}", path, error)),
+            }
+        }
+    }
+
+    /// Notes the hash of the `size` bytes a copy just wrote to `destination`,
+    /// for `wrote` to use instead of reading it back.
+    pub fn copied(&self, destination: &Path, size: u64, hash: String) {
+        self.copied.lock().unwrap().insert(destination.to_path_buf(), (size, hash));
+    }
+
+    /// Records `destination` as just written by rustsync.
+    pub fn wrote(&self, destination: &Path) {
+        let copied = self.copied.lock().unwrap().remove(destination);
+        let Ok(metadata) = fs::metadata(destination) else {
+            return;
+        };
+        let hash = match copied {
+            Some((size, hash)) if size == metadata.len() => hash,
+            _ => match hash_file(destination, ChecksumAlgorithm::Blake3) {
+                Ok(hash) => hash,
+                Err(_) => return,
+            },
+        };
+        let (size, mtime_ns) = stamp(&metadata);
+        self.remember(destination, FileState { size, mtime_ns, hash });
+    }
+
+    /// Records new metadata rustsync set on `destination`. Its contents are
+    /// taken to be unchanged as long as its size is.
+    pub fn touched(&self, destination: &Path) {
+        let Ok(metadata) = fs::metadata(destination) else {
+            return;
+        };
+        let (size, mtime_ns) = stamp(&metadata);
+        let state = match self.written.lock().unwrap().get(destination) {
+            Some(state) if state.size == size && state.mtime_ns != mtime_ns => FileState {
+                mtime_ns,
+                ..state.clone()
+            },
+            _ => return,
+        };
+        self.remember(destination, state);
+    }
+
+    /// Checks `destination`, under `output_root`, before it's copied over.
+    /// Returns whether the copy should go ahead.
+    pub fn check(&self, output_root: &Path, destination: &Path) -> bool {
+        let Some(expected) = self.written.lock().unwrap().get(destination).cloned() else {
+            return true;
+        };
+        let Ok(metadata) = fs::metadata(destination) else {
+            return true;
+        };
+        let (size, mtime_ns) = stamp(&metadata);
+        if (size, mtime_ns) == (expected.size, expected.mtime_ns) {
+            return true;
+        }
+        let hash = hash_file(destination, ChecksumAlgorithm::Blake3).unwrap_or_default();
+        if hash == expected.hash {
+            return true;
+        }
+
+        let actual = FileState { size, mtime_ns, hash };
+        metrics::add("conflicts_detected", 1);
+        let backup = match self.policy {
+            ConflictPolicy::Backup => match self.back_up(output_root, destination) {
+                Ok(backup) => Some(backup),
+                Err(error) => {
+                    report::error(ErrorKind::Copy, destination, format!("{:#}, leaving it in place", error));
+                    return false;
+                }
+            },
+            _ => None,
+        };
+        eprintln!(
+            "Conflict: {:?} changed since it was mirrored ({} bytes -> {} bytes), {}",
+            destination,
+            expected.size,
+            actual.size,
+            match self.policy {
+                ConflictPolicy::Overwrite => "overwriting".to_string(),
+                ConflictPolicy::Skip => "skipping".to_string(),
+                ConflictPolicy::Backup => format!("backed up to {:?}", backup.as_deref().unwrap_or(Path::new(""))),
+            }
+        );
+        let conflict = Conflict {
+            timestamp: unix_timestamp(),
+            path: destination,
+            expected: &expected,
+            actual: &actual,
+            resolution: self.policy,
+            backup,
+        };
+        if let Err(error) = append(&mut self.log.lock().unwrap(), &conflict) {
+            report::error(ErrorKind::Journal, destination, format!("Failed to write conflict log: {:#}", error));
+        }
+        self.policy != ConflictPolicy::Skip
+    }
+
+    /// Moves `destination` to `.rustsync/conflicts/<relative>.<timestamp>`.
+    fn back_up(&self, output_root: &Path, destination: &Path) -> Result<PathBuf> {
+        // Routed destinations outside the output root keep their full path.
+        let relative: PathBuf = destination
+            .strip_prefix(output_root)
+            .unwrap_or(destination)
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        let mut backup = self.control.join("conflicts").join(relative).into_os_string();
+        backup.push(format!(".{}", unix_timestamp()));
+        let backup = PathBuf::from(backup);
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}", parent))?;
+        }
+        fs::rename(destination, &backup).with_context(|| format!("Failed to back up {:?}", destination))?;
+        Ok(backup)
+    }
+}
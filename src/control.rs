@@ -0,0 +1,115 @@
+use anyhow::Result;
+use std::{
+    path::Path,
+    sync::mpsc::{Receiver, Sender},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Pause,
+    Resume,
+    Status,
+    Resync,
+    ConfirmDeletes,
+    RetryDeadLetters,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Option<Self> {
+        match line.trim() {
+            "pause" => Some(Command::Pause),
+            "resume" => Some(Command::Resume),
+            "status" => Some(Command::Status),
+            "resync" => Some(Command::Resync),
+            "confirm-deletes" => Some(Command::ConfirmDeletes),
+            "retry-deadletter" => Some(Command::RetryDeadLetters),
+            _ => None,
+        }
+    }
+}
+
+/// A command from the control socket, answered with one line of JSON on `reply`.
+pub struct ControlRequest {
+    pub command: Command,
+    pub reply: Sender<String>,
+    /// Where the command came from, for the log.
+    pub origin: &'static str,
+}
+
+/// Listens on a Unix domain socket and forwards each command to the returned
+/// receiver, which the event loop polls so commands run on its thread.
+#[cfg(unix)]
+pub fn listen(path: &Path) -> Result<Receiver<ControlRequest>> {
+    use anyhow::Context;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixListener,
+        sync::mpsc::channel,
+    };
+
+    if path.exists() {
+        std::fs::remove_file(path).with_context(|| format!("Failed to remove stale socket {:?}", path))?;
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Failed to bind control socket {:?}", path))?;
+    let (sender, receiver) = channel();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    eprintln!("Control socket error: {}", error);
+                    continue;
+                }
+            };
+
+            let mut line = String::new();
+            if BufReader::new(&stream).read_line(&mut line).is_err() {
+                continue;
+            }
+
+            let response = match Command::parse(&line) {
+                Some(command) => {
+                    let (reply, response) = channel();
+                    if sender.send(ControlRequest { command, reply, origin: "control socket" }).is_err() {
+                        break;
+                    }
+                    response.recv().unwrap_or_default()
+                }
+                None => serde_json::json!({ "error": format!("unknown command {:?}", line.trim()) }).to_string(),
+            };
+
+            let _ = writeln!(stream, "{}", response);
+        }
+    });
+
+    Ok(receiver)
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &Path) -> Result<Receiver<ControlRequest>> {
+    anyhow::bail!("--control-socket is only supported on Unix")
+}
+
+#[cfg(unix)]
+pub fn send(path: &Path, command: &str) -> Result<String> {
+    use anyhow::Context;
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+    };
+
+    let mut stream =
+        UnixStream::connect(path).with_context(|| format!("Failed to connect to {:?}", path))?;
+    writeln!(stream, "{}", command)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response.trim_end().to_string())
+}
+
+#[cfg(not(unix))]
+pub fn send(_path: &Path, _command: &str) -> Result<String> {
+    anyhow::bail!("Control sockets are only supported on Unix")
+}
@@ -0,0 +1,459 @@
+use clap::ValueEnum;
+use std::{
+    ffi::OsString,
+    fs, io,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+/// Marks temp files, whichever directory they're in.
+const TEMP_SUFFIX: &str = ".rustsync-tmp";
+
+/// `--temp-dir` and the device it's on.
+static TEMP_DIR: RwLock<Option<(PathBuf, Device)>> = RwLock::new(None);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Reflink {
+    /// Clone when the filesystem supports it, otherwise copy bytes
+    #[default]
+    Auto,
+    /// Clone or fail
+    Always,
+    /// Always copy bytes
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Fsync {
+    /// Leave flushing to the OS
+    #[default]
+    None,
+    /// Flush file contents after each copy
+    Data,
+    /// Flush contents and metadata, plus the parent directory after creates, renames and deletes
+    Full,
+}
+
+/// Flushes a copied file to disk according to `fsync`.
+pub fn sync_file(path: &Path, fsync: Fsync) -> io::Result<()> {
+    // FlushFileBuffers needs a writable handle, fsync doesn't.
+    let open = || fs::OpenOptions::new().read(true).write(cfg!(windows)).open(path);
+    match fsync {
+        Fsync::None => Ok(()),
+        Fsync::Data => open()?.sync_data(),
+        Fsync::Full => open()?.sync_all(),
+    }
+}
+
+/// Makes directory entry changes durable. Directories can't be opened for
+/// syncing on Windows, where NTFS journals them anyway.
+pub fn sync_directory(path: &Path) -> io::Result<()> {
+    match cfg!(unix) {
+        true => fs::File::open(path)?.sync_all(),
+        false => Ok(()),
+    }
+}
+
+/// Opens a source file for reading without updating its access time where
+/// the platform allows: `O_NOATIME` on Linux, which only the file's owner (or
+/// CAP_FOWNER) may use, so other files are opened normally.
+pub fn open_source(path: &Path) -> io::Result<fs::File> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        match fs::OpenOptions::new().read(true).custom_flags(libc::O_NOATIME).open(path) {
+            Err(error) if error.raw_os_error() == Some(libc::EPERM) => {}
+            result => return result,
+        }
+    }
+    fs::File::open(path)
+}
+
+/// Whether `error` means another process holds the file: a sharing or lock
+/// violation on Windows, a busy executable (`ETXTBSY`) on Unix.
+pub fn is_locked(error: &io::Error) -> bool {
+    #[cfg(windows)]
+    const LOCKED: [i32; 2] = [32, 33]; // ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION
+    #[cfg(unix)]
+    const LOCKED: [i32; 1] = [libc::ETXTBSY];
+    error.raw_os_error().is_some_and(|code| LOCKED.contains(&code))
+}
+
+/// What a yielding copy runs on each chunk once it's written.
+type Between<'a> = Option<&'a mut dyn FnMut(&[u8])>;
+
+/// The chunks a yielding copy gives way between.
+const CHUNK: usize = 1024 * 1024;
+
+/// `fs::copy` reading the source through `open_source`, leaving the `mask`
+/// bits of its mode off the copy.
+fn copy_contents(source: &Path, destination: &Path, mask: u32, mut between: Between) -> io::Result<()> {
+    let mut reader = open_source(source)?;
+    let metadata = reader.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the source path is not a regular file"));
+    }
+    #[cfg(unix)]
+    let permissions = {
+        use std::os::unix::fs::PermissionsExt;
+        fs::Permissions::from_mode(unix_mode(&metadata) & !mask)
+    };
+    #[cfg(not(unix))]
+    let permissions = {
+        let _ = mask;
+        metadata.permissions()
+    };
+    let mut writer = fs::File::create(destination)?;
+    if !copy_mapped(&reader, metadata.len(), &mut writer, &mut between)? {
+        writer.set_len(0)?;
+        writer.rewind()?;
+        match between {
+            Some(between) => copy_chunks(&mut reader, &mut writer, between)?,
+            None => {
+                io::copy(&mut reader, &mut writer)?;
+            }
+        }
+    }
+    writer.set_permissions(permissions)
+}
+
+fn copy_chunks(reader: &mut fs::File, writer: &mut fs::File, between: &mut dyn FnMut(&[u8])) -> io::Result<()> {
+    let mut buffer = vec![0; CHUNK];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => {
+                writer.write_all(&buffer[..read])?;
+                between(&buffer[..read]);
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Writes a source of at least `--mmap-threshold` bytes out of a memory map,
+/// or returns false to have the caller stream it instead: the file is small,
+/// can't be mapped, or changed size while it was copied. As for hashing, the
+/// map covers only the size read up front, so a truncated source can't fault.
+fn copy_mapped(reader: &fs::File, len: u64, writer: &mut fs::File, between: &mut Between) -> io::Result<bool> {
+    if len < crate::hash::mmap_threshold() || len > usize::MAX as u64 {
+        return Ok(false);
+    }
+    let _reservation = crate::inflight::reserve(crate::inflight::footprint(len, true));
+
+    // Safety: read-only and bounded by `len`; see `hash_mapped`.
+    let Ok(map) = (unsafe { memmap2::MmapOptions::new().len(len as usize).map(reader) }) else {
+        return Ok(false);
+    };
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+
+    match between {
+        Some(between) => {
+            for chunk in map.chunks(CHUNK) {
+                writer.write_all(chunk)?;
+                between(chunk);
+            }
+        }
+        None => writer.write_all(&map)?,
+    }
+    Ok(reader.metadata()?.len() == len)
+}
+
+/// The Unix mode of `metadata`. Windows only has a read-only attribute, so
+/// there it's 0o644 or 0o444, plus the execute bits for directories.
+pub fn unix_mode(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.mode()
+    }
+
+    #[cfg(not(unix))]
+    {
+        let mode = match metadata.permissions().readonly() {
+            true => 0o444,
+            false => 0o644,
+        };
+        match metadata.is_dir() {
+            true => mode | 0o111,
+            false => mode,
+        }
+    }
+}
+
+/// Sets the Unix mode of `path`. On Windows only the owner's write bit
+/// counts, as whether the file is read-only.
+pub fn set_unix_mode(path: &Path, mode: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o200 == 0);
+        fs::set_permissions(path, permissions)
+    }
+}
+
+/// Runs `write` with `destination` made writable by its owner, since a
+/// read-only file can't be opened for writing, then puts its permissions
+/// back. The source's permissions, if they're preserved, go on afterwards.
+pub fn while_writable<T>(destination: &Path, write: impl FnOnce() -> T) -> T {
+    let read_only = fs::symlink_metadata(destination)
+        .ok()
+        .filter(|metadata| metadata.is_file() && unix_mode(metadata) & 0o200 == 0);
+    if let Some(metadata) = &read_only {
+        let _ = set_unix_mode(destination, unix_mode(metadata) | 0o200);
+    }
+    let result = write();
+    if let Some(metadata) = read_only {
+        let _ = fs::set_permissions(destination, metadata.permissions());
+    }
+    result
+}
+
+pub fn temp_path(destination: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(destination.file_name().unwrap_or_default());
+    name.push(TEMP_SUFFIX);
+    destination.with_file_name(name)
+}
+
+/// A device number, and on Linux the mount point it's reached through.
+type Device = (u64, Option<PathBuf>);
+
+/// The device `path` is on, which files can only be renamed within. A bind
+/// mount shares its device number with the filesystem it exposes, but
+/// renames still can't cross it, so on Linux the mount is part of it.
+#[cfg(unix)]
+fn device(path: &Path) -> io::Result<Device> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dev = fs::metadata(path)?.dev();
+    #[cfg(target_os = "linux")]
+    let mount = crate::mounts::mount_of(path);
+    #[cfg(not(target_os = "linux"))]
+    let mount = None;
+    Ok((dev, mount))
+}
+
+/// Windows renames within a volume, taken here to be the drive or share
+/// `path` resolves to.
+#[cfg(not(unix))]
+fn device(path: &Path) -> io::Result<Device> {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    fs::canonicalize(path)?.components().next().hash(&mut hasher);
+    Ok((hasher.finish(), None))
+}
+
+/// Sets `--temp-dir`, which must exist.
+pub fn set_temp_dir(dir: &Path) -> io::Result<()> {
+    let device = device(dir)?;
+    *TEMP_DIR.write().unwrap() = Some((dir.to_path_buf(), device));
+    Ok(())
+}
+
+/// Whether `path` is on the same filesystem as `--temp-dir`, so temp files
+/// there can be renamed onto it. True when no temp dir is set.
+pub fn temp_dir_reaches(path: &Path) -> bool {
+    match &*TEMP_DIR.read().unwrap() {
+        Some((_, temp_device)) => device(path).is_ok_and(|device| device == *temp_device),
+        None => true,
+    }
+}
+
+/// Where a file is written before being renamed onto `destination`: in
+/// `--temp-dir` when it's on the destination's filesystem, otherwise beside
+/// the destination as `temp_path`. The name depends only on `destination`.
+pub fn staging_path(destination: &Path) -> PathBuf {
+    let temp_dir = TEMP_DIR.read().unwrap();
+    let parent = destination.parent().unwrap_or(Path::new("."));
+    match &*temp_dir {
+        Some((dir, temp_device)) if device(parent).is_ok_and(|device| device == *temp_device) => {
+            let key = blake3::hash(destination.as_os_str().as_encoded_bytes()).to_hex();
+            let mut name = OsString::from(&key[..16]);
+            name.push("-");
+            name.push(destination.file_name().unwrap_or_default());
+            name.push(TEMP_SUFFIX);
+            dir.join(name)
+        }
+        _ => temp_path(destination),
+    }
+}
+
+/// Removes temp files a crashed run left in `dir`. Returns how many.
+pub fn clean_temp_dir(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && entry.file_name().to_string_lossy().contains(TEMP_SUFFIX) {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Copies `source` to `destination`, cloning extents (FICLONE/clonefile) when
+/// `reflink` allows it. Clones go through a temp file and a rename since they
+/// can't be made onto an existing file.
+pub fn copy_file(source: &Path, destination: &Path, reflink: Reflink) -> io::Result<()> {
+    copy_file_masked(source, destination, reflink, 0)
+}
+
+/// The setuid and setgid bits, for `copy_file_masked` to leave off.
+pub const SETUID: u32 = 0o6000;
+
+/// `copy_file`, leaving the `mask` bits of the source's mode off the copy
+/// before it's in place.
+pub fn copy_file_masked(source: &Path, destination: &Path, reflink: Reflink, mask: u32) -> io::Result<()> {
+    copy_file_with(source, destination, reflink, mask, None)
+}
+
+/// `copy_file_masked`, running `between` on each chunk when it copies bytes,
+/// so a long copy can give way to more urgent work or hash what it wrote.
+/// A reflinked copy passes it nothing.
+pub fn copy_file_yielding(
+    source: &Path,
+    destination: &Path,
+    reflink: Reflink,
+    mask: u32,
+    between: &mut dyn FnMut(&[u8]),
+) -> io::Result<()> {
+    copy_file_with(source, destination, reflink, mask, Some(between))
+}
+
+fn copy_file_with(source: &Path, destination: &Path, reflink: Reflink, mask: u32, between: Between) -> io::Result<()> {
+    if reflink == Reflink::Never {
+        return copy_contents(source, destination, mask, between);
+    }
+
+    let temp = staging_path(destination);
+    let _ = fs::remove_file(&temp);
+
+    match reflink_copy::reflink(source, &temp) {
+        Ok(()) => {}
+        Err(error) if reflink == Reflink::Always => return Err(error),
+        Err(_) => {
+            let _ = fs::remove_file(&temp);
+            return copy_contents(source, destination, mask, between);
+        }
+    }
+
+    let masked = fs::metadata(&temp).and_then(|metadata| match unix_mode(&metadata) {
+        mode if mode & mask != 0 => set_unix_mode(&temp, mode & !mask),
+        _ => Ok(()),
+    });
+    masked.and_then(|()| fs::rename(&temp, destination)).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+fn hash_prefix(path: &Path, len: u64) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(open_source(path)?.take(len))?;
+    Ok(hasher.finalize())
+}
+
+/// Files up to this size are compared byte for byte, larger ones by hash.
+const COMPARE_IN_MEMORY: u64 = 1 << 20;
+
+/// Whether `destination` is a file already holding exactly `source`'s contents.
+pub fn same_contents(source: &Path, destination: &Path) -> io::Result<bool> {
+    let len = fs::metadata(source)?.len();
+    let destination_metadata = fs::symlink_metadata(destination)?;
+    if !destination_metadata.is_file() || destination_metadata.len() != len {
+        return Ok(false);
+    }
+
+    match len <= COMPARE_IN_MEMORY {
+        true => {
+            let mut contents = Vec::new();
+            open_source(source)?.read_to_end(&mut contents)?;
+            Ok(contents == fs::read(destination)?)
+        }
+        false => Ok(hash_prefix(source, len)? == hash_prefix(destination, len)?),
+    }
+}
+
+/// Appends the part of `source` past the end of `destination` when the
+/// destination is a prefix of the source, as with a growing log. Returns the
+/// number of bytes appended, or None when the source was rewritten, truncated
+/// or rotated and needs a full copy.
+pub fn append_tail(source: &Path, destination: &Path) -> io::Result<Option<u64>> {
+    let source_len = fs::metadata(source)?.len();
+    let destination_metadata = fs::symlink_metadata(destination)?;
+    let destination_len = destination_metadata.len();
+
+    if !destination_metadata.is_file() || destination_len == 0 || destination_len > source_len {
+        return Ok(None);
+    }
+    if hash_prefix(source, destination_len)? != hash_prefix(destination, destination_len)? {
+        return Ok(None);
+    }
+
+    let mut reader = open_source(source)?;
+    reader.seek(SeekFrom::Start(destination_len))?;
+    let mut writer = fs::OpenOptions::new().append(true).open(destination)?;
+    let appended = io::copy(&mut reader.take(source_len - destination_len), &mut writer)?;
+
+    // A source that shrank mid-copy leaves a short tail; let the caller recopy.
+    if destination_len + appended != source_len {
+        return Ok(None);
+    }
+    Ok(Some(appended))
+}
+
+/// Blocks `truncate_tail` compares to find where a shrunken file diverges.
+const TRUNCATE_BLOCK: usize = 64 * 1024;
+
+/// Brings `destination` down to a `source` that's now smaller, as with a log
+/// truncated in place: cuts it to the source's length and rewrites only the
+/// blocks from the first that differs. Returns the number of bytes
+/// rewritten, or None when the source isn't smaller or shares no leading
+/// block with the destination and needs a full copy.
+pub fn truncate_tail(source: &Path, destination: &Path) -> io::Result<Option<u64>> {
+    let source_len = fs::metadata(source)?.len();
+    let destination_metadata = fs::symlink_metadata(destination)?;
+    if !destination_metadata.is_file() || source_len >= destination_metadata.len() {
+        return Ok(None);
+    }
+
+    let mut reader = open_source(source)?;
+    let mut existing = fs::File::open(destination)?;
+    let (mut theirs, mut ours) = (vec![0; TRUNCATE_BLOCK], vec![0; TRUNCATE_BLOCK]);
+    let mut same = 0;
+    while same < source_len {
+        let len = (source_len - same).min(TRUNCATE_BLOCK as u64) as usize;
+        reader.read_exact(&mut theirs[..len])?;
+        existing.read_exact(&mut ours[..len])?;
+        if theirs[..len] != ours[..len] {
+            break;
+        }
+        same += len as u64;
+    }
+    if same == 0 && source_len > 0 {
+        return Ok(None);
+    }
+
+    let mut writer = fs::OpenOptions::new().write(true).open(destination)?;
+    writer.set_len(source_len)?;
+    reader.seek(SeekFrom::Start(same))?;
+    writer.seek(SeekFrom::Start(same))?;
+    let rewritten = io::copy(&mut reader.take(source_len - same), &mut writer)?;
+
+    // A source that changed size mid-copy needs a full copy after all.
+    if same + rewritten != source_len || fs::metadata(source)?.len() != source_len {
+        return Ok(None);
+    }
+    Ok(Some(rewritten))
+}
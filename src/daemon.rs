@@ -0,0 +1,142 @@
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::AtomicBool,
+        Arc,
+    },
+};
+
+pub struct PidFile {
+    path: PathBuf,
+}
+
+fn pid_is_alive(pid: i32) -> bool {
+    #[cfg(unix)]
+    {
+        // Signal 0 only checks that the process exists; EPERM means it exists
+        // but belongs to someone else.
+        let result = unsafe { libc::kill(pid, 0) };
+        result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        false
+    }
+}
+
+impl PidFile {
+    pub fn check(path: &Path) -> Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return Ok(()),
+        };
+
+        // Another instance has created it and is about to write its pid.
+        if contents.is_empty() {
+            anyhow::bail!("Pid file {:?} is empty: another instance is starting (remove it if none is)", path)
+        }
+        match contents.trim().parse::<i32>() {
+            Ok(pid) if pid_is_alive(pid) => {
+                anyhow::bail!("Already running as pid {} (pid file {:?})", pid, path)
+            }
+            _ => {
+                eprintln!("Removing stale pid file {:?}", path);
+                fs::remove_file(path).with_context(|| format!("Failed to remove {:?}", path))
+            }
+        }
+    }
+
+    /// Creates the pid file, failing if a live instance holds it. It's
+    /// created with `create_new`, so of two instances starting at once only
+    /// one gets it.
+    pub fn acquire(path: &Path) -> Result<Self> {
+        for _ in 0..2 {
+            let mut file = match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(file) => file,
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                    Self::check(path)?;
+                    continue;
+                }
+                Err(error) => return Err(error).with_context(|| format!("Failed to create pid file {:?}", path)),
+            };
+            let pid_file = PidFile { path: path.to_path_buf() };
+            writeln!(file, "{}", std::process::id()).with_context(|| format!("Failed to write pid file {:?}", path))?;
+            return Ok(pid_file);
+        }
+        anyhow::bail!("Another instance took pid file {:?} while this one was starting", path)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove pid file {:?}: {}", self.path, error);
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn daemonize(log_path: &Path) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .with_context(|| format!("Failed to open log file {:?}", log_path))?;
+    let null = fs::File::open("/dev/null").context("Failed to open /dev/null")?;
+
+    unsafe {
+        match libc::fork() {
+            -1 => anyhow::bail!("fork failed: {}", std::io::Error::last_os_error()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+
+        if libc::setsid() == -1 {
+            anyhow::bail!("setsid failed: {}", std::io::Error::last_os_error());
+        }
+
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_log_path: &Path) -> Result<()> {
+    anyhow::bail!("--daemonize is only supported on Unix")
+}
+
+/// Returns a flag that is set once SIGINT or SIGTERM is received.
+///
+/// Must be called before any other threads are spawned so they inherit the
+/// blocked signal mask and delivery is left to the dedicated waiter thread.
+pub fn shutdown_flag() -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+
+    #[cfg(unix)]
+    unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+
+        let waiter_flag = flag.clone();
+        std::thread::spawn(move || {
+            let mut signal = 0;
+            libc::sigwait(&signals, &mut signal);
+            waiter_flag.store(true, std::sync::atomic::Ordering::SeqCst);
+        });
+    }
+
+    flag
+}
@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    metrics,
+    mirror::{Operation, CONTROL_DIR},
+    report::unix_timestamp,
+};
+
+/// An operation that kept failing, set aside until `--retry-deadletter`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub operation: Operation,
+    pub error: String,
+    pub attempts: u32,
+    pub timestamp: u64,
+}
+
+/// `--dead-letter-after`: counts failures of the operations on each path
+/// and, past the limit, records the last one in
+/// `OUTPUT_ROOT/.rustsync/deadletter` and stops applying events to the path.
+pub struct DeadLetters {
+    after: u32,
+    path: PathBuf,
+    failures: Mutex<HashMap<PathBuf, u32>>,
+    letters: Mutex<BTreeMap<PathBuf, DeadLetter>>,
+}
+
+impl DeadLetters {
+    /// Loads what earlier runs set aside, which stays set aside.
+    pub fn open(output_root: &Path, after: u32) -> Result<Self> {
+        let control = output_root.join(CONTROL_DIR);
+        fs::create_dir_all(&control).with_context(|| format!("Failed to create {:?}", control))?;
+
+        let path = control.join("deadletter");
+        let mut letters = BTreeMap::new();
+        if let Ok(contents) = fs::read_to_string(&path) {
+            for letter in contents.lines().filter_map(|line| serde_json::from_str::<DeadLetter>(line).ok()) {
+                letters.insert(key(&letter.operation).to_path_buf(), letter);
+            }
+        }
+        metrics::set("dead_letters", letters.len() as u64);
+        Ok(DeadLetters {
+            after,
+            path,
+            failures: Mutex::new(HashMap::new()),
+            letters: Mutex::new(letters),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.letters.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn list(&self) -> Vec<DeadLetter> {
+        self.letters.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Whether `operation`'s path is set aside. If it is, `operation` takes
+    /// the place of what was recorded, being the latest change to retry. A
+    /// rename set aside holds back both its paths, and what it holds back on
+    /// the other path is set aside alongside it.
+    pub fn holds(&self, operation: &Operation) -> bool {
+        let mut letters = self.letters.lock().unwrap();
+        if let Some(letter) = letters.get_mut(key(operation)) {
+            if letter.operation != *operation {
+                letter.operation = operation.clone();
+                self.save(&letters);
+            }
+            return true;
+        }
+
+        let blocked = letters
+            .values()
+            .find(|letter| keys(&letter.operation).any(|held| keys(operation).any(|path| path == held)))
+            .map(|letter| format!("Held back behind {}", letter.operation));
+        let Some(error) = blocked else {
+            return false;
+        };
+        let letter = DeadLetter {
+            operation: operation.clone(),
+            error,
+            attempts: 0,
+            timestamp: unix_timestamp(),
+        };
+        letters.insert(key(operation).to_path_buf(), letter);
+        self.save(&letters);
+        true
+    }
+
+    /// Counts a failure of `operation`, returning how long to wait before
+    /// retrying it, or None once it's been retried `after` times and is set
+    /// aside.
+    pub fn failed(&self, operation: &Operation, error: &str) -> Option<Duration> {
+        let path = key(operation);
+        let attempts = {
+            let mut failures = self.failures.lock().unwrap();
+            let attempts = failures.entry(path.to_path_buf()).or_insert(0);
+            *attempts += 1;
+            if *attempts <= self.after {
+                // 1s, doubling up to a minute.
+                return Some((Duration::from_secs(1) * 2u32.pow((*attempts - 1).min(6))).min(Duration::from_secs(60)));
+            }
+            failures.remove(path).unwrap_or_default()
+        };
+
+        let letter = DeadLetter {
+            operation: operation.clone(),
+            error: error.to_string(),
+            attempts,
+            timestamp: unix_timestamp(),
+        };
+        let mut letters = self.letters.lock().unwrap();
+        letters.insert(path.to_path_buf(), letter);
+        self.save(&letters);
+        metrics::add("dead_lettered", 1);
+        None
+    }
+
+    /// Forgets the failures of an operation that has now been applied.
+    pub fn succeeded(&self, operation: &Operation) {
+        self.failures.lock().unwrap().remove(key(operation));
+    }
+
+    /// Takes everything set aside, to be applied again. Renames come first,
+    /// ahead of what they held back.
+    pub fn take(&self) -> Vec<DeadLetter> {
+        let mut letters = self.letters.lock().unwrap();
+        let mut taken: Vec<DeadLetter> = std::mem::take(&mut *letters).into_values().collect();
+        taken.sort_by_key(|letter| !matches!(letter.operation, Operation::Rename { .. }));
+        self.save(&letters);
+        taken
+    }
+
+    fn save(&self, letters: &BTreeMap<PathBuf, DeadLetter>) {
+        metrics::set("dead_letters", letters.len() as u64);
+        let mut contents = String::new();
+        for letter in letters.values() {
+            if let Ok(line) = serde_json::to_string(letter) {
+                contents.push_str(&line);
+                contents.push('\n');
+            }
+        }
+        let temp = self.path.with_extension("tmp");
+        if let Err(error) = fs::write(&temp, contents).and_then(|()| fs::rename(&temp, &self.path)) {
+            eprintln!("Failed to write {:?}: {}", self.path, error);
+        }
+    }
+}
+
+/// The path an operation's failures are counted against.
+fn key(operation: &Operation) -> &Path {
+    let (Operation::Create { path }
+    | Operation::Data { path }
+    | Operation::Metadata { path }
+    | Operation::Delete { path }
+    | Operation::Rename { path, .. }) = operation;
+    path
+}
+
+/// Every path an operation touches.
+fn keys(operation: &Operation) -> impl Iterator<Item = &Path> {
+    let new_path = match operation {
+        Operation::Rename { new_path, .. } => Some(new_path.as_path()),
+        _ => None,
+    };
+    std::iter::once(key(operation)).chain(new_path)
+}
@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{mirror::Operation, units::parse_duration};
+
+/// `<glob>=<duration>`: copies of matching files, relative to the watch
+/// root, wait until the file has had no events for that long.
+#[derive(Clone, Debug)]
+pub struct DebounceRule {
+    pub pattern: String,
+    pub window: Duration,
+    matcher: GlobMatcher,
+}
+
+impl FromStr for DebounceRule {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (pattern, window) = spec
+            .rsplit_once('=')
+            .with_context(|| format!("Expected <glob>=<duration>, got {:?}", spec))?;
+        let window = parse_duration(window.trim()).with_context(|| format!("Invalid debounce window {:?}", window))?;
+        let matcher = Glob::new(pattern.trim())
+            .with_context(|| format!("Invalid glob {:?}", pattern))?
+            .compile_matcher();
+
+        Ok(DebounceRule {
+            pattern: pattern.trim().to_string(),
+            window,
+            matcher,
+        })
+    }
+}
+
+/// The window of the first rule matching `relative`, or `default`.
+pub fn window_for(rules: &[DebounceRule], default: Duration, relative: &Path) -> Duration {
+    rules
+        .iter()
+        .find(|rule| rule.matcher.is_match(relative))
+        .map_or(default, |rule| rule.window)
+}
+
+struct Held {
+    operations: Vec<Operation>,
+    last_event: Instant,
+    window: Duration,
+}
+
+/// `--debounce`: copies of files held until each has gone quiet for its own
+/// window. Every event for a held file restarts its window.
+#[derive(Default)]
+pub struct Debouncer {
+    held: BTreeMap<PathBuf, Held>,
+}
+
+impl Debouncer {
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    /// Holds `operation` on `relative` along with anything already held for
+    /// it, restarting its quiet period. A repeat moves to the end instead of
+    /// running twice.
+    pub fn hold(&mut self, relative: &Path, operation: Operation, window: Duration, now: Instant) {
+        let held = self.held.entry(relative.to_path_buf()).or_insert_with(|| Held {
+            operations: Vec::new(),
+            last_event: now,
+            window,
+        });
+        held.last_event = now;
+        held.window = window;
+        held.operations.retain(|other| other != &operation);
+        held.operations.push(operation);
+    }
+
+    /// Queues `operation` behind the copy held for `relative`, if there is
+    /// one, restarting its quiet period.
+    pub fn follow(&mut self, relative: &Path, operation: &Operation, now: Instant) -> bool {
+        match self.held.get_mut(relative) {
+            Some(held) => {
+                held.last_event = now;
+                held.operations.retain(|other| other != operation);
+                held.operations.push(operation.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes out what's held for `relative` and anything under it.
+    pub fn take_under(&mut self, relative: &Path) -> Vec<Operation> {
+        let under: Vec<PathBuf> = self.held.keys().filter(|path| path.starts_with(relative)).cloned().collect();
+        under
+            .into_iter()
+            .flat_map(|path| self.held.remove(&path).unwrap().operations)
+            .collect()
+    }
+
+    /// Takes out the operations of files quiet for their whole window by `now`.
+    pub fn take_settled(&mut self, now: Instant) -> Vec<Operation> {
+        let settled: Vec<PathBuf> = self
+            .held
+            .iter()
+            .filter(|(_, held)| now.saturating_duration_since(held.last_event) >= held.window)
+            .map(|(path, _)| path.clone())
+            .collect();
+        settled
+            .into_iter()
+            .flat_map(|path| self.held.remove(&path).unwrap().operations)
+            .collect()
+    }
+}
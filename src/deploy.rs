@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    copy::{copy_file, Reflink},
+    mirror::{apply_metadata, Mirror, Options, Preserve},
+    report::unix_timestamp,
+};
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            fs::remove_dir_all(path).with_context(|| format!("Failed to remove {:?}", path))
+        }
+        Ok(_) => fs::remove_file(path).with_context(|| format!("Failed to remove {:?}", path)),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Copies the tree at `from` to `to`, cloning files where the filesystem
+/// can, then gives every copy its original's metadata as a sync would, so
+/// files a deploy didn't touch keep their times.
+fn copy_tree(from: &Path, to: &Path, options: &Options) -> Result<()> {
+    for entry in WalkDir::new(from).follow_links(false) {
+        let entry = entry.with_context(|| format!("Failed to walk {:?}", from))?;
+        let target = to.join(entry.path().strip_prefix(from)?);
+        let file_type = entry.file_type();
+
+        if file_type.is_dir() {
+            fs::create_dir_all(&target)?;
+            fs::set_permissions(&target, entry.metadata()?.permissions())?;
+        } else if file_type.is_symlink() {
+            crate::mirror::cross_platform_symlink(&fs::read_link(entry.path())?, &target)?;
+        } else {
+            copy_file(entry.path(), &target, Reflink::Auto)
+                .with_context(|| format!("Failed to copy {:?} -> {:?}", entry.path(), target))?;
+        }
+    }
+
+    // Contents first, so filling a directory doesn't move its times on again.
+    let copy = Mirror::new(from.to_path_buf(), to.to_path_buf(), options.clone());
+    for entry in WalkDir::new(from).follow_links(false).contents_first(true) {
+        let entry = entry.with_context(|| format!("Failed to walk {:?}", from))?;
+        if !entry.file_type().is_symlink() {
+            apply_metadata(&copy, entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Mirrors into `<output>.staging` and swaps it over `<output>` once events
+/// have settled, keeping the previous tree as `<output>.old`.
+///
+/// A plain directory output is swapped with two renames, which leaves a
+/// moment where `<output>` does not exist. If `<output>` is a symlink the new
+/// tree is published by atomically replacing the link instead.
+pub struct AtomicDeploy {
+    pub output_root: PathBuf,
+    pub staging: PathBuf,
+    settle_time: Duration,
+    last_change: Option<Instant>,
+    /// What staging copies of the output keep: the `--preserve` categories
+    /// and flags, as they are, since the output's owners are already mapped
+    /// and its setuid bits already stripped.
+    copy_options: Options,
+}
+
+impl AtomicDeploy {
+    pub fn new(output_root: &Path, settle_time: Duration, preserve: &[Preserve], preserve_flags: bool) -> Result<Self> {
+        let output_root = std::path::absolute(output_root)?;
+        let staging = sibling(&output_root, ".staging");
+
+        let deploy = AtomicDeploy {
+            output_root,
+            staging,
+            settle_time,
+            last_change: None,
+            copy_options: Options {
+                preserve: preserve.to_vec(),
+                preserve_flags,
+                ..Options::default()
+            },
+        };
+        deploy.prepare()?;
+        Ok(deploy)
+    }
+
+    fn prepare(&self) -> Result<()> {
+        remove_if_exists(&self.staging)?;
+        copy_tree(&fs::canonicalize(&self.output_root)?, &self.staging, &self.copy_options)
+            .with_context(|| format!("Failed to prepare staging {:?}", self.staging))
+    }
+
+    pub fn changed(&mut self) {
+        self.last_change = Some(Instant::now());
+    }
+
+    pub fn poll(&mut self) -> Result<()> {
+        match self.last_change {
+            Some(last_change) if last_change.elapsed() >= self.settle_time => {}
+            _ => return Ok(()),
+        }
+
+        self.last_change = None;
+        self.swap()?;
+        println!("Deployed {:?}", self.output_root);
+        self.prepare()
+    }
+
+    fn swap(&self) -> Result<()> {
+        let old = sibling(&self.output_root, ".old");
+
+        if self.output_root.is_symlink() {
+            let previous = fs::read_link(&self.output_root)?;
+            let mut release = sibling(&self.output_root, &format!(".{}", unix_timestamp()));
+            for attempt in 1.. {
+                if !release.exists() {
+                    break;
+                }
+                release = sibling(&self.output_root, &format!(".{}-{}", unix_timestamp(), attempt));
+            }
+            let link = sibling(&self.output_root, ".new");
+
+            fs::rename(&self.staging, &release)?;
+            remove_if_exists(&link)?;
+            crate::mirror::cross_platform_symlink(&release, &link)?;
+            fs::rename(&link, &self.output_root)
+                .with_context(|| format!("Failed to flip symlink {:?}", self.output_root))?;
+
+            let previous = self.output_root.parent().unwrap_or(Path::new("/")).join(previous);
+            remove_if_exists(&old)?;
+            fs::rename(&previous, &old)
+                .with_context(|| format!("Failed to keep previous release as {:?}", old))?;
+        } else {
+            remove_if_exists(&old)?;
+            fs::rename(&self.output_root, &old)
+                .with_context(|| format!("Failed to move {:?} aside", self.output_root))?;
+            fs::rename(&self.staging, &self.output_root)
+                .with_context(|| format!("Failed to publish {:?}", self.staging))?;
+        }
+
+        Ok(())
+    }
+}
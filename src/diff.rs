@@ -0,0 +1,113 @@
+use similar::TextDiff;
+use std::{
+    fs,
+    io::Read,
+    path::Path,
+};
+
+use crate::mirror::{destination_path, mirrored_path, read_stored, stored_size, Mirror, Operation};
+
+/// Prints unified diffs for the text files a dry run would overwrite, and size
+/// changes for everything else, until `max_lines` lines have been printed.
+pub struct DiffPrinter {
+    pub max_file_size: u64,
+    pub max_lines: usize,
+    printed: usize,
+    suppressed: usize,
+}
+
+impl DiffPrinter {
+    pub fn new(max_file_size: u64, max_lines: usize) -> Self {
+        DiffPrinter {
+            max_file_size,
+            max_lines,
+            printed: 0,
+            suppressed: 0,
+        }
+    }
+
+    pub fn operation(&mut self, mirror: &Mirror, operation: &Operation) {
+        let relative = match operation {
+            Operation::Data { path } => path,
+            _ => return,
+        };
+
+        let mirrored = match mirrored_path(mirror, relative) {
+            Some(mirrored) => mirrored,
+            None => return,
+        };
+        let destination = destination_path(mirror, &mirrored);
+        if !destination.is_file() {
+            return;
+        }
+
+        if self.printed >= self.max_lines {
+            self.suppressed += 1;
+            return;
+        }
+
+        let source = mirror.watch_root.join(relative);
+        let old = read_text(mirror, &destination, destination != mirrored, self.max_file_size);
+        let new = read_text(mirror, &source, false, self.max_file_size);
+
+        let rendered = match (old, new) {
+            (Some(old), Some(new)) => TextDiff::from_lines(&old, &new)
+                .unified_diff()
+                .header(&mirrored.to_string_lossy(), &source.to_string_lossy())
+                .to_string(),
+            _ => {
+                let old_size = file_size(mirror, &destination, destination != mirrored);
+                let new_size = fs::metadata(&source).map(|metadata| metadata.len()).unwrap_or(0);
+                format!(
+                    "Binary {:?}: {} -> {} bytes ({:+})\n",
+                    relative,
+                    old_size,
+                    new_size,
+                    new_size as i64 - old_size as i64
+                )
+            }
+        };
+
+        for line in rendered.lines() {
+            if self.printed >= self.max_lines {
+                println!("[diff truncated]");
+                break;
+            }
+            println!("{}", line);
+            self.printed += 1;
+        }
+    }
+
+    pub fn finish(&self) {
+        if self.suppressed > 0 {
+            println!("... {} more changed files not shown (--diff-max-lines {})", self.suppressed, self.max_lines);
+        }
+    }
+}
+
+fn file_size(mirror: &Mirror, path: &Path, transformed: bool) -> u64 {
+    if transformed {
+        if let Some(size) = stored_size(mirror, path) {
+            return size;
+        }
+    }
+    fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0)
+}
+
+/// Reads a file as text if it's small enough and looks like UTF-8.
+fn read_text(mirror: &Mirror, path: &Path, transformed: bool, max_size: u64) -> Option<String> {
+    if file_size(mirror, path, transformed) > max_size {
+        return None;
+    }
+
+    let mut bytes = Vec::new();
+    match transformed {
+        true => read_stored(mirror, path).ok()?.read_to_end(&mut bytes).ok()?,
+        false => fs::File::open(path).ok()?.read_to_end(&mut bytes).ok()?,
+    };
+
+    if bytes.contains(&0) {
+        return None;
+    }
+    String::from_utf8(bytes).ok()
+}
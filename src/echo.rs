@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::mirror::CONTROL_DIR;
+
+/// What a write left at a path: its size and modification time, or nothing
+/// for a delete.
+type Expected = Option<(u64, Option<SystemTime>)>;
+
+fn observe(path: &Path) -> Expected {
+    fs::symlink_metadata(path).ok().map(|metadata| (metadata.len(), metadata.modified().ok()))
+}
+
+const LOG: &str = "self-writes";
+/// Past this size a log is rewritten with only the writes still in the window.
+const LOG_LIMIT: u64 = 1 << 20;
+
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64)
+}
+
+fn from_nanos(nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos)
+}
+
+/// One write in a `.rustsync/self-writes` log.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(with = "crate::pathbytes")]
+    path: PathBuf,
+    size: Option<u64>,
+    mtime_ns: Option<u64>,
+    written_ns: u64,
+}
+
+/// The logs another process shares the set through: writes under
+/// `record_root` go to its log, and its peer's writes under `check_root` are
+/// read from that one's.
+struct Logs {
+    record_root: PathBuf,
+    check_root: PathBuf,
+    /// How much of the checked log has been read.
+    read: Mutex<u64>,
+}
+
+/// Destination paths recently written by mirrors sharing this set, so that a
+/// mirror watching another's destination, as two mirrors pointed at each
+/// other do, can drop the events those writes cause instead of copying them
+/// back. A path only counts as an echo while it's still as it was written.
+pub struct SelfWrites {
+    window: Duration,
+    writes: Mutex<HashMap<PathBuf, (Expected, SystemTime)>>,
+    logs: Option<Logs>,
+}
+
+impl SelfWrites {
+    pub fn new(window: Duration) -> Self {
+        SelfWrites {
+            window,
+            writes: Mutex::new(HashMap::new()),
+            logs: None,
+        }
+    }
+
+    /// A set shared with a separate rustsync process mirroring `output_root`
+    /// back to `watch_root`, through `.rustsync/self-writes` in each.
+    pub fn shared(window: Duration, output_root: &Path, watch_root: &Path) -> Self {
+        SelfWrites {
+            logs: Some(Logs {
+                record_root: output_root.to_path_buf(),
+                check_root: watch_root.to_path_buf(),
+                read: Mutex::new(0),
+            }),
+            ..SelfWrites::new(window)
+        }
+    }
+
+    /// Records `path` as just written (or deleted), as it is now.
+    pub fn record(&self, path: &Path) {
+        let mut writes = self.writes.lock().unwrap();
+        let now = SystemTime::now();
+        writes.retain(|_, (_, written)| now.duration_since(*written).unwrap_or_default() < self.window);
+        let expected = observe(path);
+        writes.insert(path.to_path_buf(), (expected, now));
+
+        let Some(logs) = &self.logs else {
+            return;
+        };
+        let Ok(relative) = path.strip_prefix(&logs.record_root) else {
+            return;
+        };
+        let log = logs.record_root.join(CONTROL_DIR).join(LOG);
+        let entry = Entry {
+            path: relative.to_path_buf(),
+            size: expected.map(|(size, _)| size),
+            mtime_ns: expected.and_then(|(_, modified)| modified).map(nanos),
+            written_ns: nanos(now),
+        };
+        let result = match fs::metadata(&log).map_or(0, |metadata| metadata.len()) > LOG_LIMIT {
+            true => compact(&log, &logs.record_root, &writes),
+            false => append(&log, &entry),
+        };
+        if let Err(error) = result {
+            eprintln!("Failed to write {:?}: {}", log, error);
+        }
+    }
+
+    /// Whether `path` was recorded inside the window and hasn't changed since.
+    pub fn is_echo(&self, path: &Path) -> bool {
+        self.refresh();
+        let writes = self.writes.lock().unwrap();
+        writes.get(path).is_some_and(|(expected, written)| {
+            written.elapsed().unwrap_or_default() < self.window && *expected == observe(path)
+        })
+    }
+
+    /// Takes in what the peer process logged since the last look.
+    fn refresh(&self) {
+        let Some(logs) = &self.logs else {
+            return;
+        };
+        let Ok(mut file) = File::open(logs.check_root.join(CONTROL_DIR).join(LOG)) else {
+            return;
+        };
+        let mut read = logs.read.lock().unwrap();
+        let len = file.metadata().map_or(0, |metadata| metadata.len());
+        // Compacted since, so read it afresh.
+        if len < *read {
+            *read = 0;
+        }
+        if len == *read || file.seek(SeekFrom::Start(*read)).is_err() {
+            return;
+        }
+
+        let mut writes = self.writes.lock().unwrap();
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        // A torn last line is left for the next look.
+        while reader.read_line(&mut line).is_ok_and(|count| count > 0) && line.ends_with('\n') {
+            *read += line.len() as u64;
+            if let Ok(entry) = serde_json::from_str::<Entry>(&line) {
+                let expected = entry.size.map(|size| (size, entry.mtime_ns.map(from_nanos)));
+                writes.insert(logs.check_root.join(&entry.path), (expected, from_nanos(entry.written_ns)));
+            }
+            line.clear();
+        }
+    }
+}
+
+fn append(log: &Path, entry: &Entry) -> std::io::Result<()> {
+    if let Some(parent) = log.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    OpenOptions::new().create(true).append(true).open(log)?.write_all(line.as_bytes())
+}
+
+/// Rewrites `log` with this process's writes still in the window.
+fn compact(log: &Path, root: &Path, writes: &HashMap<PathBuf, (Expected, SystemTime)>) -> std::io::Result<()> {
+    let mut contents = String::new();
+    for (path, (expected, written)) in writes {
+        let Ok(relative) = path.strip_prefix(root) else {
+            continue;
+        };
+        let entry = Entry {
+            path: relative.to_path_buf(),
+            size: expected.map(|(size, _)| size),
+            mtime_ns: expected.and_then(|(_, modified)| modified).map(nanos),
+            written_ns: nanos(*written),
+        };
+        contents.push_str(&serde_json::to_string(&entry)?);
+        contents.push('\n');
+    }
+    let temp = log.with_extension("tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, log)
+}
@@ -0,0 +1,340 @@
+use anyhow::{Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    copy::{open_source, staging_path},
+    hash::{hash_file, ChecksumAlgorithm},
+    keys::derive_key,
+    mirror::CONTROL_DIR,
+};
+
+const MAGIC: &[u8; 8] = b"RSYNCENC";
+const HEADER_LIMIT: u32 = 64 * 1024;
+/// Plaintext bytes per sealed chunk; each chunk grows by a 16 byte tag.
+const CHUNK: usize = 64 * 1024;
+const TAG: usize = 16;
+const SALT_FILE: &str = "encryption.salt";
+
+/// Settings for `--encrypt-dest`, holding the derived key.
+#[derive(Clone)]
+pub struct Encryption {
+    cipher: XChaCha20Poly1305,
+    name_key: [u8; 32],
+    hash_key: [u8; 32],
+    /// Also encrypt every path component (`--encrypt-names`).
+    pub names: bool,
+    pub algorithm: ChecksumAlgorithm,
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption").field("names", &self.names).field("algorithm", &self.algorithm).finish()
+    }
+}
+
+/// Original size and hash of an encrypted file, plus the nonce prefix of its
+/// chunks. Stored in the clear and authenticated with every chunk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub size: u64,
+    pub algorithm: ChecksumAlgorithm,
+    /// The plaintext's hash keyed by `Encryption::keyed_hash`, so that
+    /// without the key it can't be checked against a known file.
+    pub hash: String,
+    nonce: [u8; 15],
+}
+
+fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    getrandom::fill(&mut bytes).map_err(|error| anyhow::anyhow!("No randomness available: {}", error))?;
+    Ok(bytes)
+}
+
+/// Chunk nonces are the file's random prefix, a last-chunk flag and the
+/// chunk index, so chunks can't be reordered, dropped or cut off unnoticed.
+fn chunk_nonce(prefix: &[u8; 15], index: u64, last: bool) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[..15].copy_from_slice(prefix);
+    nonce[15] = last as u8;
+    nonce[16..].copy_from_slice(&index.to_be_bytes());
+    nonce.into()
+}
+
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    encoded
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in encoded.bytes() {
+        let value = BASE32.iter().position(|&symbol| symbol == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+impl Encryption {
+    /// Derives the key from `passphrase` and the destination's salt, creating
+    /// the salt in `<output_root>/.rustsync` on first use.
+    pub fn new(passphrase: &[u8], output_root: &Path, names: bool, algorithm: ChecksumAlgorithm) -> Result<Self> {
+        let salt_path = output_root.join(CONTROL_DIR).join(SALT_FILE);
+        let salt = match fs::read(&salt_path) {
+            Ok(salt) => salt,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                let salt = random::<16>()?.to_vec();
+                fs::create_dir_all(output_root.join(CONTROL_DIR))?;
+                fs::write(&salt_path, &salt).with_context(|| format!("Failed to write {:?}", salt_path))?;
+                salt
+            }
+            Err(error) => return Err(error).with_context(|| format!("Failed to read {:?}", salt_path)),
+        };
+
+        let key = derive_key(passphrase, &salt)?;
+        Ok(Encryption {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            name_key: blake3::derive_key("rustsync 2025 encrypted file names", &key),
+            hash_key: blake3::derive_key("rustsync 2025 encrypted file hashes", &key),
+            names,
+            algorithm,
+        })
+    }
+
+    /// What a header stores for a plaintext `hash`.
+    pub fn keyed_hash(&self, hash: &str) -> String {
+        blake3::keyed_hash(&self.hash_key, hash.as_bytes()).to_hex().to_string()
+    }
+
+    /// Deterministically encrypts one path component, so the same name always
+    /// maps to the same ciphertext. The synthetic nonce is a keyed hash of the
+    /// name and is checked again on decryption. Names grow by roughly 60%
+    /// plus 52 characters, so long names can exceed the filesystem's limit.
+    pub fn encrypt_name(&self, name: &OsStr) -> OsString {
+        let plain = name.as_encoded_bytes();
+        let siv: [u8; 16] = blake3::keyed_hash(&self.name_key, plain).as_bytes()[..16].try_into().unwrap();
+        let mut nonce = [0u8; 24];
+        nonce[..16].copy_from_slice(&siv);
+
+        // Only inputs far beyond any file name length can fail to encrypt.
+        let sealed = self.cipher.encrypt(&nonce.into(), plain).expect("name too long to encrypt");
+        OsString::from(base32_encode(&[&siv[..], &sealed].concat()))
+    }
+
+    pub fn decrypt_name(&self, name: &OsStr) -> Option<OsString> {
+        let bytes = base32_decode(name.to_str()?)?;
+        if bytes.len() < 16 + TAG {
+            return None;
+        }
+        let (siv, sealed) = bytes.split_at(16);
+        let mut nonce = [0u8; 24];
+        nonce[..16].copy_from_slice(siv);
+
+        let plain = self.cipher.decrypt(&nonce.into(), sealed).ok()?;
+        if blake3::keyed_hash(&self.name_key, &plain).as_bytes()[..16] != *siv {
+            return None;
+        }
+        // Safety: these bytes came from `OsStr::as_encoded_bytes` on this platform.
+        Some(unsafe { OsString::from_encoded_bytes_unchecked(plain) })
+    }
+}
+
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.file_name().unwrap_or_default());
+    name.push(".enc");
+    path.with_file_name(name)
+}
+
+/// Strips `.enc` from a file written by `encrypt_file`, or returns `None` for
+/// anything else.
+pub fn original_path(path: &Path) -> Option<PathBuf> {
+    if path.extension()? != "enc" {
+        return None;
+    }
+    read_header(path).ok()??;
+    Some(path.with_extension(""))
+}
+
+/// Encrypts `source` into `destination` via a temp file.
+pub fn encrypt_file(source: &Path, destination: &Path, encryption: &Encryption) -> Result<()> {
+    let header = Header {
+        size: fs::metadata(source)
+            .with_context(|| format!("Failed to read metadata for {:?}", source))?
+            .len(),
+        algorithm: encryption.algorithm,
+        hash: encryption.keyed_hash(&hash_file(source, encryption.algorithm)?),
+        nonce: random()?,
+    };
+
+    let temp = staging_path(destination);
+    let result = write_encrypted(source, &temp, &header, encryption)
+        .and_then(|()| fs::rename(&temp, destination).with_context(|| format!("Failed to rename {:?}", temp)));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn read_chunk(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn write_encrypted(source: &Path, temp: &Path, header: &Header, encryption: &Encryption) -> Result<()> {
+    let mut input = BufReader::new(open_source(source).with_context(|| format!("Failed to open {:?}", source))?);
+    let mut output = File::create(temp).with_context(|| format!("Failed to create {:?}", temp))?;
+
+    let payload = serde_json::to_vec(header)?;
+    output.write_all(MAGIC)?;
+    output.write_all(&(payload.len() as u32).to_le_bytes())?;
+    output.write_all(&payload)?;
+
+    // Read one chunk ahead so the final chunk, even an empty one, is flagged.
+    let mut current = vec![0u8; CHUNK];
+    let mut next = vec![0u8; CHUNK];
+    let mut len = read_chunk(&mut input, &mut current)?;
+    for index in 0.. {
+        let next_len = match len {
+            CHUNK => read_chunk(&mut input, &mut next)?,
+            _ => 0,
+        };
+        let last = len < CHUNK || next_len == 0;
+
+        let sealed = encryption
+            .cipher
+            .encrypt(&chunk_nonce(&header.nonce, index, last), Payload { msg: &current[..len], aad: &payload })
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt {:?}", source))?;
+        output.write_all(&sealed)?;
+
+        if last {
+            break;
+        }
+        std::mem::swap(&mut current, &mut next);
+        len = next_len;
+    }
+    Ok(())
+}
+
+fn read_prefix(file: &mut File) -> io::Result<Option<Vec<u8>>> {
+    let mut prefix = [0u8; 12];
+    if file.read_exact(&mut prefix).is_err() || &prefix[..8] != MAGIC {
+        return Ok(None);
+    }
+    let length = u32::from_le_bytes(prefix[8..].try_into().unwrap());
+    if length > HEADER_LIMIT {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; length as usize];
+    file.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Reads the header written by `encrypt_file`, or `None` if the file doesn't
+/// start with one. The header is only authenticated once the data is read.
+pub fn read_header(path: &Path) -> io::Result<Option<Header>> {
+    let mut file = File::open(path)?;
+    Ok(read_prefix(&mut file)?.and_then(|payload| serde_json::from_slice(&payload).ok()))
+}
+
+/// Streams the decrypted contents of an encrypted file. Reads fail if any
+/// chunk (or the header) was altered, reordered or cut off.
+pub fn decrypt(path: &Path, encryption: &Encryption) -> Result<impl Read> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let payload = read_prefix(&mut file)?.with_context(|| format!("{:?} is not an encrypted file", path))?;
+    let header: Header = serde_json::from_slice(&payload)?;
+
+    Ok(Decryptor {
+        input: BufReader::new(file),
+        cipher: encryption.cipher.clone(),
+        nonce: header.nonce,
+        aad: payload,
+        index: 0,
+        plain: Vec::new(),
+        position: 0,
+        done: false,
+    })
+}
+
+struct Decryptor {
+    input: BufReader<File>,
+    cipher: XChaCha20Poly1305,
+    nonce: [u8; 15],
+    aad: Vec<u8>,
+    index: u64,
+    plain: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl Decryptor {
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let mut sealed = vec![0u8; CHUNK + TAG];
+        let len = read_chunk(&mut self.input, &mut sealed)?;
+        // A full chunk may still be the last one; peek to find out.
+        let last = len < CHUNK + TAG || self.input.fill_buf()?.is_empty();
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "encrypted file was altered or truncated");
+        self.plain = self
+            .cipher
+            .decrypt(
+                &chunk_nonce(&self.nonce, self.index, last),
+                Payload { msg: &sealed[..len], aad: &self.aad },
+            )
+            .map_err(|_| invalid())?;
+        self.position = 0;
+        self.index += 1;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl Read for Decryptor {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let count = buffer.len().min(self.plain.len() - self.position);
+        buffer[..count].copy_from_slice(&self.plain[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    metrics,
+    mirror::{
+        drain_queued_copies, flush_deletes, flush_held, flush_transactions, handle_event, has_queued_copies, resume_pending, run_queued_copy, Mirror, Options,
+    },
+    reconcile::reconcile,
+};
+
+enum Message {
+    Event(notify::Event),
+    Reconcile,
+}
+
+struct Destination {
+    output_root: PathBuf,
+    sender: Sender<Message>,
+    backlog: Arc<AtomicU64>,
+}
+
+/// Mirrors events onto extra destinations (`--dest`), each on its own thread
+/// so a slow or failing target only backs up its own queue.
+///
+/// Metrics are kept per destination as `dest<N>_backlog` (events waiting) and
+/// `dest<N>_applied` (events handled), numbered from 1 in `--dest` order.
+pub struct FanOut {
+    destinations: Vec<Destination>,
+}
+
+impl FanOut {
+    pub fn new(watch_root: PathBuf, output_roots: Vec<PathBuf>, options: Options) -> Self {
+        let destinations = output_roots
+            .into_iter()
+            .enumerate()
+            .map(|(index, output_root)| {
+                let (sender, receiver) = channel();
+                let backlog = Arc::new(AtomicU64::new(0));
+                let mirror = Mirror::new(watch_root.clone(), output_root.clone(), options.clone());
+                let pending = backlog.clone();
+
+                thread::spawn(move || {
+                    let backlog_metric = format!("dest{}_backlog", index + 1);
+                    let applied_metric = format!("dest{}_applied", index + 1);
+
+                    loop {
+                        let timeout = match has_queued_copies(&mirror) {
+                            true => Duration::ZERO,
+                            false => Duration::from_millis(200),
+                        };
+                        match receiver.recv_timeout(timeout) {
+                            Ok(Message::Event(event)) => {
+                                handle_event(&mirror, &event);
+                                metrics::set(&backlog_metric, pending.fetch_sub(1, Ordering::SeqCst) - 1);
+                                metrics::add(&applied_metric, 1);
+                            }
+                            Ok(Message::Reconcile) => {
+                                println!("Sync complete for {:?}: {}", mirror.output_root, reconcile(&mirror));
+                            }
+                            Err(RecvTimeoutError::Timeout) => {}
+                            Err(RecvTimeoutError::Disconnected) => {
+                                flush_transactions(&mirror, true);
+                                drain_queued_copies(&mirror);
+                                flush_deletes(&mirror, true);
+                                break;
+                            }
+                        }
+                        run_queued_copy(&mirror);
+
+                        resume_pending(&mirror);
+                        flush_held(&mirror);
+                    }
+                });
+
+                Destination {
+                    output_root,
+                    sender,
+                    backlog,
+                }
+            })
+            .collect();
+
+        FanOut { destinations }
+    }
+
+    pub fn output_roots(&self) -> impl Iterator<Item = &PathBuf> {
+        self.destinations.iter().map(|destination| &destination.output_root)
+    }
+
+    pub fn handle_event(&self, event: &notify::Event) {
+        for destination in &self.destinations {
+            destination.backlog.fetch_add(1, Ordering::SeqCst);
+            if destination.sender.send(Message::Event(event.clone())).is_err() {
+                destination.backlog.fetch_sub(1, Ordering::SeqCst);
+                eprintln!("Destination {:?} stopped, dropping event", destination.output_root);
+            }
+        }
+    }
+
+    pub fn reconcile(&self) {
+        for destination in &self.destinations {
+            let _ = destination.sender.send(Message::Reconcile);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::{io, path::Path};
+
+/// `FS_IMMUTABLE_FL`: the file can't be changed, renamed, linked or removed.
+pub const IMMUTABLE: u32 = 0x10;
+/// `FS_APPEND_FL`: the file can only be opened for appending.
+pub const APPEND: u32 = 0x20;
+/// The chattr flags `--preserve-flags` copies. Setting or clearing either
+/// needs CAP_LINUX_IMMUTABLE.
+pub const MIRRORED: u32 = IMMUTABLE | APPEND;
+
+#[cfg(target_os = "linux")]
+fn open(path: &Path) -> io::Result<std::fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // Read-only and non-blocking, so directories and FIFOs open too.
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(path)
+}
+
+/// The inode flags of `path`, as `lsattr` shows them.
+#[cfg(target_os = "linux")]
+pub fn get(path: &Path) -> io::Result<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = open(path)?;
+    // The kernel reads and writes an int, whatever the ioctl number says.
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags as u32)
+}
+
+#[cfg(target_os = "linux")]
+pub fn set(path: &Path, flags: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = open(path)?;
+    let flags = flags as libc::c_int;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get(_path: &Path) -> io::Result<u32> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "inode flags are only supported on Linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set(_path: &Path, _flags: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "inode flags are only supported on Linux"))
+}
+
+/// Gives `destination` the `MIRRORED` flags of `source_flags`, keeping its
+/// other flags. Does nothing when they're already the same.
+pub fn apply(destination: &Path, source_flags: u32) -> io::Result<()> {
+    let flags = get(destination)?;
+    let wanted = (flags & !MIRRORED) | (source_flags & MIRRORED);
+    match wanted == flags {
+        true => Ok(()),
+        false => set(destination, wanted),
+    }
+}
+
+/// Runs `write` with `destination`'s immutable and append-only flags
+/// cleared, since either stops it being rewritten, then puts them back. The
+/// source's flags, if they're preserved, go on with its other metadata.
+pub fn while_cleared<T>(destination: &Path, write: impl FnOnce() -> T) -> T {
+    while_all_cleared(&[destination], write)
+}
+
+/// `while_cleared` for several paths at once. Paths that are gone by the
+/// time `write` returns are left be.
+pub fn while_all_cleared<T>(paths: &[&Path], write: impl FnOnce() -> T) -> T {
+    let cleared: Vec<(&Path, u32)> = paths
+        .iter()
+        .filter_map(|&path| get(path).ok().filter(|flags| flags & MIRRORED != 0).map(|flags| (path, flags)))
+        .collect();
+    for &(path, flags) in &cleared {
+        let _ = set(path, flags & !MIRRORED);
+    }
+    let result = write();
+    for (path, flags) in cleared {
+        let _ = apply(path, flags);
+    }
+    result
+}
@@ -0,0 +1,197 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    fmt,
+    fs::File,
+    io::Read,
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    thread,
+};
+
+use crate::{copy::open_source, inflight};
+
+/// Files at least this large are memory mapped for SHA-2 hashing.
+static MMAP_THRESHOLD: AtomicU64 = AtomicU64::new(64 * 1024 * 1024);
+
+/// Files at least this large are memory mapped for BLAKE3 hashing, which
+/// then spreads them across all cores.
+const BLAKE3_MMAP_THRESHOLD: u64 = 16 * 1024;
+
+pub fn set_mmap_threshold(bytes: u64) {
+    MMAP_THRESHOLD.store(bytes, Ordering::Relaxed);
+}
+
+pub fn mmap_threshold() -> u64 {
+    MMAP_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Files hashed at once by manifest builds, 0 for one per CPU.
+static JOBS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_jobs(jobs: usize) {
+    JOBS.store(jobs, Ordering::Relaxed);
+}
+
+pub fn jobs() -> usize {
+    match JOBS.load(Ordering::Relaxed) {
+        0 => thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+        jobs => jobs,
+    }
+}
+
+/// Runs `hash` over `items` on up to `jobs()` threads, each with one file
+/// open at a time, and returns the results in the order of `items`.
+pub fn hash_parallel<T: Sync>(items: &[T], hash: impl Fn(&T) -> Result<String> + Sync) -> Vec<Result<String>> {
+    let workers = jobs().min(items.len());
+    if workers <= 1 {
+        return items.iter().map(hash).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<String>)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            break done;
+                        };
+                        done.push((index, hash(item)));
+                    }
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+    Sha512,
+}
+
+impl ChecksumAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Blake3 => "blake3",
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Sha512 => "sha512",
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "sha512" => Ok(ChecksumAlgorithm::Sha512),
+            _ => anyhow::bail!("Unknown checksum algorithm {:?}", name),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hash_reader<D: Digest>(mut reader: impl Read, path: &Path) -> Result<String> {
+    let mut hasher = D::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = reader
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Hashes a stream, such as a decompressed file; `path` is only for errors.
+pub fn hash_stream(reader: impl Read, path: &Path, algorithm: ChecksumAlgorithm) -> Result<String> {
+    match algorithm {
+        ChecksumAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            hasher
+                .update_reader(reader)
+                .with_context(|| format!("Failed to read {:?}", path))?;
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+        ChecksumAlgorithm::Sha256 => hash_reader::<Sha256>(reader, path),
+        ChecksumAlgorithm::Sha512 => hash_reader::<Sha512>(reader, path),
+    }
+}
+
+/// Hashes a file, opened with `open_source` so its access time is left alone.
+pub fn hash_file(path: &Path, algorithm: ChecksumAlgorithm) -> Result<String> {
+    let file = open_source(path).with_context(|| format!("Failed to open {:?}", path))?;
+    if let Some(hash) = hash_mapped(&file, algorithm) {
+        return Ok(hash);
+    }
+    hash_stream(file, path, algorithm)
+}
+
+fn digest_bytes<D: Digest>(bytes: &[u8]) -> String {
+    to_hex(&D::digest(bytes))
+}
+
+/// Hashes a large file through a memory map, or returns None to have the
+/// caller fall back to buffered reads: the file is small, can't be mapped, or
+/// changed size while it was hashed. A file truncated mid-hash still raises
+/// SIGBUS, which Rust can't catch, so the size is checked up front and the map
+/// covers only that many bytes.
+fn hash_mapped(file: &File, algorithm: ChecksumAlgorithm) -> Option<String> {
+    let len = file.metadata().ok()?.len();
+    let threshold = match algorithm {
+        ChecksumAlgorithm::Blake3 => BLAKE3_MMAP_THRESHOLD,
+        _ => mmap_threshold(),
+    };
+    if len < threshold || len > usize::MAX as u64 {
+        return None;
+    }
+    let _reservation = inflight::reserve(inflight::footprint(len, true));
+
+    // Safety: the mapping is read-only and bounded by the size just read;
+    // concurrent writes can only change the bytes seen, which the size
+    // recheck below and the next sync catch.
+    let map = unsafe { memmap2::MmapOptions::new().len(len as usize).map(file) }.ok()?;
+    #[cfg(unix)]
+    let _ = map.advise(memmap2::Advice::Sequential);
+
+    let hash = hash_map(&map, algorithm);
+    (file.metadata().ok()?.len() == len).then_some(hash)
+}
+
+fn hash_map(map: &Mmap, algorithm: ChecksumAlgorithm) -> String {
+    match algorithm {
+        ChecksumAlgorithm::Blake3 => blake3::Hasher::new().update_rayon(map).finalize().to_hex().to_string(),
+        ChecksumAlgorithm::Sha256 => digest_bytes::<Sha256>(map),
+        ChecksumAlgorithm::Sha512 => digest_bytes::<Sha512>(map),
+    }
+}
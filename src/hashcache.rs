@@ -0,0 +1,153 @@
+use anyhow::{Context, Result};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{copy::temp_path, hash::ChecksumAlgorithm, mirror::CONTROL_DIR};
+
+/// Entries kept across runs; the least recently used are dropped beyond this.
+const CAPACITY: NonZeroUsize = NonZeroUsize::new(1_000_000).unwrap();
+
+/// What a cached hash was computed from. Any change means the file was
+/// rewritten and has to be hashed again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    size: u64,
+    mtime_ns: u128,
+    inode: u64,
+}
+
+impl Stamp {
+    fn of(metadata: &fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        Stamp {
+            size: metadata.len(),
+            mtime_ns: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_nanos()),
+            inode,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(with = "crate::pathbytes")]
+    path: PathBuf,
+    stamp: Stamp,
+    hash: String,
+}
+
+/// File hashes for one root and algorithm, persisted under
+/// `~/.rustsync/hashcache` so repeated manifest builds only hash files that
+/// changed since the last run.
+pub struct HashCache {
+    file: PathBuf,
+    entries: LruCache<PathBuf, (Stamp, String)>,
+    hits: u64,
+    misses: u64,
+}
+
+pub fn cache_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(CONTROL_DIR).join("hashcache"))
+}
+
+/// Removes every cached hash.
+pub fn clear() -> Result<()> {
+    let dir = cache_dir().context("No home directory")?;
+    match fs::remove_dir_all(&dir) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+            Err(error).with_context(|| format!("Failed to remove {:?}", dir))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl HashCache {
+    /// Loads the cache for `root`. A missing or unreadable cache starts empty.
+    pub fn open(root: &Path, algorithm: ChecksumAlgorithm) -> Result<Self> {
+        let dir = cache_dir().context("No home directory")?;
+        let key = blake3::hash(root.as_os_str().as_encoded_bytes()).to_hex();
+        let file = dir.join(format!("{}.{}", &key[..32], algorithm));
+
+        let mut entries = LruCache::new(CAPACITY);
+        if let Ok(contents) = fs::read(&file) {
+            match serde_json::from_slice::<Vec<Entry>>(&contents) {
+                // Saved most recent first, so insert oldest first.
+                Ok(saved) => saved.into_iter().rev().for_each(|entry| {
+                    entries.put(entry.path, (entry.stamp, entry.hash));
+                }),
+                Err(error) => eprintln!("Ignoring corrupt hash cache {:?}: {}", file, error),
+            }
+        }
+
+        Ok(HashCache { file, entries, hits: 0, misses: 0 })
+    }
+
+    /// The cached hash of `path` if its size, mtime and inode are unchanged,
+    /// otherwise `compute`'s result, which is cached. `key` names the entry,
+    /// normally the path relative to the root.
+    pub fn hash(&mut self, key: &Path, path: &Path, compute: impl FnOnce() -> Result<String>) -> Result<String> {
+        let (stamp, cached) = self.lookup(key, path)?;
+        if let Some(hash) = cached {
+            return Ok(hash);
+        }
+
+        let hash = compute()?;
+        self.insert(key, stamp, hash.clone());
+        Ok(hash)
+    }
+
+    /// `hash` in two halves, for hashing elsewhere in between: `path`'s stamp
+    /// now, and its cached hash if that's unchanged.
+    pub fn lookup(&mut self, key: &Path, path: &Path) -> Result<(Stamp, Option<String>)> {
+        let stamp = Stamp::of(&fs::metadata(path).with_context(|| format!("Failed to stat {:?}", path))?);
+
+        if let Some((cached, hash)) = self.entries.get(key) {
+            if *cached == stamp {
+                self.hits += 1;
+                return Ok((stamp, Some(hash.clone())));
+            }
+        }
+
+        self.misses += 1;
+        Ok((stamp, None))
+    }
+
+    /// Caches `hash`, computed from the file as it was at `stamp`.
+    pub fn insert(&mut self, key: &Path, stamp: Stamp, hash: String) {
+        self.entries.put(key.to_path_buf(), (stamp, hash));
+    }
+
+    /// (hits, misses) since the cache was opened.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let entries: Vec<Entry> = self
+            .entries
+            .iter()
+            .map(|(path, (stamp, hash))| Entry { path: path.clone(), stamp: *stamp, hash: hash.clone() })
+            .collect();
+
+        if let Some(dir) = self.file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp = temp_path(&self.file);
+        fs::write(&temp, serde_json::to_vec(&entries)?)
+            .and_then(|()| fs::rename(&temp, &self.file))
+            .with_context(|| format!("Failed to write hash cache {:?}", self.file))
+    }
+}
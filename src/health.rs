@@ -0,0 +1,115 @@
+use anyhow::{Context, Result};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::report::{self, unix_timestamp};
+
+/// How long the event loop can go without coming round before `/healthz`
+/// reports it dead. Generous, since a full sync runs on the loop's thread.
+pub const LIVENESS_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What `--health-addr` reports: when the event loop last came round, and
+/// whether the watcher is up and the startup sync done.
+pub struct Health {
+    last_beat: AtomicU64,
+    ready: AtomicBool,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            last_beat: AtomicU64::new(unix_timestamp()),
+            ready: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Health {
+    /// Called by the event loop on every pass.
+    pub fn beat(&self) {
+        self.last_beat.store(unix_timestamp(), Ordering::Relaxed);
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_alive(&self) -> bool {
+        unix_timestamp().saturating_sub(self.last_beat.load(Ordering::Relaxed)) < LIVENESS_TIMEOUT.as_secs()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.is_alive() && self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Status code and JSON body for a request to `path`.
+    fn respond(&self, path: &str) -> (u16, String) {
+        let ok = match path {
+            "/healthz" => self.is_alive(),
+            "/readyz" => self.is_ready(),
+            _ => return (404, serde_json::json!({ "error": "not found" }).to_string()),
+        };
+        let body = serde_json::json!({
+            "alive": self.is_alive(),
+            "ready": self.is_ready(),
+            "last_beat": self.last_beat.load(Ordering::Relaxed),
+            "last_error": report::last_error(),
+        });
+        (if ok { 200 } else { 503 }, body.to_string())
+    }
+}
+
+fn answer(health: &Health, stream: TcpStream) -> std::io::Result<()> {
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    // "GET /readyz HTTP/1.1"; query strings are ignored.
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    let path = path.split('?').next().unwrap_or(path);
+    let (status, body) = health.respond(path);
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        _ => "Service Unavailable",
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )
+}
+
+/// Serves `/healthz` and `/readyz` on `address` from a background thread,
+/// one connection at a time. Returns the address bound, for port 0.
+pub fn serve(address: SocketAddr, health: Arc<Health>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(address).with_context(|| format!("Failed to bind --health-addr {}", address))?;
+    let bound = listener.local_addr()?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    eprintln!("Health endpoint error: {}", error);
+                    continue;
+                }
+            };
+            // A client that never sends its request line mustn't block others.
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            if let Err(error) = answer(&health, stream) {
+                report::debug(format_args!("Health request failed: {}", error));
+            }
+        }
+    });
+
+    Ok(bound)
+}
@@ -0,0 +1,148 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::coalesce::Coalescer;
+
+/// `<glob>=<command>`, the glob matched against paths relative to the watch root.
+#[derive(Clone, Debug)]
+pub struct Hook {
+    pub pattern: String,
+    pub command: String,
+    matcher: GlobMatcher,
+}
+
+impl FromStr for Hook {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (pattern, command) = spec
+            .split_once('=')
+            .with_context(|| format!("Expected <glob>=<command>, got {:?}", spec))?;
+        let matcher = Glob::new(pattern)
+            .with_context(|| format!("Invalid glob {:?}", pattern))?
+            .compile_matcher();
+
+        Ok(Hook {
+            pattern: pattern.to_string(),
+            command: command.to_string(),
+            matcher,
+        })
+    }
+}
+
+impl Hook {
+    pub fn matches(&self, relative: &Path) -> bool {
+        self.matcher.is_match(relative)
+    }
+}
+
+/// Runs hooks on a background thread, once per hook after its matching paths
+/// have been quiet for `debounce`, so a burst of changes triggers one run.
+pub struct HookRunner {
+    hooks: Vec<Hook>,
+    sender: Sender<(usize, PathBuf)>,
+    thread: JoinHandle<()>,
+}
+
+impl HookRunner {
+    pub fn new(hooks: Vec<Hook>, debounce: Duration) -> Self {
+        let (sender, receiver) = channel::<(usize, PathBuf)>();
+        let commands: Vec<Hook> = hooks.clone();
+
+        let thread = thread::spawn(move || {
+            let mut pending = Coalescer::new(debounce);
+            let mut latest: Vec<PathBuf> = vec![PathBuf::new(); commands.len()];
+
+            loop {
+                match receiver.recv_timeout(Duration::from_millis(100)) {
+                    Ok((index, path)) => {
+                        latest[index] = path;
+                        pending.touch(index, Instant::now());
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    // Finishing: what's still waiting runs now.
+                    Err(RecvTimeoutError::Disconnected) => {
+                        for index in pending.take_all() {
+                            run(&commands[index], &latest[index]);
+                        }
+                        break;
+                    }
+                }
+
+                for index in pending.take_settled(Instant::now()) {
+                    run(&commands[index], &latest[index]);
+                }
+            }
+        });
+
+        HookRunner {
+            hooks,
+            sender,
+            thread,
+        }
+    }
+
+    /// Runs every hook still waiting out its debounce, and returns once
+    /// they've all exited.
+    pub fn finish(self) {
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+
+    /// Queues every hook whose glob matches `relative`.
+    pub fn changed(&self, relative: &Path) {
+        for (index, hook) in self.hooks.iter().enumerate() {
+            if hook.matches(relative) {
+                let _ = self.sender.send((index, relative.to_path_buf()));
+            }
+        }
+    }
+}
+
+fn shell(command: &str) -> Command {
+    #[cfg(unix)]
+    {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command).arg("rustsync-hook");
+        shell
+    }
+
+    #[cfg(windows)]
+    {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    }
+}
+
+fn run(hook: &Hook, path: &Path) {
+    println!("Hook[{}]: {} ({:?})", hook.pattern, hook.command, path);
+
+    // The changed path is both $1 and $RUSTSYNC_PATH.
+    let output = shell(&hook.command).arg(path).env("RUSTSYNC_PATH", path).output();
+
+    match output {
+        Ok(output) => {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                println!("Hook[{}] stdout: {}", hook.pattern, line);
+            }
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                eprintln!("Hook[{}] stderr: {}", hook.pattern, line);
+            }
+            if output.status.success() {
+                println!("Hook[{}] exited with {}", hook.pattern, output.status);
+            } else {
+                eprintln!("Hook[{}] exited with {}", hook.pattern, output.status);
+            }
+        }
+        Err(error) => eprintln!("Hook[{}] failed to start {:?}: {}", hook.pattern, hook.command, error),
+    }
+}
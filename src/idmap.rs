@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use std::str::FromStr;
+
+/// IDs a subordinate ID range covers when none is given.
+const DEFAULT_COUNT: u32 = 65536;
+
+/// A subordinate ID range as in `/etc/subuid`. As in a rootless user
+/// namespace, ID 0 is the user's own and IDs `1..=count` are shifted to
+/// `start..start + count`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+impl IdRange {
+    /// Where ID `id` in the namespace is outside it, given the user's own
+    /// ID `own` that 0 maps to.
+    pub fn map(&self, id: u32, own: u32) -> Option<u32> {
+        match id {
+            0 => Some(own),
+            id => (id <= self.count).then(|| self.start.checked_add(id - 1)).flatten(),
+        }
+    }
+}
+
+impl FromStr for IdRange {
+    type Err = anyhow::Error;
+
+    /// `START:COUNT`, or `START` for 65536 IDs.
+    fn from_str(value: &str) -> Result<Self> {
+        let (start, count) = value.split_once(':').unwrap_or((value, ""));
+        let start = start.trim().parse().with_context(|| format!("Bad ID range start in {:?}", value))?;
+        let count = match count.trim() {
+            "" => DEFAULT_COUNT,
+            count => count.parse().with_context(|| format!("Bad ID range count in {:?}", value))?,
+        };
+        Ok(IdRange { start, count })
+    }
+}
+
+/// The first range `subid` (in `/etc/subuid` format: `user:start:count`
+/// lines, the user given by name or ID) grants `name`/`id`.
+pub fn find_range(subid: &str, name: &str, id: u32) -> Option<IdRange> {
+    subid.lines().find_map(|line| {
+        let (owner, range) = line.trim().split_once(':')?;
+        if owner != name && owner.parse() != Ok(id) {
+            return None;
+        }
+        range.parse().ok()
+    })
+}
+
+/// `--map-root-uid-via-subuid`: how source owners are shifted before being
+/// set on the mirror, for destinations owned by a rootless user namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdMap {
+    pub uids: IdRange,
+    pub gids: IdRange,
+    /// The user and group root in the namespace is outside it.
+    pub user: u32,
+    pub group: u32,
+}
+
+impl IdMap {
+    /// `uids` and `gids`, with root mapped to the current user.
+    #[cfg(unix)]
+    pub fn new(uids: IdRange, gids: IdRange) -> Self {
+        let (user, group) = unsafe { (libc::getuid(), libc::getgid()) };
+        IdMap { uids, gids, user, group }
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(uids: IdRange, gids: IdRange) -> Self {
+        IdMap { uids, gids, user: 0, group: 0 }
+    }
+
+    /// The current user's ranges from `/etc/subuid` and `/etc/subgid`.
+    #[cfg(unix)]
+    pub fn for_current_user() -> Result<Self> {
+        use std::{ffi::CStr, fs, path::Path};
+
+        let uid = unsafe { libc::getuid() };
+        let passwd = unsafe { libc::getpwuid(uid) };
+        let name = match passwd.is_null() {
+            true => String::new(),
+            false => unsafe { CStr::from_ptr((*passwd).pw_name) }.to_string_lossy().into_owned(),
+        };
+
+        let range = |file: &str| -> Result<IdRange> {
+            let contents = fs::read_to_string(Path::new(file)).with_context(|| format!("Failed to read {}", file))?;
+            find_range(&contents, &name, uid).with_context(|| format!("No range for {:?} (uid {}) in {}", name, uid, file))
+        };
+        Ok(IdMap::new(range("/etc/subuid")?, range("/etc/subgid")?))
+    }
+
+    /// Windows has no subordinate ID ranges to look up.
+    #[cfg(not(unix))]
+    pub fn for_current_user() -> Result<Self> {
+        anyhow::bail!("There's no /etc/subuid or /etc/subgid to read here, give --subuid-range instead")
+    }
+
+    /// The owner and group to give a mirror of a file owned by `uid`/`gid`,
+    /// or which of them is outside its range.
+    pub fn map(&self, uid: u32, gid: u32) -> Result<(u32, u32), (&'static str, u32)> {
+        let uid = self.uids.map(uid, self.user).ok_or(("uid", uid))?;
+        let gid = self.gids.map(gid, self.group).ok_or(("gid", gid))?;
+        Ok((uid, gid))
+    }
+}
@@ -0,0 +1,76 @@
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+};
+
+use crate::metrics;
+
+/// What a streamed copy (plain, compressed or encrypted) is counted as at
+/// most: its buffers, not the file.
+pub const STREAM_FOOTPRINT: u64 = 1024 * 1024;
+
+/// The `--max-inflight-bytes` cap, 0 for none.
+static LIMIT: AtomicU64 = AtomicU64::new(0);
+static INFLIGHT: Mutex<u64> = Mutex::new(0);
+static RELEASED: Condvar = Condvar::new();
+
+thread_local! {
+    /// Reservations this thread holds, which never wait on themselves.
+    static HELD: Cell<u32> = const { Cell::new(0) };
+}
+
+pub fn set_limit(bytes: u64) {
+    LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+/// Memory a copy of `size` bytes takes: all of it when it's read into
+/// memory (transforms) or mapped (hashing), otherwise its buffers.
+pub fn footprint(size: u64, in_memory: bool) -> u64 {
+    match in_memory {
+        true => size,
+        false => size.min(STREAM_FOOTPRINT),
+    }
+}
+
+/// Bytes held against `--max-inflight-bytes` until dropped, on the thread
+/// that reserved them.
+pub struct Reservation {
+    bytes: u64,
+    _thread: PhantomData<*const ()>,
+}
+
+/// Reserves `bytes` of the cap, waiting while other threads' copies hold
+/// too much of it for them to fit. Anything larger than the cap waits for
+/// it to be free and then runs alone, and a thread that already holds a
+/// reservation never waits.
+pub fn reserve(bytes: u64) -> Reservation {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    let bytes = match limit {
+        0 => bytes,
+        limit => bytes.min(limit),
+    };
+    let nested = HELD.get() > 0;
+
+    let mut inflight = INFLIGHT.lock().unwrap();
+    while limit != 0 && !nested && *inflight > 0 && *inflight + bytes > limit {
+        inflight = RELEASED.wait(inflight).unwrap();
+    }
+    *inflight += bytes;
+    metrics::set("inflight_bytes", *inflight);
+    HELD.set(HELD.get() + 1);
+    Reservation { bytes, _thread: PhantomData }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut inflight = INFLIGHT.lock().unwrap();
+        *inflight -= self.bytes;
+        metrics::set("inflight_bytes", *inflight);
+        HELD.set(HELD.get() - 1);
+        RELEASED.notify_all();
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Component, Path},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::mirror::Operation;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub operation: Operation,
+}
+
+pub struct Journal {
+    file: Mutex<File>,
+}
+
+fn validate_relative(path: &Path) -> Result<()> {
+    if path
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        anyhow::bail!("Path {:?} is not a plain relative path", path);
+    }
+    Ok(())
+}
+
+impl Record {
+    pub fn parse(line: &str) -> Result<Self> {
+        let record: Record = serde_json::from_str(line).context("Malformed journal record")?;
+
+        match &record.operation {
+            Operation::Rename { path, new_path } => {
+                validate_relative(path)?;
+                validate_relative(new_path)?;
+            }
+            Operation::Create { path }
+            | Operation::Data { path }
+            | Operation::Metadata { path }
+            | Operation::Delete { path } => validate_relative(path)?,
+        }
+
+        Ok(record)
+    }
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open journal {:?}", path))?;
+        Ok(Journal { file: Mutex::new(file) })
+    }
+
+    pub fn append(&self, operation: &Operation) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        let record = Record {
+            timestamp,
+            operation: operation.clone(),
+        };
+
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+pub fn read_records(path: &Path) -> Result<Vec<Result<Record>>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read journal {:?}", path))?;
+
+    Ok(contents
+        .lines()
+        .enumerate()
+        .map(|(number, line)| {
+            Record::parse(line).with_context(|| format!("Journal {:?} line {}", path, number + 1))
+        })
+        .collect())
+}
@@ -1,21 +1,42 @@
 use anyhow::{Context, Result};
 use dirs::home_dir;
-use libp2p::identity;
+use libp2p::{identity, PeerId};
+use sha2::{Digest, Sha256};
 use std::{
     fs,
     path::{Path, PathBuf},
-    process,
 };
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 
+use crate::{copy::temp_path, mirror::CONTROL_DIR};
+
+const ALLOWLIST: &str = "peers.allow";
+
+/// Prefixed to what's signed, so a manifest signature can't be passed off as
+/// a signature over anything else the key signs.
+const MANIFEST_CONTEXT: &[u8] = b"rustsync manifest v1\n";
+
+/// Identity multihash code, under which peer IDs embed small public keys.
+const IDENTITY_MULTIHASH: u64 = 0;
+
 fn write_key(path: &Path, data: &[u8], mode: u32) -> Result<()> {
     fs::write(path, data)?;
-    #[cfg(unix)]
+    set_mode(path, mode)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
     fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
     Ok(())
 }
 
+/// Files inherit their directory's ACL on Windows, so there's no mode to set.
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
 pub fn save_keypair(dir: &Path, keypair: &identity::Keypair) -> Result<String> {
     fs::create_dir_all(dir)?;
 
@@ -55,27 +76,168 @@ pub fn load_keypair(dir: &Path, peer_id: &str) -> Result<identity::Keypair> {
     Ok(keypair)
 }
 
-pub fn default_rustsync_dir() -> String {
-    home_dir()
-        .expect("No home directory")
-        .join(".rustsync")
-        .to_string_lossy()
-        .into_owned()
+/// SHA-256 of the protobuf-encoded public key, as lowercase hex.
+pub fn fingerprint(keypair: &identity::Keypair) -> String {
+    Sha256::digest(keypair.public().encode_protobuf())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// `load_keypair`, plus a check that the public key has the fingerprint the
+/// caller expects, so a substituted key file is caught even when its name
+/// matches its peer ID.
+pub fn load_keypair_pinned(dir: &Path, peer_id: &str, expected_fingerprint: &str) -> Result<identity::Keypair> {
+    let keypair = load_keypair(dir, peer_id)?;
+
+    let actual = fingerprint(&keypair);
+    if !actual.eq_ignore_ascii_case(expected_fingerprint.trim()) {
+        anyhow::bail!(
+            "Key fingerprint mismatch for {}: expected {}, got {}",
+            peer_id,
+            expected_fingerprint.trim(),
+            actual
+        );
+    }
+
+    Ok(keypair)
+}
+
+/// The public key `peer_id` embeds, as Ed25519 peer IDs do. Keys too large
+/// to embed (RSA) are only hashed into the ID and can't be recovered.
+pub fn public_key_of(peer_id: &PeerId) -> Option<identity::PublicKey> {
+    let multihash = peer_id.as_ref();
+    if multihash.code() != IDENTITY_MULTIHASH {
+        return None;
+    }
+    identity::PublicKey::try_decode_protobuf(multihash.digest()).ok()
+}
+
+/// Signs a serialized manifest with `keypair`.
+pub fn sign_manifest(keypair: &identity::Keypair, payload: &[u8]) -> Result<Vec<u8>> {
+    let message = [MANIFEST_CONTEXT, payload].concat();
+    keypair.sign(&message).context("Failed to sign manifest")
+}
+
+/// Checks that `signature` is `peer_id`'s signature over a serialized
+/// manifest.
+pub fn verify_manifest(peer_id: &PeerId, payload: &[u8], signature: &[u8]) -> Result<()> {
+    if signature.is_empty() {
+        anyhow::bail!("Manifest from {} is unsigned", peer_id);
+    }
+    let public = public_key_of(peer_id).with_context(|| format!("Peer ID {} doesn't embed a key to verify with", peer_id))?;
+    let message = [MANIFEST_CONTEXT, payload].concat();
+    if !public.verify(&message, signature) {
+        anyhow::bail!("Bad manifest signature from {}", peer_id);
+    }
+    Ok(())
 }
 
+/// Peers in `dir`'s `peers.allow`, one peer ID per line with `#` comments.
+/// A missing file is an empty allowlist.
+pub fn list_peers(dir: &Path) -> Result<Vec<PeerId>> {
+    let path = dir.join(ALLOWLIST);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error).with_context(|| format!("Failed to read {:?}", path)),
+    };
+
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.parse()
+                .with_context(|| format!("Invalid peer ID {:?} in {:?}", line, path))
+        })
+        .collect()
+}
+
+/// Adds `peer_id` to the allowlist, returning false if it was already there.
+pub fn add_peer(dir: &Path, peer_id: &str) -> Result<bool> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .with_context(|| format!("Invalid peer ID {:?}", peer_id))?;
+
+    let mut peers = list_peers(dir)?;
+    if peers.contains(&peer_id) {
+        return Ok(false);
+    }
+    peers.push(peer_id);
+    write_peers(dir, &peers)?;
+    Ok(true)
+}
+
+/// Removes `peer_id` from the allowlist, returning false if it wasn't there.
+pub fn remove_peer(dir: &Path, peer_id: &str) -> Result<bool> {
+    let peer_id: PeerId = peer_id
+        .parse()
+        .with_context(|| format!("Invalid peer ID {:?}", peer_id))?;
+
+    let mut peers = list_peers(dir)?;
+    let before = peers.len();
+    peers.retain(|peer| *peer != peer_id);
+    if peers.len() == before {
+        return Ok(false);
+    }
+    write_peers(dir, &peers)?;
+    Ok(true)
+}
+
+/// Rewrites the allowlist through a temp file and a rename so an interrupted
+/// edit leaves the old list intact. Comments are not preserved.
+fn write_peers(dir: &Path, peers: &[PeerId]) -> Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(ALLOWLIST);
+    let temp = temp_path(&path);
+    let contents: String = peers.iter().map(|peer| format!("{}\n", peer)).collect();
+
+    let result = write_key(&temp, contents.as_bytes(), 0o644)
+        .and_then(|()| Ok(fs::File::open(&temp)?.sync_all()?))
+        .and_then(|()| Ok(fs::rename(&temp, &path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.with_context(|| format!("Failed to write {:?}", path))
+}
+
+/// Stretches a passphrase into a 256-bit key with Argon2id. `salt` should be
+/// random and at least 16 bytes, and stored next to whatever the key protects.
+pub fn derive_key(passphrase: &[u8], salt: &[u8]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|error| anyhow::anyhow!("Key derivation failed: {}", error))?;
+    Ok(key)
+}
+
+/// `~/.rustsync`, or `%USERPROFILE%\.rustsync` on Windows.
+pub fn default_rustsync_dir() -> Result<PathBuf> {
+    Ok(home_dir().context("No home directory")?.join(CONTROL_DIR))
+}
+
+#[cfg(unix)]
 pub fn test_rustsync_dir(dir: &PathBuf) -> Result<()> {
-    #[cfg(unix)]
-    {
-        if dir.exists() {
-            let perms = fs::metadata(&dir)?.permissions();
-            if perms.mode() & 0o077 != 0 {
-                eprintln!(
-                    "Error: {:?} must not be accessible by group or others",
-                    dir
-                );
-                process::exit(1);
-            }
+    if dir.exists() {
+        let perms = fs::metadata(dir)?.permissions();
+        if perms.mode() & 0o077 != 0 {
+            anyhow::bail!("{:?} must not be accessible by group or others", dir);
         }
     }
     Ok(())
 }
+
+/// Windows keeps access in ACLs rather than mode bits, which this doesn't
+/// read, so it only warns.
+#[cfg(not(unix))]
+pub fn test_rustsync_dir(dir: &PathBuf) -> Result<()> {
+    if dir.exists() {
+        eprintln!(
+            "Warning: can't verify that {:?} is private on this platform; make sure only your account can read it",
+            dir
+        );
+    }
+    Ok(())
+}
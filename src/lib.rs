@@ -1 +1,61 @@
+pub mod age;
+pub mod alert;
+pub mod bundle;
+pub mod cas;
+pub mod clock;
+pub mod coalesce;
+pub mod compress;
+pub mod conflict;
+pub mod control;
+pub mod copy;
+pub mod daemon;
+pub mod deadletter;
+pub mod debounce;
+pub mod deploy;
+pub mod diff;
+pub mod echo;
+pub mod encrypt;
+pub mod fanout;
+pub mod fsflags;
+pub mod hash;
+pub mod hashcache;
+pub mod health;
+pub mod hooks;
+pub mod idmap;
+pub mod inflight;
+pub mod journal;
 pub mod keys;
+pub mod listing;
+pub mod manifest;
+pub mod merkle;
+pub mod metrics;
+pub mod mirror;
+#[cfg(feature = "test-util")]
+pub mod mock;
+pub mod mounts;
+pub mod p2p;
+pub mod pathbytes;
+pub mod priority;
+pub mod probe;
+pub mod receipt;
+pub mod reconcile;
+pub mod relpath;
+pub mod remote;
+pub mod rename;
+pub mod report;
+pub mod route;
+pub mod safety;
+pub mod schedule;
+pub mod snapshot;
+pub mod space;
+pub mod stable;
+pub mod trace;
+pub mod transaction;
+pub mod transfer;
+pub mod transform;
+pub mod trickle;
+pub mod units;
+pub mod vcs;
+pub mod watch;
+#[cfg(feature = "web-ui")]
+pub mod web;
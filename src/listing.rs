@@ -0,0 +1,180 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    copy::unix_mode,
+    mirror::{is_ignored, Mirror},
+    report::{self, ErrorKind},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    /// Like `rsync --list-only`: mode, size, local modification time, path
+    #[default]
+    Human,
+    /// mode,size,mtime,path with Unix times and a header row
+    Csv,
+    /// One JSON object per line
+    Json,
+}
+
+/// An entry of `--list`, with its path relative to the watch root.
+#[derive(Debug, Serialize)]
+pub struct Listed {
+    pub mode: String,
+    pub size: u64,
+    pub mtime: i64,
+    #[serde(serialize_with = "crate::pathbytes::serialize")]
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "crate::pathbytes::option::serialize")]
+    pub target: Option<PathBuf>,
+}
+
+/// `ls -l`'s mode string, such as `drwxr-xr-x` or `-rwsr-x---`.
+pub fn mode_string(metadata: &fs::Metadata) -> String {
+    let file_type = metadata.file_type();
+    let kind = match () {
+        _ if file_type.is_dir() => 'd',
+        _ if file_type.is_symlink() => 'l',
+        _ => special_kind(&file_type).unwrap_or('-'),
+    };
+
+    let mode = unix_mode(metadata);
+    let mut string = String::from(kind);
+    for (shift, special, set, unset) in [(6, 0o4000, 's', 'S'), (3, 0o2000, 's', 'S'), (0, 0o1000, 't', 'T')] {
+        let bits = mode >> shift;
+        string.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        string.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        string.push(match (bits & 0o1 != 0, mode & special != 0) {
+            (true, true) => set,
+            (false, true) => unset,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    string
+}
+
+/// `ls -l`'s letter for a device, FIFO or socket.
+#[cfg(unix)]
+fn special_kind(file_type: &fs::FileType) -> Option<char> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match () {
+        _ if file_type.is_block_device() => Some('b'),
+        _ if file_type.is_char_device() => Some('c'),
+        _ if file_type.is_fifo() => Some('p'),
+        _ if file_type.is_socket() => Some('s'),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn special_kind(_file_type: &fs::FileType) -> Option<char> {
+    None
+}
+
+/// Walks the watch root in name order, skipping what a sync would: ignored
+/// paths and files outside the age window.
+pub fn entries(mirror: &Mirror) -> Vec<Listed> {
+    let age = &mirror.options.age;
+    let walker = WalkDir::new(&mirror.watch_root)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| !is_ignored(mirror, entry.path()));
+
+    let mut listed = Vec::new();
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                let path = error.path().unwrap_or(&mirror.watch_root).to_path_buf();
+                report::error(ErrorKind::Metadata, &path, format!("Failed to walk {:?}: {}", path, error));
+                continue;
+            }
+        };
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_file() && !age.is_empty() && !metadata.modified().is_ok_and(|modified| age.admits(modified)) {
+            continue;
+        }
+
+        listed.push(Listed {
+            mode: mode_string(&metadata),
+            size: metadata.len(),
+            mtime: filetime::FileTime::from_last_modification_time(&metadata).unix_seconds(),
+            path: entry.path().strip_prefix(&mirror.watch_root).unwrap_or(entry.path()).to_path_buf(),
+            target: entry.path_is_symlink().then(|| fs::read_link(entry.path()).ok()).flatten(),
+        });
+    }
+    listed
+}
+
+/// `1234567` as `1,234,567`, as rsync prints sizes.
+fn with_commas(number: u64) -> String {
+    let digits = number.to_string();
+    let mut string = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            string.push(',');
+        }
+        string.push(digit);
+    }
+    string
+}
+
+fn local_time(mtime: i64) -> String {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    #[cfg(unix)]
+    let failed = unsafe { libc::localtime_r(&mtime, &mut tm) }.is_null();
+    #[cfg(windows)]
+    let failed = unsafe { libc::localtime_s(&mut tm, &mtime) } != 0;
+    if failed {
+        return mtime.to_string();
+    }
+    format!(
+        "{:04}/{:02}/{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+fn csv_field(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    match path.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", path.replace('"', "\"\"")),
+        false => path.into_owned(),
+    }
+}
+
+pub fn write_list(listed: &[Listed], format: ListFormat, out: &mut impl Write) -> io::Result<()> {
+    if format == ListFormat::Csv {
+        writeln!(out, "mode,size,mtime,path")?;
+    }
+    for entry in listed {
+        match format {
+            ListFormat::Human => {
+                write!(out, "{} {:>14} {} {}", entry.mode, with_commas(entry.size), local_time(entry.mtime), entry.path.display())?;
+                match &entry.target {
+                    Some(target) => writeln!(out, " -> {}", target.display())?,
+                    None => writeln!(out)?,
+                }
+            }
+            ListFormat::Csv => writeln!(out, "{},{},{},{}", entry.mode, entry.size, entry.mtime, csv_field(&entry.path))?,
+            ListFormat::Json => writeln!(out, "{}", serde_json::to_string(entry)?)?,
+        }
+    }
+    Ok(())
+}
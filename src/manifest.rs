@@ -0,0 +1,431 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    compress::{self, decompress},
+    encrypt::{self, decrypt, Encryption},
+    hash::{hash_file, hash_parallel, hash_stream, ChecksumAlgorithm},
+    hashcache::{HashCache, Stamp},
+    mirror::CONTROL_DIR,
+    pathbytes,
+    transform::{MirrorEvent, TransformOutcome, Transforms},
+};
+
+const HEADER_PREFIX: &str = "# rustsync-manifest v1 algorithm=";
+
+/// Versions of its own manifest a node remembers the changes of. A peer
+/// further behind than this gets the full manifest.
+pub const MAX_DELTA_VERSIONS: usize = 64;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub algorithm: ChecksumAlgorithm,
+    #[serde(with = "crate::pathbytes::map")]
+    pub entries: BTreeMap<PathBuf, String>,
+}
+
+/// Hashes `files`, (relative, path) pairs, into `entries` with `compute`,
+/// reusing hashes from `cache` for files that haven't changed. The rest are
+/// hashed on `hash::jobs()` threads; entries are keyed by path, so the result
+/// doesn't depend on which finishes first. `fallback` turns a failure into a
+/// hash or the build's error.
+fn hash_into(
+    entries: &mut BTreeMap<PathBuf, String>,
+    files: Vec<(PathBuf, PathBuf)>,
+    mut cache: Option<&mut HashCache>,
+    compute: impl Fn(&Path) -> Result<String> + Sync,
+    fallback: impl Fn(&Path, anyhow::Error) -> Result<String>,
+) -> Result<()> {
+    let mut pending: Vec<(PathBuf, PathBuf, Option<Stamp>)> = Vec::new();
+    for (relative, path) in files {
+        match cache.as_deref_mut() {
+            Some(cache) => match cache.lookup(&relative, &path)? {
+                (_, Some(hash)) => {
+                    entries.insert(relative, hash);
+                }
+                (stamp, None) => pending.push((relative, path, Some(stamp))),
+            },
+            None => pending.push((relative, path, None)),
+        }
+    }
+
+    let hashes = hash_parallel(&pending, |(_, path, _)| compute(path));
+    for ((relative, path, stamp), hash) in pending.into_iter().zip(hashes) {
+        let hash = match hash {
+            Ok(hash) => {
+                if let (Some(cache), Some(stamp)) = (cache.as_deref_mut(), stamp) {
+                    cache.insert(&relative, stamp, hash.clone());
+                }
+                hash
+            }
+            Err(error) => fallback(&path, error)?,
+        };
+        entries.insert(relative, hash);
+    }
+    Ok(())
+}
+
+pub enum Difference {
+    Missing(PathBuf),
+    Extra(PathBuf),
+    Mismatch(PathBuf),
+}
+
+impl Manifest {
+    pub fn build(root: &Path, algorithm: ChecksumAlgorithm) -> Result<Self> {
+        Self::build_cached(root, algorithm, None)
+    }
+
+    /// `build`, reusing hashes from `cache` for files that haven't changed.
+    pub fn build_cached(root: &Path, algorithm: ChecksumAlgorithm, cache: Option<&mut HashCache>) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+        let mut files = Vec::new();
+
+        let walker = WalkDir::new(root)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|entry| entry.depth() == 0 || entry.file_name() != CONTROL_DIR);
+        for entry in walker {
+            let entry = entry.with_context(|| format!("Failed to walk {:?}", root))?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let relative = entry.path().strip_prefix(root)?.to_path_buf();
+            files.push((relative, entry.into_path()));
+        }
+        hash_into(&mut entries, files, cache, |path| hash_file(path, algorithm), |_, error| Err(error))?;
+
+        Ok(Manifest { algorithm, entries })
+    }
+
+    /// Like `build`, but files written by `--compress-dest` are hashed by
+    /// their decompressed contents and listed under their original names.
+    pub fn build_decompressed(
+        root: &Path,
+        algorithm: ChecksumAlgorithm,
+        cache: Option<&mut HashCache>,
+    ) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+        let mut files = Vec::new();
+
+        let walker = WalkDir::new(root)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|entry| entry.depth() == 0 || entry.file_name() != CONTROL_DIR);
+        for entry in walker {
+            let entry = entry.with_context(|| format!("Failed to walk {:?}", root))?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            // Cached under the original name, which a plain build never uses
+            // for the `.zst` file.
+            let original = compress::original_path(entry.path());
+            let relative = original.as_deref().unwrap_or(entry.path()).strip_prefix(root)?.to_path_buf();
+            files.push((relative, entry.into_path()));
+        }
+        let compute = |path: &Path| match compress::original_path(path) {
+            Some(_) => hash_stream(decompress(path)?, path, algorithm),
+            None => hash_file(path, algorithm),
+        };
+        hash_into(&mut entries, files, cache, compute, |_, error| Err(error))?;
+
+        Ok(Manifest { algorithm, entries })
+    }
+
+    /// Like `build`, but files written by `--encrypt-dest` are decrypted,
+    /// which also authenticates them, and listed under their original (and,
+    /// with `--encrypt-names`, decrypted) names. Files that weren't encrypted
+    /// with this key are listed as they are, so they show up as extras.
+    pub fn build_decrypted(
+        root: &Path,
+        algorithm: ChecksumAlgorithm,
+        encryption: &Encryption,
+        cache: Option<&mut HashCache>,
+    ) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+        let mut files = Vec::new();
+
+        let walker = WalkDir::new(root)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != CONTROL_DIR);
+        for entry in walker {
+            let entry = entry.with_context(|| format!("Failed to walk {:?}", root))?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let original = encrypt::original_path(entry.path());
+            let stored = original.as_deref().unwrap_or(entry.path()).strip_prefix(root)?;
+            let relative = match encryption.names {
+                true => stored
+                    .iter()
+                    .map(|name| encryption.decrypt_name(name))
+                    .collect::<Option<PathBuf>>()
+                    .unwrap_or_else(|| entry.path().strip_prefix(root).unwrap().to_path_buf()),
+                false => stored.to_path_buf(),
+            };
+            files.push((relative, entry.into_path()));
+        }
+        let compute = |path: &Path| match encrypt::original_path(path) {
+            Some(_) => hash_stream(decrypt(path, encryption)?, path, algorithm),
+            None => hash_file(path, algorithm),
+        };
+        // A wrong key or a tampered file is a mismatch, not a failed check.
+        let fallback = |path: &Path, error: anyhow::Error| match encrypt::original_path(path) {
+            Some(_) => {
+                eprintln!("Cannot decrypt {:?}: {:#}", path, error);
+                Ok(String::from("undecryptable"))
+            }
+            None => Err(error),
+        };
+        hash_into(&mut entries, files, cache, compute, fallback)?;
+
+        Ok(Manifest { algorithm, entries })
+    }
+
+    /// Rehashes the entries of a source manifest that `transforms` rewrite
+    /// by what they write to the mirror, so `--check` expects that and not
+    /// the source. Files a transform skips, drops or fails on are left out,
+    /// as they are of the mirror.
+    pub fn apply_transforms(&mut self, root: &Path, transforms: &Transforms) -> Result<()> {
+        let transformed: Vec<PathBuf> =
+            self.entries.keys().filter(|relative| transforms.applies_to(relative)).cloned().collect();
+        for relative in transformed {
+            let source = root.join(&relative);
+            let mut content = fs::read(&source).with_context(|| format!("Failed to read {:?}", source))?;
+            let event = MirrorEvent { relative: &relative, source: &source };
+            match transforms.run(&event, &mut content) {
+                Ok(TransformOutcome::Write) => {
+                    let hash = hash_stream(content.as_slice(), &source, self.algorithm)?;
+                    self.entries.insert(relative, hash);
+                }
+                Ok(TransformOutcome::Skip | TransformOutcome::Drop) => {
+                    self.entries.remove(&relative);
+                }
+                Err(error) => {
+                    eprintln!("{:#}, leaving it out of the manifest", error);
+                    self.entries.remove(&relative);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read manifest {:?}", path))?;
+        let mut lines = contents.lines();
+
+        let algorithm = lines
+            .next()
+            .and_then(|header| header.strip_prefix(HEADER_PREFIX))
+            .with_context(|| format!("Missing manifest header in {:?}", path))?
+            .parse()?;
+
+        let mut entries = BTreeMap::new();
+        for (number, line) in lines.enumerate() {
+            let (hash, relative) = line
+                .split_once("  ")
+                .with_context(|| format!("Malformed manifest line {} in {:?}", number + 2, path))?;
+            let relative = pathbytes::decode(relative)
+                .with_context(|| format!("Malformed path on manifest line {} in {:?}", number + 2, path))?;
+            entries.insert(relative, hash.to_string());
+        }
+
+        Ok(Manifest { algorithm, entries })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut contents = format!("{}{}\n", HEADER_PREFIX, self.algorithm);
+        for (relative, hash) in &self.entries {
+            contents.push_str(&format!("{}  {}\n", hash, pathbytes::encode(relative)));
+        }
+
+        fs::write(path, contents).with_context(|| format!("Failed to write manifest {:?}", path))
+    }
+
+    pub fn compare(&self, actual: &Manifest) -> Result<Vec<Difference>> {
+        if self.algorithm != actual.algorithm {
+            anyhow::bail!(
+                "Manifest algorithm mismatch: expected {}, got {}",
+                self.algorithm,
+                actual.algorithm
+            );
+        }
+
+        let mut differences = Vec::new();
+        for (relative, hash) in &self.entries {
+            match actual.entries.get(relative) {
+                None => differences.push(Difference::Missing(relative.clone())),
+                Some(other) if other != hash => differences.push(Difference::Mismatch(relative.clone())),
+                Some(_) => {}
+            }
+        }
+        for relative in actual.entries.keys() {
+            if !self.entries.contains_key(relative) {
+                differences.push(Difference::Extra(relative.clone()));
+            }
+        }
+
+        Ok(differences)
+    }
+}
+
+/// A manifest a node has given out, tagged with the version it was. `epoch`
+/// is picked at random when the node starts, so versions from before a
+/// restart aren't mistaken for current ones.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VersionedManifest {
+    pub epoch: u64,
+    pub version: u64,
+    pub manifest: Manifest,
+}
+
+/// What changed in a node's manifest between versions `base` and `version`:
+/// files that are new or have a new hash, and files that are gone.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestDelta {
+    pub epoch: u64,
+    pub base: u64,
+    pub version: u64,
+    #[serde(with = "crate::pathbytes::map")]
+    pub changed: BTreeMap<PathBuf, String>,
+    #[serde(with = "crate::pathbytes::vec")]
+    pub removed: Vec<PathBuf>,
+}
+
+impl ManifestDelta {
+    pub fn len(&self) -> usize {
+        self.changed.len() + self.removed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl VersionedManifest {
+    /// Brings this copy of a peer's manifest up to `delta.version`. Fails,
+    /// leaving it as it was, unless `delta` starts from this version.
+    pub fn apply(&mut self, delta: &ManifestDelta) -> Result<()> {
+        if delta.epoch != self.epoch || delta.base != self.version {
+            anyhow::bail!(
+                "Manifest delta from version {} doesn't apply to version {}",
+                delta.base,
+                self.version
+            );
+        }
+
+        for (relative, hash) in &delta.changed {
+            self.manifest.entries.insert(relative.clone(), hash.clone());
+        }
+        for relative in &delta.removed {
+            self.manifest.entries.remove(relative);
+        }
+        self.version = delta.version;
+        Ok(())
+    }
+}
+
+/// A node's own manifest, versioned, with the changes that made each of the
+/// last `MAX_DELTA_VERSIONS` versions, so peers can be sent only what changed
+/// since the version they last saw.
+pub struct ManifestHistory {
+    current: VersionedManifest,
+    /// The entries each version changed, oldest first; `None` for a removal.
+    changes: VecDeque<(u64, BTreeMap<PathBuf, Option<String>>)>,
+}
+
+impl ManifestHistory {
+    pub fn new(manifest: Manifest) -> Result<Self> {
+        let epoch = getrandom::u64().map_err(|error| anyhow::anyhow!("No randomness available: {}", error))?;
+        Ok(ManifestHistory {
+            current: VersionedManifest {
+                epoch,
+                version: 1,
+                manifest,
+            },
+            changes: VecDeque::new(),
+        })
+    }
+
+    pub fn current(&self) -> &VersionedManifest {
+        &self.current
+    }
+
+    /// Makes `manifest` the current version, bumping the version number only
+    /// if it differs from the last one.
+    pub fn update(&mut self, manifest: Manifest) -> u64 {
+        let old = &self.current.manifest.entries;
+        let mut changed: BTreeMap<PathBuf, Option<String>> = manifest
+            .entries
+            .iter()
+            .filter(|(relative, hash)| old.get(*relative) != Some(*hash))
+            .map(|(relative, hash)| (relative.clone(), Some(hash.clone())))
+            .collect();
+        changed.extend(
+            old.keys()
+                .filter(|relative| !manifest.entries.contains_key(*relative))
+                .map(|relative| (relative.clone(), None)),
+        );
+
+        if !changed.is_empty() || manifest.algorithm != self.current.manifest.algorithm {
+            self.current.version += 1;
+            self.changes.push_back((self.current.version, changed));
+            if self.changes.len() > MAX_DELTA_VERSIONS {
+                self.changes.pop_front();
+            }
+            // Hashes from another algorithm can't be patched in.
+            if manifest.algorithm != self.current.manifest.algorithm {
+                self.changes.clear();
+            }
+            self.current.manifest = manifest;
+        }
+        self.current.version
+    }
+
+    /// The changes since `version` of this epoch, or None when the full
+    /// manifest has to be sent instead: on first contact (version 0), after
+    /// a restart, when the peer is too many versions behind, or when the
+    /// delta would be no smaller than the manifest.
+    pub fn since(&self, epoch: u64, version: u64) -> Option<ManifestDelta> {
+        let oldest_base = self.current.version - self.changes.len() as u64;
+        if epoch != self.current.epoch || version == 0 || version < oldest_base || version > self.current.version {
+            return None;
+        }
+
+        let mut merged = BTreeMap::new();
+        for (_, changes) in self.changes.iter().filter(|(changed_in, _)| *changed_in > version) {
+            merged.extend(changes.iter().map(|(relative, hash)| (relative.clone(), hash.clone())));
+        }
+        if !merged.is_empty() && merged.len() >= self.current.manifest.entries.len() {
+            return None;
+        }
+
+        let mut delta = ManifestDelta {
+            epoch,
+            base: version,
+            version: self.current.version,
+            changed: BTreeMap::new(),
+            removed: Vec::new(),
+        };
+        for (relative, hash) in merged {
+            match hash {
+                Some(hash) => {
+                    delta.changed.insert(relative, hash);
+                }
+                None => delta.removed.push(relative),
+            }
+        }
+        Some(delta)
+    }
+}
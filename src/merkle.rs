@@ -0,0 +1,137 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{hash::ChecksumAlgorithm, manifest::Manifest, mirror::CONTROL_DIR};
+
+/// Where `--merkle` keeps the mirror's root hash, under the output root's
+/// control directory.
+pub const MERKLE_ROOT_FILE: &str = "merkle-root";
+
+/// A Merkle tree over a mirror's files: each leaf hashes a file's relative
+/// path with its content hash, in path order, and each node the pair below
+/// it, so the root changes with any file's name, contents, or presence.
+/// Leaves are kept; the nodes are rebuilt from them when the root is asked
+/// for after a change.
+pub struct MerkleTree {
+    pub algorithm: ChecksumAlgorithm,
+    leaves: BTreeMap<PathBuf, blake3::Hash>,
+    root: Option<String>,
+}
+
+fn leaf(relative: &Path, hash: &str) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[0]);
+    hasher.update(relative.as_os_str().as_encoded_bytes());
+    hasher.update(&[0]);
+    hasher.update(hash.as_bytes());
+    hasher.finalize()
+}
+
+fn node(left: &blake3::Hash, right: &blake3::Hash) -> blake3::Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[1]);
+    hasher.update(left.as_bytes());
+    hasher.update(right.as_bytes());
+    hasher.finalize()
+}
+
+impl MerkleTree {
+    pub fn new(manifest: &Manifest) -> Self {
+        MerkleTree {
+            algorithm: manifest.algorithm,
+            leaves: manifest
+                .entries
+                .iter()
+                .map(|(relative, hash)| (relative.clone(), leaf(relative, hash)))
+                .collect(),
+            root: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn insert(&mut self, relative: PathBuf, hash: &str) {
+        let leaf = leaf(&relative, hash);
+        self.leaves.insert(relative, leaf);
+        self.root = None;
+    }
+
+    /// Drops `relative` and everything under it.
+    pub fn remove_under(&mut self, relative: &Path) {
+        let under: Vec<PathBuf> = self
+            .leaves
+            .range(relative.to_path_buf()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(relative))
+            .cloned()
+            .collect();
+        if !under.is_empty() {
+            self.root = None;
+        }
+        for path in under {
+            self.leaves.remove(&path);
+        }
+    }
+
+    /// Whether the root has to be recomputed since it was last asked for.
+    pub fn is_stale(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// The root hash in hex. An odd node out at any level moves up as is.
+    pub fn root(&mut self) -> &str {
+        let leaves = &self.leaves;
+        self.root.get_or_insert_with(|| {
+            let mut level: Vec<blake3::Hash> = leaves.values().copied().collect();
+            if level.is_empty() {
+                return blake3::hash(b"").to_hex().to_string();
+            }
+            while level.len() > 1 {
+                level = level
+                    .chunks(2)
+                    .map(|pair| match pair {
+                        [left, right] => node(left, right),
+                        [odd] => *odd,
+                        _ => unreachable!(),
+                    })
+                    .collect();
+            }
+            level[0].to_hex().to_string()
+        })
+    }
+}
+
+pub fn root_path(output_root: &Path) -> PathBuf {
+    output_root.join(CONTROL_DIR).join(MERKLE_ROOT_FILE)
+}
+
+/// Stores `root` as `<algorithm> <root>`, replacing the file in one rename.
+pub fn save_root(output_root: &Path, algorithm: ChecksumAlgorithm, root: &str) -> Result<()> {
+    let path = root_path(output_root);
+    let directory = path.parent().unwrap();
+    fs::create_dir_all(directory).with_context(|| format!("Failed to create {:?}", directory))?;
+
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, format!("{} {}\n", algorithm, root)).with_context(|| format!("Failed to write {:?}", temp))?;
+    fs::rename(&temp, &path).with_context(|| format!("Failed to write {:?}", path))
+}
+
+pub fn load_root(output_root: &Path) -> Result<(ChecksumAlgorithm, String)> {
+    let path = root_path(output_root);
+    let contents = fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+    let (algorithm, root) = contents
+        .trim()
+        .split_once(' ')
+        .with_context(|| format!("Malformed Merkle root in {:?}", path))?;
+    Ok((algorithm.parse()?, root.to_string()))
+}
@@ -0,0 +1,93 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use crate::{report, units::format_duration};
+
+fn registry() -> &'static Mutex<BTreeMap<String, u64>> {
+    static METRICS: OnceLock<Mutex<BTreeMap<String, u64>>> = OnceLock::new();
+    METRICS.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+pub fn set(name: &str, value: u64) {
+    registry().lock().unwrap().insert(name.to_string(), value);
+}
+
+pub fn add(name: &str, delta: u64) {
+    *registry().lock().unwrap().entry(name.to_string()).or_insert(0) += delta;
+}
+
+pub fn get(name: &str) -> u64 {
+    registry().lock().unwrap().get(name).copied().unwrap_or(0)
+}
+
+pub fn snapshot() -> BTreeMap<String, u64> {
+    registry().lock().unwrap().clone()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SummaryFormat {
+    /// One line per counter group
+    Text,
+    /// A single JSON object
+    Json,
+}
+
+/// What a run has done so far, built from the counters above and the
+/// reported errors.
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub uptime_secs: u64,
+    pub events_received: u64,
+    /// Applied operations by kind (create, data, metadata, delete, rename).
+    pub operations: BTreeMap<String, u64>,
+    pub bytes_copied: u64,
+    pub appended_bytes: u64,
+    pub errors: BTreeMap<String, u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peers_connected: Option<u64>,
+}
+
+pub fn run_summary(uptime: Duration) -> RunSummary {
+    let metrics = snapshot();
+    let get = |name: &str| metrics.get(name).copied().unwrap_or(0);
+
+    RunSummary {
+        uptime_secs: uptime.as_secs(),
+        events_received: get("events_received"),
+        operations: metrics
+            .iter()
+            .filter_map(|(name, value)| Some((name.strip_prefix("operations_")?.to_string(), *value)))
+            .collect(),
+        bytes_copied: get("bytes_copied"),
+        appended_bytes: get("appended_bytes"),
+        errors: report::error_counts()
+            .into_iter()
+            .map(|(kind, count)| (serde_json::to_value(kind).unwrap().as_str().unwrap_or_default().to_string(), count))
+            .collect(),
+        peers_connected: metrics.get("p2p_peers_connected").copied(),
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pairs = |counts: &BTreeMap<String, u64>| match counts.is_empty() {
+            true => String::from("none"),
+            false => counts.iter().map(|(name, count)| format!("{}={}", name, count)).collect::<Vec<_>>().join(" "),
+        };
+
+        writeln!(f, "Run summary after {}:", format_duration(Duration::from_secs(self.uptime_secs)))?;
+        writeln!(f, "  events received: {}", self.events_received)?;
+        writeln!(f, "  operations: {}", pairs(&self.operations))?;
+        writeln!(f, "  bytes copied: {} ({} appended)", self.bytes_copied, self.appended_bytes)?;
+        if let Some(peers) = self.peers_connected {
+            writeln!(f, "  peers connected: {}", peers)?;
+        }
+        write!(f, "  errors: {}", pairs(&self.errors))
+    }
+}
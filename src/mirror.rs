@@ -0,0 +1,3026 @@
+use clap::ValueEnum;
+use filetime::FileTime;
+use notify::{
+    event::{AccessKind, AccessMode, DataChange, MetadataKind, ModifyKind, RenameMode},
+    EventKind,
+};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt,
+    fs,
+    io::{self, Read},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    age::AgeFilter,
+    clock::Clock,
+    coalesce::{Coalescer, DeleteBatch, InFlight},
+    conflict::ConflictLog,
+    deadletter::{DeadLetter, DeadLetters},
+    compress::{self, compress_file, compressed_path, Compression},
+    encrypt::{self, encrypt_file, encrypted_path, Encryption},
+    debounce::{window_for, DebounceRule, Debouncer},
+    copy::{
+        self, append_tail, copy_file, copy_file_masked, copy_file_yielding, is_locked, open_source, same_contents, staging_path, sync_directory, sync_file, truncate_tail, while_writable,
+        Fsync, Reflink,
+    },
+    hash::hash_file,
+    echo::SelfWrites,
+    fsflags,
+    hooks::HookRunner,
+    idmap::IdMap,
+    inflight,
+    journal::Journal,
+    merkle::{self, MerkleTree},
+    metrics,
+    priority::{priority_of, CopyOrder, CopyQueue, PriorityRule, QueueSummary},
+    relpath::{is_case_insensitive, CaseIndex, Listings, RelPath},
+    remote::Backend,
+    transform::{MirrorEvent, TransformOutcome, Transforms},
+    rename::{RenameTracker, Shape},
+    route::Route,
+    report::{self, ErrorKind},
+    safety::{link_loop, DeleteGuard, DeleteLimit, Verdict},
+    space::{disk_space, MinFreeSpace},
+    stable::{signature, StabilityCheck},
+    trace::EventTrace,
+    transaction::{group_of, TransactionGlob, Transactions},
+    units::format_duration,
+    vcs::VcsIgnore,
+};
+
+/// Metadata categories `handle_event_metadata` may copy onto the mirror.
+///
+/// `owner` needs root (or CAP_CHOWN) to give files away, and `xattrs` outside
+/// the `user.` namespace need CAP_SYS_ADMIN (`trusted.`) or similar privileges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Preserve {
+    Perms,
+    Times,
+    Owner,
+    Xattrs,
+}
+
+/// What happens to a source symlink whose target doesn't exist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum DanglingSymlinks {
+    /// Mirror the link as it is, broken
+    #[default]
+    Keep,
+    /// Leave it out, logging it at debug level
+    Skip,
+    /// Leave it out and report it as a symlink error
+    Error,
+}
+
+/// What happens to a file another process holds locked when it's copied.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockedFiles {
+    /// Report it as a copy error
+    #[default]
+    Error,
+    /// Leave it for its next event, logging it at debug level
+    Skip,
+    /// Retry it with backoff, up to `LOCKED_RETRIES` times
+    Wait,
+}
+
+/// Retries of a locked file's copy before it's reported as an error.
+pub const LOCKED_RETRIES: u32 = 8;
+
+/// How a mirrored file's access time is set along with its modification time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Atime {
+    /// Copy the source's access time
+    #[default]
+    Preserve,
+    /// Leave the destination's access time as copying it left it
+    Omit,
+    /// Set it to the time the metadata is applied
+    Now,
+}
+
+/// Which kinds of change to existing files are mirrored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Changes {
+    #[default]
+    All,
+    /// Contents only: metadata events are ignored (`--sync-content-only`).
+    Content,
+    /// Metadata only: edits to existing files are ignored (`--metadata-only`).
+    Metadata,
+}
+
+#[derive(Clone)]
+pub struct Options {
+    pub preserve: Vec<Preserve>,
+    pub min_free_space: Option<MinFreeSpace>,
+    pub reflink: Reflink,
+    pub directory_metadata_window: Duration,
+    pub max_queue: usize,
+    pub fsync: Fsync,
+    pub strip_setuid: bool,
+    pub compress: Option<Compression>,
+    pub encrypt: Option<Encryption>,
+    pub routes: Vec<Route>,
+    /// Copy files when their writer closes them (close-write) rather than on
+    /// every modification. Only inotify reports closes.
+    pub sync_on_close: bool,
+    pub changes: Changes,
+    pub transforms: Transforms,
+    pub delete_limit: DeleteLimit,
+    pub copy_order: CopyOrder,
+    pub priorities: Vec<PriorityRule>,
+    /// Also skip what git ignores under the watch root (`--exclude-vcs`).
+    pub exclude_vcs: bool,
+    /// On a case-insensitive destination, mirror a path that clashes by case
+    /// with one already mirrored under a name with this suffix, instead of
+    /// skipping it.
+    pub case_suffix: Option<String>,
+    /// Groups of paths whose changes are held until the group goes quiet for
+    /// `transaction_settle`, then applied together.
+    pub transaction_globs: Vec<TransactionGlob>,
+    pub transaction_settle: Duration,
+    /// Mirror files hard linked to each other in the source as hard links in
+    /// full syncs, instead of as separate copies.
+    pub preserve_hardlinks: bool,
+    /// Deleting a directory whose mirror is a destination link empties the
+    /// directory behind it and keeps the link, instead of removing the link.
+    pub keep_dest_links: bool,
+    /// Hold copies of files until their size and modification time are the
+    /// same twice this far apart, checking at most `stable_checks` times.
+    pub stable_time: Option<Duration>,
+    pub stable_checks: u32,
+    /// Only files modified inside this window are copied.
+    pub age: AgeFilter,
+    /// Report every failure to set times, even on destinations that can't.
+    pub require_times: bool,
+    /// Shift owners into these subordinate ID ranges on the mirror.
+    pub id_map: Option<IdMap>,
+    /// Hold copies of files until they've had no events for the window of
+    /// the first matching rule, or `debounce_default`.
+    pub debounce: Vec<DebounceRule>,
+    pub debounce_default: Duration,
+    pub dangling_symlinks: DanglingSymlinks,
+    /// Only mirror this many levels below the root's direct contents; 0 is
+    /// the direct contents alone.
+    pub max_depth: Option<usize>,
+    /// What `Preserve::Times` does with access times.
+    pub atime: Atime,
+    /// Hold live deletes until none has arrived for this long, then apply
+    /// them together; zero applies each as it comes.
+    pub delete_batch_window: Duration,
+    pub locked: LockedFiles,
+    /// Report paths that aren't valid UTF-8 as errors and leave them out.
+    pub require_utf8: bool,
+    /// Mirror the immutable and append-only flags, clearing them on the
+    /// destination while it's written.
+    pub preserve_flags: bool,
+    /// Don't set a mirrored entry's times back when it's newer than its
+    /// source by more than this, and only copy over it when the contents
+    /// differ (`--only-newer`).
+    pub only_newer: Option<Duration>,
+    /// The output root is `--atomic-deploy`'s `<output>.staging`, whose
+    /// `.old`, `.new` and release siblings are output too.
+    pub atomic_deploy: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            preserve: vec![Preserve::Perms, Preserve::Times, Preserve::Owner],
+            min_free_space: None,
+            reflink: Reflink::Auto,
+            directory_metadata_window: Duration::from_secs(1),
+            max_queue: 100_000,
+            fsync: Fsync::None,
+            strip_setuid: false,
+            compress: None,
+            encrypt: None,
+            routes: Vec::new(),
+            sync_on_close: false,
+            changes: Changes::All,
+            transforms: Transforms::default(),
+            delete_limit: DeleteLimit::default(),
+            copy_order: CopyOrder::Arrival,
+            priorities: Vec::new(),
+            exclude_vcs: false,
+            case_suffix: None,
+            transaction_globs: Vec::new(),
+            transaction_settle: Duration::from_secs(1),
+            preserve_hardlinks: false,
+            keep_dest_links: false,
+            stable_time: None,
+            stable_checks: 10,
+            age: AgeFilter::default(),
+            require_times: false,
+            id_map: None,
+            debounce: Vec::new(),
+            debounce_default: Duration::ZERO,
+            dangling_symlinks: DanglingSymlinks::Keep,
+            max_depth: None,
+            atime: Atime::Preserve,
+            delete_batch_window: Duration::from_millis(200),
+            locked: LockedFiles::Error,
+            require_utf8: false,
+            preserve_flags: false,
+            only_newer: None,
+            atomic_deploy: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    Create {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+    },
+    Data {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+    },
+    Metadata {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+    },
+    Delete {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+    },
+    Rename {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+        #[serde(with = "crate::pathbytes")]
+        new_path: PathBuf,
+    },
+}
+
+impl Operation {
+    /// Metric counting applied operations of this kind.
+    pub fn metric(&self) -> &'static str {
+        match self {
+            Operation::Create { .. } => "operations_create",
+            Operation::Data { .. } => "operations_data",
+            Operation::Metadata { .. } => "operations_metadata",
+            Operation::Delete { .. } => "operations_delete",
+            Operation::Rename { .. } => "operations_rename",
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Create { path } => write!(f, "Create {:?}", path),
+            Operation::Data { path } => write!(f, "Data {:?}", path),
+            Operation::Metadata { path } => write!(f, "Metadata {:?}", path),
+            Operation::Delete { path } => write!(f, "Delete {:?}", path),
+            Operation::Rename { path, new_path } => write!(f, "Rename {:?} -> {:?}", path, new_path),
+        }
+    }
+}
+
+/// How long a rename `From` waits for its `To` before it's treated as a move
+/// out of the watch root.
+const RENAME_WINDOW: Duration = Duration::from_millis(500);
+
+/// Destination directories remembered as existing by `ensure_parent`.
+const KNOWN_DIRECTORIES: NonZeroUsize = NonZeroUsize::new(4096).unwrap();
+
+pub struct Mirror {
+    pub watch_root: PathBuf,
+    pub output_root: PathBuf,
+    pub options: Options,
+    pub journal: Option<Journal>,
+    pub hooks: Option<HookRunner>,
+    /// The output root's Merkle tree, kept up to date with `--merkle`.
+    pub merkle: Option<Mutex<MerkleTree>>,
+    /// Logs raw watcher events with `--trace-events`.
+    pub trace: Option<EventTrace>,
+    /// Where operations go instead of `output_root` when it's on another host.
+    pub backend: Option<Box<dyn Backend>>,
+    /// Checks destination files against what was last written to them
+    /// before copying over them, with `--on-conflict`.
+    pub conflicts: Option<ConflictLog>,
+    /// Retries failed operations and sets aside those that keep failing,
+    /// with `--dead-letter-after`.
+    pub dead_letters: Option<DeadLetters>,
+    /// Destination writes shared with mirrors that watch this one's
+    /// destination, so neither copies the other's writes back.
+    pub self_writes: Option<Arc<SelfWrites>>,
+    /// What the holds between events measure time against.
+    pub clock: Clock,
+    pending: Mutex<VecDeque<Operation>>,
+    /// The first path seen under each case-folded name.
+    case_index: Mutex<CaseIndex>,
+    /// Whether each destination root matches names case-insensitively.
+    case_insensitive: Mutex<HashMap<PathBuf, bool>>,
+    /// Watch-root directories listed to check case owners still exist.
+    listings: Mutex<Listings>,
+    directory_metadata: Mutex<Coalescer<PathBuf>>,
+    applied_directory_metadata: Mutex<HashMap<PathBuf, DirectorySignature>>,
+    renames: Mutex<RenameTracker>,
+    known_directories: Mutex<LruCache<PathBuf, ()>>,
+    delete_guard: Mutex<DeleteGuard>,
+    copies: Mutex<CopyQueue>,
+    /// Destinations being copied to right now.
+    in_flight: InFlight<PathBuf>,
+    /// Mount points, relative to the watch root, whose filesystem went away.
+    unmounted: Mutex<BTreeSet<PathBuf>>,
+    vcs_ignore: Option<VcsIgnore>,
+    /// Symlinks under the watch root that lead to a destination; what the
+    /// watcher reports through them is our own writes.
+    looping_links: Mutex<BTreeSet<PathBuf>>,
+    /// Symlinks in a destination standing where the source has a directory,
+    /// such as one pointing at a bigger disk.
+    dest_links: Mutex<BTreeSet<PathBuf>>,
+    /// Destination roots found unable to set timestamps.
+    no_times: Mutex<BTreeSet<PathBuf>>,
+    /// Owners found outside `id_map`'s ranges, each warned about once.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    unmapped_ids: Mutex<BTreeSet<(&'static str, u32)>>,
+    transactions: Mutex<Transactions>,
+    debounced: Mutex<Debouncer>,
+    deletes: Mutex<DeleteBatch>,
+    /// Retries so far of copies waiting for `LockedFiles::Wait`.
+    locked: Mutex<HashMap<PathBuf, u32>>,
+    /// The last `RECENT_OPERATIONS` operations applied, for status.
+    recent: Mutex<VecDeque<Applied>>,
+    /// Set while a delete batch is applied, which logs one summary line
+    /// instead of one per delete.
+    quiet_deletes: AtomicBool,
+    stability: Mutex<StabilityCheck>,
+    paused: AtomicBool,
+    overflowed: AtomicBool,
+}
+
+impl Mirror {
+    pub fn new(watch_root: PathBuf, output_root: PathBuf, options: Options) -> Self {
+        let vcs_ignore = options.exclude_vcs.then(|| VcsIgnore::new(&watch_root));
+        Mirror {
+            watch_root,
+            output_root,
+            journal: None,
+            hooks: None,
+            merkle: None,
+            trace: None,
+            backend: None,
+            conflicts: None,
+            dead_letters: None,
+            self_writes: None,
+            clock: Clock::default(),
+            pending: Mutex::new(VecDeque::new()),
+            case_index: Mutex::new(CaseIndex::default()),
+            case_insensitive: Mutex::new(HashMap::new()),
+            listings: Mutex::new(Listings::default()),
+            directory_metadata: Mutex::new(Coalescer::new(options.directory_metadata_window)),
+            applied_directory_metadata: Mutex::new(HashMap::new()),
+            renames: Mutex::new(RenameTracker::new(RENAME_WINDOW)),
+            known_directories: Mutex::new(LruCache::new(KNOWN_DIRECTORIES)),
+            delete_guard: Mutex::new(DeleteGuard::default()),
+            copies: Mutex::new(CopyQueue::default()),
+            in_flight: InFlight::default(),
+            unmounted: Mutex::new(BTreeSet::new()),
+            vcs_ignore,
+            looping_links: Mutex::new(BTreeSet::new()),
+            dest_links: Mutex::new(BTreeSet::new()),
+            no_times: Mutex::new(BTreeSet::new()),
+            unmapped_ids: Mutex::new(BTreeSet::new()),
+            transactions: Mutex::new(Transactions::new(options.transaction_settle)),
+            debounced: Mutex::new(Debouncer::default()),
+            deletes: Mutex::new(DeleteBatch::new(options.delete_batch_window)),
+            locked: Mutex::new(HashMap::new()),
+            recent: Mutex::new(VecDeque::new()),
+            quiet_deletes: AtomicBool::new(false),
+            stability: Mutex::new(StabilityCheck::new(
+                options.stable_time.unwrap_or_default(),
+                options.stable_checks,
+            )),
+            paused: AtomicBool::new(false),
+            overflowed: AtomicBool::new(false),
+            options,
+        }
+    }
+}
+
+/// Name of rustsync's control directory (keys, journals, logs), which is never
+/// mirrored wherever it turns up.
+pub const CONTROL_DIR: &str = ".rustsync";
+
+fn is_digits(text: &str) -> bool {
+    !text.is_empty() && text.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Suffixes `AtomicDeploy` gives the trees it keeps beside the output root:
+/// `staging`, `old`, `new`, and releases named `<timestamp>` or
+/// `<timestamp>-<attempt>`.
+fn is_deploy_suffix(suffix: &str) -> bool {
+    match suffix.split_once('-') {
+        _ if matches!(suffix, "staging" | "old" | "new") => true,
+        Some((timestamp, attempt)) => is_digits(timestamp) && is_digits(attempt),
+        None => is_digits(suffix),
+    }
+}
+
+/// Paths rustsync always skips, ahead of any other rule: anything inside a
+/// `.rustsync` directory; the output roots when they sit inside the watch
+/// root, along with their staging/old/release siblings under
+/// `--atomic-deploy`; and
+/// anything seen through a symlink leading to a destination. Mirroring any
+/// of these would feed rustsync its own writes. After those come
+/// `--max-depth` and git's ignore rules with `--exclude-vcs`.
+pub fn is_ignored(mirror: &Mirror, path: &Path) -> bool {
+    let relative = path.strip_prefix(&mirror.watch_root).unwrap_or(path);
+    if relative.components().any(|component| component.as_os_str() == CONTROL_DIR) {
+        return true;
+    }
+    if mirror.options.max_depth.is_some_and(|max_depth| relative.components().count() > max_depth + 1) {
+        return true;
+    }
+    if is_output_root(mirror, path) {
+        return true;
+    }
+    if mirror.looping_links.lock().unwrap().iter().any(|link| path != link && path.starts_with(link)) {
+        return true;
+    }
+
+    mirror.vcs_ignore.as_ref().is_some_and(|vcs| vcs.is_ignored(path))
+}
+
+fn is_output_root(mirror: &Mirror, path: &Path) -> bool {
+    // The watch root and what holds it are never output, whatever their names.
+    let below_watch_root = |ancestor: &&Path| !mirror.watch_root.starts_with(ancestor);
+
+    output_roots(mirror).into_iter().any(|root| {
+        let deploy_base = root
+            .to_str()
+            .and_then(|root| root.strip_suffix(".staging"))
+            .filter(|_| mirror.options.atomic_deploy && root == mirror.output_root)
+            .map(Path::new);
+
+        path.ancestors().filter(below_watch_root).any(|ancestor| {
+            ancestor == root
+                || deploy_base.is_some_and(|base| {
+                    let base_name = base.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+                    ancestor == base
+                        || (ancestor.parent() == base.parent()
+                            && ancestor
+                                .file_name()
+                                .and_then(|name| name.to_str())
+                                .and_then(|name| name.strip_prefix(base_name)?.strip_prefix('.'))
+                                .is_some_and(is_deploy_suffix))
+                })
+        })
+    })
+}
+
+pub fn cross_platform_symlink(path: &Path, sym_path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs as unix_fs;
+        unix_fs::symlink(path, sym_path)
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs as windows_fs;
+        if path.is_dir() {
+            windows_fs::symlink_dir(path, sym_path)
+        } else {
+            windows_fs::symlink_file(path, sym_path)
+        }
+    }
+}
+
+fn is_case_insensitive_root(mirror: &Mirror, root: &Path) -> bool {
+    *mirror
+        .case_insensitive
+        .lock()
+        .unwrap()
+        .entry(root.to_path_buf())
+        .or_insert_with(|| match is_case_insensitive(root) {
+            Ok(insensitive) => {
+                if insensitive {
+                    println!("Destination {:?} is case-insensitive, checking for case conflicts", root);
+                }
+                insensitive
+            }
+            Err(error) => {
+                report::debug(format_args!("Case sensitivity probe of {:?} failed: {}", root, error));
+                false
+            }
+        })
+}
+
+/// The path `relative` is mirrored under, given the paths already seen: the
+/// first one seen with a case-folded name owns it, for as long as it exists.
+/// On a case-insensitive destination a later path that clashes with an
+/// owner, or whose parent does, is Err(owner), or gets `case_suffix` added
+/// to the clashing name. Elsewhere clashes are only warned about.
+fn resolve_case(mirror: &Mirror, relative: &RelPath, root: &Path) -> Result<RelPath, RelPath> {
+    let insensitive = is_case_insensitive_root(mirror, root);
+    let mut case_index = mirror.case_index.lock().unwrap();
+    let mut resolved = relative.clone();
+
+    let depths = match insensitive {
+        true => 1..=relative.depth(),
+        false => relative.depth()..=relative.depth(),
+    };
+    for depth in depths {
+        // Keyed by the name it's mirrored under, so the children of a
+        // renamed directory don't clash with its namesake's.
+        let key = resolved.prefix(depth).case_key();
+        let prefix = relative.prefix(depth);
+        let owner = match case_index.get(&key) {
+            Some(owner)
+                if *owner != prefix
+                    && mirror.listings.lock().unwrap().exists_exactly(&owner.materialize(&mirror.watch_root)) =>
+            {
+                owner.clone()
+            }
+            _ => {
+                case_index.insert(key, prefix);
+                continue;
+            }
+        };
+
+        match (insensitive, &mirror.options.case_suffix) {
+            (false, _) => eprintln!(
+                "Warning: {:?} and {:?} differ only by case and will clash on a case-insensitive destination",
+                owner.to_string(),
+                relative.to_string()
+            ),
+            (true, None) => return Err(owner),
+            (true, Some(suffix)) => resolved = resolved.with_suffix(depth - 1, suffix),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Drops `path`, which was deleted or renamed away, and anything under it
+/// from the case index.
+fn forget_case(mirror: &Mirror, path: &Path) {
+    if let Some(relative) = RelPath::from_root(&mirror.watch_root, path) {
+        mirror.case_index.lock().unwrap().forget(&relative);
+    }
+}
+
+/// Reports and returns true when `relative` can't be mirrored because it
+/// clashes by case with a path already mirrored to a case-insensitive
+/// destination.
+fn case_conflict(mirror: &Mirror, relative: &Path) -> bool {
+    let Some(relative) = RelPath::new(relative) else {
+        return false;
+    };
+    let root = output_root_for(mirror, &relative);
+    if !is_case_insensitive_root(mirror, root) {
+        return false;
+    }
+    match resolve_case(mirror, &relative, root) {
+        Ok(_) => false,
+        Err(owner) => {
+            let path = relative.materialize(&mirror.watch_root);
+            report::error(
+                ErrorKind::CaseConflict,
+                &path,
+                format!(
+                    "Skipped {:?}: its name clashes by case with {:?}, already mirrored in case-insensitive {:?} (--case-conflict-suffix keeps both)",
+                    relative.to_string(),
+                    owner.to_string(),
+                    root
+                ),
+            );
+            true
+        }
+    }
+}
+
+/// The destination root `relative` is mirrored under: the first `--route`
+/// whose glob matches, otherwise the output root.
+fn output_root_for<'a>(mirror: &'a Mirror, relative: &RelPath) -> &'a Path {
+    let key = relative.to_string();
+    mirror
+        .options
+        .routes
+        .iter()
+        .find(|route| route.matches(&key))
+        .map_or(&mirror.output_root, |route| &route.destination)
+}
+
+/// Every destination root: the output root followed by each `--route` target.
+pub fn output_roots(mirror: &Mirror) -> Vec<&Path> {
+    let mut roots = vec![mirror.output_root.as_path()];
+    for route in &mirror.options.routes {
+        if !roots.contains(&route.destination.as_path()) {
+            roots.push(&route.destination);
+        }
+    }
+    roots
+}
+
+/// `relative` under `root`, with its names encrypted under `--encrypt-names`.
+fn materialize(mirror: &Mirror, relative: &RelPath, root: &Path) -> PathBuf {
+    match &mirror.options.encrypt {
+        Some(encryption) if encryption.names => {
+            relative.map_components(|name| encryption.encrypt_name(name)).materialize(root)
+        }
+        _ => relative.materialize(root),
+    }
+}
+
+/// Where a path relative to the watch root is mirrored, following `--route`.
+pub fn mirrored_path(mirror: &Mirror, relative: &Path) -> Option<PathBuf> {
+    let relative = RelPath::new(relative)?;
+    let root = output_root_for(mirror, &relative);
+    let relative = match is_case_insensitive_root(mirror, root) {
+        true => resolve_case(mirror, &relative, root).unwrap_or(relative),
+        false => relative,
+    };
+    Some(materialize(mirror, &relative, root))
+}
+
+/// The inverse of `destination_path`: the path relative to the watch root
+/// that `stored`, relative to a destination root, mirrors. None when `stored`
+/// can't have come from this mirror, such as a name that doesn't decrypt.
+pub fn watched_relative(mirror: &Mirror, root: &Path, stored: &Path) -> Option<PathBuf> {
+    let original = match (&mirror.options.compress, &mirror.options.encrypt) {
+        (Some(_), _) => compress::original_path(&root.join(stored)),
+        (_, Some(_)) => encrypt::original_path(&root.join(stored)),
+        _ => None,
+    };
+    let relative = match &original {
+        Some(original) => original.strip_prefix(root).ok()?,
+        None => stored,
+    };
+
+    match &mirror.options.encrypt {
+        Some(encryption) if encryption.names => relative
+            .iter()
+            .map(|name| encryption.decrypt_name(name))
+            .collect(),
+        _ => Some(relative.to_path_buf()),
+    }
+}
+
+fn change_root(mirror: &Mirror, path: &Path) -> Option<PathBuf> {
+    let relative = RelPath::from_root(&mirror.watch_root, path)?;
+    let root = output_root_for(mirror, &relative);
+    let relative = resolve_case(mirror, &relative, root).ok()?;
+    Some(materialize(mirror, &relative, root))
+}
+
+/// Directories can hold routed files under several destinations, so a
+/// directory delete or rename also applies to its counterparts under the
+/// other roots.
+fn other_roots_with_directory<'a>(mirror: &'a Mirror, path: &Path, mirrored_path: &Path) -> Vec<&'a Path> {
+    let relative = match RelPath::from_root(&mirror.watch_root, path) {
+        Some(relative) => relative,
+        None => return Vec::new(),
+    };
+
+    output_roots(mirror)
+        .into_iter()
+        .filter(|root| {
+            let other = materialize(mirror, &relative, root);
+            other != mirrored_path && other.is_dir()
+        })
+        .collect()
+}
+
+/// `path`'s counterpart under another destination root.
+fn materialize_under(mirror: &Mirror, path: &Path, root: &Path) -> Option<PathBuf> {
+    let relative = RelPath::from_root(&mirror.watch_root, path)?;
+    Some(materialize(mirror, &relative, root))
+}
+
+pub fn handle_watch_error(error: &notify::Error) {
+    report::error(ErrorKind::Watch, Path::new(""), format!("Watch error: {:?}", error));
+}
+
+fn handle_not_under_watch_error(watch_root: &Path, path: &Path) {
+    report::error(
+        ErrorKind::NotUnderWatch,
+        path,
+        format!("Path {:?} is not under watch root {:?}", path, watch_root),
+    );
+}
+
+fn handle_create_dir_error(path: &Path, error: &io::Error) {
+    report::error(ErrorKind::CreateDir, path, format!("Failed to create dir {:?}: {:?}", path, error));
+}
+
+fn handle_event_unknown(event: &notify::Event, path: &Path) {
+    eprintln!("Unknown[unsupported]: {:?} {:?}", path, event);
+}
+
+fn handle_event_malformed(event: &notify::Event, expected: usize) {
+    eprintln!(
+        "Malformed[skipped]: expected {} path(s), got {}: {:?}",
+        expected,
+        event.paths.len(),
+        event
+    );
+}
+
+fn handle_event_other(_mirror: &Mirror, path: &Path) {
+    eprintln!("Other[unsupported]: {:?}", path);
+}
+
+fn handle_event_modify_other(_mirror: &Mirror, path: &Path) {
+    eprintln!("Modify[unsupported][other]: {:?}", path);
+}
+
+/// Whether `path` was deleted since its event fired. Its delete event will
+/// bring the mirror in line, so failures it causes are only logged at debug
+/// level rather than reported as errors.
+fn vanished(path: &Path) -> bool {
+    matches!(fs::symlink_metadata(path), Err(error) if error.kind() == io::ErrorKind::NotFound)
+}
+
+fn handle_vanished(path: &Path, label: &str) {
+    metrics::add("vanished_files", 1);
+    report::debug(format_args!("{}[vanished]: {:?}", label, path));
+}
+
+fn handle_event_create_other(_mirror: &Mirror, path: &Path) {
+    eprintln!("Created[unsupported][other]: {:?}", path);
+}
+
+fn sync_parent(mirror: &Mirror, mirrored_path: &Path) {
+    if mirror.options.fsync != Fsync::Full {
+        return;
+    }
+
+    if let Some(parent) = mirrored_path.parent() {
+        if let Err(error) = sync_directory(parent) {
+            report::error(ErrorKind::Fsync, parent, format!("Failed to sync directory {:?}: {}", parent, error));
+        }
+    }
+}
+
+/// Creates the parent directories of `mirrored_path` unless they were seen
+/// recently, saving a stat (or several mkdirs) per copy in bulk syncs.
+fn ensure_parent(mirror: &Mirror, mirrored_path: &Path) -> io::Result<()> {
+    let parent = match mirrored_path.parent() {
+        Some(parent) => parent,
+        None => return Ok(()),
+    };
+
+    let mut known_directories = mirror.known_directories.lock().unwrap();
+    if known_directories.get(parent).is_some() {
+        metrics::add("dir_cache_hits", 1);
+        return Ok(());
+    }
+
+    metrics::add("dir_cache_misses", 1);
+    fs::create_dir_all(parent)?;
+    known_directories.put(parent.to_path_buf(), ());
+    Ok(())
+}
+
+/// Drops cached directories at or below `mirrored_path` after it was deleted
+/// or renamed away.
+fn forget_directories(mirror: &Mirror, mirrored_path: &Path) {
+    let mut known_directories = mirror.known_directories.lock().unwrap();
+    let stale: Vec<PathBuf> = known_directories
+        .iter()
+        .map(|(directory, _)| directory)
+        .filter(|directory| directory.starts_with(mirrored_path))
+        .cloned()
+        .collect();
+
+    for directory in stale {
+        known_directories.pop(&directory);
+    }
+}
+
+/// The name a file is stored under after `--compress-dest` or
+/// `--encrypt-dest` transformed it.
+fn transformed_path(mirror: &Mirror, mirrored_path: &Path) -> PathBuf {
+    match mirror.options.encrypt {
+        Some(_) => encrypted_path(mirrored_path),
+        None => compressed_path(mirrored_path),
+    }
+}
+
+/// Where the mirror of a file actually lives: its `.zst` or `.enc` sibling
+/// when `--compress-dest` or `--encrypt-dest` stored it transformed,
+/// otherwise `mirrored_path` itself.
+pub fn destination_path(mirror: &Mirror, mirrored_path: &Path) -> PathBuf {
+    if fs::symlink_metadata(mirrored_path).is_ok() {
+        return mirrored_path.to_path_buf();
+    }
+    let transformed = match (&mirror.options.compress, &mirror.options.encrypt) {
+        (Some(_), _) => Some(compressed_path(mirrored_path)).filter(|path| compress::original_path(path).is_some()),
+        (_, Some(_)) => Some(encrypted_path(mirrored_path)).filter(|path| encrypt::original_path(path).is_some()),
+        _ => None,
+    };
+    transformed.unwrap_or_else(|| mirrored_path.to_path_buf())
+}
+
+/// The source entry stored under the name `path` would take compressed:
+/// `big.txt.zst`, for `big.txt`, when it's kept as it is.
+fn compressed_clash(mirror: &Mirror, path: &Path) -> Option<PathBuf> {
+    let compression = mirror.options.compress.as_ref().filter(|_| mirror.options.encrypt.is_none())?;
+    let clash = compressed_path(path);
+    let metadata = fs::symlink_metadata(&clash).ok()?;
+    let kept = !metadata.is_file() || !compression.applies_to(&clash, metadata.len());
+    kept.then_some(clash)
+}
+
+/// Whether the mirror of `path` is missing or another size, which
+/// `--metadata-only` still copies: a file created and then written would
+/// otherwise stay as empty as it was created.
+fn lacks_contents(mirror: &Mirror, path: &Path) -> bool {
+    let (Some(mirrored), Ok(source)) = (change_root(mirror, path), fs::metadata(path)) else {
+        return false;
+    };
+    let destination = destination_path(mirror, &mirrored);
+    let size = match destination != mirrored {
+        true => stored_size(mirror, &destination),
+        false => fs::symlink_metadata(&destination).ok().filter(fs::Metadata::is_file).map(|metadata| metadata.len()),
+    };
+    size != Some(source.len())
+}
+
+/// The original size recorded in a compressed or encrypted file's header.
+pub fn stored_size(mirror: &Mirror, stored: &Path) -> Option<u64> {
+    match mirror.options.encrypt {
+        Some(_) => encrypt::read_header(stored).ok().flatten().map(|header| header.size),
+        None => compress::read_header(stored).ok().flatten().map(|header| header.size),
+    }
+}
+
+/// Streams the original contents of a compressed or encrypted file.
+pub fn read_stored(mirror: &Mirror, stored: &Path) -> anyhow::Result<Box<dyn Read>> {
+    Ok(match &mirror.options.encrypt {
+        Some(encryption) => Box::new(encrypt::decrypt(stored, encryption)?),
+        None => Box::new(compress::decompress(stored)?),
+    })
+}
+
+fn handle_event_delete(mirror: &Mirror, path: &Path) {
+    match mirror.quiet_deletes.load(Ordering::SeqCst) {
+        true => report::debug(format_args!("Deleted: {:?}", path)),
+        false => println!("Deleted: {:?}", report::shown(path)),
+    }
+
+    let mirrored_path = match change_root(mirror, path) {
+        Some(path) => destination_path(mirror, &path),
+        None => return handle_not_under_watch_error(&mirror.watch_root, path),
+    };
+
+    let mut targets = vec![mirrored_path.clone()];
+    targets.extend(
+        other_roots_with_directory(mirror, path, &mirrored_path)
+            .into_iter()
+            .filter_map(|root| materialize_under(mirror, path, root)),
+    );
+
+    for mirrored_path in targets {
+        let result = while_unflagged(mirror, &[&mirrored_path], || {
+            if is_dest_link(mirror, &mirrored_path) {
+                forget_directories(mirror, &mirrored_path);
+                remove_dest_link(mirror, &mirrored_path)
+            } else if mirrored_path.is_dir() {
+                forget_directories(mirror, &mirrored_path);
+                if mirror.options.preserve_flags {
+                    clear_flags_under(&mirrored_path);
+                }
+                fs::remove_dir_all(&mirrored_path)
+            } else {
+                fs::remove_file(&mirrored_path)
+            }
+        });
+
+        match result {
+            Ok(()) => sync_parent(mirror, &mirrored_path),
+            Err(error) => report::error(
+                ErrorKind::Delete,
+                &mirrored_path,
+                format!("Failed to delete {:?}: {}", mirrored_path, error),
+            ),
+        }
+    }
+    forget_case(mirror, path);
+}
+
+/// Remembers `mirrored_path`, a symlink to a directory, as a destination
+/// link: file operations go through it, and deleting the directory it stands
+/// for never deletes what it points at.
+pub fn note_dest_link(mirror: &Mirror, mirrored_path: &Path) {
+    mirror.dest_links.lock().unwrap().insert(mirrored_path.to_path_buf());
+}
+
+fn is_dest_link(mirror: &Mirror, mirrored_path: &Path) -> bool {
+    mirror.dest_links.lock().unwrap().contains(mirrored_path) && mirrored_path.is_symlink()
+}
+
+/// Deletes the directory a destination link stands for: with
+/// `--keep-dest-links`, everything in the directory it points at, keeping
+/// the link; otherwise only the link itself.
+fn remove_dest_link(mirror: &Mirror, link: &Path) -> io::Result<()> {
+    if !mirror.options.keep_dest_links {
+        mirror.dest_links.lock().unwrap().remove(link);
+        return fs::remove_file(link);
+    }
+
+    mirror.dest_links.lock().unwrap().retain(|path| path == link || !path.starts_with(link));
+    for entry in fs::read_dir(link)? {
+        let entry = entry?;
+        if entry.file_name() == CONTROL_DIR {
+            continue;
+        }
+        match entry.file_type()?.is_dir() {
+            true => fs::remove_dir_all(entry.path())?,
+            false => fs::remove_file(entry.path())?,
+        }
+    }
+    Ok(())
+}
+
+/// Renames within a destination, or moves between destinations when a
+/// `--route` sends the new name somewhere else.
+fn rename_mirrored(mirror: &Mirror, from: &Path, to: &Path) -> io::Result<()> {
+    ensure_parent(mirror, to)?;
+    // The entry's flags go with it to its new name.
+    let flags = fsflags::get(from).ok().filter(|_| mirror.options.preserve_flags);
+    while_unflagged(mirror, &[from, to], || match fs::rename(from, to) {
+        Err(error) if error.kind() == io::ErrorKind::CrossesDevices && from.is_file() => {
+            copy_file(from, to, mirror.options.reflink)?;
+            fs::remove_file(from)
+        }
+        result => result,
+    })?;
+    if let Some(flags) = flags {
+        let _ = fsflags::apply(to, flags);
+    }
+    Ok(())
+}
+
+fn handle_event_rename(mirror: &Mirror, path: &Path, new_path: &Path) {
+    println!("Renamed: {:?} -> {:?}", report::shown(path), report::shown(new_path));
+
+    let (mirrored_path, transformed) = match change_root(mirror, path) {
+        Some(path) => {
+            let destination = destination_path(mirror, &path);
+            let transformed = destination != path;
+            (destination, transformed)
+        }
+        None => return handle_not_under_watch_error(&mirror.watch_root, path),
+    };
+
+    let mirrored_new_path = match change_root(mirror, new_path) {
+        Some(path) if transformed => transformed_path(mirror, &path),
+        Some(path) => path,
+        None => return handle_not_under_watch_error(&mirror.watch_root, new_path),
+    };
+
+    let mut renames = vec![(mirrored_path.clone(), mirrored_new_path)];
+    for root in other_roots_with_directory(mirror, path, &mirrored_path) {
+        if let (Some(from), Some(to)) = (materialize_under(mirror, path, root), materialize_under(mirror, new_path, root)) {
+            renames.push((from, to));
+        }
+    }
+
+    let mut recopy = false;
+    for (mirrored_path, mirrored_new_path) in renames {
+        if mirrored_path.is_dir() {
+            forget_directories(mirror, &mirrored_path);
+        }
+
+        // A kept link stays put, emptied, and the directory is mirrored
+        // afresh under its new name.
+        if mirror.options.keep_dest_links && is_dest_link(mirror, &mirrored_path) {
+            if let Err(error) = remove_dest_link(mirror, &mirrored_path).and_then(|()| fs::create_dir_all(&mirrored_new_path)) {
+                report::error(
+                    ErrorKind::Rename,
+                    &mirrored_path,
+                    format!("Failed to rename {:?} -> {:?}: {}", mirrored_path, mirrored_new_path, error),
+                );
+                continue;
+            }
+            recopy = true;
+            continue;
+        }
+        if mirror.dest_links.lock().unwrap().remove(&mirrored_path) {
+            note_dest_link(mirror, &mirrored_new_path);
+        }
+
+        if let Err(error) = rename_mirrored(mirror, &mirrored_path, &mirrored_new_path) {
+            report::error(
+                ErrorKind::Rename,
+                &mirrored_path,
+                format!("Failed to rename {:?} -> {:?}: {}", mirrored_path, mirrored_new_path, error),
+            );
+            continue;
+        }
+
+        sync_parent(mirror, &mirrored_new_path);
+        if mirrored_path.parent() != mirrored_new_path.parent() {
+            sync_parent(mirror, &mirrored_path);
+        }
+    }
+
+    forget_case(mirror, path);
+    if recopy {
+        if let Ok(relative) = new_path.strip_prefix(&mirror.watch_root) {
+            println!("Recopied kept link's directory: {}", crate::reconcile::reconcile_under(mirror, relative));
+        }
+    }
+}
+
+/// The `--preserve`d metadata categories (xattrs aside) in which a mirrored
+/// entry differs from its source.
+pub fn metadata_differences(mirror: &Mirror, source: &fs::Metadata, destination: &fs::Metadata) -> Vec<&'static str> {
+    let preserve = &mirror.options.preserve;
+    let (_, source_modified, source_uid, source_gid) = directory_signature(source);
+    let (destination_permissions, destination_modified, destination_uid, destination_gid) =
+        directory_signature(destination);
+    let mut differences = Vec::new();
+
+    let source_owner = match &mirror.options.id_map {
+        Some(id_map) => id_map.map(source_uid, source_gid).ok(),
+        None => Some((source_uid, source_gid)),
+    };
+    if preserve.contains(&Preserve::Owner) && source_owner.is_some_and(|owner| owner != (destination_uid, destination_gid)) {
+        differences.push("owner");
+    }
+    if preserve.contains(&Preserve::Perms) && mirrored_permissions(mirror, source) != destination_permissions {
+        differences.push("perms");
+    }
+    if preserve.contains(&Preserve::Times)
+        && source_modified != destination_modified
+        && newer_by(mirror, source, destination).is_none()
+    {
+        differences.push("times");
+    }
+    differences
+}
+
+/// The mode bits copies leave off: setuid and setgid under `strip_setuid`,
+/// whether or not permissions are preserved.
+fn setuid_mask(mirror: &Mirror) -> u32 {
+    match mirror.options.strip_setuid {
+        true => copy::SETUID,
+        false => 0,
+    }
+}
+
+/// The permissions the mirror of a file with `metadata` should have. On Unix
+/// this is the full mode including setuid/setgid/sticky, minus setuid (and
+/// setgid on non-directories) when `strip_setuid` is set.
+pub fn mirrored_permissions(mirror: &Mirror, metadata: &fs::Metadata) -> fs::Permissions {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let mut mode = metadata.mode();
+        if mirror.options.strip_setuid {
+            mode &= !0o4000;
+            if !metadata.is_dir() {
+                mode &= !0o2000;
+            }
+        }
+        fs::Permissions::from_mode(mode)
+    }
+
+    #[cfg(windows)]
+    {
+        let _ = mirror;
+        metadata.permissions()
+    }
+}
+
+fn apply_permissions(mirror: &Mirror, mirrored_path: &Path, metadata: &fs::Metadata) {
+    if let Err(error) = fs::set_permissions(mirrored_path, mirrored_permissions(mirror, metadata)) {
+        report::error(ErrorKind::Permissions, mirrored_path, format!("Failed to set permissions for {:?}: {}", mirrored_path, error));
+    }
+}
+
+/// Whether `error` means the filesystem can't do what was asked at all.
+fn is_unsupported(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::Unsupported
+        || matches!(error.raw_os_error(), Some(code) if code == libc::ENOTSUP || code == libc::EOPNOTSUPP || code == libc::ENOSYS)
+}
+
+/// How much newer `destination` is than `source`, when `--only-newer` is on
+/// and it's by more than the tolerance.
+fn newer_by(mirror: &Mirror, source: &fs::Metadata, destination: &fs::Metadata) -> Option<Duration> {
+    let tolerance = mirror.options.only_newer?;
+    let newer = destination.modified().ok()?.duration_since(source.modified().ok()?).ok()?;
+    (newer > tolerance).then_some(newer)
+}
+
+/// Sets the source's times on `mirrored_path`. Unless this run has just
+/// `written` it, a mirror newer by more than `--only-newer` keeps its own.
+fn apply_times(mirror: &Mirror, mirrored_path: &Path, metadata: &fs::Metadata, written: bool) {
+    let root = output_roots(mirror).into_iter().find(|root| mirrored_path.starts_with(root));
+    if root.is_some_and(|root| mirror.no_times.lock().unwrap().contains(root)) {
+        return;
+    }
+    let destination = fs::metadata(mirrored_path).ok().filter(|_| !written);
+    if let Some(newer) = destination.and_then(|destination| newer_by(mirror, metadata, &destination)) {
+        return report::debug(format_args!(
+            "Skipped[newer] times {:?}: the mirror is {} newer",
+            mirrored_path,
+            format_duration(newer)
+        ));
+    }
+
+    #[cfg(unix)]
+    let (atime, mtime) = {
+        use std::os::unix::fs::MetadataExt;
+
+        (
+            FileTime::from_unix_time(metadata.atime(), metadata.atime_nsec() as u32),
+            FileTime::from_unix_time(metadata.mtime(), metadata.mtime_nsec() as u32),
+        )
+    };
+
+    #[cfg(windows)]
+    let (atime, mtime) = (
+        FileTime::from_last_access_time(metadata),
+        FileTime::from_last_modification_time(metadata),
+    );
+
+    let result = match mirror.options.atime {
+        Atime::Preserve => filetime::set_file_times(mirrored_path, atime, mtime),
+        Atime::Omit => filetime::set_file_mtime(mirrored_path, mtime),
+        Atime::Now => filetime::set_file_times(mirrored_path, FileTime::now(), mtime),
+    };
+    match (result, root) {
+        (Ok(()), _) => {}
+        // Once per destination rather than once per file.
+        (Err(error), Some(root)) if is_unsupported(&error) && !mirror.options.require_times => {
+            eprintln!(
+                "Warning: {:?} can't set timestamps ({}), not syncing times there for the rest of the run",
+                root, error
+            );
+            mirror.no_times.lock().unwrap().insert(root.to_path_buf());
+        }
+        (Err(error), _) => {
+            report::error(ErrorKind::Times, mirrored_path, format!("Failed to set timestamps for {:?}: {}", mirrored_path, error))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn apply_owner(mirror: &Mirror, mirrored_path: &Path, metadata: &fs::Metadata) {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt, os::unix::fs::MetadataExt};
+
+    let (uid, gid) = match &mirror.options.id_map {
+        None => (metadata.uid(), metadata.gid()),
+        Some(id_map) => match id_map.map(metadata.uid(), metadata.gid()) {
+            Ok(owner) => owner,
+            Err((kind, id)) => {
+                if mirror.unmapped_ids.lock().unwrap().insert((kind, id)) {
+                    eprintln!("Warning: {} {} is outside the subordinate ID range, not setting owners for it", kind, id);
+                }
+                report::debug(format_args!("Skipped[owner] {:?}: {} {} can't be mapped", mirrored_path, kind, id));
+                return;
+            }
+        },
+    };
+
+    let c_path = match CString::new(mirrored_path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(error) => {
+            report::error(ErrorKind::Owner, mirrored_path, format!("Failed to convert path for chown {:?}: {}", mirrored_path, error));
+            return;
+        }
+    };
+
+    unsafe {
+        if libc::chown(c_path.as_ptr(), uid, gid) != 0 {
+            report::error(ErrorKind::Owner, mirrored_path, format!("Failed to set owner/group for {:?}", mirrored_path));
+        }
+    }
+}
+
+#[cfg(windows)]
+fn apply_owner(_mirror: &Mirror, _mirrored_path: &Path, _metadata: &fs::Metadata) {}
+
+#[cfg(unix)]
+fn apply_xattrs(path: &Path, mirrored_path: &Path) {
+    let names = match xattr::list(path) {
+        Ok(names) => names.collect::<Vec<_>>(),
+        Err(error) => return report::error(ErrorKind::Xattr, path, format!("Failed to list xattrs for {:?}: {}", path, error)),
+    };
+
+    for name in &names {
+        match xattr::get(path, name) {
+            Ok(Some(value)) => {
+                if let Err(error) = xattr::set(mirrored_path, name, &value) {
+                    report::error(
+                        ErrorKind::Xattr,
+                        mirrored_path,
+                        format!("Failed to set xattr {:?} on {:?}: {}", name, mirrored_path, error),
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(error) => report::error(
+                ErrorKind::Xattr,
+                path,
+                format!("Failed to read xattr {:?} from {:?}: {}", name, path, error),
+            ),
+        }
+    }
+
+    if let Ok(existing) = xattr::list(mirrored_path) {
+        for name in existing.filter(|name| !names.contains(name)) {
+            if let Err(error) = xattr::remove(mirrored_path, &name) {
+                report::error(
+                    ErrorKind::Xattr,
+                    mirrored_path,
+                    format!("Failed to remove xattr {:?} from {:?}: {}", name, mirrored_path, error),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+fn apply_xattrs(_path: &Path, _mirrored_path: &Path) {}
+
+fn clear_flags(mirrored_path: &Path) {
+    let result = match fsflags::get(mirrored_path) {
+        Ok(flags) if flags & fsflags::MIRRORED != 0 => fsflags::set(mirrored_path, flags & !fsflags::MIRRORED),
+        Ok(_) => Ok(()),
+        Err(error) => Err(error),
+    };
+    if let Err(error) = result {
+        report::error(ErrorKind::Flags, mirrored_path, format!("Failed to clear flags on {:?}: {}", mirrored_path, error));
+    }
+}
+
+fn apply_flags(path: &Path, mirrored_path: &Path) {
+    let flags = match fsflags::get(path) {
+        Ok(flags) => flags,
+        Err(error) => return report::error(ErrorKind::Flags, path, format!("Failed to read flags for {:?}: {}", path, error)),
+    };
+    if let Err(error) = fsflags::apply(mirrored_path, flags) {
+        report::error(ErrorKind::Flags, mirrored_path, format!("Failed to set flags on {:?}: {}", mirrored_path, error));
+    }
+}
+
+/// `while_writable`, also clearing the destination's immutable and
+/// append-only flags, and its directory's, for the write under
+/// `--preserve-flags`.
+fn while_unlocked<T>(mirror: &Mirror, destination: &Path, write: impl FnOnce() -> T) -> T {
+    while_unflagged(mirror, &[destination], || while_writable(destination, write))
+}
+
+/// Runs `change` with the immutable and append-only flags of `entries` and
+/// their directories cleared under `--preserve-flags`: an immutable entry
+/// can't be removed or renamed, and nothing can be added to or removed from
+/// an immutable directory.
+fn while_unflagged<T>(mirror: &Mirror, entries: &[&Path], change: impl FnOnce() -> T) -> T {
+    if !mirror.options.preserve_flags {
+        return change();
+    }
+    let mut paths = Vec::new();
+    for path in entries.iter().flat_map(|&entry| [Some(entry), entry.parent()]).flatten() {
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    fsflags::while_all_cleared(&paths, change)
+}
+
+/// Clears the immutable and append-only flags of everything under
+/// `directory`, which is about to be deleted.
+fn clear_flags_under(directory: &Path) {
+    for entry in WalkDir::new(directory).min_depth(1).into_iter().filter_map(Result::ok) {
+        if entry.file_type().is_file() || entry.file_type().is_dir() {
+            clear_flags(entry.path());
+        }
+    }
+}
+
+fn handle_event_metadata(mirror: &Mirror, path: &Path) {
+    println!("Modify[metadata]: {:?}", report::shown(path));
+    apply_metadata(mirror, path);
+}
+
+/// Copies the `--preserve`d metadata of `path` onto its mirror.
+pub fn apply_metadata(mirror: &Mirror, path: &Path) {
+    apply_metadata_after(mirror, path, false);
+}
+
+/// `apply_metadata`, for a mirror this run has just `written` or not.
+fn apply_metadata_after(mirror: &Mirror, path: &Path, written: bool) {
+    let mirrored_path = match change_root(mirror, path) {
+        Some(path) => destination_path(mirror, &path),
+        None => return handle_not_under_watch_error(&mirror.watch_root, path),
+    };
+
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) if vanished(path) => return handle_vanished(path, "Modify[metadata]"),
+        Err(error) => return report::error(ErrorKind::Metadata, path, format!("Failed to read metadata for {:?}: {}", path, error)),
+    };
+
+    let preserve = &mirror.options.preserve;
+
+    // An immutable or append-only mirror takes no other metadata, so its
+    // flags come off first and the source's go on last.
+    if mirror.options.preserve_flags {
+        clear_flags(&mirrored_path);
+    }
+    // chown clears setuid/setgid, so ownership goes first. It clears file
+    // capabilities too, which are xattrs and come after it.
+    if preserve.contains(&Preserve::Owner) {
+        apply_owner(mirror, &mirrored_path, &metadata);
+    }
+    if preserve.contains(&Preserve::Perms) {
+        apply_permissions(mirror, &mirrored_path, &metadata);
+    }
+    if preserve.contains(&Preserve::Times) {
+        apply_times(mirror, &mirrored_path, &metadata, written);
+    }
+    if preserve.contains(&Preserve::Xattrs) {
+        apply_xattrs(path, &mirrored_path);
+    }
+    if mirror.options.preserve_flags {
+        apply_flags(path, &mirrored_path);
+    }
+    if let Some(conflicts) = &mirror.conflicts {
+        conflicts.touched(&mirrored_path);
+    }
+}
+
+/// Whether `--dangling-symlinks` leaves out the link at `path` because its
+/// target doesn't exist.
+fn skips_dangling(mirror: &Mirror, path: &Path) -> bool {
+    if mirror.options.dangling_symlinks == DanglingSymlinks::Keep || fs::metadata(path).is_ok() {
+        return false;
+    }
+    let target = fs::read_link(path).unwrap_or_default();
+    match mirror.options.dangling_symlinks {
+        DanglingSymlinks::Keep => false,
+        DanglingSymlinks::Skip => {
+            report::debug(format_args!("Skipped[dangling symlink]: {:?} -> {:?}", path, target));
+            true
+        }
+        DanglingSymlinks::Error => {
+            report::error(
+                ErrorKind::Symlink,
+                path,
+                format!("Symlink {:?} -> {:?} is dangling, not mirroring it", path, target),
+            );
+            true
+        }
+    }
+}
+
+fn handle_event_create_symlink(mirror: &Mirror, path: &Path) {
+    if skips_dangling(mirror, path) {
+        return;
+    }
+    println!("Created[symlink]: {:?}", report::shown(path));
+
+    let roots: Vec<PathBuf> = output_roots(mirror).into_iter().map(Path::to_path_buf).collect();
+    if let Some(destination) = link_loop(path, &roots) {
+        report::error(
+            ErrorKind::Symlink,
+            path,
+            format!("Symlink {:?} leads to destination {:?}, ignoring changes seen through it", path, destination),
+        );
+        mirror.looping_links.lock().unwrap().insert(path.to_path_buf());
+    }
+
+    let mirrored_path = match change_root(mirror, path) {
+        Some(path) => path,
+        None => return handle_not_under_watch_error(&mirror.watch_root, path),
+    };
+
+    let original_target = match fs::read_link(path) {
+        Ok(target) => target,
+        Err(error) => {
+            report::error(ErrorKind::Symlink, path, format!("Failed to read symlink {:?}: {}", path, error));
+            return;
+        }
+    };
+
+    let mirrored_target =
+        change_root(mirror, &original_target).unwrap_or(original_target);
+
+    if let Err(error) = while_unflagged(mirror, &[&mirrored_path], || cross_platform_symlink(&mirrored_target, &mirrored_path)) {
+        report::error(
+            ErrorKind::Symlink,
+            &mirrored_path,
+            format!("Failed to create symlink {:?} -> {:?}: {}", mirrored_path, mirrored_target, error),
+        );
+        return;
+    }
+
+    sync_parent(mirror, &mirrored_path);
+}
+
+/// Copies `path` to the mirror unless it already holds the same contents.
+/// Returns whether it now does.
+fn sync_file_to_mirror(mirror: &Mirror, path: &Path, event_label: &str) -> bool {
+    let mirrored_path = match change_root(mirror, path) {
+        Some(path) => path,
+        None => {
+            handle_not_under_watch_error(&mirror.watch_root, path);
+            return false;
+        }
+    };
+
+    if already_mirrored(mirror, path, &mirrored_path) {
+        // Its times aren't set back either; see apply_times.
+        match newer_mirror(mirror, path, &mirrored_path) {
+            Some(newer) => println!("Skipped[newer]: {:?} (the mirror is {} newer)", report::shown(path), format_duration(newer)),
+            None => report::debug(format_args!("{}[unchanged]: {:?}", event_label, path)),
+        }
+        return true;
+    }
+    println!("{}: {:?}", event_label, report::shown(path));
+
+    if let Err(error) = ensure_parent(mirror, &mirrored_path) {
+        report::error(
+            ErrorKind::CreateDir,
+            &mirrored_path,
+            format!("Failed to create parent dirs for {:?}: {}", mirrored_path, error),
+        );
+        return false;
+    }
+
+    let relative = path.strip_prefix(&mirror.watch_root).unwrap_or(path);
+    let source_size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+    let _reservation = inflight::reserve(inflight::footprint(source_size, mirror.options.transforms.applies_to(relative)));
+
+    // Transformed content is staged next to the mirror and copied from there.
+    let transformed = match transform_to_staging(mirror, path, &mirrored_path) {
+        Staged::Source => None,
+        Staged::Transformed(staged) => Some(staged),
+        Staged::Done => return false,
+    };
+    let original = path;
+    let path = transformed.as_deref().unwrap_or(path);
+
+    let size = fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+    let compression = mirror.options.compress.as_ref().filter(|compression| compression.applies_to(original, size));
+    let clash = compressed_clash(mirror, original);
+    if let (Some(_), Some(clash)) = (compression, &clash) {
+        if let Some(staged) = &transformed {
+            let _ = fs::remove_file(staged);
+        }
+        report::error(
+            ErrorKind::Copy,
+            original,
+            format!("Skipped {:?}: compressed, it would overwrite the mirror of {:?}", original, clash),
+        );
+        return false;
+    }
+
+    // Scheduled copies give way between chunks to those that would go first.
+    let urgency = schedules_copies(mirror).then(|| (priority_of(&mirror.options.priorities, relative), size));
+    let write = || match (compression, &mirror.options.encrypt) {
+        (_, Some(encryption)) => {
+            let encrypted = encrypted_path(&mirrored_path);
+            let result = while_unlocked(mirror, &encrypted, || encrypt_file(path, &encrypted, encryption));
+            (encrypted, result)
+        }
+        (Some(compression), None) => {
+            let compressed = compressed_path(&mirrored_path);
+            let result = while_unlocked(mirror, &compressed, || compress_file(path, &compressed, compression));
+            (compressed, result)
+        }
+        (None, None) => {
+            // Under --on-conflict the copy hashes what it writes, for the index.
+            let copy = || {
+                let mut hasher = mirror.conflicts.as_ref().map(|_| blake3::Hasher::new());
+                if urgency.is_none() && hasher.is_none() {
+                    return copy_file_masked(path, &mirrored_path, mirror.options.reflink, setuid_mask(mirror));
+                }
+                let mut between = |chunk: &[u8]| {
+                    if let Some(hasher) = &mut hasher {
+                        hasher.update(chunk);
+                    }
+                    if let Some((priority, bytes)) = urgency {
+                        give_way(mirror, priority, bytes);
+                    }
+                };
+                let result =
+                    copy_file_yielding(path, &mirrored_path, mirror.options.reflink, setuid_mask(mirror), &mut between);
+                if let (Ok(()), Some(conflicts), Some(hasher)) = (&result, &mirror.conflicts, hasher) {
+                    conflicts.copied(&mirrored_path, hasher.count(), hasher.finalize().to_hex().to_string());
+                }
+                result
+            };
+            let result = while_unlocked(mirror, &mirrored_path, copy)
+                .map_err(anyhow::Error::from);
+            (mirrored_path.clone(), result)
+        }
+    };
+
+    let (mut written, mut result) = write();
+
+    // The cached parent may have been removed from under us; recreate it once.
+    if let (Err(_), Some(parent)) = (&result, mirrored_path.parent()) {
+        if !parent.is_dir() {
+            forget_directories(mirror, parent);
+            if ensure_parent(mirror, &mirrored_path).is_ok() {
+                (written, result) = write();
+            }
+        }
+    }
+
+    if let Some(staged) = &transformed {
+        let _ = fs::remove_file(staged);
+    }
+    let path = original;
+
+    if let Err(error) = result {
+        // Deleted, or replaced by a directory, since the event; the delete or
+        // create event that follows takes care of the mirror.
+        if vanished(path) || path.is_dir() {
+            handle_vanished(path, event_label);
+            return false;
+        }
+        let locked = error.chain().any(|cause| cause.downcast_ref::<io::Error>().is_some_and(is_locked));
+        if locked && handle_locked(mirror, path) {
+            return false;
+        }
+        report::error(
+            ErrorKind::Copy,
+            path,
+            format!("Failed to copy file {:?} -> {:?}: {:#}", path, written, error),
+        );
+        return false;
+    }
+    if mirror.options.locked == LockedFiles::Wait {
+        mirror.locked.lock().unwrap().remove(path);
+    }
+
+    // A file that crossed the compression threshold leaves its other form behind.
+    if mirror.options.compress.is_some() && clash.is_none() {
+        let stale = match written == mirrored_path {
+            true => compressed_path(&mirrored_path),
+            false => mirrored_path.clone(),
+        };
+        if stale.is_file() && (stale == mirrored_path || compress::original_path(&stale).is_some()) {
+            let _ = fs::remove_file(&stale);
+        }
+    }
+
+    metrics::add("bytes_copied", size);
+
+    // No metadata events follow in content-only mode, and compressed,
+    // encrypted or cloned copies are new files with default permissions, so
+    // copies take the source's permission bits here.
+    if mirror.options.changes == Changes::Content && mirror.options.preserve.contains(&Preserve::Perms) {
+        if let Ok(metadata) = fs::metadata(path) {
+            apply_permissions(mirror, &written, &metadata);
+        }
+    }
+
+    if let Err(error) = sync_file(&written, mirror.options.fsync) {
+        report::error(ErrorKind::Fsync, &written, format!("Failed to sync {:?}: {}", written, error));
+    }
+    sync_parent(mirror, &written);
+    true
+}
+
+/// Deals with a copy that failed because another process holds `path`
+/// locked, as `--skip-locked` or `--wait-locked` say. Returns false when it's
+/// to be reported as an error after all.
+fn handle_locked(mirror: &Mirror, path: &Path) -> bool {
+    metrics::add("locked_files", 1);
+    match mirror.options.locked {
+        LockedFiles::Error => false,
+        LockedFiles::Skip => {
+            report::debug(format_args!("Skipped locked file {:?}", path));
+            true
+        }
+        LockedFiles::Wait => {
+            let retries = {
+                let mut locked = mirror.locked.lock().unwrap();
+                let retries = locked.entry(path.to_path_buf()).or_insert(0);
+                *retries += 1;
+                if *retries > LOCKED_RETRIES {
+                    locked.remove(path);
+                    return false;
+                }
+                *retries
+            };
+            // 250ms, doubling up to 30s: about a minute in all.
+            let delay = (Duration::from_millis(250) * 2u32.pow(retries - 1)).min(Duration::from_secs(30));
+            report::debug(format_args!("{:?} is locked, retry {} in {:?}", path, retries, delay));
+            let relative = path.strip_prefix(&mirror.watch_root).unwrap_or(path).to_path_buf();
+            mirror.debounced.lock().unwrap().hold(&relative, Operation::Data { path: relative.clone() }, delay, mirror.clock.now());
+            true
+        }
+    }
+}
+
+/// Whether the mirror already holds the file's exact contents, as when an
+/// application saves a file unchanged. Stored forms that differ from the
+/// source (compressed, encrypted, transformed) are always rewritten.
+fn already_mirrored(mirror: &Mirror, path: &Path, mirrored_path: &Path) -> bool {
+    if mirror.options.compress.is_some() || mirror.options.encrypt.is_some() {
+        return false;
+    }
+    if mirror.options.transforms.applies_to(path.strip_prefix(&mirror.watch_root).unwrap_or(path)) {
+        return false;
+    }
+
+    let same = same_contents(path, mirrored_path).unwrap_or(false);
+    if same {
+        metrics::add("copies_skipped", 1);
+    }
+    same
+}
+
+/// `newer_by` for `path` and its mirror.
+fn newer_mirror(mirror: &Mirror, path: &Path, mirrored_path: &Path) -> Option<Duration> {
+    mirror.options.only_newer?;
+    newer_by(mirror, &fs::metadata(path).ok()?, &fs::metadata(mirrored_path).ok()?)
+}
+
+/// What `sync_file_to_mirror` copies once `--transform`s have run.
+enum Staged {
+    /// No transform applies; copy the source.
+    Source,
+    /// Copy the transformed content from here, then remove it.
+    Transformed(PathBuf),
+    /// Nothing to copy: skipped, dropped or failed, and already logged.
+    Done,
+}
+
+/// Runs `--transform`s over a file's content and stages the result.
+fn transform_to_staging(mirror: &Mirror, path: &Path, mirrored_path: &Path) -> Staged {
+    let relative = path.strip_prefix(&mirror.watch_root).unwrap_or(path);
+    if !mirror.options.transforms.applies_to(relative) {
+        return Staged::Source;
+    }
+
+    let mut content = Vec::new();
+    match open_source(path).and_then(|mut file| file.read_to_end(&mut content)) {
+        Ok(_) => {}
+        Err(_) if vanished(path) => {
+            handle_vanished(path, "Transform");
+            return Staged::Done;
+        }
+        Err(error) => {
+            report::error(ErrorKind::Copy, path, format!("Failed to read {:?}: {}", path, error));
+            return Staged::Done;
+        }
+    }
+
+    let event = MirrorEvent { relative, source: path };
+    match mirror.options.transforms.run(&event, &mut content) {
+        Ok(TransformOutcome::Write) => {}
+        Ok(TransformOutcome::Skip) => {
+            report::debug(format_args!("Transform[skip]: {:?}", path));
+            return Staged::Done;
+        }
+        Ok(TransformOutcome::Drop) => {
+            println!("Transform[drop]: {:?}", report::shown(path));
+            let destination = destination_path(mirror, mirrored_path);
+            if destination.is_file() {
+                if let Err(error) = fs::remove_file(&destination) {
+                    report::error(ErrorKind::Delete, &destination, format!("Failed to delete {:?}: {}", destination, error));
+                }
+            }
+            return Staged::Done;
+        }
+        Err(error) => {
+            report::error(ErrorKind::Copy, path, format!("{:#}", error));
+            return Staged::Done;
+        }
+    }
+
+    let mut staged = staging_path(mirrored_path).into_os_string();
+    staged.push("-transform");
+    let staged = PathBuf::from(staged);
+    if let Err(error) = fs::write(&staged, &content) {
+        let _ = fs::remove_file(&staged);
+        report::error(ErrorKind::Copy, &staged, format!("Failed to write {:?}: {}", staged, error));
+        return Staged::Done;
+    }
+    Staged::Transformed(staged)
+}
+
+/// What both a file's create event and its data events do, since platforms
+/// differ in which they send for the same write (FSEvents favours creates,
+/// inotify modifies): copy whatever the mirror lacks, appending where the
+/// file only grew, then apply its metadata. Running it again is harmless.
+fn upsert_file(mirror: &Mirror, path: &Path, event_label: &str) {
+    one_copy_at_a_time(mirror, path, event_label, || {
+        let conflicts = mirror.conflicts.as_ref().zip(change_root(mirror, path));
+        if let Some((conflicts, mirrored_path)) = &conflicts {
+            let output_root = output_roots(mirror).into_iter().find(|root| mirrored_path.starts_with(root));
+            if !conflicts.check(output_root.unwrap_or(&mirror.output_root), mirrored_path) {
+                return;
+            }
+        }
+        // Whether the mirror is newer is measured before the write, which
+        // makes any mirror newer.
+        let modified = || {
+            let mirrored_path = destination_path(mirror, &change_root(mirror, path)?);
+            fs::metadata(mirrored_path).and_then(|metadata| metadata.modified()).ok()
+        };
+        let before = modified();
+        if !update_in_place(mirror, path) && !sync_file_to_mirror(mirror, path, event_label) {
+            return;
+        }
+        // Content-only copies took their permission bits already.
+        if mirror.options.changes != Changes::Content {
+            apply_metadata_after(mirror, path, modified() != before);
+        }
+        if let Some((conflicts, mirrored_path)) = &conflicts {
+            conflicts.wrote(mirrored_path);
+        }
+    });
+}
+
+/// Runs `copy` once no other thread is copying to `path`'s destination.
+/// Duplicate events for a path (FSEvents and recursive watches send plenty)
+/// would otherwise race on its temp file; past the first waiting duplicate
+/// they're dropped, since that one copies the latest contents anyway.
+fn one_copy_at_a_time(mirror: &Mirror, path: &Path, event_label: &str, copy: impl FnOnce()) {
+    let Some(mirrored_path) = change_root(mirror, path) else {
+        return copy();
+    };
+
+    match mirror.in_flight.claim(mirrored_path) {
+        Some(_claim) => copy(),
+        None => {
+            metrics::add("duplicate_copies", 1);
+            report::debug(format_args!("{}[duplicate]: {:?}", event_label, path));
+        }
+    }
+}
+
+/// Copies only the new tail of a file that grew by appending, or only what
+/// changed of one that shrank by truncation. Returns false when the mirror
+/// needs a full copy instead.
+fn update_in_place(mirror: &Mirror, path: &Path) -> bool {
+    if mirror.options.compress.is_some() || mirror.options.encrypt.is_some() {
+        return false;
+    }
+    if mirror.options.transforms.applies_to(path.strip_prefix(&mirror.watch_root).unwrap_or(path)) {
+        return false;
+    }
+    let mirrored_path = match change_root(mirror, path) {
+        Some(path) => path,
+        None => return false,
+    };
+
+    match while_unlocked(mirror, &mirrored_path, || append_tail(path, &mirrored_path)) {
+        Ok(Some(0)) => {
+            report::debug(format_args!("Modified[file][unchanged]: {:?}", path));
+            metrics::add("copies_skipped", 1);
+            true
+        }
+        Ok(Some(appended)) => {
+            println!("Appended[file]: {:?} (+{} bytes)", report::shown(path), appended);
+            metrics::add("appended_bytes", appended);
+            if let Err(error) = sync_file(&mirrored_path, mirror.options.fsync) {
+                report::error(ErrorKind::Fsync, &mirrored_path, format!("Failed to sync {:?}: {}", mirrored_path, error));
+            }
+            true
+        }
+        Ok(None) => truncate_in_mirror(mirror, path, &mirrored_path),
+        Err(error) => {
+            report::debug(format_args!("Append check failed for {:?}, copying in full: {}", path, error));
+            false
+        }
+    }
+}
+
+fn truncate_in_mirror(mirror: &Mirror, path: &Path, mirrored_path: &Path) -> bool {
+    let before = fs::metadata(mirrored_path).map_or(0, |metadata| metadata.len());
+    match while_unlocked(mirror, mirrored_path, || truncate_tail(path, mirrored_path)) {
+        Ok(Some(rewritten)) => {
+            let after = fs::metadata(mirrored_path).map_or(0, |metadata| metadata.len());
+            println!("Truncated[file]: {:?} (-{} bytes, {} rewritten)", report::shown(path), before.saturating_sub(after), rewritten);
+            metrics::add("truncated_files", 1);
+            metrics::add("bytes_copied", rewritten);
+            if let Err(error) = sync_file(mirrored_path, mirror.options.fsync) {
+                report::error(ErrorKind::Fsync, mirrored_path, format!("Failed to sync {:?}: {}", mirrored_path, error));
+            }
+            true
+        }
+        Ok(None) => false,
+        Err(error) => {
+            report::debug(format_args!("Truncate check failed for {:?}, copying in full: {}", path, error));
+            false
+        }
+    }
+}
+
+fn handle_event_data(mirror: &Mirror, path: &Path) {
+    upsert_file(mirror, path, "Modified[file]");
+}
+
+fn handle_event_create_dir(mirror: &Mirror, path: &Path) {
+    println!("Created[dir]: {:?}", report::shown(path));
+
+    let mirrored_path = match change_root(mirror, path) {
+        Some(path) => path,
+        None => return handle_not_under_watch_error(&mirror.watch_root, path),
+    };
+
+    match while_unflagged(mirror, &[&mirrored_path], || fs::create_dir(&mirrored_path)) {
+        Ok(()) => sync_parent(mirror, &mirrored_path),
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists && mirrored_path.is_symlink() && mirrored_path.is_dir() => {
+            note_dest_link(mirror, &mirrored_path)
+        }
+        Err(error) => handle_create_dir_error(&mirrored_path, &error),
+    }
+}
+
+fn handle_event_create(mirror: &Mirror, path: &Path) {
+    if path.is_symlink() {
+        handle_event_create_symlink(mirror, path);
+    } else if path.is_file() {
+        upsert_file(mirror, path, "Created[file]");
+    } else if path.is_dir() {
+        handle_event_create_dir(mirror, path);
+    } else if vanished(path) {
+        handle_vanished(path, "Created");
+    } else {
+        handle_event_create_other(mirror, path);
+    }
+}
+
+/// Whether `path` is a file modified outside `--newer-than`/`--older-than`.
+/// What's already mirrored of it is left alone.
+pub fn outside_age_window(mirror: &Mirror, path: &Path) -> bool {
+    if mirror.options.age.is_empty() {
+        return false;
+    }
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => {
+            metadata.modified().is_ok_and(|modified| !mirror.options.age.admits(modified))
+        }
+        _ => false,
+    }
+}
+
+pub fn apply_event(mirror: &Mirror, operation: &Operation) {
+    let renamed_away;
+    let mut operation = operation;
+    if mirror.options.require_utf8 {
+        let (Operation::Create { path }
+        | Operation::Data { path }
+        | Operation::Metadata { path }
+        | Operation::Delete { path }
+        | Operation::Rename { new_path: path, .. }) = operation;
+        if path.to_str().is_none() {
+            let source = mirror.watch_root.join(path);
+            report::error(ErrorKind::NonUtf8, &source, format!("Skipped non-UTF-8 path {:?}", source));
+            // Renamed out of what's mirrored, so its old mirror goes.
+            match operation {
+                Operation::Rename { path, .. } if path.to_str().is_some() => {
+                    renamed_away = Operation::Delete { path: path.clone() };
+                    operation = &renamed_away;
+                }
+                _ => return,
+            }
+        }
+    }
+    let Some(letters) = &mirror.dead_letters else {
+        apply(mirror, operation);
+        return;
+    };
+    if letters.holds(operation) {
+        return report::debug(format_args!("Skipped[dead letter]: {}", operation));
+    }
+
+    let Some(error) = apply(mirror, operation) else {
+        return letters.succeeded(operation);
+    };
+    match letters.failed(operation, &error) {
+        Some(delay) => {
+            report::debug(format_args!("{} failed, retrying in {:?}", operation, delay));
+            let (Operation::Create { path }
+            | Operation::Data { path }
+            | Operation::Metadata { path }
+            | Operation::Delete { path }
+            | Operation::Rename { path, .. }) = operation;
+            mirror.debounced.lock().unwrap().hold(path, operation.clone(), delay, mirror.clock.now());
+        }
+        None => {
+            eprintln!("Dead-lettered after repeated failures: {}", operation);
+        }
+    }
+}
+
+/// How many recently applied operations status shows.
+const RECENT_OPERATIONS: usize = 50;
+
+/// An operation applied to the mirror, and when.
+#[derive(Clone, Debug, Serialize)]
+pub struct Applied {
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub operation: Operation,
+}
+
+fn remember_applied(mirror: &Mirror, operation: &Operation) {
+    let mut recent = mirror.recent.lock().unwrap();
+    if recent.len() == RECENT_OPERATIONS {
+        recent.pop_front();
+    }
+    recent.push_back(Applied {
+        timestamp: report::unix_timestamp(),
+        operation: operation.clone(),
+    });
+}
+
+/// The operations applied most recently, newest first.
+pub fn recent_operations(mirror: &Mirror) -> Vec<Applied> {
+    mirror.recent.lock().unwrap().iter().rev().cloned().collect()
+}
+
+/// Applies what `--dead-letter-after` set aside again, returning how many
+/// operations it was. Any that still fail are retried and set aside anew.
+pub fn retry_dead_letters(mirror: &Mirror) -> usize {
+    let Some(letters) = &mirror.dead_letters else {
+        return 0;
+    };
+    let taken: Vec<DeadLetter> = letters.take();
+    for letter in &taken {
+        apply_event(mirror, &letter.operation);
+    }
+    taken.len()
+}
+
+/// Applies `operation` to the mirror, returning the error that failed it,
+/// if one did. Hooks only run for operations that succeeded.
+fn apply(mirror: &Mirror, operation: &Operation) -> Option<String> {
+    let source = |relative: &Path| mirror.watch_root.join(relative);
+    let conflicted = match operation {
+        Operation::Rename { path, new_path } => case_conflict(mirror, path) || case_conflict(mirror, new_path),
+        Operation::Create { path }
+        | Operation::Data { path }
+        | Operation::Metadata { path }
+        | Operation::Delete { path } => case_conflict(mirror, path),
+    };
+    if conflicted {
+        return None;
+    }
+    if let Operation::Create { path } | Operation::Data { path } | Operation::Metadata { path } = operation {
+        if outside_age_window(mirror, &source(path)) {
+            metrics::add("age_skipped", 1);
+            report::debug(format_args!("Skipped[age]: {:?}", source(path)));
+            return None;
+        }
+    }
+    metrics::add(operation.metric(), 1);
+    remember_applied(mirror, operation);
+
+    report::take_failure();
+    match (operation, &mirror.backend) {
+        (_, Some(backend)) => apply_remote(mirror, backend.as_ref(), operation),
+        (Operation::Create { path }, None) => handle_event_create(mirror, &source(path)),
+        (Operation::Data { path }, None) => handle_event_data(mirror, &source(path)),
+        (Operation::Metadata { path }, None) => handle_event_metadata(mirror, &source(path)),
+        (Operation::Delete { path }, None) => handle_event_delete(mirror, &source(path)),
+        (Operation::Rename { path, new_path }, None) => {
+            handle_event_rename(mirror, &source(path), &source(new_path))
+        }
+    }
+    let failure = report::take_failure();
+    if mirror.backend.is_none() {
+        match operation {
+            Operation::Rename { path, new_path } => {
+                record_self_write(mirror, path);
+                record_self_write(mirror, new_path);
+            }
+            Operation::Create { path }
+            | Operation::Data { path }
+            | Operation::Metadata { path }
+            | Operation::Delete { path } => record_self_write(mirror, path),
+        }
+    }
+
+    match operation {
+        Operation::Metadata { .. } => {}
+        Operation::Rename { path, new_path } => {
+            merkle_changed(mirror, path);
+            merkle_changed(mirror, new_path);
+        }
+        Operation::Create { path } | Operation::Data { path } | Operation::Delete { path } => {
+            merkle_changed(mirror, path)
+        }
+    }
+
+    if let (Some(hooks), None) = (&mirror.hooks, &failure) {
+        match operation {
+            Operation::Rename { new_path, .. } => hooks.changed(new_path),
+            Operation::Create { path }
+            | Operation::Data { path }
+            | Operation::Metadata { path }
+            | Operation::Delete { path } => hooks.changed(path),
+        }
+    }
+    failure
+}
+
+fn record_self_write(mirror: &Mirror, relative: &Path) {
+    if let (Some(writes), Some(mirrored)) = (&mirror.self_writes, mirrored_path(mirror, relative)) {
+        writes.record(&destination_path(mirror, &mirrored));
+    }
+}
+
+/// `apply_event` for a destination on another host. Paths are mirrored
+/// as-is, since what rewrites them (routes, compression, encryption) needs a
+/// local destination.
+fn apply_remote(mirror: &Mirror, backend: &dyn Backend, operation: &Operation) {
+    let source = |relative: &Path| mirror.watch_root.join(relative);
+    let (kind, path, result) = match operation {
+        Operation::Create { path } | Operation::Data { path } => {
+            let result = fs::symlink_metadata(source(path)).map_err(anyhow::Error::from).and_then(|metadata| {
+                if metadata.is_dir() {
+                    println!("Created[dir]: {:?}", report::shown(&source(path)));
+                    backend.mkdir(path)
+                } else if metadata.is_symlink() {
+                    if skips_dangling(mirror, &source(path)) {
+                        return Ok(());
+                    }
+                    println!("Created[symlink]: {:?}", report::shown(&source(path)));
+                    backend.symlink(path, &fs::read_link(source(path))?)
+                } else {
+                    println!("Uploaded: {:?}", report::shown(&source(path)));
+                    backend.write(path, &source(path), &metadata)
+                }
+            });
+            (ErrorKind::Copy, path, result)
+        }
+        Operation::Metadata { path } => {
+            println!("Modify[metadata]: {:?}", report::shown(&source(path)));
+            let result = fs::symlink_metadata(source(path))
+                .map_err(anyhow::Error::from)
+                .and_then(|metadata| match metadata.is_symlink() {
+                    true => Ok(()),
+                    false => backend.set_metadata(path, &metadata, &mirror.options.preserve),
+                });
+            (ErrorKind::Metadata, path, result)
+        }
+        Operation::Delete { path } => {
+            println!("Deleted: {:?}", report::shown(&source(path)));
+            (ErrorKind::Delete, path, backend.delete(path))
+        }
+        Operation::Rename { path, new_path } => {
+            println!("Renamed: {:?} -> {:?}", report::shown(&source(path)), report::shown(&source(new_path)));
+            (ErrorKind::Rename, path, backend.rename(path, new_path))
+        }
+    };
+    if let Err(error) = result {
+        report::error(kind, &source(path), format!("{:#}", error));
+    }
+}
+
+/// What `handle_event` does with an event.
+enum Handled {
+    Apply(Operation),
+    /// Half of a rename, waiting for the other.
+    Held,
+    Skipped,
+}
+
+impl fmt::Display for Handled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Handled::Apply(operation) => write!(f, "act: {}", operation),
+            Handled::Held => f.write_str("act: rename half held for its pair"),
+            Handled::Skipped => f.write_str("ignore"),
+        }
+    }
+}
+
+pub fn handle_event(mirror: &Mirror, event: &notify::Event) {
+    metrics::add("events_received", 1);
+    let handled = classify(mirror, event);
+    if let Some(trace) = mirror.trace.as_ref().filter(|trace| trace.matches(&mirror.watch_root, &event.paths)) {
+        trace.log(event, &handled);
+    }
+    if let Handled::Apply(operation) = handled {
+        record(mirror, &event.paths[0], operation);
+    }
+}
+
+/// The operation an event calls for, logging why when there's none.
+fn classify(mirror: &Mirror, event: &notify::Event) -> Handled {
+    let event_kind = &event.kind;
+    let paths = &event.paths;
+    let expected = match event_kind {
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => 2,
+        _ => 1,
+    };
+    if paths.len() != expected {
+        handle_event_malformed(event, expected);
+        return Handled::Skipped;
+    }
+    let path = &paths[0];
+
+    paths.iter().for_each(|path| mirror.listings.lock().unwrap().forget(path));
+    if let Some(vcs) = &mirror.vcs_ignore {
+        paths.iter().for_each(|path| vcs.changed(path));
+    }
+    if paths.iter().any(|path| is_ignored(mirror, path)) {
+        report::debug(format_args!("Ignored: {:?}", paths));
+        return Handled::Skipped;
+    }
+    if mirror.self_writes.as_ref().is_some_and(|writes| paths.iter().all(|path| writes.is_echo(path))) {
+        metrics::add("echoes_suppressed", 1);
+        report::debug(format_args!("Echo: {:?}", paths));
+        return Handled::Skipped;
+    }
+
+    let relative_path = match path.strip_prefix(&mirror.watch_root) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => {
+            handle_not_under_watch_error(&mirror.watch_root, path);
+            return Handled::Skipped;
+        }
+    };
+
+    let operation = match event_kind {
+        EventKind::Other => {
+            handle_event_other(mirror, path);
+            return Handled::Skipped;
+        }
+        EventKind::Remove(_) => Operation::Delete { path: relative_path },
+        EventKind::Modify(modify_kind) => match modify_kind {
+            ModifyKind::Other => {
+                handle_event_modify_other(mirror, path);
+                return Handled::Skipped;
+            }
+            ModifyKind::Name(RenameMode::Both) => {
+                if mirror.renames.lock().unwrap().already_paired(event.tracker()) {
+                    return Handled::Skipped;
+                }
+                let new_path = &paths[1];
+                match new_path.strip_prefix(&mirror.watch_root) {
+                    Ok(relative) => Operation::Rename {
+                        path: relative_path,
+                        new_path: relative.to_path_buf(),
+                    },
+                    Err(_) => {
+                        handle_not_under_watch_error(&mirror.watch_root, new_path);
+                        return Handled::Skipped;
+                    }
+                }
+            }
+            ModifyKind::Name(RenameMode::From) => {
+                let shape = change_root(mirror, path).and_then(|mirrored| rename_shape(&mirrored));
+                mirror.renames.lock().unwrap().moved_from(event.tracker(), relative_path, shape, mirror.clock.now());
+                return Handled::Held;
+            }
+            ModifyKind::Name(RenameMode::To) => {
+                let moved_from = mirror.renames.lock().unwrap().moved_to(event.tracker(), rename_shape(path), mirror.clock.now());
+                match moved_from {
+                    Some(from) => Operation::Rename {
+                        path: from,
+                        new_path: relative_path,
+                    },
+                    None => Operation::Create { path: relative_path },
+                }
+            }
+            ModifyKind::Metadata(_) if mirror.options.changes == Changes::Content => {
+                report::debug(format_args!("Modify[metadata][content only]: {:?}", path));
+                return Handled::Skipped;
+            }
+            ModifyKind::Data(_) if mirror.options.changes == Changes::Metadata && !lacks_contents(mirror, path) => {
+                report::debug(format_args!("Modify[data][metadata only]: {:?}", path));
+                return Handled::Skipped;
+            }
+            ModifyKind::Metadata(MetadataKind::Any) => Operation::Metadata { path: relative_path },
+            ModifyKind::Data(_) if mirror.options.sync_on_close => {
+                report::debug(format_args!("Modify[data][deferred until close]: {:?}", path));
+                return Handled::Skipped;
+            }
+            ModifyKind::Data(DataChange::Any) => Operation::Data { path: relative_path },
+            _ => {
+                report::debug(format_args!("Modify[ignored][{:?}]: {:?}", modify_kind, path));
+                return Handled::Skipped;
+            }
+        },
+        EventKind::Create(_) => Operation::Create { path: relative_path },
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) if mirror.options.sync_on_close => {
+            Operation::Data { path: relative_path }
+        }
+        EventKind::Access(access_kind) => {
+            report::debug(format_args!("Access[ignored][{:?}]: {:?}", access_kind, path));
+            return Handled::Skipped;
+        }
+        _ => {
+            handle_event_unknown(event, path);
+            return Handled::Skipped;
+        }
+    };
+
+    Handled::Apply(operation)
+}
+
+fn record(mirror: &Mirror, path: &Path, operation: Operation) {
+    if let Operation::Delete { path: relative } = &operation {
+        if is_unmounted(mirror, relative) {
+            return println!("Skipped delete[unmounted]: {:?}", path);
+        }
+        if !allow_delete(mirror, relative) {
+            return;
+        }
+    }
+
+    journal(mirror, path, &operation);
+    dispatch(mirror, operation);
+}
+
+/// Journals and dispatches a planned operation as a live event would be, so
+/// it waits out pauses, low space and the copy queue the same way.
+pub fn apply_planned(mirror: &Mirror, operation: Operation) {
+    let (Operation::Create { path }
+    | Operation::Data { path }
+    | Operation::Metadata { path }
+    | Operation::Delete { path }
+    | Operation::Rename { path, .. }) = &operation;
+    journal(mirror, &mirror.watch_root.join(path), &operation);
+    dispatch(mirror, operation);
+}
+
+fn journal(mirror: &Mirror, path: &Path, operation: &Operation) {
+    if let Some(journal) = &mirror.journal {
+        if let Err(error) = journal.append(operation) {
+            report::error(ErrorKind::Journal, path, format!("Failed to write journal: {:?}", error));
+        }
+    }
+}
+
+/// Applies `--max-deletes`/`--max-delete-percent` to a live delete. Once a
+/// burst goes over the limit it and every later delete are held until
+/// `confirm_deletes`.
+fn allow_delete(mirror: &Mirror, relative: &Path) -> bool {
+    let limit = &mirror.options.delete_limit;
+    if !limit.is_set() {
+        return true;
+    }
+
+    let verdict = mirror
+        .delete_guard
+        .lock()
+        .unwrap()
+        .check(limit, crate::reconcile::deleted_entries(mirror, relative), || {
+            crate::reconcile::mirrored_entries(mirror)
+        });
+    match verdict {
+        Verdict::Allow => true,
+        Verdict::Trip if limit.force => {
+            println!("Over the delete limit of {}, deleting anyway (--force): {:?}", limit, relative);
+            true
+        }
+        Verdict::Trip => {
+            report::error(
+                ErrorKind::Delete,
+                &mirror.output_root,
+                format!(
+                    "More than {} within {:?}, holding deletes until confirmed (rustsyncctl confirm-deletes)",
+                    limit, limit.window
+                ),
+            );
+            hold_deletes(mirror, [relative.to_path_buf()]);
+            false
+        }
+        Verdict::Hold => {
+            hold_deletes(mirror, [relative.to_path_buf()]);
+            false
+        }
+    }
+}
+
+/// Logs and keeps deletes refused by the delete limit.
+pub fn hold_deletes(mirror: &Mirror, relatives: impl IntoIterator<Item = PathBuf>) {
+    let mut guard = mirror.delete_guard.lock().unwrap();
+    for relative in relatives {
+        println!("Blocked delete: {:?}", relative);
+        metrics::add("blocked_deletes", 1);
+        guard.blocked.insert(relative);
+    }
+}
+
+pub fn blocked_deletes(mirror: &Mirror) -> usize {
+    mirror.delete_guard.lock().unwrap().blocked.len()
+}
+
+/// Carries out the deletes held by the delete limit, except those whose
+/// source has come back since, and starts counting deletes afresh.
+pub fn confirm_deletes(mirror: &Mirror) -> usize {
+    let blocked = mirror.delete_guard.lock().unwrap().release();
+    let mut deleted = 0;
+
+    for relative in blocked {
+        let path = mirror.watch_root.join(&relative);
+        if fs::symlink_metadata(&path).is_ok() {
+            continue;
+        }
+        let operation = Operation::Delete { path: relative };
+        journal(mirror, &path, &operation);
+        apply_event(mirror, &operation);
+        deleted += 1;
+    }
+
+    deleted
+}
+
+/// Stops deleting what vanished under the mount point `relative` when its
+/// filesystem was unmounted, until `remounted`.
+pub fn unmounted(mirror: &Mirror, relative: &Path) {
+    mirror.unmounted.lock().unwrap().insert(relative.to_path_buf());
+}
+
+pub fn remounted(mirror: &Mirror, relative: &Path) {
+    mirror.unmounted.lock().unwrap().remove(relative);
+}
+
+/// Whether `relative` is inside an unmounted mount point (the mount point
+/// itself is still a real directory).
+pub fn is_unmounted(mirror: &Mirror, relative: &Path) -> bool {
+    let unmounted = mirror.unmounted.lock().unwrap();
+    !unmounted.is_empty()
+        && relative
+            .ancestors()
+            .skip(1)
+            .any(|ancestor| unmounted.contains(ancestor))
+}
+
+fn rename_shape(path: &Path) -> Option<Shape> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    match metadata.is_dir() {
+        true => Some(Shape::Dir),
+        false => Some(Shape::File(metadata.len())),
+    }
+}
+
+/// Lets through whatever the holds between events have finished with:
+/// expired renames, coalesced directory metadata, settled transactions,
+/// delete batches and debounced or stable copies, then updates the Merkle
+/// root. Event loops call it after every event and timeout.
+pub fn flush_held(mirror: &Mirror) {
+    expire_renames(mirror);
+    flush_directory_metadata(mirror);
+    flush_transactions(mirror, false);
+    flush_deletes(mirror, false);
+    flush_debounced(mirror);
+    flush_stable(mirror);
+    flush_merkle(mirror);
+}
+
+/// Deletes the mirrors of paths whose rename `From` never got a `To`, i.e.
+/// that were moved out of the watch root.
+pub fn expire_renames(mirror: &Mirror) {
+    let expired = mirror.renames.lock().unwrap().expire(mirror.clock.now());
+    for relative in expired {
+        println!("Moved out: {:?}", relative);
+        let path = mirror.watch_root.join(&relative);
+        record(mirror, &path, Operation::Delete { path: relative });
+    }
+}
+
+fn has_room_for(mirror: &Mirror, operation: &Operation) -> bool {
+    let min_free_space = match &mirror.options.min_free_space {
+        Some(min_free_space) => min_free_space,
+        None => return true,
+    };
+
+    let relative = match operation {
+        Operation::Create { path } | Operation::Data { path } => path,
+        _ => return true,
+    };
+
+    let size = fs::symlink_metadata(mirror.watch_root.join(relative)).map(|metadata| metadata.len()).unwrap_or(0);
+    let output_root = match RelPath::new(relative) {
+        Some(relative) => output_root_for(mirror, &relative),
+        None => &mirror.output_root,
+    };
+
+    match disk_space(output_root) {
+        Ok(space) => {
+            metrics::set("free_space_bytes", space.available);
+            space.available.saturating_sub(size) >= min_free_space.bytes(space.total)
+        }
+        Err(error) => {
+            report::error(
+                ErrorKind::Metadata,
+                output_root,
+                format!("Failed to read free space for {:?}: {}", output_root, error),
+            );
+            true
+        }
+    }
+}
+
+/// Applies an operation, or queues it behind earlier ones while syncing is
+/// paused or the destination is short on space, so ordering is preserved.
+///
+/// Past `max_queue` operations the queue is dropped and a full reconcile
+/// runs once the queue can drain instead. With `--copy-order size` or
+/// `--priority`, file copies wait in the copy queue first.
+pub fn dispatch(mirror: &Mirror, operation: Operation) {
+    if let Some(group) = group_of(&mirror.options.transaction_globs, &operation) {
+        let mut transactions = mirror.transactions.lock().unwrap();
+        transactions.hold(group, operation, mirror.clock.now());
+        metrics::set("transaction_operations", transactions.len() as u64);
+        return;
+    }
+
+    // Copies waiting out a lock are held alongside debounced ones.
+    if (debounces(mirror) || mirror.options.locked == LockedFiles::Wait) && debounce(mirror, &operation) {
+        return;
+    }
+
+    if !mirror.options.delete_batch_window.is_zero() {
+        if let Operation::Delete { path } = &operation {
+            let mut deletes = mirror.deletes.lock().unwrap();
+            deletes.push(path.clone(), mirror.clock.now());
+            metrics::set("batched_deletes", deletes.len() as u64);
+            return;
+        }
+        // Anything else could depend on the deletes before it.
+        flush_deletes(mirror, true);
+    }
+
+    release(mirror, operation);
+}
+
+/// Applies the deletes batched by `delete_batch_window` once none has
+/// arrived for the window, or straight away with `all`. Deletes under
+/// another delete in the batch are dropped, since removing the directory
+/// removes them, and a batch of more than one logs a single summary.
+pub fn flush_deletes(mirror: &Mirror, all: bool) {
+    let (deletes, common, batched) = {
+        let mut batch = mirror.deletes.lock().unwrap();
+        if batch.is_empty() || !(all || batch.is_settled(mirror.clock.now())) {
+            return;
+        }
+        let batched = batch.len();
+        let (deletes, common) = batch.take();
+        metrics::set("batched_deletes", 0);
+        (deletes, common, batched)
+    };
+    metrics::add("deletes_coalesced", (batched - deletes.len()) as u64);
+
+    let quiet = batched > 1;
+    mirror.quiet_deletes.store(quiet, Ordering::SeqCst);
+    for path in deletes {
+        release(mirror, Operation::Delete { path });
+    }
+    mirror.quiet_deletes.store(false, Ordering::SeqCst);
+    if quiet {
+        println!("Removed {} files under {:?}", batched, report::shown(&mirror.watch_root.join(common)));
+    }
+}
+
+/// Passes an operation through `--stable-time` and directory metadata
+/// coalescing on its way to being scheduled.
+fn release(mirror: &Mirror, operation: Operation) {
+    if mirror.options.stable_time.is_some() && hold_until_stable(mirror, &operation) {
+        return;
+    }
+
+    if let Operation::Metadata { path } = &operation {
+        if mirror.watch_root.join(path).is_dir() {
+            mirror.directory_metadata.lock().unwrap().touch(path.clone(), mirror.clock.now());
+            return;
+        }
+    }
+
+    schedule(mirror, operation);
+}
+
+/// Queues or applies an operation that's through any holds.
+fn schedule(mirror: &Mirror, operation: Operation) {
+    if schedules_copies(mirror) {
+        match &operation {
+            Operation::Create { path } | Operation::Data { path } => {
+                if let Ok(metadata) = fs::symlink_metadata(mirror.watch_root.join(path)) {
+                    if metadata.is_file() {
+                        let priority = priority_of(&mirror.options.priorities, path);
+                        let mut copies = mirror.copies.lock().unwrap();
+                        copies.push(path, operation.clone(), priority, metadata.len(), mirror.options.copy_order);
+                        metrics::set("queued_copies", copies.len() as u64);
+                        return;
+                    }
+                }
+            }
+            Operation::Rename { path, new_path } => {
+                run_queued_under(mirror, path);
+                run_queued_under(mirror, new_path);
+            }
+            Operation::Metadata { path } | Operation::Delete { path } => run_queued_under(mirror, path),
+        }
+    }
+
+    apply_or_hold(mirror, operation);
+}
+
+fn debounces(mirror: &Mirror) -> bool {
+    !mirror.options.debounce.is_empty() || !mirror.options.debounce_default.is_zero()
+}
+
+/// With `--debounce`, holds copies of regular files, and the metadata changes
+/// that follow them, until `flush_debounced` finds the file quiet for its
+/// window. Deletes and renames treat what's held the way `hold_until_stable`
+/// does.
+fn debounce(mirror: &Mirror, operation: &Operation) -> bool {
+    let mut debounced = mirror.debounced.lock().unwrap();
+    let (held, released) = match operation {
+        Operation::Create { path } | Operation::Data { path } => {
+            let window = window_for(&mirror.options.debounce, mirror.options.debounce_default, path);
+            let is_file =
+                fs::symlink_metadata(mirror.watch_root.join(path)).is_ok_and(|metadata| metadata.is_file());
+            if is_file && !window.is_zero() {
+                debounced.hold(path, operation.clone(), window, mirror.clock.now());
+                (true, Vec::new())
+            } else {
+                (false, Vec::new())
+            }
+        }
+        Operation::Metadata { path } => (debounced.follow(path, operation, mirror.clock.now()), Vec::new()),
+        Operation::Delete { path } => {
+            debounced.take_under(path);
+            (false, Vec::new())
+        }
+        Operation::Rename { path, new_path } => {
+            let mut released = debounced.take_under(path);
+            released.extend(debounced.take_under(new_path));
+            (false, released)
+        }
+    };
+    metrics::set("debounced_files", debounced.len() as u64);
+    drop(debounced);
+
+    for operation in released {
+        release(mirror, operation);
+    }
+    held
+}
+
+/// Lets copies held by `--debounce` go ahead once their files go quiet.
+pub fn flush_debounced(mirror: &Mirror) {
+    let settled = {
+        let mut debounced = mirror.debounced.lock().unwrap();
+        if debounced.is_empty() {
+            return;
+        }
+        let settled = debounced.take_settled(mirror.clock.now());
+        metrics::set("debounced_files", debounced.len() as u64);
+        settled
+    };
+    for operation in settled {
+        release(mirror, operation);
+    }
+}
+
+/// With `--stable-time`, holds copies of regular files, and the metadata
+/// changes that follow them, until `flush_stable` finds the file settled.
+/// Held copies under a path that's deleted are dropped, and under one that's
+/// renamed run first, as queued copies do.
+fn hold_until_stable(mirror: &Mirror, operation: &Operation) -> bool {
+    let mut stability = mirror.stability.lock().unwrap();
+    let (held, released) = match operation {
+        Operation::Create { path } | Operation::Data { path } => {
+            let source = mirror.watch_root.join(path);
+            let is_file = fs::symlink_metadata(&source).is_ok_and(|metadata| metadata.is_file());
+            match signature(&source) {
+                Ok(signature) if is_file => {
+                    stability.hold(path, operation.clone(), signature, mirror.clock.now());
+                    (true, Vec::new())
+                }
+                _ => (false, Vec::new()),
+            }
+        }
+        Operation::Metadata { path } => (stability.follow(path, operation), Vec::new()),
+        Operation::Delete { path } => {
+            stability.take_under(path);
+            (false, Vec::new())
+        }
+        Operation::Rename { path, new_path } => {
+            let mut released = stability.take_under(path);
+            released.extend(stability.take_under(new_path));
+            (false, released)
+        }
+    };
+    metrics::set("unstable_files", stability.len() as u64);
+    drop(stability);
+
+    for operation in released {
+        schedule(mirror, operation);
+    }
+    held
+}
+
+/// Lets copies held by `--stable-time` go ahead once their files settle.
+pub fn flush_stable(mirror: &Mirror) {
+    let stable = {
+        let mut stability = mirror.stability.lock().unwrap();
+        if stability.is_empty() {
+            return;
+        }
+        let stable = stability.take_stable(&mirror.watch_root, mirror.clock.now());
+        metrics::set("unstable_files", stability.len() as u64);
+        stable
+    };
+    for operation in stable {
+        schedule(mirror, operation);
+    }
+}
+
+fn apply_or_hold(mirror: &Mirror, operation: Operation) {
+    let mut pending = mirror.pending.lock().unwrap();
+    let paused = mirror.paused.load(Ordering::SeqCst);
+
+    if !paused && pending.is_empty() && has_room_for(mirror, &operation) {
+        drop(pending);
+        return apply_event(mirror, &operation);
+    }
+
+    if mirror.overflowed.load(Ordering::SeqCst) {
+        return;
+    }
+
+    if pending.len() >= mirror.options.max_queue {
+        eprintln!("Queue limit of {} reached, a full resync will run instead", mirror.options.max_queue);
+        mirror.overflowed.store(true, Ordering::SeqCst);
+        pending.clear();
+        metrics::set("pending_operations", 0);
+        return;
+    }
+
+    if pending.is_empty() && !paused {
+        println!("Destination below --min-free-space, pausing copies");
+    }
+    pending.push_back(operation);
+    metrics::set("pending_operations", pending.len() as u64);
+}
+
+/// Whether file copies wait in the copy queue to be run in priority order
+/// (`--copy-order size`, `--priority`) rather than as their events arrive.
+fn schedules_copies(mirror: &Mirror) -> bool {
+    mirror.options.copy_order == CopyOrder::Size || !mirror.options.priorities.is_empty()
+}
+
+/// Runs waiting copies of `relative` and of anything under it, so other
+/// operations on those paths see them in the order the events arrived.
+fn run_queued_under(mirror: &Mirror, relative: &Path) {
+    let copies = mirror.copies.lock().unwrap().take_under(relative);
+    for operation in copies {
+        apply_or_hold(mirror, operation);
+    }
+}
+
+/// Runs the most urgent waiting copy. The event loop calls this once per
+/// turn, so copies keep moving under a steady stream of events and those
+/// queued behind a large one are reordered before they run. Returns false
+/// when the queue is empty.
+pub fn run_queued_copy(mirror: &Mirror) -> bool {
+    let (operation, left) = {
+        let mut copies = mirror.copies.lock().unwrap();
+        (copies.pop(), copies.len())
+    };
+    metrics::set("queued_copies", left as u64);
+
+    match operation {
+        Some(operation) => {
+            apply_or_hold(mirror, operation);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Between chunks of a copy of a `priority` file of `bytes`, runs the waiting
+/// copies that would have gone before it, then lets it carry on.
+fn give_way(mirror: &Mirror, priority: i32, bytes: u64) {
+    loop {
+        let (operation, left) = {
+            let mut copies = mirror.copies.lock().unwrap();
+            (copies.pop_ahead_of(priority, bytes, mirror.options.copy_order), copies.len())
+        };
+        let Some(operation) = operation else {
+            return;
+        };
+        metrics::set("queued_copies", left as u64);
+        report::keep_failure(|| apply_or_hold(mirror, operation));
+    }
+}
+
+/// Runs every waiting copy, so none is lost at shutdown.
+pub fn drain_queued_copies(mirror: &Mirror) {
+    while run_queued_copy(mirror) {}
+}
+
+pub fn has_queued_copies(mirror: &Mirror) -> bool {
+    !mirror.copies.lock().unwrap().is_empty()
+}
+
+pub fn queued_copies(mirror: &Mirror) -> QueueSummary {
+    mirror.copies.lock().unwrap().summary()
+}
+
+type DirectorySignature = (fs::Permissions, Option<SystemTime>, u32, u32);
+
+fn directory_signature(metadata: &fs::Metadata) -> DirectorySignature {
+    #[cfg(unix)]
+    let (uid, gid) = {
+        use std::os::unix::fs::MetadataExt;
+        (metadata.uid(), metadata.gid())
+    };
+
+    #[cfg(windows)]
+    let (uid, gid) = (0, 0);
+
+    (metadata.permissions(), metadata.modified().ok(), uid, gid)
+}
+
+/// Applies directory metadata events once a directory has been quiet for
+/// `directory_metadata_window`, so a bulk extract touching a parent thousands
+/// of times costs one update. Unchanged directories are skipped entirely.
+pub fn flush_directory_metadata(mirror: &Mirror) {
+    let settled = mirror.directory_metadata.lock().unwrap().take_settled(mirror.clock.now());
+
+    for relative in settled {
+        let path = mirror.watch_root.join(&relative);
+        let signature = match fs::metadata(&path) {
+            Ok(metadata) => directory_signature(&metadata),
+            Err(_) => continue,
+        };
+
+        let mut applied = mirror.applied_directory_metadata.lock().unwrap();
+        if applied.get(&relative) == Some(&signature) {
+            metrics::add("directory_metadata_skipped", 1);
+            continue;
+        }
+
+        match &mirror.backend {
+            Some(backend) => apply_remote(mirror, backend.as_ref(), &Operation::Metadata { path: relative.clone() }),
+            None => {
+                handle_event_metadata(mirror, &path);
+                record_self_write(mirror, &relative);
+            }
+        }
+        applied.insert(relative, signature);
+    }
+}
+
+/// Applies `--transaction-glob` groups that have gone quiet. Copies are
+/// staged beside their destinations first and then renamed into place one
+/// after another, so readers see a mix of old and new files only for as long
+/// as the renames take; the group's other operations follow in order. Copies
+/// that can't be staged (compressed, encrypted or transformed, or under a
+/// path the group deletes or renames) are applied in order with the rest.
+/// With `all`, as at shutdown, groups still settling are applied too.
+pub fn flush_transactions(mirror: &Mirror, all: bool) {
+    if is_paused(mirror) {
+        return;
+    }
+    let settled = {
+        let mut transactions = mirror.transactions.lock().unwrap();
+        match all {
+            true => transactions.take_all(),
+            false => transactions.take_settled(mirror.clock.now()),
+        }
+    };
+
+    for (group, operations) in settled {
+        println!(
+            "Transaction[{}]: applying {} operations",
+            mirror.options.transaction_globs[group],
+            operations.len()
+        );
+
+        let moved: Vec<&Path> = operations
+            .iter()
+            .flat_map(|operation| match operation {
+                Operation::Delete { path } => vec![path.as_path()],
+                Operation::Rename { path, new_path } => vec![path.as_path(), new_path.as_path()],
+                _ => Vec::new(),
+            })
+            .collect();
+        let mut staged: Vec<(&Operation, PathBuf, PathBuf)> = Vec::new();
+        let mut rest = Vec::new();
+        for operation in &operations {
+            let stageable = match operation {
+                Operation::Create { path } | Operation::Data { path } => {
+                    // A create and a write of the same file need one copy.
+                    if staged.iter().any(|(other, _, _)| same_path(other, path)) {
+                        continue;
+                    }
+                    !moved.iter().any(|moved| path.starts_with(moved))
+                }
+                _ => false,
+            };
+            match stageable.then(|| stage_copy(mirror, operation)).flatten() {
+                Some((temp, destination)) => staged.push((operation, temp, destination)),
+                None => rest.push(operation),
+            }
+        }
+
+        for (operation, temp, destination) in &staged {
+            if let Err(error) = fs::rename(temp, destination) {
+                let _ = fs::remove_file(temp);
+                report::error(
+                    ErrorKind::Copy,
+                    destination,
+                    format!("Failed to move {:?} into place: {}", destination, error),
+                );
+                continue;
+            }
+            println!("Transaction[copied]: {:?}", destination);
+            if let Err(error) = sync_file(destination, mirror.options.fsync) {
+                report::error(ErrorKind::Fsync, destination, format!("Failed to sync {:?}: {}", destination, error));
+            }
+            sync_parent(mirror, destination);
+            // The rest goes as any other apply does: the contents are already
+            // there, so it applies metadata, records the write and runs hooks.
+            apply_or_hold(mirror, (*operation).clone());
+        }
+        for operation in rest {
+            apply_or_hold(mirror, operation.clone());
+        }
+
+        metrics::add("transactions_applied", 1);
+        metrics::set("transaction_operations", mirror.transactions.lock().unwrap().len() as u64);
+    }
+}
+
+fn same_path(operation: &Operation, relative: &Path) -> bool {
+    matches!(operation, Operation::Create { path } | Operation::Data { path } if path == relative)
+}
+
+/// Copies a transaction's file to a temp file beside its destination,
+/// returning both, or None when it isn't a plain copy or the copy fails.
+/// Copies a direct apply might skip or treat specially (conflict checks,
+/// dead letters, non-UTF-8 names, case-insensitive destinations, the age
+/// window, free space) aren't staged, and are applied as usual instead.
+fn stage_copy(mirror: &Mirror, operation: &Operation) -> Option<(PathBuf, PathBuf)> {
+    let (Operation::Create { path: relative } | Operation::Data { path: relative }) = operation else {
+        return None;
+    };
+    if mirror.options.compress.is_some()
+        || mirror.options.encrypt.is_some()
+        || mirror.options.transforms.applies_to(relative)
+        || mirror.backend.is_some()
+        || mirror.conflicts.is_some()
+        || mirror.dead_letters.as_ref().is_some_and(|letters| letters.holds(operation))
+        || (mirror.options.require_utf8 && relative.to_str().is_none())
+        || !has_room_for(mirror, operation)
+    {
+        return None;
+    }
+    let output_root = match RelPath::new(relative) {
+        Some(relative) => output_root_for(mirror, &relative),
+        None => &mirror.output_root,
+    };
+    if is_case_insensitive_root(mirror, output_root) {
+        return None;
+    }
+
+    let path = mirror.watch_root.join(relative);
+    if !fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_file()) || outside_age_window(mirror, &path) {
+        return None;
+    }
+    let mirrored_path = change_root(mirror, &path)?;
+    if already_mirrored(mirror, &path, &mirrored_path) || ensure_parent(mirror, &mirrored_path).is_err() {
+        return None;
+    }
+
+    let mut temp = staging_path(&mirrored_path).into_os_string();
+    temp.push("-transaction");
+    let temp = PathBuf::from(temp);
+    match copy_file_masked(&path, &temp, mirror.options.reflink, setuid_mask(mirror)) {
+        Ok(()) => {
+            metrics::add("bytes_copied", fs::metadata(&temp).map_or(0, |metadata| metadata.len()));
+            Some((temp, mirrored_path))
+        }
+        Err(_) => {
+            let _ = fs::remove_file(&temp);
+            None
+        }
+    }
+}
+
+/// Brings the `--merkle` tree's leaves for `relative`, and anything under
+/// it, in line with what's now in the output root. Paths routed elsewhere
+/// aren't covered.
+pub fn merkle_changed(mirror: &Mirror, relative: &Path) {
+    let Some(merkle) = &mirror.merkle else {
+        return;
+    };
+    let Some(mirrored) = mirrored_path(mirror, relative) else {
+        return;
+    };
+    let destination = destination_path(mirror, &mirrored);
+    let (Ok(stored), Ok(plain)) = (
+        destination.strip_prefix(&mirror.output_root),
+        mirrored.strip_prefix(&mirror.output_root),
+    ) else {
+        return;
+    };
+
+    let mut tree = merkle.lock().unwrap();
+    tree.remove_under(plain);
+    tree.remove_under(stored);
+
+    let walker = WalkDir::new(&destination)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != CONTROL_DIR);
+    for entry in walker.filter_map(Result::ok).filter(|entry| entry.file_type().is_file()) {
+        let Ok(relative) = entry.path().strip_prefix(&mirror.output_root) else {
+            continue;
+        };
+        match hash_file(entry.path(), tree.algorithm) {
+            Ok(hash) => tree.insert(relative.to_path_buf(), &hash),
+            Err(error) => report::error(ErrorKind::Merkle, entry.path(), format!("{:#}", error)),
+        }
+    }
+}
+
+/// Stores the `--merkle` root if it changed since it was last stored.
+pub fn flush_merkle(mirror: &Mirror) {
+    let Some(merkle) = &mirror.merkle else {
+        return;
+    };
+    let mut tree = merkle.lock().unwrap();
+    if !tree.is_stale() {
+        return;
+    }
+
+    let algorithm = tree.algorithm;
+    let root = tree.root().to_string();
+    metrics::set("merkle_leaves", tree.len() as u64);
+    if let Err(error) = merkle::save_root(&mirror.output_root, algorithm, &root) {
+        report::error(ErrorKind::Merkle, &merkle::root_path(&mirror.output_root), format!("{:#}", error));
+    }
+    report::debug(format_args!("Merkle root: {}", root));
+}
+
+pub fn merkle_root(mirror: &Mirror) -> Option<String> {
+    mirror.merkle.as_ref().map(|merkle| merkle.lock().unwrap().root().to_string())
+}
+
+pub fn pause(mirror: &Mirror) {
+    mirror.paused.store(true, Ordering::SeqCst);
+}
+
+pub fn resume(mirror: &Mirror) {
+    mirror.paused.store(false, Ordering::SeqCst);
+}
+
+pub fn is_paused(mirror: &Mirror) -> bool {
+    mirror.paused.load(Ordering::SeqCst)
+}
+
+pub fn queue_depth(mirror: &Mirror) -> usize {
+    mirror.pending.lock().unwrap().len()
+}
+
+/// Drains operations queued by `dispatch` for as long as space allows.
+pub fn resume_pending(mirror: &Mirror) {
+    if is_paused(mirror) {
+        return;
+    }
+
+    if mirror.overflowed.swap(false, Ordering::SeqCst) {
+        println!("Resync after queue overflow: {}", crate::reconcile::reconcile(mirror));
+        return;
+    }
+
+    let mut pending = mirror.pending.lock().unwrap();
+
+    while let Some(operation) = pending.front() {
+        if !has_room_for(mirror, operation) {
+            break;
+        }
+
+        let operation = pending.pop_front().unwrap();
+        apply_event(mirror, &operation);
+
+        if pending.is_empty() {
+            println!("Queue drained, resuming copies");
+        }
+    }
+
+    metrics::set("pending_operations", pending.len() as u64);
+}
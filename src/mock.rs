@@ -0,0 +1,99 @@
+use notify::{Event, EventKind};
+use std::{
+    path::PathBuf,
+    sync::mpsc::Sender,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::mirror::{flush_held, handle_event, Mirror};
+
+/// How often `run` flushes held operations through a wait, as the main
+/// loop's receive timeout does.
+const TICK: Duration = Duration::from_millis(10);
+
+enum Step {
+    Event(Event),
+    Wait(Duration),
+}
+
+/// A scripted sequence of watcher events, for tests that need the event
+/// pipeline (debouncing, coalescing, rename pairing) without a live watcher
+/// and its timing. Paths are absolute, as a watcher reports them. Given a
+/// mirror with a manual `Clock`, `run` moves the clock through waits instead
+/// of sleeping.
+#[derive(Default)]
+pub struct MockEventSource {
+    steps: Vec<Step>,
+}
+
+impl MockEventSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an event of `kind` on `paths`.
+    pub fn event(self, kind: EventKind, paths: Vec<PathBuf>) -> Self {
+        let event = paths.into_iter().fold(Event::new(kind), Event::add_path);
+        self.raw(event)
+    }
+
+    /// Adds an event as it is, such as a rename half with a tracker.
+    pub fn raw(mut self, event: Event) -> Self {
+        self.steps.push(Step::Event(event));
+        self
+    }
+
+    /// Adds a pause of `duration` before the next event.
+    pub fn wait(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Wait(duration));
+        self
+    }
+
+    /// Sends the script to `sender` from a thread, as `watch::watch` does,
+    /// and hangs up at the end.
+    pub fn spawn(self, sender: Sender<notify::Result<Event>>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            for step in self.steps {
+                match step {
+                    Step::Event(event) => {
+                        if sender.send(Ok(event)).is_err() {
+                            return;
+                        }
+                    }
+                    Step::Wait(duration) => thread::sleep(duration),
+                }
+            }
+        })
+    }
+
+    /// Plays the script into `mirror` on this thread, doing what the main
+    /// loop does: each event is handled and followed by `flush_held`, which
+    /// also runs every few milliseconds through each wait.
+    pub fn run(self, mirror: &Mirror) {
+        for step in self.steps {
+            match step {
+                Step::Event(event) => handle_event(mirror, &event),
+                Step::Wait(duration) if mirror.clock.is_manual() => {
+                    let mut left = duration;
+                    while !left.is_zero() {
+                        mirror.clock.advance(left.min(TICK));
+                        left = left.saturating_sub(TICK);
+                        flush_held(mirror);
+                    }
+                }
+                Step::Wait(duration) => {
+                    let until = Instant::now() + duration;
+                    while let Some(left) = until.checked_duration_since(Instant::now()) {
+                        thread::sleep(left.min(TICK));
+                        flush_held(mirror);
+                        if left <= TICK {
+                            break;
+                        }
+                    }
+                }
+            }
+            flush_held(mirror);
+        }
+    }
+}
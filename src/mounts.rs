@@ -0,0 +1,149 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How often `--follow-new-mounts` looks for filesystems mounted or
+/// unmounted under the watch root. Reading Linux's mount table is cheap, and
+/// the sooner an unmount is seen the less chance a sync takes it for deletes;
+/// elsewhere every directory has to be stat'ed.
+#[cfg(target_os = "linux")]
+pub const MOUNT_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+#[cfg(not(target_os = "linux"))]
+pub const MOUNT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MountChange {
+    Mounted(PathBuf),
+    Unmounted(PathBuf),
+}
+
+/// Notices filesystems mounted on, or unmounted from, directories under a
+/// watch root. Native watches stop at the directory a filesystem is mounted
+/// over, so new mounts need watches of their own.
+pub struct MountWatcher {
+    root: PathBuf,
+    known: BTreeSet<PathBuf>,
+    next_check: Instant,
+}
+
+impl MountWatcher {
+    pub fn new(root: &Path) -> Self {
+        MountWatcher {
+            root: root.to_path_buf(),
+            known: mount_points(root),
+            next_check: Instant::now() + MOUNT_CHECK_INTERVAL,
+        }
+    }
+
+    /// Mounts and unmounts since the last check, once the check is due.
+    pub fn poll(&mut self) -> Vec<MountChange> {
+        if Instant::now() < self.next_check {
+            return Vec::new();
+        }
+        self.next_check = Instant::now() + MOUNT_CHECK_INTERVAL;
+
+        let current = mount_points(&self.root);
+        let mut changes: Vec<_> = self
+            .known
+            .difference(&current)
+            .map(|path| MountChange::Unmounted(path.clone()))
+            .collect();
+        changes.extend(current.difference(&self.known).map(|path| MountChange::Mounted(path.clone())));
+        self.known = current;
+        changes
+    }
+}
+
+/// Mount points strictly under `root`.
+#[cfg(target_os = "linux")]
+pub fn mount_points(root: &Path) -> BTreeSet<PathBuf> {
+    match std::fs::read("/proc/self/mountinfo") {
+        Ok(mountinfo) => parse_mountinfo(&mountinfo)
+            .into_iter()
+            .filter(|mount| mount != root && mount.starts_with(root))
+            .collect(),
+        Err(_) => scan_devices(root),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn mount_points(root: &Path) -> BTreeSet<PathBuf> {
+    scan_devices(root)
+}
+
+/// The mount point of the mount `path` is on, which tells bind mounts of the
+/// same filesystem apart where device numbers can't.
+#[cfg(target_os = "linux")]
+pub fn mount_of(path: &Path) -> Option<PathBuf> {
+    let path = std::fs::canonicalize(path).ok()?;
+    let mountinfo = std::fs::read("/proc/self/mountinfo").ok()?;
+    parse_mountinfo(&mountinfo)
+        .into_iter()
+        .filter(|mount| path.starts_with(mount))
+        .max_by_key(|mount| mount.components().count())
+}
+
+/// The mount point column (the fifth) of each line of `/proc/self/mountinfo`,
+/// where spaces, tabs, newlines and backslashes are octal escapes.
+#[cfg(target_os = "linux")]
+fn parse_mountinfo(mountinfo: &[u8]) -> Vec<PathBuf> {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+    let unescape = |field: &[u8]| {
+        let mut bytes = Vec::with_capacity(field.len());
+        let mut rest = field;
+        while let Some((&byte, tail)) = rest.split_first() {
+            let escaped = tail
+                .get(..3)
+                .filter(|digits| byte == b'\\' && digits.iter().all(|digit| (b'0'..=b'7').contains(digit)))
+                .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+            match escaped {
+                Some(decoded) => {
+                    bytes.push(decoded);
+                    rest = &tail[3..];
+                }
+                None => {
+                    bytes.push(byte);
+                    rest = tail;
+                }
+            }
+        }
+        PathBuf::from(OsString::from_vec(bytes))
+    };
+
+    mountinfo
+        .split(|byte| *byte == b'\n')
+        .filter_map(|line| line.split(|byte| *byte == b' ').nth(4))
+        .map(unescape)
+        .collect()
+}
+
+/// Directories under `root` on a different device from their parent, for
+/// platforms without a mount table to read.
+#[cfg(unix)]
+fn scan_devices(root: &Path) -> BTreeSet<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let device = |path: &Path| std::fs::metadata(path).map(|metadata| metadata.dev()).ok();
+    walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_dir())
+        .filter(|entry| {
+            let parent = entry.path().parent().and_then(device);
+            let own = entry.metadata().ok().map(|metadata| metadata.dev());
+            own.is_some() && parent.is_some() && own != parent
+        })
+        .map(|entry| entry.into_path())
+        .collect()
+}
+
+/// Windows has no device numbers to compare, so mounted folders aren't found.
+#[cfg(not(unix))]
+fn scan_devices(_root: &Path) -> BTreeSet<PathBuf> {
+    BTreeSet::new()
+}
@@ -0,0 +1,863 @@
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use libp2p::{
+    identity,
+    multiaddr::Protocol,
+    ping,
+    request_response::{self, ProtocolSupport},
+    swarm::{NetworkBehaviour, SwarmEvent},
+    Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder,
+};
+use clap::ValueEnum;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::{
+    hash::{hash_file, hash_stream, ChecksumAlgorithm},
+    keys::{sign_manifest, verify_manifest},
+    manifest::{Difference, Manifest, ManifestDelta, ManifestHistory, VersionedManifest},
+    metrics,
+    receipt::{wire_size, Direction, Receipt},
+    transfer::{read_chunk, Chunk, Download, Received},
+};
+
+const SYNC_PROTOCOL: &str = "/rustsync/sync/1";
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Request {
+    Manifest,
+    /// The manifest as changed since `version` of `epoch`; version 0 asks
+    /// for the whole thing.
+    ManifestSince { epoch: u64, version: u64 },
+    File {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+    },
+    /// Chunk `seq` of `path`, acknowledging everything before `offset`.
+    Chunk {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+        seq: u64,
+        offset: u64,
+    },
+    Push {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+        data: Vec<u8>,
+    },
+}
+
+/// A manifest (or delta) as sent between peers: its JSON, signed with the
+/// sender's key so that whoever relays it can't alter it or its hashes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedManifest {
+    pub payload: String,
+    #[serde(default)]
+    pub signature: Vec<u8>,
+}
+
+impl SignedManifest {
+    pub fn sign(keypair: &identity::Keypair, manifest: &impl Serialize) -> Result<Self> {
+        let payload = serde_json::to_string(manifest)?;
+        let signature = sign_manifest(keypair, payload.as_bytes())?;
+        Ok(SignedManifest { payload, signature })
+    }
+
+    /// The manifest, once it's checked to be signed by `peer_id`.
+    pub fn open<T: DeserializeOwned>(&self, peer_id: &PeerId) -> Result<T> {
+        verify_manifest(peer_id, self.payload.as_bytes(), &self.signature)?;
+        serde_json::from_str(&self.payload).with_context(|| format!("Malformed manifest from {}", peer_id))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Manifest(SignedManifest),
+    VersionedManifest(SignedManifest),
+    ManifestDelta(SignedManifest),
+    File {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+        data: Vec<u8>,
+    },
+    Chunk(Chunk),
+    Stored {
+        #[serde(with = "crate::pathbytes")]
+        path: PathBuf,
+    },
+    Error { message: String },
+}
+
+#[derive(NetworkBehaviour)]
+pub struct Behaviour {
+    ping: ping::Behaviour,
+    sync: request_response::json::Behaviour<Request, Response>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerState {
+    Connected,
+    Reconnecting,
+    Down,
+}
+
+impl fmt::Display for PeerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerState::Connected => f.write_str("connected"),
+            PeerState::Reconnecting => f.write_str("reconnecting"),
+            PeerState::Down => f.write_str("down"),
+        }
+    }
+}
+
+/// What a node does with its peers. Every role serves manifests and files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Role {
+    /// Pushes its files to peers and never accepts changes
+    Source,
+    /// Pulls from and accepts pushes from its sources
+    #[default]
+    Replica,
+    /// Compares itself against peers but never writes
+    Readonly,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Source => f.write_str("source"),
+            Role::Replica => f.write_str("replica"),
+            Role::Readonly => f.write_str("readonly"),
+        }
+    }
+}
+
+/// Capped exponential backoff between redials.
+pub struct Backoff {
+    initial: Duration,
+    cap: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, cap: Duration) -> Self {
+        Backoff {
+            initial,
+            cap,
+            current: initial,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.cap);
+        delay
+    }
+
+    pub fn is_capped(&self) -> bool {
+        self.current >= self.cap
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+struct Peer {
+    address: Multiaddr,
+    state: PeerState,
+    backoff: Backoff,
+    redial_at: Option<Instant>,
+}
+
+pub struct NodeConfig {
+    pub root: PathBuf,
+    pub algorithm: ChecksumAlgorithm,
+    pub listen: Vec<Multiaddr>,
+    pub dial: Vec<Multiaddr>,
+    pub keepalive: Duration,
+    pub max_backoff: Duration,
+    pub role: Role,
+    /// Peers a replica accepts changes from; empty means any peer.
+    pub source_peers: Vec<PeerId>,
+    /// Write a receipt of every peer session under `root`.
+    pub receipts: bool,
+    /// Print what each sync would transfer instead of transferring it.
+    pub dry_run: bool,
+}
+
+pub struct Node {
+    swarm: Swarm<Behaviour>,
+    /// Signs the manifests we send.
+    keypair: identity::Keypair,
+    config: NodeConfig,
+    peers: HashMap<PeerId, Peer>,
+    sessions: HashMap<PeerId, Receipt>,
+    /// Our manifest's versions, built on the first manifest request.
+    history: Option<ManifestHistory>,
+    /// The last manifest each peer sent, kept up to date with its deltas.
+    remotes: HashMap<PeerId, VersionedManifest>,
+    /// Files being fetched a chunk at a time.
+    downloads: HashMap<PathBuf, Download>,
+    /// The size and hash of each file pushed to a peer that hasn't yet said
+    /// it stored it, for its receipt.
+    pushes: HashMap<(PeerId, PathBuf), (u64, String)>,
+}
+
+pub fn peer_id_of(address: &Multiaddr) -> Option<PeerId> {
+    address.iter().find_map(|protocol| match protocol {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    })
+}
+
+fn safe_relative(path: &Path) -> bool {
+    path.components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// What syncing `sender`'s manifest to `receiver` would transfer, as printed
+/// by `--dry-run`.
+pub struct TransferPlan {
+    /// Files the receiver is missing or holds another version of, with their
+    /// sizes where the sender's side is local.
+    pub send: Vec<(PathBuf, Option<u64>)>,
+    /// Files only the receiver has. P2P sync leaves them in place.
+    pub extra: Vec<PathBuf>,
+}
+
+impl TransferPlan {
+    pub fn new(sender: &Manifest, receiver: &Manifest, size_of: impl Fn(&Path) -> Option<u64>) -> Result<Self> {
+        let mut plan = TransferPlan {
+            send: Vec::new(),
+            extra: Vec::new(),
+        };
+        for difference in sender.compare(receiver)? {
+            match difference {
+                Difference::Missing(path) | Difference::Mismatch(path) => {
+                    let size = size_of(&path);
+                    plan.send.push((path, size));
+                }
+                Difference::Extra(path) => plan.extra.push(path),
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Bytes of the files whose size is known.
+    pub fn known_bytes(&self) -> u64 {
+        self.send.iter().filter_map(|(_, size)| *size).sum()
+    }
+
+    fn print(&self, verb: &str, peer_id: PeerId) {
+        for (path, size) in &self.send {
+            match size {
+                Some(size) => println!("Would {} {:?} ({} bytes)", verb, path, size),
+                None => println!("Would {} {:?} (size unknown)", verb, path),
+            }
+        }
+        for path in &self.extra {
+            println!("Extra {:?} on the receiving side, left in place", path);
+        }
+        let unknown = self.send.iter().filter(|(_, size)| size.is_none()).count();
+        println!(
+            "Dry run {} with {}: {} files, {} bytes{}, {} extra",
+            verb,
+            peer_id,
+            self.send.len(),
+            self.known_bytes(),
+            match unknown {
+                0 => String::new(),
+                unknown => format!(" plus {} files of unknown size", unknown),
+            },
+            self.extra.len()
+        );
+    }
+}
+
+impl Node {
+    pub fn new(keypair: identity::Keypair, config: NodeConfig) -> Result<Self> {
+        let keepalive = config.keepalive;
+
+        let swarm = SwarmBuilder::with_existing_identity(keypair.clone())
+            .with_tokio()
+            .with_quic_config(|mut quic| {
+                quic.keep_alive_interval = keepalive;
+                quic.max_idle_timeout = (keepalive * 3).as_millis().min(u32::MAX as u128) as u32;
+                quic
+            })
+            .with_behaviour(|_| Behaviour {
+                ping: ping::Behaviour::new(ping::Config::new().with_interval(keepalive)),
+                sync: request_response::json::Behaviour::with_codec(
+                    request_response::json::codec::Codec::default()
+                        .set_response_size_maximum(MAX_RESPONSE_SIZE),
+                    [(StreamProtocol::new(SYNC_PROTOCOL), ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                ),
+            })?
+            .with_swarm_config(|swarm| swarm.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
+            .build();
+
+        let mut peers = HashMap::new();
+        for address in &config.dial {
+            let peer_id = peer_id_of(address)
+                .with_context(|| format!("Dial address {} has no /p2p/<peer id>", address))?;
+            peers.insert(
+                peer_id,
+                Peer {
+                    address: address.clone(),
+                    state: PeerState::Reconnecting,
+                    backoff: Backoff::new(Duration::from_secs(1), config.max_backoff),
+                    redial_at: Some(Instant::now()),
+                },
+            );
+        }
+
+        Ok(Node {
+            swarm,
+            keypair,
+            config,
+            peers,
+            sessions: HashMap::new(),
+            history: None,
+            remotes: HashMap::new(),
+            downloads: HashMap::new(),
+            pushes: HashMap::new(),
+        })
+    }
+
+    fn set_state(&mut self, peer_id: PeerId, state: PeerState) {
+        let peer = match self.peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        if peer.state != state {
+            println!("Peer {}: {} -> {}", peer_id, peer.state, state);
+            peer.state = state;
+        }
+
+        let count = |state| self.peers.values().filter(|peer| peer.state == state).count() as u64;
+        metrics::set("p2p_peers_connected", count(PeerState::Connected));
+        metrics::set("p2p_peers_reconnecting", count(PeerState::Reconnecting));
+        metrics::set("p2p_peers_down", count(PeerState::Down));
+    }
+
+    fn schedule_redial(&mut self, peer_id: PeerId) {
+        let peer = match self.peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let delay = peer.backoff.next_delay();
+        let state = match peer.backoff.is_capped() {
+            true => PeerState::Down,
+            false => PeerState::Reconnecting,
+        };
+        peer.redial_at = Some(Instant::now() + delay);
+        println!("Redialing {} in {:?}", peer_id, delay);
+        self.set_state(peer_id, state);
+    }
+
+    fn next_redial(&self) -> Option<Instant> {
+        self.peers.values().filter_map(|peer| peer.redial_at).min()
+    }
+
+    fn redial_due(&mut self) {
+        let now = Instant::now();
+        let due: Vec<(PeerId, Multiaddr)> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, peer)| peer.redial_at.is_some_and(|at| at <= now))
+            .map(|(peer_id, peer)| {
+                peer.redial_at = None;
+                (*peer_id, peer.address.clone())
+            })
+            .collect();
+
+        for (peer_id, address) in due {
+            if let Err(error) = self.swarm.dial(address.clone()) {
+                eprintln!("Failed to dial {}: {}", address, error);
+                self.schedule_redial(peer_id);
+            }
+        }
+    }
+
+    /// The receipt for the open session with `peer_id`, if receipts are on.
+    fn session(&mut self, peer_id: &PeerId) -> Option<&mut Receipt> {
+        self.sessions.get_mut(peer_id)
+    }
+
+    fn record_transfer(&mut self, peer_id: &PeerId, direction: Direction, path: &Path, data: &[u8]) {
+        let algorithm = self.config.algorithm;
+        if let Some(session) = self.session(peer_id) {
+            let hash = hash_stream(data, path, algorithm).unwrap_or_default();
+            session.transferred(direction, path, data.len() as u64, hash);
+        }
+    }
+
+    fn send(&mut self, peer_id: &PeerId, request: Request) {
+        if let Some(session) = self.sessions.get_mut(peer_id) {
+            session.bytes_sent += wire_size(&request);
+        }
+        self.swarm.behaviour_mut().sync.send_request(peer_id, request);
+    }
+
+    fn local_manifest(&self) -> Result<Manifest> {
+        Manifest::build(&self.config.root, self.config.algorithm)
+    }
+
+    /// Requests `peer_id`'s manifest as changed since the version we last
+    /// saw, or in full if we haven't seen one.
+    fn request_manifest(&mut self, peer_id: &PeerId) {
+        let (epoch, version) = self
+            .remotes
+            .get(peer_id)
+            .map_or((0, 0), |remote| (remote.epoch, remote.version));
+        self.send(peer_id, Request::ManifestSince { epoch, version });
+    }
+
+    /// Our manifest as changed since `version`, or in full when that's not
+    /// possible.
+    fn manifest_since(&mut self, epoch: u64, version: u64) -> Result<Response> {
+        let manifest = self.local_manifest()?;
+        let history = match &mut self.history {
+            Some(history) => {
+                history.update(manifest);
+                history
+            }
+            None => self.history.insert(ManifestHistory::new(manifest)?),
+        };
+
+        Ok(match history.since(epoch, version) {
+            Some(delta) => Response::ManifestDelta(SignedManifest::sign(&self.keypair, &delta)?),
+            None => Response::VersionedManifest(SignedManifest::sign(&self.keypair, history.current())?),
+        })
+    }
+
+    fn accepts_writes_from(&self, peer_id: &PeerId) -> bool {
+        self.config.role == Role::Replica
+            && (self.config.source_peers.is_empty() || self.config.source_peers.contains(peer_id))
+    }
+
+    fn write_file(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let target = self.config.root.join(path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}", parent))?;
+        }
+        fs::write(&target, data).with_context(|| format!("Failed to write {:?}", target))
+    }
+
+    fn answer(&mut self, peer_id: PeerId, request: Request) -> Response {
+        if let Some(session) = self.session(&peer_id) {
+            session.bytes_received += wire_size(&request);
+        }
+
+        let result = match request {
+            Request::Push { path, .. } if !self.accepts_writes_from(&peer_id) => {
+                eprintln!("Rejected push of {:?} from {}: not allowed for role {}", path, peer_id, self.config.role);
+                Err(anyhow::anyhow!("Role {} does not accept pushes from {}", self.config.role, peer_id))
+            }
+            Request::Push { path, data } if self.config.dry_run => {
+                println!("Dry run: not storing pushed {:?} ({} bytes) from {}", path, data.len(), peer_id);
+                Err(anyhow::anyhow!("Peer is running a dry run, {:?} not stored", path))
+            }
+            Request::Push { path, data } if safe_relative(&path) => self.write_file(&path, &data).map(|()| {
+                println!("Stored pushed {:?} ({} bytes) from {}", path, data.len(), peer_id);
+                self.record_transfer(&peer_id, Direction::Received, &path, &data);
+                Response::Stored { path }
+            }),
+            Request::Push { path, .. } => Err(anyhow::anyhow!("Refusing unsafe path {:?}", path)),
+            Request::Manifest => self
+                .local_manifest()
+                .and_then(|manifest| SignedManifest::sign(&self.keypair, &manifest))
+                .map(Response::Manifest),
+            Request::ManifestSince { epoch, version } => self.manifest_since(epoch, version),
+            Request::File { path } if safe_relative(&path) => fs::read(self.config.root.join(&path))
+                .map(|data| Response::File { path, data })
+                .map_err(Into::into),
+            Request::File { path } => Err(anyhow::anyhow!("Refusing unsafe path {:?}", path)),
+            Request::Chunk { path, seq, offset } if safe_relative(&path) => {
+                read_chunk(&self.config.root, &path, seq, offset).map(Response::Chunk)
+            }
+            Request::Chunk { path, .. } => Err(anyhow::anyhow!("Refusing unsafe path {:?}", path)),
+        };
+
+        let response = result.unwrap_or_else(|error| Response::Error {
+            message: format!("{:#}", error),
+        });
+        if let Response::File { path, data } = &response {
+            self.record_transfer(&peer_id, Direction::Sent, path, data);
+        }
+        if let Response::Chunk(chunk) = &response {
+            if chunk.is_last() {
+                let hash = hash_file(&self.config.root.join(&chunk.path), self.config.algorithm).unwrap_or_default();
+                if let Some(session) = self.session(&peer_id) {
+                    session.transferred(Direction::Sent, &chunk.path, chunk.size, hash);
+                }
+            }
+        }
+        if let Some(session) = self.session(&peer_id) {
+            session.bytes_sent += wire_size(&response);
+            if let Response::Error { message } = &response {
+                session.failed(format!("Answered with error: {}", message));
+            }
+        }
+        response
+    }
+
+    fn receive(&mut self, peer_id: PeerId, response: Response) {
+        if let Some(session) = self.session(&peer_id) {
+            session.bytes_received += wire_size(&response);
+        }
+
+        match response {
+            Response::Manifest(signed) => {
+                if let Some(remote) = self.open_manifest(peer_id, &signed) {
+                    self.resync(peer_id, remote);
+                }
+            }
+            Response::VersionedManifest(signed) => {
+                let Some(remote) = self.open_manifest::<VersionedManifest>(peer_id, &signed) else {
+                    return;
+                };
+                let manifest = remote.manifest.clone();
+                self.remotes.insert(peer_id, remote);
+                self.resync(peer_id, manifest);
+            }
+            Response::ManifestDelta(signed) => {
+                let Some(delta) = self.open_manifest::<ManifestDelta>(peer_id, &signed) else {
+                    return;
+                };
+                let applied = match self.remotes.get_mut(&peer_id) {
+                    Some(remote) => remote.apply(&delta).map(|()| remote.manifest.clone()),
+                    None => Err(anyhow::anyhow!("No manifest from {} to apply a delta to", peer_id)),
+                };
+                match applied {
+                    Ok(manifest) => {
+                        println!(
+                            "Manifest delta from {}: version {} to {}, {} entries",
+                            peer_id,
+                            delta.base,
+                            delta.version,
+                            delta.len()
+                        );
+                        self.resync(peer_id, manifest);
+                    }
+                    Err(error) => {
+                        eprintln!("{:#}, requesting the full manifest", error);
+                        self.remotes.remove(&peer_id);
+                        self.request_manifest(&peer_id);
+                    }
+                }
+            }
+            Response::File { path, .. } if !self.accepts_writes_from(&peer_id) => {
+                eprintln!("Rejected file {:?} from {}: not allowed for role {}", path, peer_id, self.config.role);
+            }
+            Response::File { path, data } => {
+                if !safe_relative(&path) {
+                    return eprintln!("Peer {} sent unsafe path {:?}", peer_id, path);
+                }
+                match self.write_file(&path, &data) {
+                    Ok(()) => {
+                        println!("Received {:?} ({} bytes) from {}", path, data.len(), peer_id);
+                        self.record_transfer(&peer_id, Direction::Received, &path, &data);
+                    }
+                    Err(error) => {
+                        eprintln!("{:#}", error);
+                        if let Some(session) = self.session(&peer_id) {
+                            session.failed(format!("{:#}", error));
+                        }
+                    }
+                }
+            }
+            Response::Chunk(chunk) if !self.accepts_writes_from(&peer_id) => {
+                eprintln!("Rejected file {:?} from {}: not allowed for role {}", chunk.path, peer_id, self.config.role);
+            }
+            Response::Chunk(chunk) => self.receive_chunk(peer_id, chunk),
+            Response::Stored { path } => {
+                println!("Peer {} stored {:?}", peer_id, path);
+                if let Some((size, hash)) = self.pushes.remove(&(peer_id, path.clone())) {
+                    if let Some(session) = self.session(&peer_id) {
+                        session.transferred(Direction::Sent, &path, size, hash);
+                    }
+                }
+            }
+            Response::Error { message } => {
+                eprintln!("Peer {} error: {}", peer_id, message);
+                if let Some(session) = self.session(&peer_id) {
+                    session.failed(format!("Peer error: {}", message));
+                }
+            }
+        }
+    }
+
+    /// A manifest from `peer_id`, unless it isn't signed by that peer, in
+    /// which case it's rejected before anything is applied.
+    fn open_manifest<T: DeserializeOwned>(&mut self, peer_id: PeerId, signed: &SignedManifest) -> Option<T> {
+        match signed.open(&peer_id) {
+            Ok(manifest) => Some(manifest),
+            Err(error) => {
+                eprintln!("Rejected manifest from {}: {:#}", peer_id, error);
+                metrics::add("p2p_manifests_rejected", 1);
+                if let Some(session) = self.session(&peer_id) {
+                    session.failed(format!("Rejected manifest: {:#}", error));
+                }
+                None
+            }
+        }
+    }
+
+    /// Writes a chunk of a download and asks for the next, or reports the
+    /// file once it's complete.
+    fn receive_chunk(&mut self, peer_id: PeerId, chunk: Chunk) {
+        let Some(download) = self.downloads.get_mut(&chunk.path) else {
+            return eprintln!("Peer {} sent a chunk of {:?}, which isn't being fetched", peer_id, chunk.path);
+        };
+
+        match download.receive(&chunk) {
+            Ok(Received::More { seq, offset }) => {
+                let path = chunk.path;
+                self.send(&peer_id, Request::Chunk { path, seq, offset });
+            }
+            Ok(Received::Done { size }) => {
+                let download = self.downloads.remove(&chunk.path).unwrap();
+                println!("Received {:?} ({} bytes) from {}", chunk.path, size, peer_id);
+                if let Some(session) = self.session(&peer_id) {
+                    session.transferred(Direction::Received, &chunk.path, size, download.hash().to_string());
+                }
+            }
+            Ok(Received::Ignored) => {}
+            Err(error) => {
+                self.downloads.remove(&chunk.path);
+                eprintln!("{:#}", error);
+                if let Some(session) = self.session(&peer_id) {
+                    session.failed(format!("{:#}", error));
+                }
+            }
+        }
+    }
+
+    /// Starts or resumes fetching `path` from `peer_id`, at the version
+    /// whose hash is `hash`.
+    fn fetch(&mut self, peer_id: PeerId, path: PathBuf, hash: &str) -> Result<()> {
+        if !safe_relative(&path) {
+            anyhow::bail!("Peer {} listed unsafe path {:?}", peer_id, path);
+        }
+        let download = Download::open(&self.config.root, &path, hash, self.config.algorithm)?;
+        if download.offset() > 0 {
+            println!("Resuming {:?} from {} bytes", path, download.offset());
+            metrics::add("p2p_bytes_resumed", download.offset());
+        }
+        let (seq, offset) = (download.seq(), download.offset());
+        self.downloads.insert(path.clone(), download);
+        self.send(&peer_id, Request::Chunk { path, seq, offset });
+        Ok(())
+    }
+
+    /// Acts on a peer's manifest according to our role: a replica requests
+    /// what it's missing or holds a different version of, a source pushes
+    /// what the peer is missing, and a readonly node only reports differences.
+    fn resync(&mut self, peer_id: PeerId, remote: Manifest) {
+        let local = match self.local_manifest() {
+            Ok(local) => local,
+            Err(error) => return eprintln!("Failed to build local manifest: {:#}", error),
+        };
+
+        match self.config.role {
+            Role::Replica if self.accepts_writes_from(&peer_id) => {}
+            Role::Replica => return eprintln!("Ignoring manifest from {}: not a source peer", peer_id),
+            Role::Source => return self.push(peer_id, &local, &remote),
+            Role::Readonly => return self.verify(peer_id, &local, &remote),
+        }
+
+        if self.config.dry_run {
+            // The peer's manifest has hashes but no sizes.
+            return match TransferPlan::new(&remote, &local, |_| None) {
+                Ok(plan) => plan.print("fetch", peer_id),
+                Err(error) => eprintln!("Cannot plan a resync with {}: {:#}", peer_id, error),
+            };
+        }
+
+        let differences = match remote.compare(&local) {
+            Ok(differences) => differences,
+            Err(error) => return eprintln!("Cannot resync with {}: {:#}", peer_id, error),
+        };
+
+        let mut requested = 0;
+        for difference in differences {
+            if let Difference::Missing(path) | Difference::Mismatch(path) = difference {
+                let hash = remote.entries.get(&path).cloned().unwrap_or_default();
+                match self.fetch(peer_id, path, &hash) {
+                    Ok(()) => requested += 1,
+                    Err(error) => eprintln!("{:#}", error),
+                }
+            }
+        }
+        println!("Resync with {}: requested {} files", peer_id, requested);
+    }
+
+    fn push(&mut self, peer_id: PeerId, local: &Manifest, remote: &Manifest) {
+        if self.config.dry_run {
+            let root = &self.config.root;
+            let size_of = |path: &Path| fs::metadata(root.join(path)).ok().map(|metadata| metadata.len());
+            return match TransferPlan::new(local, remote, size_of) {
+                Ok(plan) => plan.print("send", peer_id),
+                Err(error) => eprintln!("Cannot plan a push to {}: {:#}", peer_id, error),
+            };
+        }
+
+        let differences = match local.compare(remote) {
+            Ok(differences) => differences,
+            Err(error) => return eprintln!("Cannot push to {}: {:#}", peer_id, error),
+        };
+
+        let mut pushed = 0;
+        for difference in differences {
+            if let Difference::Missing(path) | Difference::Mismatch(path) = difference {
+                match fs::read(self.config.root.join(&path)) {
+                    Ok(data) => {
+                        let hash = hash_stream(&data[..], &path, self.config.algorithm).unwrap_or_default();
+                        self.pushes.insert((peer_id, path.clone()), (data.len() as u64, hash));
+                        self.send(&peer_id, Request::Push { path, data });
+                        pushed += 1;
+                    }
+                    Err(error) => eprintln!("Failed to read {:?}: {}", path, error),
+                }
+            }
+        }
+        println!("Push to {}: sent {} files", peer_id, pushed);
+    }
+
+    fn verify(&self, peer_id: PeerId, local: &Manifest, remote: &Manifest) {
+        let differences = match remote.compare(local) {
+            Ok(differences) => differences,
+            Err(error) => return eprintln!("Cannot verify against {}: {:#}", peer_id, error),
+        };
+
+        for difference in &differences {
+            match difference {
+                Difference::Missing(path) => println!("Verify {}: missing {:?}", peer_id, path),
+                Difference::Extra(path) => println!("Verify {}: extra {:?}", peer_id, path),
+                Difference::Mismatch(path) => println!("Verify {}: mismatch {:?}", peer_id, path),
+            }
+        }
+        println!("Verify against {}: {} differences", peer_id, differences.len());
+    }
+
+    fn handle_swarm_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                println!("Listening on {}/p2p/{}", address, self.swarm.local_peer_id())
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                println!("Connected to {}", peer_id);
+                if self.config.receipts {
+                    self.sessions.entry(peer_id).or_insert_with(|| Receipt::new(peer_id));
+                }
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.backoff.reset();
+                    peer.redial_at = None;
+                    self.set_state(peer_id, PeerState::Connected);
+                    self.request_manifest(&peer_id);
+                }
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, cause, .. } => {
+                println!("Disconnected from {}: {:?}", peer_id, cause);
+                self.pushes.retain(|(peer, _), _| *peer != peer_id);
+                if let Some(mut session) = self.sessions.remove(&peer_id) {
+                    if let Some(cause) = cause {
+                        session.failed(format!("Connection closed: {}", cause));
+                    }
+                    let _ = session.finish(&self.config.root);
+                }
+                self.schedule_redial(peer_id);
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } => {
+                eprintln!("Failed to connect to {}: {}", peer_id, error);
+                self.schedule_redial(peer_id);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Sync(request_response::Event::Message { peer, message, .. })) => {
+                match message {
+                    request_response::Message::Request { request, channel, .. } => {
+                        let response = self.answer(peer, request);
+                        if self.swarm.behaviour_mut().sync.send_response(channel, response).is_err() {
+                            eprintln!("Failed to respond to {}", peer);
+                        }
+                    }
+                    request_response::Message::Response { response, .. } => self.receive(peer, response),
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Sync(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                eprintln!("Request to {} failed: {}", peer, error);
+                if let Some(session) = self.session(&peer) {
+                    session.failed(format!("Request failed: {}", error));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes the receipts of sessions still open, waiting for them to land.
+    fn close_sessions(&mut self) {
+        let writers: Vec<_> = self
+            .sessions
+            .drain()
+            .map(|(_, session)| session.finish(&self.config.root))
+            .collect();
+        for writer in writers {
+            let _ = writer.join();
+        }
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        for address in self.config.listen.clone() {
+            self.swarm.listen_on(address)?;
+        }
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+
+        loop {
+            let redial_at = self.next_redial();
+
+            tokio::select! {
+                _ = &mut shutdown => {
+                    println!("Shutting down");
+                    self.close_sessions();
+                    return Ok(());
+                }
+                event = self.swarm.select_next_some() => self.handle_swarm_event(event),
+                _ = tokio::time::sleep_until(redial_at.unwrap_or_else(Instant::now)), if redial_at.is_some() => {
+                    self.redial_due();
+                }
+            }
+        }
+    }
+}
+
+/// Resolves on Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            },
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,131 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+const MARKER: char = '\0';
+
+#[cfg(unix)]
+fn to_bytes(name: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    name.as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes))
+}
+
+#[cfg(windows)]
+fn to_bytes(name: &OsStr) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+    name.encode_wide().flat_map(u16::to_le_bytes).collect()
+}
+
+#[cfg(windows)]
+fn from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::windows::ffi::OsStringExt;
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+    let wide: Vec<u16> = bytes.chunks(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+    Some(OsString::from_wide(&wide))
+}
+
+/// `path` as it's serialized, for paths that needn't be UTF-8: a UTF-8 path
+/// is its string, as serde writes a `PathBuf`, and any other is a NUL, which
+/// no path can contain, followed by its bytes in hex (UTF-16 code units on
+/// Windows). Fields use it with `#[serde(with = "pathbytes")]`, or with
+/// `pathbytes::option`, `pathbytes::map` or `pathbytes::vec`.
+pub fn encode(path: &Path) -> Cow<'_, str> {
+    match path.to_str() {
+        Some(text) => Cow::Borrowed(text),
+        None => {
+            let hex: String = to_bytes(path.as_os_str()).iter().map(|byte| format!("{:02x}", byte)).collect();
+            Cow::Owned(format!("{}{}", MARKER, hex))
+        }
+    }
+}
+
+/// The path `encode` gave `text`, or None if it isn't one it could give.
+pub fn decode(text: &str) -> Option<PathBuf> {
+    let Some(hex) = text.strip_prefix(MARKER) else {
+        return Some(PathBuf::from(text));
+    };
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(&hex[at..at + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    from_bytes(bytes).map(PathBuf::from)
+}
+
+fn decode_or_error<E: de::Error>(text: &str) -> Result<PathBuf, E> {
+    decode(text).ok_or_else(|| E::custom(format!("invalid encoded path {:?}", text)))
+}
+
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode(path))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    decode_or_error(&String::deserialize(deserializer)?)
+}
+
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+        match path {
+            Some(path) => serializer.serialize_some(&encode(path)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PathBuf>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|text| decode_or_error(&text))
+            .transpose()
+    }
+}
+
+pub mod map {
+    use super::*;
+
+    pub fn serialize<V: Serialize, S: Serializer>(
+        map: &BTreeMap<PathBuf, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(map.iter().map(|(path, value)| (encode(path), value)))
+    }
+
+    pub fn deserialize<'de, V: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<PathBuf, V>, D::Error> {
+        BTreeMap::<String, V>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(text, value)| Ok((decode_or_error(&text)?, value)))
+            .collect()
+    }
+}
+
+pub mod vec {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(paths.iter().map(|path| encode(path)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|text| decode_or_error(text))
+            .collect()
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use globset::{Glob, GlobMatcher};
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BinaryHeap, HashSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::mirror::Operation;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CopyOrder {
+    /// Copy files in the order their events arrive
+    #[default]
+    Arrival,
+    /// Copy the smallest waiting file first
+    Size,
+}
+
+/// `<glob>=<priority>`: waiting copies of matching paths, relative to the
+/// watch root, go before those with a lower priority (default 0).
+#[derive(Clone, Debug)]
+pub struct PriorityRule {
+    pub pattern: String,
+    pub priority: i32,
+    matcher: GlobMatcher,
+}
+
+impl FromStr for PriorityRule {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (pattern, priority) = spec
+            .rsplit_once('=')
+            .with_context(|| format!("Expected <glob>=<priority>, got {:?}", spec))?;
+        let priority = priority
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid priority {:?}", priority))?;
+        let matcher = Glob::new(pattern)
+            .with_context(|| format!("Invalid glob {:?}", pattern))?
+            .compile_matcher();
+
+        Ok(PriorityRule {
+            pattern: pattern.to_string(),
+            priority,
+            matcher,
+        })
+    }
+}
+
+/// The priority of the first rule matching `relative`, or 0.
+pub fn priority_of(rules: &[PriorityRule], relative: &Path) -> i32 {
+    rules
+        .iter()
+        .find(|rule| rule.matcher.is_match(relative))
+        .map_or(0, |rule| rule.priority)
+}
+
+struct Queued {
+    priority: i32,
+    /// Zero unless copies are ordered by size.
+    size: u64,
+    sequence: u64,
+    bytes: u64,
+    operation: Operation,
+}
+
+impl Queued {
+    fn key(&self) -> (i32, std::cmp::Reverse<u64>, std::cmp::Reverse<u64>) {
+        (self.priority, std::cmp::Reverse(self.size), std::cmp::Reverse(self.sequence))
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// What's waiting in a `CopyQueue`, for the control socket's `status`.
+#[derive(Debug, Default, Serialize)]
+pub struct QueueSummary {
+    pub files: usize,
+    pub bytes: u64,
+    pub by_priority: BTreeMap<i32, usize>,
+}
+
+/// File copies waiting to run, highest priority first, then (with
+/// `CopyOrder::Size`) smallest first, then in arrival order. A path is
+/// queued at most once.
+#[derive(Default)]
+pub struct CopyQueue {
+    heap: BinaryHeap<Queued>,
+    paths: HashSet<PathBuf>,
+    sequence: u64,
+}
+
+impl CopyQueue {
+    /// Queues a copy of `relative`; false if it was already waiting.
+    pub fn push(&mut self, relative: &Path, operation: Operation, priority: i32, bytes: u64, order: CopyOrder) -> bool {
+        if !self.paths.insert(relative.to_path_buf()) {
+            return false;
+        }
+
+        self.sequence += 1;
+        self.heap.push(Queued {
+            priority,
+            size: match order {
+                CopyOrder::Size => bytes,
+                CopyOrder::Arrival => 0,
+            },
+            sequence: self.sequence,
+            bytes,
+            operation,
+        });
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<Operation> {
+        let queued = self.heap.pop()?;
+        self.forget(&queued.operation);
+        Some(queued.operation)
+    }
+
+    /// Pops the most urgent copy if it would have gone before a copy of
+    /// `priority` and `bytes` queued now, regardless of arrival order.
+    pub fn pop_ahead_of(&mut self, priority: i32, bytes: u64, order: CopyOrder) -> Option<Operation> {
+        let top = self.heap.peek()?;
+        let ahead = match top.priority == priority {
+            true => order == CopyOrder::Size && top.bytes < bytes,
+            false => top.priority > priority,
+        };
+        match ahead {
+            true => self.pop(),
+            false => None,
+        }
+    }
+
+    /// Takes out, in arrival order, the copies of `relative` and of anything
+    /// under it.
+    pub fn take_under(&mut self, relative: &Path) -> Vec<Operation> {
+        if self.heap.is_empty() {
+            return Vec::new();
+        }
+
+        let mut taken = Vec::new();
+        self.heap.retain(|queued| match queued_path(&queued.operation).starts_with(relative) {
+            true => {
+                taken.push((queued.sequence, queued.operation.clone()));
+                false
+            }
+            false => true,
+        });
+        taken.sort_by_key(|(sequence, _)| *sequence);
+
+        let taken: Vec<_> = taken.into_iter().map(|(_, operation)| operation).collect();
+        for operation in &taken {
+            self.forget(operation);
+        }
+        taken
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    pub fn summary(&self) -> QueueSummary {
+        let mut summary = QueueSummary::default();
+        for queued in &self.heap {
+            summary.files += 1;
+            summary.bytes += queued.bytes;
+            *summary.by_priority.entry(queued.priority).or_insert(0) += 1;
+        }
+        summary
+    }
+
+    fn forget(&mut self, operation: &Operation) {
+        self.paths.remove(queued_path(operation));
+    }
+}
+
+fn queued_path(operation: &Operation) -> &Path {
+    match operation {
+        Operation::Create { path }
+        | Operation::Data { path }
+        | Operation::Metadata { path }
+        | Operation::Delete { path }
+        | Operation::Rename { path, .. } => path,
+    }
+}
@@ -0,0 +1,379 @@
+use anyhow::{Context, Result};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    copy::Reflink,
+    fsflags,
+    mirror::{Options, Preserve, CONTROL_DIR},
+    relpath::is_case_insensitive,
+    space::disk_space,
+};
+
+/// Owner the chown probe gives its scratch file: `nobody` on most systems.
+#[cfg(unix)]
+const PROBE_OWNER: u32 = 65534;
+
+/// A row of the capability matrix: whether a destination supports something
+/// and which option, if any, needs it.
+pub struct Capability {
+    pub name: &'static str,
+    pub needed_by: Option<&'static str>,
+    /// What was found, or why it's missing.
+    pub found: Result<String, String>,
+}
+
+impl Capability {
+    /// Missing and needed by the current options.
+    pub fn is_missing(&self) -> bool {
+        self.needed_by.is_some() && self.found.is_err()
+    }
+}
+
+/// A scratch directory in the destination's control directory, removed
+/// (along with the control directory, if this created it) when dropped.
+struct Scratch {
+    path: PathBuf,
+    control: Option<PathBuf>,
+}
+
+impl Scratch {
+    fn new(destination: &Path) -> io::Result<Self> {
+        let control = destination.join(CONTROL_DIR);
+        let created = !control.exists();
+        let path = control.join(format!("probe-{}", std::process::id()));
+        fs::create_dir_all(&path)?;
+        Ok(Scratch {
+            path,
+            control: created.then_some(control),
+        })
+    }
+
+    fn file(&self, name: &str) -> io::Result<PathBuf> {
+        let path = self.path.join(name);
+        fs::write(&path, b"rustsync probe")?;
+        Ok(path)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+        if let Some(control) = &self.control {
+            let _ = fs::remove_dir(control);
+        }
+    }
+}
+
+fn mebibytes(bytes: u64) -> String {
+    format!("{} MiB", bytes >> 20)
+}
+
+/// Tests `destination` for what a sync with `options` relies on, writing
+/// only scratch files under its control directory.
+pub fn probe(destination: &Path, options: &Options) -> Result<Vec<Capability>> {
+    let scratch = Scratch::new(destination).with_context(|| format!("Failed to write to {:?}", destination))?;
+    let preserves = |preserve| options.preserve.contains(&preserve);
+    let reason = |error: io::Error| error.to_string();
+
+    let mut capabilities = vec![
+        Capability {
+            name: "chown",
+            needed_by: preserves(Preserve::Owner).then_some("--preserve owner"),
+            found: scratch.file("chown").map_err(reason).and_then(|file| probe_chown(&file)),
+        },
+        Capability {
+            name: "xattr",
+            needed_by: preserves(Preserve::Xattrs).then_some("--preserve xattrs"),
+            found: scratch.file("xattr").map_err(reason).and_then(|file| probe_xattr(&file)),
+        },
+        Capability {
+            name: "acl",
+            needed_by: preserves(Preserve::Xattrs).then_some("--preserve xattrs"),
+            found: scratch.file("acl").map_err(reason).and_then(|file| probe_acl(&file)),
+        },
+        Capability {
+            name: "fcaps",
+            needed_by: None,
+            found: scratch.file("fcaps").map_err(reason).and_then(|file| probe_fcaps(&file)),
+        },
+        Capability {
+            name: "flags",
+            needed_by: options.preserve_flags.then_some("--preserve-flags"),
+            found: scratch.file("flags").map_err(reason).and_then(|file| probe_flags(&file)),
+        },
+        Capability {
+            name: "times",
+            needed_by: preserves(Preserve::Times).then_some("--preserve times"),
+            found: scratch.file("times").map_err(reason).and_then(|file| {
+                let time = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+                filetime::set_file_times(&file, time, time)
+                    .map(|()| "sets access and modification times".to_string())
+                    .map_err(reason)
+            }),
+        },
+        Capability {
+            name: "reflink",
+            needed_by: (options.reflink == Reflink::Always).then_some("--reflink always"),
+            found: scratch.file("reflink").map_err(reason).and_then(|file| {
+                reflink_copy::reflink(&file, file.with_extension("clone"))
+                    .map(|()| "clones extents".to_string())
+                    .map_err(reason)
+            }),
+        },
+        Capability {
+            name: "sparse",
+            needed_by: None,
+            found: probe_sparse(&scratch.path.join("sparse")),
+        },
+    ];
+    drop(scratch);
+
+    capabilities.push(Capability {
+        name: "case",
+        needed_by: None,
+        found: is_case_insensitive(destination)
+            .map(|insensitive| match insensitive {
+                true => "insensitive, checking for conflicts".to_string(),
+                false => "sensitive".to_string(),
+            })
+            .map_err(reason),
+    });
+    capabilities.push(Capability {
+        name: "free space",
+        needed_by: options.min_free_space.map(|_| "--min-free-space"),
+        found: disk_space(destination).map_err(reason).and_then(|space| {
+            let found = format!("{} of {}", mebibytes(space.available), mebibytes(space.total));
+            match options.min_free_space.map(|min| min.bytes(space.total)) {
+                Some(min) if space.available < min => Err(format!("{}, below {}", found, mebibytes(min))),
+                _ => Ok(found),
+            }
+        }),
+    });
+    Ok(capabilities)
+}
+
+#[cfg(unix)]
+fn probe_chown(file: &Path) -> Result<String, String> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt, os::unix::fs::MetadataExt};
+
+    let c_path = CString::new(file.as_os_str().as_bytes()).map_err(|error| error.to_string())?;
+    if unsafe { libc::chown(c_path.as_ptr(), PROBE_OWNER, PROBE_OWNER) } == 0 {
+        return match fs::metadata(file) {
+            Ok(metadata) if metadata.uid() == PROBE_OWNER => Ok("gives files away".to_string()),
+            Ok(_) => Err("owners are squashed or remapped".to_string()),
+            Err(error) => Err(error.to_string()),
+        };
+    }
+    let refused = io::Error::last_os_error();
+    if refused.raw_os_error() != Some(libc::EPERM) {
+        return Err(refused.to_string());
+    }
+
+    // Without CAP_CHOWN a user can still hand files to any group they're in,
+    // which is all preserving the owners of their own files takes.
+    let current = fs::metadata(file).map_err(|error| error.to_string())?.gid();
+    let group = own_groups().into_iter().find(|&group| group != current).unwrap_or(current);
+    if unsafe { libc::chown(c_path.as_ptr(), libc::geteuid(), group) } != 0 {
+        return Err(format!("{}; needs root or CAP_CHOWN", io::Error::last_os_error()));
+    }
+    match fs::metadata(file) {
+        Ok(metadata) if metadata.gid() == group => Ok("gives files to the user's own groups".to_string()),
+        Ok(_) => Err("owners are squashed or remapped".to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// The effective and supplementary groups of this process.
+#[cfg(unix)]
+fn own_groups() -> Vec<u32> {
+    let mut groups = vec![unsafe { libc::getegid() }];
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if count > 0 {
+        let mut supplementary = vec![0; count as usize];
+        let count = unsafe { libc::getgroups(count, supplementary.as_mut_ptr()) };
+        supplementary.truncate(count.max(0) as usize);
+        groups.extend(supplementary);
+    }
+    groups
+}
+
+#[cfg(windows)]
+fn probe_chown(_file: &Path) -> Result<String, String> {
+    Err("not supported on Windows".to_string())
+}
+
+#[cfg(unix)]
+fn probe_xattr(file: &Path) -> Result<String, String> {
+    xattr::set(file, "user.rustsync.probe", b"1").map_err(|error| error.to_string())?;
+    match xattr::get(file, "user.rustsync.probe") {
+        Ok(Some(value)) if value == b"1" => Ok("user namespace".to_string()),
+        Ok(_) => Err("attributes aren't kept".to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+#[cfg(windows)]
+fn probe_xattr(_file: &Path) -> Result<String, String> {
+    Err("not supported on Windows".to_string())
+}
+
+/// POSIX ACLs travel as `system.posix_acl_*` xattrs. Sets the minimal ACL,
+/// which only restates the mode: owner rw, group r, other r.
+#[cfg(unix)]
+fn probe_acl(file: &Path) -> Result<String, String> {
+    const VERSION: u32 = 2;
+    const UNDEFINED_ID: u32 = u32::MAX;
+    let entries: [(u16, u16); 3] = [(0x01, 6), (0x04, 4), (0x20, 4)];
+
+    let mut acl = VERSION.to_le_bytes().to_vec();
+    for (tag, permissions) in entries {
+        acl.extend_from_slice(&tag.to_le_bytes());
+        acl.extend_from_slice(&permissions.to_le_bytes());
+        acl.extend_from_slice(&UNDEFINED_ID.to_le_bytes());
+    }
+    xattr::set(file, "system.posix_acl_access", &acl)
+        .map(|()| "POSIX ACLs".to_string())
+        .map_err(|error| error.to_string())
+}
+
+#[cfg(windows)]
+fn probe_acl(_file: &Path) -> Result<String, String> {
+    Err("not probed on Windows".to_string())
+}
+
+/// File capabilities are the `security.capability` xattr, which only
+/// CAP_SETFCAP may set. Sets an empty version 2 set: no capabilities.
+#[cfg(unix)]
+fn probe_fcaps(file: &Path) -> Result<String, String> {
+    const VERSION_2: u32 = 0x0200_0000;
+
+    let mut capabilities = VERSION_2.to_le_bytes().to_vec();
+    capabilities.extend_from_slice(&[0; 16]);
+    xattr::set(file, "security.capability", &capabilities)
+        .map(|()| "file capabilities".to_string())
+        .map_err(|error| format!("{}; needs root or CAP_SETFCAP", error))
+}
+
+#[cfg(windows)]
+fn probe_fcaps(_file: &Path) -> Result<String, String> {
+    Err("not supported on Windows".to_string())
+}
+
+fn probe_flags(file: &Path) -> Result<String, String> {
+    let flags = fsflags::get(file).map_err(|error| error.to_string())?;
+    fsflags::set(file, flags | fsflags::IMMUTABLE)
+        .map_err(|error| format!("{}; needs root or CAP_LINUX_IMMUTABLE", error))?;
+    // Put back, or the scratch directory couldn't be removed.
+    fsflags::set(file, flags).map_err(|error| error.to_string())?;
+    Ok("immutable and append-only".to_string())
+}
+
+/// Whether a file that's mostly a hole takes less space than its length.
+#[cfg(unix)]
+fn probe_sparse(path: &Path) -> Result<String, String> {
+    use std::{
+        io::{Seek, SeekFrom, Write},
+        os::unix::fs::MetadataExt,
+    };
+
+    const LEN: u64 = 8 << 20;
+    let sparse = || -> io::Result<bool> {
+        let mut file = fs::File::create(path)?;
+        file.seek(SeekFrom::Start(LEN - 1))?;
+        file.write_all(b"\0")?;
+        file.sync_all()?;
+        Ok(fs::metadata(path)?.blocks() * 512 < LEN)
+    };
+    match sparse() {
+        Ok(true) => Ok("keeps holes".to_string()),
+        Ok(false) => Err("allocates holes".to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+#[cfg(windows)]
+fn probe_sparse(_path: &Path) -> Result<String, String> {
+    Err("not probed on Windows".to_string())
+}
+
+pub fn print_matrix(destination: &Path, capabilities: &[Capability]) {
+    println!("Destination {:?} capabilities:", destination);
+    for capability in capabilities {
+        let (supported, detail) = match &capability.found {
+            Ok(detail) => ("yes", detail),
+            Err(reason) => ("no", reason),
+        };
+        let needed = capability.needed_by.map_or("-".to_string(), |option| format!("needed by {}", option));
+        println!("  {:<10} {:<3} {:<28} {}", capability.name, supported, needed, detail);
+    }
+}
+
+/// Probes every destination and prints its matrix. Where something the
+/// options need is missing, fails with `strict`, and otherwise warns and
+/// turns off what needs it where that's possible.
+pub fn preflight(destinations: &[PathBuf], options: &mut Options, strict: bool) -> Result<()> {
+    let mut missing = Vec::new();
+    for destination in destinations {
+        let capabilities = probe(destination, options)?;
+        print_matrix(destination, &capabilities);
+        for capability in capabilities.into_iter().filter(Capability::is_missing) {
+            missing.push((destination.clone(), capability));
+        }
+    }
+
+    // --require-times makes missing times as fatal as --strict would.
+    let fatal: Vec<_> = missing
+        .iter()
+        .filter(|(_, capability)| strict || (capability.name == "times" && options.require_times))
+        .collect();
+    if !fatal.is_empty() {
+        let names: Vec<String> = fatal
+            .iter()
+            .map(|(destination, capability)| format!("{} on {:?}", capability.name, destination))
+            .collect();
+        let flag = match strict {
+            true => "--strict",
+            false => "--require-times",
+        };
+        anyhow::bail!("Destination lacks what the options need ({}): {}", flag, names.join(", "));
+    }
+    for (destination, capability) in &missing {
+        let downgrade = match capability.name {
+            "chown" => {
+                options.preserve.retain(|preserve| *preserve != Preserve::Owner);
+                "not preserving owners"
+            }
+            "xattr" => {
+                options.preserve.retain(|preserve| *preserve != Preserve::Xattrs);
+                "not preserving xattrs"
+            }
+            "reflink" => {
+                options.reflink = Reflink::Auto;
+                "falling back to byte copies"
+            }
+            "times" => {
+                options.preserve.retain(|preserve| *preserve != Preserve::Times);
+                "not syncing times"
+            }
+            "flags" => {
+                options.preserve_flags = false;
+                "not preserving flags"
+            }
+            "acl" => "copying ACL xattrs will fail",
+            // Free space, the only other capability an option needs.
+            _ => "copies will pause until there's room",
+        };
+        eprintln!(
+            "Warning: {:?} lacks {} ({}), needed by {}: {}",
+            destination,
+            capability.name,
+            capability.found.as_ref().err().map_or("", String::as_str),
+            capability.needed_by.unwrap_or_default(),
+            downgrade
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use serde::Serialize;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::mirror::CONTROL_DIR;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Transfer {
+    pub direction: Direction,
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: String,
+}
+
+/// What was transferred to and from one peer over one connection, written
+/// to `<root>/.rustsync/receipts/<started_ms>-<peer id>.json` when it closes.
+#[derive(Clone, Debug, Serialize)]
+pub struct Receipt {
+    pub peer: String,
+    pub started_ms: u64,
+    pub ended_ms: Option<u64>,
+    pub files: Vec<Transfer>,
+    /// Encoded size of every request and response, in each direction.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub success: bool,
+    pub errors: Vec<String>,
+}
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn receipts_dir(root: &Path) -> PathBuf {
+    root.join(CONTROL_DIR).join("receipts")
+}
+
+impl Receipt {
+    pub fn new(peer: impl ToString) -> Self {
+        Receipt {
+            peer: peer.to_string(),
+            started_ms: unix_millis(),
+            ended_ms: None,
+            files: Vec::new(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            success: true,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn transferred(&mut self, direction: Direction, path: &Path, size: u64, hash: String) {
+        self.files.push(Transfer {
+            direction,
+            path: path.to_path_buf(),
+            size,
+            hash,
+        });
+    }
+
+    pub fn failed(&mut self, error: impl ToString) {
+        self.success = false;
+        self.errors.push(error.to_string());
+    }
+
+    /// Closes the receipt and writes it on a thread of its own, so a slow
+    /// disk never holds up transfers.
+    pub fn finish(mut self, root: &Path) -> thread::JoinHandle<()> {
+        self.ended_ms = Some(unix_millis());
+        let dir = receipts_dir(root);
+
+        thread::spawn(move || {
+            let path = dir.join(format!("{}-{}.json", self.started_ms, self.peer));
+            let result = fs::create_dir_all(&dir).and_then(|()| {
+                let json = serde_json::to_vec_pretty(&self).map_err(io::Error::other)?;
+                fs::write(&path, json)
+            });
+            if let Err(error) = result {
+                eprintln!("Failed to write receipt {:?}: {}", path, error);
+            }
+        })
+    }
+}
+
+struct Counter(u64);
+
+impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Bytes `message` takes on the wire with the JSON codec, without buffering it.
+pub fn wire_size(message: &impl Serialize) -> u64 {
+    let mut counter = Counter(0);
+    let _ = serde_json::to_writer(&mut counter, message);
+    counter.0
+}
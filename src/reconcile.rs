@@ -0,0 +1,489 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use walkdir::WalkDir;
+
+use crate::{
+    copy::{same_contents, staging_path},
+    metrics,
+    mirror::{
+        apply_event, apply_metadata, destination_path, hold_deletes, is_ignored, is_unmounted, merkle_changed, metadata_differences, mirrored_path, note_dest_link,
+        mirrored_permissions, output_roots, stored_size, watched_relative, Changes, Mirror, Operation, Preserve, CONTROL_DIR,
+    },
+    report::{self, ErrorKind},
+};
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub files_copied: u64,
+    pub bytes_copied: u64,
+    pub created: u64,
+    pub deleted: u64,
+    pub metadata_updated: u64,
+    pub elapsed: Duration,
+}
+
+impl Summary {
+    pub fn changes(&self) -> u64 {
+        self.files_copied + self.created + self.deleted + self.metadata_updated
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files copied ({} bytes), {} created, {} deleted, {} metadata updates in {:.1?}",
+            self.files_copied, self.bytes_copied, self.created, self.deleted, self.metadata_updated, self.elapsed
+        )
+    }
+}
+
+fn needs_copy(mirror: &Mirror, source: &fs::Metadata, destination: &fs::Metadata, destination_len: u64) -> bool {
+    if source.len() != destination_len || !destination.is_file() {
+        return true;
+    }
+
+    match (source.modified(), destination.modified()) {
+        (Ok(source), Ok(destination)) if mirror.options.preserve.contains(&Preserve::Times) => {
+            source != destination
+        }
+        (Ok(source), Ok(destination)) => source > destination,
+        _ => true,
+    }
+}
+
+fn needs_metadata(mirror: &Mirror, source: &fs::Metadata, destination: &fs::Metadata) -> bool {
+    mirrored_permissions(mirror, source) != destination.permissions()
+}
+
+/// Brings the output root in line with the watch root by walking both trees
+/// and feeding the differences through the same operations live events use.
+pub fn reconcile(mirror: &Mirror) -> Summary {
+    reconcile_under(mirror, Path::new(""))
+}
+
+/// `reconcile`, for the subtree at `relative` only.
+pub fn reconcile_under(mirror: &Mirror, relative: &Path) -> Summary {
+    let started = Instant::now();
+    let (mut operations, mut summary) = plan_under(mirror, relative);
+    limit_deletes(mirror, &mut operations, &mut summary);
+
+    let mut linked = mirror.options.preserve_hardlinks.then(HashMap::new);
+    for operation in &operations {
+        if let (Some(linked), Operation::Data { path }) = (&mut linked, operation) {
+            if link_copy(mirror, linked, path) {
+                continue;
+            }
+        }
+        apply_event(mirror, operation);
+    }
+
+    summary.elapsed = started.elapsed();
+    summary
+}
+
+/// Where this sync mirrored each file that has other hard links in the
+/// source, by device and inode (`--preserve-hardlinks-within-batch`).
+type Linked = HashMap<(u64, u64), PathBuf>;
+
+/// The device and inode of a file that has other hard links.
+#[cfg(unix)]
+fn link_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+/// Windows only counts a file's links through an open handle, so links
+/// aren't found there and each one is copied.
+#[cfg(not(unix))]
+fn link_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Mirrors a copy as a hard link to the mirrored copy of a file it's linked
+/// to in the source. False when it needs copying after all: it has no other
+/// links, it's the first of them this sync, or linking failed (the two are
+/// routed to different filesystems, say). Ignored links never get here, so
+/// the first one not ignored is the one copied.
+fn link_copy(mirror: &Mirror, linked: &mut Linked, relative: &Path) -> bool {
+    let id = match fs::symlink_metadata(mirror.watch_root.join(relative)) {
+        Ok(metadata) if metadata.is_file() => link_id(&metadata),
+        _ => None,
+    };
+    let Some(id) = id else {
+        return false;
+    };
+    let Some(destination) = mirrored_path(mirror, relative).map(|mirrored| destination_path(mirror, &mirrored)) else {
+        return false;
+    };
+    let first = match linked.get(&id) {
+        Some(first) => first,
+        None => {
+            linked.insert(id, destination);
+            return false;
+        }
+    };
+
+    let mut temp = staging_path(&destination).into_os_string();
+    temp.push("-link");
+    let temp = PathBuf::from(temp);
+    let _ = fs::remove_file(&temp);
+    match fs::hard_link(first, &temp).and_then(|()| fs::rename(&temp, &destination)) {
+        Ok(()) => {
+            println!("Linked[hardlink]: {:?} -> {:?}", destination, first);
+            metrics::add("hardlinks_preserved", 1);
+            merkle_changed(mirror, relative);
+            true
+        }
+        Err(_) => {
+            let _ = fs::remove_file(&temp);
+            false
+        }
+    }
+}
+
+/// Holds back every delete of a sync that would delete more than
+/// `--max-deletes`/`--max-delete-percent` allows, rather than just the excess.
+pub fn limit_deletes(mirror: &Mirror, operations: &mut Vec<Operation>, summary: &mut Summary) {
+    let limit = &mirror.options.delete_limit;
+    if !limit.is_set() || summary.deleted == 0 {
+        return;
+    }
+
+    let deletes = operations
+        .iter()
+        .map(|operation| match operation {
+            Operation::Delete { path } => deleted_entries(mirror, path),
+            _ => 0,
+        })
+        .sum();
+
+    let total = match limit.max_percent {
+        Some(_) => mirrored_entries(mirror),
+        None => 0,
+    };
+    if !limit.exceeded(deletes, total) {
+        return;
+    }
+
+    if limit.force {
+        println!("Sync deletes {} entries, over the limit of {}, deleting anyway (--force)", deletes, limit);
+        return;
+    }
+
+    report::error(
+        ErrorKind::Delete,
+        &mirror.output_root,
+        format!(
+            "Sync would delete {} entries, over the limit of {}, holding them until confirmed (rustsyncctl confirm-deletes)",
+            deletes, limit
+        ),
+    );
+    let mut held = Vec::new();
+    operations.retain(|operation| match operation {
+        Operation::Delete { path } => {
+            held.push(path.clone());
+            false
+        }
+        _ => true,
+    });
+    hold_deletes(mirror, held);
+    summary.deleted = 0;
+}
+
+/// Entries in the mirror's output roots, leaving out control directories.
+pub fn mirrored_entries(mirror: &Mirror) -> u64 {
+    let mut entries = 0;
+    for output_root in output_roots(mirror) {
+        let walker = WalkDir::new(output_root)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != CONTROL_DIR);
+        entries += walker.filter(Result::is_ok).count() as u64;
+    }
+    entries
+}
+
+/// Mirrored entries a delete of `relative` removes: the entry itself and,
+/// for a directory, everything under it.
+pub fn deleted_entries(mirror: &Mirror, relative: &Path) -> u64 {
+    let mut entries = 0;
+    if let Some(mirrored) = mirrored_path(mirror, relative) {
+        let walker = WalkDir::new(destination_path(mirror, &mirrored))
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != CONTROL_DIR);
+        entries = walker.filter(Result::is_ok).count() as u64;
+    }
+    entries.max(1)
+}
+
+/// The operations `reconcile` would apply, in order, without touching the
+/// output root.
+pub fn plan(mirror: &Mirror) -> (Vec<Operation>, Summary) {
+    plan_under(mirror, Path::new(""))
+}
+
+fn plan_under(mirror: &Mirror, under: &Path) -> (Vec<Operation>, Summary) {
+    let started = Instant::now();
+    let mut summary = Summary::default();
+    let mut operations = Vec::new();
+
+    let walker = WalkDir::new(mirror.watch_root.join(under))
+        .min_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| !is_ignored(mirror, entry.path()));
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                let path = error.path().unwrap_or(&mirror.watch_root).to_path_buf();
+                report::error(ErrorKind::Metadata, &path, format!("Failed to walk {:?}: {}", path, error));
+                continue;
+            }
+        };
+
+        let relative = match entry.path().strip_prefix(&mirror.watch_root) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => continue,
+        };
+        let source = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let file_type = entry.file_type();
+        // Files outside the age window are neither copied nor deleted.
+        let age = &mirror.options.age;
+        if file_type.is_file() && !age.is_empty() && !source.modified().is_ok_and(|modified| age.admits(modified)) {
+            continue;
+        }
+
+        // Files behind a backend are compared by size and modification time
+        // only; extras are found from its listing below.
+        if let Some(backend) = &mirror.backend {
+            if backend.matches(&relative, &source) {
+                continue;
+            }
+            if !file_type.is_file() {
+                operations.push(Operation::Create { path: relative });
+                summary.created += 1;
+            } else {
+                operations.push(Operation::Data { path: relative.clone() });
+                if mirror.options.changes != Changes::Content {
+                    operations.push(Operation::Metadata { path: relative });
+                }
+                summary.files_copied += 1;
+                summary.bytes_copied += source.len();
+            }
+            continue;
+        }
+
+        let mirrored = match mirrored_path(mirror, &relative) {
+            Some(mirrored) => mirrored,
+            None => continue,
+        };
+        let destination_file = destination_path(mirror, &mirrored);
+        let destination_len = |destination: &fs::Metadata| match destination_file != mirrored {
+            true => stored_size(mirror, &destination_file).unwrap_or(u64::MAX),
+            false => destination.len(),
+        };
+
+        let changes = mirror.options.changes;
+        match fs::symlink_metadata(&destination_file) {
+            Ok(destination)
+                if file_type.is_file()
+                    && ((changes == Changes::Metadata && destination_len(&destination) == source.len())
+                        || !needs_copy(mirror, &source, &destination, destination_len(&destination))) =>
+            {
+                if changes != Changes::Content && needs_metadata(mirror, &source, &destination) {
+                    operations.push(Operation::Metadata { path: relative });
+                    summary.metadata_updated += 1;
+                }
+            }
+            _ if file_type.is_file() => {
+                operations.push(Operation::Data { path: relative.clone() });
+                if changes != Changes::Content {
+                    operations.push(Operation::Metadata { path: relative });
+                }
+                summary.files_copied += 1;
+                summary.bytes_copied += source.len();
+            }
+            Err(_) => {
+                operations.push(Operation::Create { path: relative });
+                summary.created += 1;
+            }
+            Ok(destination)
+                if file_type.is_dir() && changes != Changes::Content && needs_metadata(mirror, &source, &destination) =>
+            {
+                operations.push(Operation::Metadata { path: relative });
+                summary.metadata_updated += 1;
+            }
+            Ok(_) => {}
+        }
+    }
+
+    let mut deleted = HashSet::new();
+    let listed = mirror.backend.as_ref().and_then(|backend| backend.listed()).unwrap_or_default();
+    for path in listed.into_iter().filter(|path| path.starts_with(under)) {
+        let parent_deleted = path.ancestors().skip(1).any(|ancestor| deleted.contains(ancestor));
+        if !parent_deleted && fs::symlink_metadata(mirror.watch_root.join(&path)).is_err() && deleted.insert(path.clone()) {
+            operations.push(Operation::Delete { path });
+            summary.deleted += 1;
+        }
+    }
+    for output_root in output_roots(mirror).into_iter().filter(|_| mirror.backend.is_none()) {
+        let mut starts = vec![output_root.join(under)];
+        let mut followed = HashSet::new();
+        let inside = fs::canonicalize(output_root).unwrap_or_else(|_| output_root.to_path_buf());
+        while let Some(start) = starts.pop() {
+            let mut walker = WalkDir::new(start).min_depth(1).into_iter();
+            while let Some(entry) = walker.next() {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                let relative = match entry.path().strip_prefix(output_root) {
+                    Ok(relative) => relative.to_path_buf(),
+                    Err(_) => continue,
+                };
+                // Never delete a control directory that lives in the mirror.
+                if entry.file_name() == CONTROL_DIR {
+                    if entry.file_type().is_dir() {
+                        walker.skip_current_dir();
+                    }
+                    continue;
+                }
+
+                // Compressed and encrypted files are `<name>.zst`/`<name>.enc` on
+                // this side, possibly with encrypted names. Names that don't
+                // decrypt weren't written with this key and are left alone.
+                let source = match watched_relative(mirror, output_root, &relative) {
+                    Some(source) => source,
+                    None => continue,
+                };
+
+                if fs::symlink_metadata(mirror.watch_root.join(&source)).is_err() {
+                    if is_unmounted(mirror, &source) {
+                        if entry.file_type().is_dir() {
+                            walker.skip_current_dir();
+                        }
+                        continue;
+                    }
+                    if entry.file_type().is_dir() {
+                        walker.skip_current_dir();
+                    }
+                    if deleted.insert(source.clone()) {
+                        operations.push(Operation::Delete { path: source });
+                        summary.deleted += 1;
+                    }
+                } else if is_dest_link(mirror, &entry, &source) {
+                    // Followed once, and only out of the destination, so nothing
+                    // is walked (or deleted) twice under two names.
+                    note_dest_link(mirror, entry.path());
+                    if let Ok(target) = fs::canonicalize(entry.path()) {
+                        if !target.starts_with(&inside) && followed.insert(target) {
+                            starts.push(entry.path().to_path_buf());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    summary.elapsed = started.elapsed();
+    (operations, summary)
+}
+
+/// Whether `entry` in a destination is a symlink to a directory where the
+/// source has a real directory at `source`.
+fn is_dest_link(mirror: &Mirror, entry: &walkdir::DirEntry, source: &Path) -> bool {
+    entry.path_is_symlink()
+        && entry.path().is_dir()
+        && fs::symlink_metadata(mirror.watch_root.join(source)).is_ok_and(|metadata| metadata.is_dir())
+}
+
+#[derive(Debug, Default)]
+pub struct MetadataSummary {
+    pub updated: u64,
+    pub matched: u64,
+    /// Missing from the mirror, or with different contents or type.
+    pub skipped: u64,
+}
+
+impl fmt::Display for MetadataSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} updated, {} already matched, {} skipped", self.updated, self.matched, self.skipped)
+    }
+}
+
+/// Copies metadata onto mirrored files and directories whose contents
+/// already match the source, without copying any data. Entries are visited
+/// children first so directory timestamps are set last.
+pub fn metadata_sync(mirror: &Mirror) -> MetadataSummary {
+    let mut summary = MetadataSummary::default();
+
+    let walker = WalkDir::new(&mirror.watch_root)
+        .min_depth(1)
+        .contents_first(true)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| !is_ignored(mirror, entry.path()));
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                let path = error.path().unwrap_or(&mirror.watch_root).to_path_buf();
+                report::error(ErrorKind::Metadata, &path, format!("Failed to walk {:?}: {}", path, error));
+                continue;
+            }
+        };
+        let file_type = entry.file_type();
+        if file_type.is_symlink() {
+            continue;
+        }
+
+        let relative = match entry.path().strip_prefix(&mirror.watch_root) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+        let mirrored = match mirrored_path(mirror, relative) {
+            Some(mirrored) => mirrored,
+            None => continue,
+        };
+        let (source, destination) = match (entry.metadata(), fs::symlink_metadata(&mirrored)) {
+            (Ok(source), Ok(destination)) => (source, destination),
+            _ => {
+                println!("Skipped[missing]: {:?}", entry.path());
+                summary.skipped += 1;
+                continue;
+            }
+        };
+
+        let matches = match file_type.is_dir() {
+            true => destination.is_dir(),
+            false => destination.is_file() && same_contents(entry.path(), &mirrored).unwrap_or(false),
+        };
+        if !matches {
+            println!("Skipped[differs]: {:?}", entry.path());
+            summary.skipped += 1;
+            continue;
+        }
+
+        // Xattrs aren't compared, they're copied along with any other difference.
+        let differences = metadata_differences(mirror, &source, &destination);
+        if differences.is_empty() {
+            summary.matched += 1;
+            continue;
+        }
+        println!("Metadata[{}]: {:?}", differences.join(","), entry.path());
+        apply_metadata(mirror, entry.path());
+        summary.updated += 1;
+    }
+
+    summary
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+/// A path relative to a sync root, independent of the platform it came from.
+///
+/// Components are kept exactly as named (case preserved) and rendered with
+/// forward slashes; `materialize` turns them back into a native path.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RelPath {
+    components: Vec<OsString>,
+}
+
+impl RelPath {
+    /// Accepts only plain relative paths, `..`, roots and prefixes are rejected.
+    /// Separators are the platform's: a backslash is one on Windows, but
+    /// just another character in a Unix file name.
+    pub fn new(path: &Path) -> Option<Self> {
+        let mut components = Vec::new();
+
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => components.push(name.to_os_string()),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+
+        if components.iter().any(|name| name == ".." || name == ".") {
+            return None;
+        }
+
+        Some(RelPath { components })
+    }
+
+    pub fn from_root(root: &Path, path: &Path) -> Option<Self> {
+        path.strip_prefix(root).ok().and_then(RelPath::new)
+    }
+
+    /// Whether `self` is `other` or under it.
+    pub fn starts_with(&self, other: &RelPath) -> bool {
+        self.components.starts_with(&other.components)
+    }
+
+    pub fn components(&self) -> impl Iterator<Item = &OsStr> {
+        self.components.iter().map(OsString::as_os_str)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.components.len()
+    }
+
+    pub fn materialize(&self, root: &Path) -> PathBuf {
+        let mut path = root.to_path_buf();
+        path.extend(&self.components);
+        path
+    }
+
+    /// The same path with every component passed through `rename`.
+    pub fn map_components(&self, rename: impl Fn(&OsStr) -> OsString) -> Self {
+        RelPath { components: self.components().map(rename).collect() }
+    }
+
+    /// The key two paths share when they would collide on a case-insensitive filesystem.
+    pub fn case_key(&self) -> String {
+        self.to_string().to_lowercase()
+    }
+
+    /// The first `depth` components.
+    pub fn prefix(&self, depth: usize) -> Self {
+        RelPath { components: self.components[..depth.min(self.components.len())].to_vec() }
+    }
+
+    /// The same path with `suffix` added to component `index`, before its
+    /// extension: `docs/README.md` becomes `docs/README~case.md`.
+    pub fn with_suffix(&self, index: usize, suffix: &str) -> Self {
+        let mut components = self.components.clone();
+        if let Some(name) = components.get_mut(index) {
+            let path = Path::new(name.as_os_str());
+            let mut renamed = path.file_stem().unwrap_or(name.as_os_str()).to_os_string();
+            renamed.push(suffix);
+            if let Some(extension) = path.extension() {
+                renamed.push(".");
+                renamed.push(extension);
+            }
+            *name = renamed;
+        }
+        RelPath { components }
+    }
+}
+
+/// The first path seen under each case-folded name, which can be forgotten
+/// again once the path is gone.
+#[derive(Default)]
+pub struct CaseIndex {
+    owners: HashMap<String, RelPath>,
+    keys: BTreeMap<RelPath, Vec<String>>,
+}
+
+impl CaseIndex {
+    pub fn get(&self, key: &str) -> Option<&RelPath> {
+        self.owners.get(key)
+    }
+
+    /// Makes `owner` the owner of `key`, in place of any earlier one.
+    pub fn insert(&mut self, key: String, owner: RelPath) {
+        let keys = self.keys.entry(owner.clone()).or_default();
+        if !keys.contains(&key) {
+            keys.push(key.clone());
+        }
+        let Some(previous) = self.owners.insert(key.clone(), owner.clone()).filter(|previous| *previous != owner) else {
+            return;
+        };
+        if let Some(keys) = self.keys.get_mut(&previous) {
+            keys.retain(|other| *other != key);
+            if keys.is_empty() {
+                self.keys.remove(&previous);
+            }
+        }
+    }
+
+    /// Drops `path` and everything under it, which sort right after it.
+    pub fn forget(&mut self, path: &RelPath) {
+        let gone: Vec<RelPath> = self
+            .keys
+            .range(path..)
+            .map(|(owner, _)| owner)
+            .take_while(|owner| owner.starts_with(path))
+            .cloned()
+            .collect();
+        for owner in gone {
+            for key in self.keys.remove(&owner).unwrap_or_default() {
+                if self.owners.get(&key) == Some(&owner) {
+                    self.owners.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `path` exists under exactly that name, which a case-insensitive
+/// filesystem doesn't tell apart from a name that differs only by case.
+pub fn exists_exactly(path: &Path) -> bool {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return false;
+    };
+    fs::symlink_metadata(path).is_ok()
+        && fs::read_dir(parent).is_ok_and(|entries| entries.filter_map(Result::ok).any(|entry| entry.file_name() == name))
+}
+
+/// Directory listings for `exists_exactly` checks on every event, each kept
+/// until `forget` is told something in or of it changed.
+#[derive(Default)]
+pub struct Listings {
+    names: HashMap<PathBuf, HashSet<OsString>>,
+}
+
+impl Listings {
+    /// `exists_exactly`, listing `path`'s parent only if it isn't cached. A
+    /// deleted path is still caught by looking it up.
+    pub fn exists_exactly(&mut self, path: &Path) -> bool {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return false;
+        };
+        if fs::symlink_metadata(path).is_err() {
+            return false;
+        }
+        if !self.names.contains_key(parent) {
+            let Ok(entries) = fs::read_dir(parent) else {
+                return false;
+            };
+            let names = entries.filter_map(Result::ok).map(|entry| entry.file_name()).collect();
+            self.names.insert(parent.to_path_buf(), names);
+        }
+        self.names[parent].contains(name)
+    }
+
+    /// Drops the listings `path` changing could make stale: its parent's, and
+    /// its own and those under it if it's a directory.
+    pub fn forget(&mut self, path: &Path) {
+        if let Some(parent) = path.parent() {
+            self.names.remove(parent);
+        }
+        self.names.retain(|dir, _| !dir.starts_with(path));
+    }
+}
+
+/// Whether names in `dir` are looked up case-insensitively: the default on
+/// macOS and Windows, and on FAT/exFAT volumes anywhere. Probes with an entry
+/// already in `dir` where one has letters, so nothing is written, and with a
+/// scratch file otherwise.
+pub fn is_case_insensitive(dir: &Path) -> io::Result<bool> {
+    let flip = |name: &str| -> String {
+        name.chars()
+            .map(|c| match c.is_lowercase() {
+                true => c.to_uppercase().next().unwrap_or(c),
+                false => c.to_lowercase().next().unwrap_or(c),
+            })
+            .collect()
+    };
+    let same_entry = |a: &Path, b: &Path| match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => same_file(&a, &b),
+        _ => false,
+    };
+
+    for entry in fs::read_dir(dir)?.filter_map(Result::ok) {
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let flipped = flip(name);
+        if flipped != name {
+            return Ok(same_entry(&entry.path(), &dir.join(flipped)));
+        }
+    }
+
+    let probe = dir.join(".rustsync-case-probe");
+    fs::write(&probe, b"")?;
+    let insensitive = same_entry(&probe, &dir.join(".RUSTSYNC-CASE-PROBE"));
+    let _ = fs::remove_file(&probe);
+    Ok(insensitive)
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    a.len() == b.len() && a.modified().ok() == b.modified().ok()
+}
+
+impl fmt::Display for RelPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.components.iter().map(|name| name.to_string_lossy()).collect();
+        f.write_str(&names.join("/"))
+    }
+}
@@ -0,0 +1,422 @@
+use anyhow::Result;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::mirror::Preserve;
+
+/// `[user@]host:path`, scp's syntax for a destination on another host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SshTarget {
+    pub user: Option<String>,
+    pub host: String,
+    pub path: PathBuf,
+}
+
+impl SshTarget {
+    /// Parses `value` the way scp does: a colon before any slash makes it
+    /// remote. Single letters before the colon are Windows drives, not hosts.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (host, path) = value.split_once(':')?;
+        if host.contains('/') || host.len() < 2 {
+            return None;
+        }
+        let (user, host) = match host.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()).filter(|user| !user.is_empty()), host),
+            None => (None, host),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let path = match path {
+            "" => ".",
+            path => path,
+        };
+        Some(SshTarget {
+            user,
+            host: host.to_string(),
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl fmt::Display for SshTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        write!(f, "{}:{}", self.host, self.path.display())
+    }
+}
+
+/// A destination that isn't laid out as a plain copy of the source, such as
+/// a directory on another host or a content-addressable store. Mirrored
+/// operations are handed to it with paths relative to its root; local
+/// sources are read as usual.
+pub trait Backend: Send + Sync {
+    /// Whether `relative` is already there as `source` is: a directory, a
+    /// symlink, or a file with the same size and modification time.
+    fn matches(&self, relative: &Path, source: &fs::Metadata) -> bool;
+
+    /// Copies `source` to `relative` through a temp file renamed into place,
+    /// creating parent directories as needed.
+    fn write(&self, relative: &Path, source: &Path, metadata: &fs::Metadata) -> Result<()>;
+
+    fn mkdir(&self, relative: &Path) -> Result<()>;
+
+    fn symlink(&self, relative: &Path, target: &Path) -> Result<()>;
+
+    /// Deletes `relative`, and everything under it if it's a directory.
+    fn delete(&self, relative: &Path) -> Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Applies the `preserve`d categories of `metadata` (xattrs aside).
+    fn set_metadata(&self, relative: &Path, metadata: &fs::Metadata, preserve: &[Preserve]) -> Result<()>;
+
+    /// Every path the destination holds, where it can list them cheaply, so
+    /// full syncs can delete the ones the source no longer has.
+    fn listed(&self) -> Option<Vec<PathBuf>> {
+        None
+    }
+}
+
+#[cfg(feature = "ssh")]
+pub use ssh::SshBackend;
+
+#[cfg(feature = "ssh")]
+mod ssh {
+    use anyhow::{Context, Result};
+    use ssh2::{CheckResult, ErrorCode, FileStat, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+    use std::{
+        cell::RefCell,
+        collections::HashSet,
+        fs,
+        io::{self, Read, Write},
+        net::TcpStream,
+        path::{Component, Path, PathBuf},
+        sync::Mutex,
+        time::UNIX_EPOCH,
+    };
+
+    use super::{Backend, SshTarget};
+    use crate::{copy::temp_path, mirror::Preserve};
+
+    struct Connection {
+        // Dropping the session closes the channel the Sftp runs over.
+        _session: Session,
+        sftp: Sftp,
+        /// Remote directories known to exist.
+        directories: HashSet<PathBuf>,
+    }
+
+    /// Mirrors over SFTP. One session and SFTP channel carry every operation,
+    /// reconnecting once when the connection has dropped.
+    pub struct SshBackend {
+        target: SshTarget,
+        port: u16,
+        key: Option<PathBuf>,
+        connection: Mutex<Option<Connection>>,
+    }
+
+    fn mtime(metadata: &fs::Metadata) -> Option<u64> {
+        metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok().map(|elapsed| elapsed.as_secs())
+    }
+
+    #[cfg(unix)]
+    fn stat_of(metadata: &fs::Metadata, preserve: &[Preserve]) -> FileStat {
+        use std::os::unix::fs::MetadataExt;
+
+        let owner = preserve.contains(&Preserve::Owner);
+        let times = preserve.contains(&Preserve::Times);
+        FileStat {
+            size: None,
+            uid: owner.then(|| metadata.uid()),
+            gid: owner.then(|| metadata.gid()),
+            perm: preserve.contains(&Preserve::Perms).then(|| metadata.mode() & 0o7777),
+            atime: times.then(|| metadata.atime() as u64),
+            mtime: times.then(|| metadata.mtime() as u64),
+        }
+    }
+
+    #[cfg(windows)]
+    fn stat_of(metadata: &fs::Metadata, preserve: &[Preserve]) -> FileStat {
+        let times = preserve.contains(&Preserve::Times);
+        FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: None,
+            atime: None,
+            mtime: mtime(metadata).filter(|_| times),
+        }
+    }
+
+    // libssh2's LIBSSH2_ERROR_SOCKET_SEND, and SFTP's SSH_FX_NO_SUCH_FILE
+    // and SSH_FX_FAILURE.
+    const SOCKET_SEND: i32 = -7;
+    const SFTP_NO_SUCH_FILE: i32 = 2;
+    const SFTP_FAILURE: i32 = 4;
+
+    /// Whether `error` means the connection rather than the request failed.
+    fn is_disconnect(error: &ssh2::Error) -> bool {
+        matches!(error.code(), ErrorCode::Session(_))
+    }
+
+    fn is_missing(error: &ssh2::Error) -> bool {
+        error.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE)
+    }
+
+    impl SshBackend {
+        /// Connects to `target`, authenticating with `key` or else the SSH
+        /// agent. The host's key must already be in ~/.ssh/known_hosts.
+        pub fn connect(target: SshTarget, port: u16, key: Option<PathBuf>) -> Result<Self> {
+            let backend = SshBackend {
+                target,
+                port,
+                key,
+                connection: Mutex::new(None),
+            };
+            let connection = backend.open()?;
+            connection
+                .sftp
+                .stat(&backend.target.path)
+                .with_context(|| format!("Remote directory {} doesn't exist", backend.target))?;
+            *backend.connection.lock().unwrap() = Some(connection);
+            Ok(backend)
+        }
+
+        fn open(&self) -> Result<Connection> {
+            let target = &self.target;
+            let stream = TcpStream::connect((target.host.as_str(), self.port))
+                .with_context(|| format!("Failed to connect to {}:{}", target.host, self.port))?;
+            let mut session = Session::new()?;
+            session.set_tcp_stream(stream);
+            session.handshake().with_context(|| format!("SSH handshake with {} failed", target.host))?;
+            self.check_host_key(&session)?;
+
+            let user = match &target.user {
+                Some(user) => user.clone(),
+                None => std::env::var("USER").context("No user in the target and $USER isn't set")?,
+            };
+            match &self.key {
+                Some(key) => session
+                    .userauth_pubkey_file(&user, None, key, None)
+                    .with_context(|| format!("Authenticating as {} with {:?} failed", user, key))?,
+                None => session
+                    .userauth_agent(&user)
+                    .with_context(|| format!("Authenticating as {} with the SSH agent failed (try --ssh-key)", user))?,
+            }
+
+            let sftp = session.sftp().context("Failed to start SFTP")?;
+            Ok(Connection {
+                _session: session,
+                sftp,
+                directories: HashSet::new(),
+            })
+        }
+
+        fn check_host_key(&self, session: &Session) -> Result<()> {
+            let host = &self.target.host;
+            let (key, _) = session.host_key().context("The server sent no host key")?;
+            let mut known_hosts = session.known_hosts()?;
+            let file = dirs::home_dir().context("No home directory")?.join(".ssh/known_hosts");
+            let _ = known_hosts.read_file(&file, KnownHostFileKind::OpenSSH);
+            match known_hosts.check_port(host, self.port, key) {
+                CheckResult::Match => Ok(()),
+                CheckResult::NotFound => anyhow::bail!(
+                    "{} isn't in {:?}; connect once with ssh to check and add its key",
+                    host,
+                    file
+                ),
+                CheckResult::Mismatch => anyhow::bail!("Host key for {} doesn't match {:?}", host, file),
+                CheckResult::Failure => anyhow::bail!("Failed to check the host key for {}", host),
+            }
+        }
+
+        /// Runs `operation` over the shared connection, reconnecting and
+        /// retrying once when the connection dropped.
+        fn with_connection<T>(&self, operation: impl Fn(&mut Connection) -> Result<T, ssh2::Error>) -> Result<T> {
+            let mut connection = self.connection.lock().unwrap();
+            if connection.is_none() {
+                *connection = Some(self.open()?);
+            }
+            match operation(connection.as_mut().unwrap()) {
+                Err(error) if is_disconnect(&error) => {
+                    eprintln!("Connection to {} lost ({}), reconnecting", self.target.host, error);
+                    *connection = None;
+                    let reopened = connection.insert(self.open()?);
+                    Ok(operation(reopened)?)
+                }
+                result => Ok(result?),
+            }
+        }
+
+        fn remote(&self, relative: &Path) -> PathBuf {
+            self.target.path.join(relative)
+        }
+    }
+
+    impl Connection {
+        fn create_dir_all(&mut self, path: &Path) -> Result<(), ssh2::Error> {
+            let mut current = PathBuf::new();
+            for component in path.components() {
+                current.push(component);
+                if matches!(component, Component::RootDir | Component::CurDir) || self.directories.contains(&current) {
+                    continue;
+                }
+                if self.sftp.stat(&current).is_err() {
+                    self.sftp.mkdir(&current, 0o755).or_else(|error| match self.sftp.stat(&current) {
+                        Ok(_) => Ok(()),
+                        Err(_) => Err(error),
+                    })?;
+                }
+                self.directories.insert(current.clone());
+            }
+            Ok(())
+        }
+
+        /// Renames over an existing file. SFTP version 3 servers (OpenSSH)
+        /// refuse to, so the target is removed first there.
+        fn replace(&mut self, from: &Path, to: &Path) -> Result<(), ssh2::Error> {
+            let flags = Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE);
+            match self.sftp.rename(from, to, flags) {
+                Ok(()) => Ok(()),
+                Err(error) => match self.sftp.lstat(to) {
+                    Ok(stat) if !stat.is_dir() => {
+                        self.sftp.unlink(to)?;
+                        self.sftp.rename(from, to, flags)
+                    }
+                    _ => Err(error),
+                },
+            }
+        }
+
+        fn remove_all(&mut self, path: &Path) -> Result<(), ssh2::Error> {
+            let stat = match self.sftp.lstat(path) {
+                Ok(stat) => stat,
+                Err(error) if is_missing(&error) => return Ok(()),
+                Err(error) => return Err(error),
+            };
+            if !stat.is_dir() {
+                return self.sftp.unlink(path);
+            }
+            for (child, _) in self.sftp.readdir(path)? {
+                self.remove_all(&child)?;
+            }
+            self.directories.retain(|directory| !directory.starts_with(path));
+            self.sftp.rmdir(path)
+        }
+    }
+
+    impl Backend for SshBackend {
+        fn matches(&self, relative: &Path, source: &fs::Metadata) -> bool {
+            let remote = self.remote(relative);
+            let stat = match self.with_connection(|connection| connection.sftp.lstat(&remote)) {
+                Ok(stat) => stat,
+                Err(_) => return false,
+            };
+            match source.file_type() {
+                kind if kind.is_dir() => stat.is_dir(),
+                kind if kind.is_symlink() => stat.file_type().is_symlink(),
+                _ => stat.is_file() && stat.size == Some(source.len()) && stat.mtime == mtime(source),
+            }
+        }
+
+        fn write(&self, relative: &Path, source: &Path, metadata: &fs::Metadata) -> Result<()> {
+            let remote = self.remote(relative);
+            let temp = temp_path(&remote);
+            // Times are always kept remotely: `matches` compares them.
+            let mut stat = stat_of(metadata, &[Preserve::Perms, Preserve::Times]);
+            stat.mtime = mtime(metadata);
+
+            // A failure on our side isn't the connection's, and isn't retried.
+            let local = RefCell::new(None);
+            let result = self.with_connection(|connection| {
+                if let Some(parent) = remote.parent() {
+                    connection.create_dir_all(parent)?;
+                }
+                let mut file = connection.sftp.open_mode(
+                    &temp,
+                    OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                    0o600,
+                    OpenType::File,
+                )?;
+                let failed_locally = |error| {
+                    local.replace(Some(error));
+                    ssh2::Error::new(ErrorCode::SFTP(SFTP_FAILURE), "failed to read the source")
+                };
+                let mut reader = fs::File::open(source).map_err(failed_locally)?;
+                let mut buffer = vec![0; 64 * 1024];
+                loop {
+                    let read = match reader.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(read) => read,
+                        Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(error) => {
+                            drop(file);
+                            let _ = connection.sftp.unlink(&temp);
+                            return Err(failed_locally(error));
+                        }
+                    };
+                    // Retried as a dropped connection, the likelier cause.
+                    file.write_all(&buffer[..read])
+                        .map_err(|_| ssh2::Error::new(ErrorCode::Session(SOCKET_SEND), "transfer interrupted"))?;
+                }
+                drop(file);
+                connection.sftp.setstat(&temp, stat.clone())?;
+                connection.replace(&temp, &remote).inspect_err(|_| {
+                    let _ = connection.sftp.unlink(&temp);
+                })
+            });
+            if let Some(error) = local.take() {
+                return Err(error).with_context(|| format!("Failed to read {:?}", source));
+            }
+            result.with_context(|| format!("Failed to upload {:?} to {}:{}", source, self.target.host, remote.display()))
+        }
+
+        fn mkdir(&self, relative: &Path) -> Result<()> {
+            let remote = self.remote(relative);
+            self.with_connection(|connection| connection.create_dir_all(&remote))
+                .with_context(|| format!("Failed to create {}:{}", self.target.host, remote.display()))
+        }
+
+        fn symlink(&self, relative: &Path, target: &Path) -> Result<()> {
+            let remote = self.remote(relative);
+            self.with_connection(|connection| {
+                if let Some(parent) = remote.parent() {
+                    connection.create_dir_all(parent)?;
+                }
+                let _ = connection.sftp.unlink(&remote);
+                connection.sftp.symlink(target, &remote)
+            })
+            .with_context(|| format!("Failed to create symlink {}:{}", self.target.host, remote.display()))
+        }
+
+        fn delete(&self, relative: &Path) -> Result<()> {
+            let remote = self.remote(relative);
+            self.with_connection(|connection| connection.remove_all(&remote))
+                .with_context(|| format!("Failed to delete {}:{}", self.target.host, remote.display()))
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+            let (from, to) = (self.remote(from), self.remote(to));
+            self.with_connection(|connection| {
+                if let Some(parent) = to.parent() {
+                    connection.create_dir_all(parent)?;
+                }
+                connection.directories.retain(|directory| !directory.starts_with(&from));
+                connection.replace(&from, &to)
+            })
+            .with_context(|| format!("Failed to rename {}:{} -> {}", self.target.host, from.display(), to.display()))
+        }
+
+        fn set_metadata(&self, relative: &Path, metadata: &fs::Metadata, preserve: &[Preserve]) -> Result<()> {
+            let remote = self.remote(relative);
+            let stat = stat_of(metadata, preserve);
+            self.with_connection(|connection| connection.sftp.setstat(&remote, stat.clone()))
+                .with_context(|| format!("Failed to set metadata on {}:{}", self.target.host, remote.display()))
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// What the source side of a rename looked like, used to pair halves that
+/// arrive without a tracker: directories match directories, files match
+/// files of the same size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shape {
+    Dir,
+    File(u64),
+}
+
+struct Untracked {
+    path: PathBuf,
+    shape: Option<Shape>,
+    seen: Instant,
+}
+
+/// Pairs `RenameMode::From` and `RenameMode::To` halves into renames.
+///
+/// Halves carrying a tracker (the inotify cookie) are matched exactly. Without
+/// one, a `To` is paired with the oldest pending `From` of the same shape.
+/// A `From` still unpaired after `window` was moved out of the watch.
+pub struct RenameTracker {
+    window: Duration,
+    tracked: HashMap<usize, (PathBuf, Instant)>,
+    untracked: VecDeque<Untracked>,
+    resolved: HashMap<usize, Instant>,
+}
+
+impl RenameTracker {
+    pub fn new(window: Duration) -> Self {
+        RenameTracker {
+            window,
+            tracked: HashMap::new(),
+            untracked: VecDeque::new(),
+            resolved: HashMap::new(),
+        }
+    }
+
+    pub fn moved_from(&mut self, tracker: Option<usize>, path: PathBuf, shape: Option<Shape>, now: Instant) {
+        match tracker {
+            Some(tracker) => {
+                self.tracked.insert(tracker, (path, now));
+            }
+            None => self.untracked.push_back(Untracked { path, shape, seen: now }),
+        }
+    }
+
+    /// Returns the path this `To` was renamed from, or `None` if it was moved
+    /// in from outside the watch.
+    pub fn moved_to(&mut self, tracker: Option<usize>, shape: Option<Shape>, now: Instant) -> Option<PathBuf> {
+        if let Some(tracker) = tracker {
+            let (path, _) = self.tracked.remove(&tracker)?;
+            self.resolved.insert(tracker, now);
+            return Some(path);
+        }
+
+        let shape = shape?;
+        let index = self.untracked.iter().position(|pending| pending.shape == Some(shape))?;
+        self.untracked.remove(index).map(|pending| pending.path)
+    }
+
+    /// For a `RenameMode::Both` event: true if its halves were already paired
+    /// by `moved_to`, so the rename must not be applied twice. Otherwise the
+    /// pending `From` is dropped since `Both` carries both paths.
+    pub fn already_paired(&mut self, tracker: Option<usize>) -> bool {
+        let tracker = match tracker {
+            Some(tracker) => tracker,
+            None => return false,
+        };
+
+        if self.resolved.remove(&tracker).is_some() {
+            return true;
+        }
+        self.tracked.remove(&tracker);
+        false
+    }
+
+    /// Removes and returns `From` paths that went unpaired for the whole
+    /// window by `now`.
+    pub fn expire(&mut self, now: Instant) -> Vec<PathBuf> {
+        let window = self.window;
+        let mut expired = Vec::new();
+
+        self.tracked.retain(|_, (path, seen)| {
+            let keep = now.saturating_duration_since(*seen) < window;
+            if !keep {
+                expired.push(path.clone());
+            }
+            keep
+        });
+
+        while let Some(pending) = self.untracked.front() {
+            if now.saturating_duration_since(pending.seen) < window {
+                break;
+            }
+            expired.extend(self.untracked.pop_front().map(|pending| pending.path));
+        }
+
+        self.resolved.retain(|_, seen| now.saturating_duration_since(*seen) < window);
+        expired
+    }
+}
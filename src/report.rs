@@ -0,0 +1,300 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::units::format_duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Watch,
+    NotUnderWatch,
+    Metadata,
+    CreateDir,
+    Delete,
+    Rename,
+    Permissions,
+    Times,
+    Owner,
+    Xattr,
+    Flags,
+    Symlink,
+    Copy,
+    Fsync,
+    Journal,
+    CaseConflict,
+    Merkle,
+    Remote,
+    NonUtf8,
+}
+
+impl ErrorKind {
+    /// Whether an error of this kind means the operation it came from wasn't
+    /// applied, rather than only part of its metadata.
+    pub fn fails_operation(self) -> bool {
+        matches!(
+            self,
+            ErrorKind::CreateDir | ErrorKind::Delete | ErrorKind::Rename | ErrorKind::Symlink | ErrorKind::Copy | ErrorKind::Remote
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorEvent {
+    pub kind: ErrorKind,
+    #[serde(serialize_with = "crate::pathbytes::serialize")]
+    pub path: PathBuf,
+    pub message: String,
+    pub timestamp: u64,
+}
+
+pub trait Sink: Send {
+    fn error(&self, event: &ErrorEvent);
+    /// Delivers whatever the sink is still holding, before rustsync exits.
+    fn finish(self: Box<Self>) {}
+}
+
+fn sinks() -> &'static Mutex<Vec<Box<dyn Sink>>> {
+    static SINKS: OnceLock<Mutex<Vec<Box<dyn Sink>>>> = OnceLock::new();
+    SINKS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+fn counts() -> &'static Mutex<BTreeMap<ErrorKind, u64>> {
+    static COUNTS: OnceLock<Mutex<BTreeMap<ErrorKind, u64>>> = OnceLock::new();
+    COUNTS.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+pub fn error_counts() -> BTreeMap<ErrorKind, u64> {
+    counts().lock().unwrap().clone()
+}
+
+static LAST_ERROR: AtomicU64 = AtomicU64::new(0);
+
+/// Unix time of the most recent error reported, if any.
+pub fn last_error() -> Option<u64> {
+    Some(LAST_ERROR.load(Ordering::Relaxed)).filter(|&timestamp| timestamp != 0)
+}
+
+thread_local! {
+    /// The last error on this thread that failed an operation, for
+    /// `take_failure`.
+    static FAILURE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The last error failing an operation reported on this thread since the
+/// previous call, which tells whether an operation run in between failed.
+/// Warnings such as a timestamp or xattr that couldn't be set don't count.
+pub fn take_failure() -> Option<String> {
+    FAILURE.take()
+}
+
+/// Runs `run` in the middle of another operation, leaving the failure that one
+/// has reported so far as it was.
+pub fn keep_failure<T>(run: impl FnOnce() -> T) -> T {
+    let failure = FAILURE.take();
+    let result = run();
+    FAILURE.set(failure);
+    result
+}
+
+/// Errors printed recently, by kind and message with the paths taken out, so
+/// the same failure across a whole subtree is printed once per window.
+struct Throttle {
+    window: Duration,
+    repeats: HashMap<(ErrorKind, String), Repeats>,
+}
+
+struct Repeats {
+    since: Instant,
+    suppressed: u64,
+    last: String,
+}
+
+impl Repeats {
+    fn new(message: &str) -> Self {
+        Repeats {
+            since: Instant::now(),
+            suppressed: 0,
+            last: message.to_string(),
+        }
+    }
+
+    fn summarize(&self) {
+        if self.suppressed > 0 {
+            eprintln!(
+                "Last error repeated {} more times in {}: {}",
+                self.suppressed,
+                format_duration(self.since.elapsed()),
+                self.last
+            );
+        }
+    }
+}
+
+fn throttle() -> &'static Mutex<Throttle> {
+    static THROTTLE: OnceLock<Mutex<Throttle>> = OnceLock::new();
+    THROTTLE.get_or_init(|| {
+        Mutex::new(Throttle {
+            window: Duration::ZERO,
+            repeats: HashMap::new(),
+        })
+    })
+}
+
+/// Prints each kind of error once per `window` and counts the rest; zero
+/// prints every one.
+pub fn set_log_throttle(window: Duration) {
+    throttle().lock().unwrap().window = window;
+}
+
+/// `message` with quoted strings (the paths) blanked.
+fn shape(message: &str) -> String {
+    let mut shape = String::with_capacity(message.len());
+    let (mut quoted, mut escaped) = (false, false);
+    for c in message.chars() {
+        match (quoted, escaped, c) {
+            (true, false, '\\') => escaped = true,
+            (true, true, _) => escaped = false,
+            (_, _, '"') => {
+                quoted = !quoted;
+                if quoted {
+                    shape.push_str("\"…");
+                } else {
+                    shape.push('"');
+                }
+            }
+            (true, _, _) => {}
+            (false, _, c) => shape.push(c),
+        }
+    }
+    shape
+}
+
+/// Whether to print `message` now, rather than count it as a repeat.
+fn should_print(kind: ErrorKind, message: &str) -> bool {
+    let mut throttle = throttle().lock().unwrap();
+    let window = throttle.window;
+    if window.is_zero() {
+        return true;
+    }
+
+    let key = (kind, shape(message));
+    let Some(repeats) = throttle.repeats.get_mut(&key) else {
+        throttle.repeats.insert(key, Repeats::new(message));
+        return true;
+    };
+    if repeats.since.elapsed() < window {
+        repeats.suppressed += 1;
+        repeats.last = message.to_string();
+        return false;
+    }
+    repeats.summarize();
+    *repeats = Repeats::new(message);
+    true
+}
+
+/// Prints a summary of each error repeated in a window that has ended, or
+/// in every window with `all` (at exit).
+pub fn flush_throttled(all: bool) {
+    let mut throttle = throttle().lock().unwrap();
+    let window = throttle.window;
+    throttle.repeats.retain(|_, repeats| {
+        if !all && repeats.since.elapsed() < window {
+            return true;
+        }
+        repeats.summarize();
+        false
+    });
+}
+
+pub fn add_sink(sink: Box<dyn Sink>) {
+    sinks().lock().unwrap().push(sink);
+}
+
+/// Removes every sink, waiting for each to deliver what it's holding.
+pub fn finish_sinks() {
+    let sinks = std::mem::take(&mut *sinks().lock().unwrap());
+    for sink in sinks {
+        sink.finish();
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogLevel {
+    /// Actions taken and errors
+    #[default]
+    Info,
+    /// Also events that were deliberately not acted on
+    Debug,
+}
+
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn set_log_level(level: LogLevel) {
+    DEBUG.store(level == LogLevel::Debug, Ordering::Relaxed);
+}
+
+pub fn debug_enabled() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+}
+
+/// `--relative-to`'s base, resolved.
+static PATH_BASE: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn set_path_base(base: PathBuf) {
+    let _ = PATH_BASE.set(base);
+}
+
+/// `path` as logs show it: relative to `--relative-to`'s base when it's under
+/// it, otherwise as it is.
+pub fn shown(path: &Path) -> &Path {
+    PATH_BASE
+        .get()
+        .and_then(|base| path.strip_prefix(base).ok())
+        .unwrap_or(path)
+}
+
+pub fn debug(message: impl fmt::Display) {
+    if debug_enabled() {
+        println!("[debug] {}", message);
+    }
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn error(kind: ErrorKind, path: &Path, message: impl fmt::Display) {
+    let event = ErrorEvent {
+        kind,
+        path: shown(path).to_path_buf(),
+        message: message.to_string(),
+        timestamp: unix_timestamp(),
+    };
+
+    if should_print(kind, &event.message) {
+        eprintln!("{}", event.message);
+    }
+    *counts().lock().unwrap().entry(kind).or_insert(0) += 1;
+    LAST_ERROR.store(event.timestamp, Ordering::Relaxed);
+    if kind.fails_operation() {
+        FAILURE.set(Some(event.message.clone()));
+    }
+
+    for sink in sinks().lock().unwrap().iter() {
+        sink.error(&event);
+    }
+}
@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use std::{path::PathBuf, str::FromStr};
+
+/// `<glob>=><dir>`: paths matching the glob, relative to the watch root, are
+/// mirrored under `dir` instead of the output root.
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub pattern: String,
+    pub destination: PathBuf,
+    matcher: GlobMatcher,
+}
+
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (pattern, destination) = spec
+            .split_once("=>")
+            .with_context(|| format!("Expected <glob>=><dir>, got {:?}", spec))?;
+        let matcher = Glob::new(pattern)
+            .with_context(|| format!("Invalid glob {:?}", pattern))?
+            .compile_matcher();
+
+        Ok(Route {
+            pattern: pattern.to_string(),
+            destination: PathBuf::from(destination),
+            matcher,
+        })
+    }
+}
+
+impl Route {
+    pub fn matches(&self, relative: &str) -> bool {
+        self.matcher.is_match(relative)
+    }
+}
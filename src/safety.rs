@@ -0,0 +1,200 @@
+use anyhow::Context;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
+use walkdir::WalkDir;
+
+use crate::mirror::CONTROL_DIR;
+
+/// `--max-deletes`/`--max-delete-percent`: how many deletions a reconcile, or
+/// a burst of live delete events within `window`, may make before rustsync
+/// stops deleting and waits for confirmation.
+#[derive(Clone, Debug)]
+pub struct DeleteLimit {
+    pub max_deletes: Option<u64>,
+    /// Percentage of the entries in the mirror.
+    pub max_percent: Option<f64>,
+    pub window: Duration,
+    /// Log deletions over the limit but carry them out anyway.
+    pub force: bool,
+}
+
+impl Default for DeleteLimit {
+    fn default() -> Self {
+        DeleteLimit {
+            max_deletes: None,
+            max_percent: None,
+            window: Duration::from_secs(10),
+            force: false,
+        }
+    }
+}
+
+impl DeleteLimit {
+    pub fn is_set(&self) -> bool {
+        self.max_deletes.is_some() || self.max_percent.is_some()
+    }
+
+    /// Whether `deletes` out of `total` mirrored entries is over the limit.
+    pub fn exceeded(&self, deletes: u64, total: u64) -> bool {
+        let over_count = self.max_deletes.is_some_and(|max| deletes > max);
+        let over_percent = self
+            .max_percent
+            .is_some_and(|max| total > 0 && deletes as f64 * 100.0 / total as f64 > max);
+        over_count || over_percent
+    }
+}
+
+impl fmt::Display for DeleteLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.max_deletes, self.max_percent) {
+            (Some(count), Some(percent)) => write!(f, "{} deletes or {}% of the mirror", count, percent),
+            (Some(count), None) => write!(f, "{} deletes", count),
+            (None, Some(percent)) => write!(f, "{}% of the mirror", percent),
+            (None, None) => f.write_str("no limit"),
+        }
+    }
+}
+
+pub fn parse_percent(value: &str) -> anyhow::Result<f64> {
+    let percent: f64 = value.trim().trim_end_matches('%').parse()?;
+    if !(0.0..=100.0).contains(&percent) {
+        anyhow::bail!("Percentage {} out of range", percent);
+    }
+    Ok(percent)
+}
+
+/// Live deletes seen within the window, and the ones held back once the
+/// limit tripped.
+#[derive(Default)]
+pub struct DeleteGuard {
+    /// When each delete in the current window happened, and how many entries
+    /// it removed.
+    recent: VecDeque<(Instant, u64)>,
+    /// Entries in the mirror when the current window started.
+    total: u64,
+    tripped: bool,
+    pub blocked: BTreeSet<PathBuf>,
+}
+
+/// What `DeleteGuard::check` decided about a delete.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// This delete pushed the window over the limit.
+    Trip,
+    /// The limit tripped earlier and hasn't been confirmed yet.
+    Hold,
+}
+
+impl DeleteGuard {
+    /// Counts a delete of `entries` mirrored entries towards the window.
+    /// `total` is only called when a new window starts, since counting the
+    /// mirror means walking it.
+    pub fn check(&mut self, limit: &DeleteLimit, entries: u64, total: impl FnOnce() -> u64) -> Verdict {
+        if self.tripped {
+            return Verdict::Hold;
+        }
+
+        let now = Instant::now();
+        while self.recent.front().is_some_and(|&(seen, _)| now.duration_since(seen) > limit.window) {
+            self.recent.pop_front();
+        }
+        if self.recent.is_empty() {
+            self.total = total();
+        }
+        self.recent.push_back((now, entries));
+
+        let deletes = self.recent.iter().map(|&(_, entries)| entries).sum();
+        match limit.exceeded(deletes, self.total) {
+            true => {
+                self.tripped = !limit.force;
+                Verdict::Trip
+            }
+            false => Verdict::Allow,
+        }
+    }
+
+    /// Hands back the held deletes and starts counting afresh.
+    pub fn release(&mut self) -> BTreeSet<PathBuf> {
+        self.recent.clear();
+        self.tripped = false;
+        std::mem::take(&mut self.blocked)
+    }
+}
+
+/// `path` fully resolved, or for a path that doesn't exist yet (a
+/// `--relative-to` destination before the sync creates it), its nearest
+/// existing ancestor resolved with the rest applied lexically, `..` included,
+/// since nothing in it exists to be a symlink.
+fn resolve(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    while !existing.as_os_str().is_empty() && !existing.exists() {
+        let (Some(parent), Some(last)) = (existing.parent(), existing.components().next_back()) else {
+            break;
+        };
+        missing.push(last);
+        existing = parent;
+    }
+    let existing = match existing.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => existing,
+    };
+
+    let mut resolved = fs::canonicalize(existing).with_context(|| format!("Failed to resolve {:?}", path))?;
+    for component in missing.into_iter().rev() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Refuses roots that would have rustsync mirror its own writes forever: a
+/// watch root at or inside a destination, or a symlink under the watch root
+/// leading into a destination or to a directory holding one, since native
+/// watches follow symlinks and writes through it come back as events. Paths
+/// are compared fully resolved, so symlinks in either root don't hide an
+/// overlap. A destination inside the watch root is fine; it's skipped.
+pub fn check_roots(watch_root: &Path, destinations: &[&Path]) -> anyhow::Result<()> {
+    let watch_root = resolve(watch_root)?;
+    let destinations = destinations.iter().map(|path| resolve(path)).collect::<anyhow::Result<Vec<_>>>()?;
+
+    for destination in &destinations {
+        if watch_root.starts_with(destination) {
+            anyhow::bail!("Watch root {:?} is inside destination {:?}, which would mirror into itself", watch_root, destination);
+        }
+    }
+
+    // Mirrored symlinks in a destination under the watch root point into it.
+    let walker = WalkDir::new(&watch_root).follow_links(false).into_iter().filter_entry(|entry| {
+        entry.file_name() != CONTROL_DIR && !destinations.iter().any(|destination| destination == entry.path())
+    });
+    for entry in walker.filter_map(Result::ok).filter(|entry| entry.path_is_symlink()) {
+        if let Some(destination) = link_loop(entry.path(), &destinations) {
+            anyhow::bail!(
+                "Symlink {:?} leads to destination {:?}, so mirroring would loop through it",
+                entry.path(),
+                destination
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The destination that `link`'s fully resolved target is in or contains.
+pub fn link_loop<'a>(link: &Path, destinations: &'a [PathBuf]) -> Option<&'a Path> {
+    let target = fs::canonicalize(link).ok()?;
+    destinations
+        .iter()
+        .find(|destination| destination.starts_with(&target) || target.starts_with(destination))
+        .map(PathBuf::as_path)
+}
@@ -0,0 +1,103 @@
+use anyhow::{Context, Result};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily time range such as `22:00-06:00` in which background full syncs
+/// may run. Ranges that end before they start wrap past midnight. Times are
+/// local unless suffixed with ` UTC`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ActiveWindow {
+    start: u32,
+    end: u32,
+    utc: bool,
+}
+
+fn parse_time(value: &str) -> Result<u32> {
+    let (hours, minutes) = value
+        .split_once(':')
+        .with_context(|| format!("Expected HH:MM, got {:?}", value))?;
+    let hours: u32 = hours.trim().parse().with_context(|| format!("Bad hour in {:?}", value))?;
+    let minutes: u32 = minutes.trim().parse().with_context(|| format!("Bad minute in {:?}", value))?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes != 0) {
+        anyhow::bail!("Time out of range: {:?}", value);
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for ActiveWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        let (range, utc) = match value.strip_suffix("UTC") {
+            Some(range) => (range.trim_end(), true),
+            None => (value, false),
+        };
+        let (start, end) = range
+            .split_once('-')
+            .with_context(|| format!("Expected START-END such as 22:00-06:00, got {:?}", value))?;
+        Ok(ActiveWindow {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            utc,
+        })
+    }
+}
+
+impl fmt::Display for ActiveWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}{}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60,
+            if self.utc { " UTC" } else { "" }
+        )
+    }
+}
+
+impl ActiveWindow {
+    /// Whether `minute` (minutes since midnight) falls inside the window.
+    pub fn contains(&self, minute: u32) -> bool {
+        match self.start <= self.end {
+            true => (self.start..self.end).contains(&minute),
+            false => minute >= self.start || minute < self.end,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        let minute = match self.utc {
+            true => utc_minute(),
+            false => local_minute(),
+        };
+        self.contains(minute)
+    }
+}
+
+fn utc_minute() -> u32 {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    ((seconds / 60) % MINUTES_PER_DAY as u64) as u32
+}
+
+#[cfg(unix)]
+fn local_minute() -> u32 {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut local: libc::tm = unsafe { std::mem::zeroed() };
+    match unsafe { libc::localtime_r(&now, &mut local) }.is_null() {
+        true => utc_minute(),
+        false => (local.tm_hour * 60 + local.tm_min) as u32,
+    }
+}
+
+/// No time zone lookup on other platforms; windows there are in UTC.
+#[cfg(not(unix))]
+fn local_minute() -> u32 {
+    utc_minute()
+}
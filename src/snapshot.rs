@@ -0,0 +1,261 @@
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use walkdir::WalkDir;
+
+use crate::{
+    copy::{copy_file, set_unix_mode, unix_mode},
+    hash::{hash_file, ChecksumAlgorithm},
+    hashcache::HashCache,
+    manifest::Manifest,
+    mirror::{cross_platform_symlink, is_ignored, Mirror, CONTROL_DIR},
+    report::{self, ErrorKind},
+};
+
+/// Snapshots still being written carry this suffix until they're complete.
+const PARTIAL: &str = ".partial";
+
+#[derive(Debug, Default)]
+pub struct SnapshotSummary {
+    pub name: String,
+    pub linked: u64,
+    pub copied: u64,
+    pub pruned: u64,
+}
+
+impl fmt::Display for SnapshotSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "snapshot={} linked={} copied={} pruned={}",
+            self.name, self.linked, self.copied, self.pruned
+        )
+    }
+}
+
+/// A UTC timestamp such as `2025-01-31T180000Z`, which sorts by age.
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64);
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    #[cfg(unix)]
+    let failed = unsafe { libc::gmtime_r(&seconds, &mut tm) }.is_null();
+    #[cfg(windows)]
+    let failed = unsafe { libc::gmtime_s(&mut tm, &seconds) } != 0;
+    if failed {
+        return seconds.to_string();
+    }
+    format!(
+        "{:04}-{:02}-{:02}T{:02}{:02}{:02}Z",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// Whether `name` is one `take_snapshot` gives: a `timestamp`, maybe with a
+/// `-<n>` suffix telling apart snapshots taken within the same second.
+fn is_snapshot_name(name: &str) -> bool {
+    let Some((stamp, suffix)) = name.split_at_checked(18) else {
+        return false;
+    };
+    let shape = stamp.bytes().zip("0000-00-00T000000Z".bytes()).all(|(byte, expected)| match expected {
+        b'0' => byte.is_ascii_digit(),
+        _ => byte == expected,
+    });
+    let suffix = match suffix.strip_prefix('-') {
+        Some(number) => !number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit()),
+        None => suffix.is_empty(),
+    };
+    shape && suffix
+}
+
+fn manifest_path(snapshot: &Path) -> PathBuf {
+    snapshot.join(CONTROL_DIR).join("manifest")
+}
+
+/// The directory snapshots are kept in under `--snapshot-dir`.
+pub fn snapshots_root(snapshot_dir: &Path) -> PathBuf {
+    snapshot_dir.join("snapshots")
+}
+
+/// Snapshots under `root`, oldest first: the completed ones, or with
+/// `partial`, the ones an interrupted run left incomplete. Directories not
+/// named like a snapshot weren't made by rustsync and are left alone.
+fn read_snapshots(root: &Path, partial: bool) -> Result<Vec<PathBuf>> {
+    let mut snapshots = Vec::new();
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(snapshots),
+        Err(error) => return Err(error).with_context(|| format!("Failed to read {:?}", root)),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let name = file_name.to_string_lossy();
+        let name = match name.strip_suffix(PARTIAL) {
+            Some(name) if partial => name,
+            None if !partial => &name,
+            _ => continue,
+        };
+        if entry.file_type()?.is_dir() && is_snapshot_name(name) {
+            snapshots.push(entry.path());
+        }
+    }
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+/// Completed snapshots under `root`, oldest first.
+pub fn list_snapshots(root: &Path) -> Result<Vec<PathBuf>> {
+    read_snapshots(root, false)
+}
+
+/// Whether the previous snapshot's copy of a file can stand in for it: same
+/// contents by hash, and the same mode and modification time, which a hard
+/// link would share.
+fn unchanged(previous: &Path, hash: &str, recorded: Option<&String>, metadata: &fs::Metadata) -> bool {
+    recorded.is_some_and(|recorded| recorded == hash)
+        && fs::symlink_metadata(previous).is_ok_and(|old| {
+            old.is_file()
+                && unix_mode(&old) == unix_mode(metadata)
+                && FileTime::from_last_modification_time(&old) == FileTime::from_last_modification_time(metadata)
+        })
+}
+
+fn copy_into(mirror: &Mirror, source: &Path, destination: &Path, metadata: &fs::Metadata) -> Result<()> {
+    copy_file(source, destination, mirror.options.reflink)?;
+    set_unix_mode(destination, unix_mode(metadata) & 0o7777)?;
+    filetime::set_file_mtime(destination, FileTime::from_last_modification_time(metadata))?;
+    Ok(())
+}
+
+/// `--snapshot-dir`: writes the watch root out as a new snapshot under
+/// `mirror.output_root/snapshots`, hard linking files unchanged since the
+/// latest one and copying the rest, then removes all but the newest `keep`.
+pub fn take_snapshot(
+    mirror: &Mirror,
+    algorithm: ChecksumAlgorithm,
+    mut cache: Option<&mut HashCache>,
+    keep: usize,
+) -> Result<SnapshotSummary> {
+    let root = &snapshots_root(&mirror.output_root);
+    for stale in read_snapshots(root, true)? {
+        remove_snapshot(&stale)?;
+    }
+    let previous = list_snapshots(root)?.pop();
+    let recorded = previous
+        .as_deref()
+        .and_then(|previous| Manifest::load(&manifest_path(previous)).ok())
+        .filter(|manifest| manifest.algorithm == algorithm)
+        .map_or_else(BTreeMap::new, |manifest| manifest.entries);
+
+    let mut name = timestamp(SystemTime::now());
+    let mut suffix = 0;
+    while root.join(&name).exists() || root.join(format!("{}{}", name, PARTIAL)).exists() {
+        suffix += 1;
+        name = format!("{}-{}", timestamp(SystemTime::now()), suffix);
+    }
+    let partial = root.join(format!("{}{}", name, PARTIAL));
+    fs::create_dir_all(&partial).with_context(|| format!("Failed to create {:?}", partial))?;
+
+    let mut summary = SnapshotSummary { name: name.clone(), ..SnapshotSummary::default() };
+    let mut manifest = Manifest { algorithm, entries: BTreeMap::new() };
+    let mut directories = Vec::new();
+    let walker = WalkDir::new(&mirror.watch_root)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| !is_ignored(mirror, entry.path()));
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                let path = error.path().unwrap_or(&mirror.watch_root).to_path_buf();
+                report::error(ErrorKind::Metadata, &path, format!("Failed to walk {:?}: {}", path, error));
+                continue;
+            }
+        };
+        let relative = entry.path().strip_prefix(&mirror.watch_root)?.to_path_buf();
+        let destination = partial.join(&relative);
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+
+        if metadata.is_dir() {
+            if let Err(error) = fs::create_dir(&destination) {
+                report::error(ErrorKind::CreateDir, &destination, format!("Failed to create {:?}: {}", destination, error));
+            }
+            directories.push((destination, unix_mode(&metadata) & 0o7777));
+        } else if metadata.is_symlink() {
+            let linked = fs::read_link(entry.path()).and_then(|target| cross_platform_symlink(&target, &destination));
+            if let Err(error) = linked {
+                report::error(ErrorKind::Symlink, &destination, format!("Failed to create symlink {:?}: {}", destination, error));
+            }
+        } else if metadata.is_file() {
+            let hash = match cache.as_deref_mut() {
+                Some(cache) => cache.hash(&relative, entry.path(), || hash_file(entry.path(), algorithm)),
+                None => hash_file(entry.path(), algorithm),
+            };
+            let hash = match hash {
+                Ok(hash) => hash,
+                Err(error) => {
+                    report::error(ErrorKind::Copy, entry.path(), format!("Failed to hash {:?}: {:#}", entry.path(), error));
+                    continue;
+                }
+            };
+
+            let old = previous.as_deref().map(|previous| previous.join(&relative));
+            let linked = match &old {
+                Some(old) if unchanged(old, &hash, recorded.get(&relative), &metadata) => {
+                    fs::hard_link(old, &destination).is_ok()
+                }
+                _ => false,
+            };
+            if linked {
+                summary.linked += 1;
+            } else if let Err(error) = copy_into(mirror, entry.path(), &destination, &metadata) {
+                report::error(ErrorKind::Copy, entry.path(), format!("Failed to copy {:?}: {:#}", entry.path(), error));
+                continue;
+            } else {
+                summary.copied += 1;
+            }
+            manifest.entries.insert(relative, hash);
+        }
+    }
+
+    // Last, so read-only directories don't stop their contents being written.
+    for (path, mode) in directories.into_iter().rev() {
+        let _ = set_unix_mode(&path, mode);
+    }
+    fs::create_dir_all(partial.join(CONTROL_DIR))?;
+    manifest.save(&manifest_path(&partial))?;
+    fs::rename(&partial, root.join(&name)).with_context(|| format!("Failed to complete snapshot {:?}", name))?;
+
+    let snapshots = list_snapshots(root)?;
+    for old in &snapshots[..snapshots.len().saturating_sub(keep.max(1))] {
+        match remove_snapshot(old) {
+            Ok(()) => summary.pruned += 1,
+            Err(error) => report::error(ErrorKind::Delete, old, format!("Failed to prune {:?}: {:#}", old, error)),
+        }
+    }
+    Ok(summary)
+}
+
+/// Removes a snapshot, making its read-only directories writable first.
+fn remove_snapshot(snapshot: &Path) -> Result<()> {
+    for entry in WalkDir::new(snapshot).into_iter().filter_map(Result::ok) {
+        if entry.file_type().is_dir() {
+            let _ = set_unix_mode(entry.path(), 0o700);
+        }
+    }
+    fs::remove_dir_all(snapshot)?;
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use anyhow::Result;
+use std::{io, path::Path, str::FromStr};
+
+use crate::units::parse_size;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MinFreeSpace {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl MinFreeSpace {
+    pub fn bytes(&self, total: u64) -> u64 {
+        match self {
+            MinFreeSpace::Bytes(bytes) => *bytes,
+            MinFreeSpace::Percent(percent) => (total as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+impl FromStr for MinFreeSpace {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.strip_suffix('%') {
+            Some(percent) => {
+                let percent: f64 = percent.trim().parse()?;
+                if !(0.0..=100.0).contains(&percent) {
+                    anyhow::bail!("Percentage {} out of range", percent);
+                }
+                Ok(MinFreeSpace::Percent(percent))
+            }
+            None => Ok(MinFreeSpace::Bytes(parse_size(value)?)),
+        }
+    }
+}
+
+pub struct DiskSpace {
+    pub available: u64,
+    pub total: u64,
+}
+
+#[cfg(unix)]
+pub fn disk_space(path: &Path) -> io::Result<DiskSpace> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let fragment = stats.f_frsize as u64;
+    Ok(DiskSpace {
+        available: stats.f_bavail as u64 * fragment,
+        total: stats.f_blocks as u64 * fragment,
+    })
+}
+
+#[cfg(windows)]
+pub fn disk_space(path: &Path) -> io::Result<DiskSpace> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    let mut total = 0u64;
+
+    if unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, &mut total, std::ptr::null_mut()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(DiskSpace { available, total })
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::mirror::Operation;
+
+/// Size and modification time, which change while a file is being written.
+pub type Signature = (u64, Option<SystemTime>);
+
+pub fn signature(path: &Path) -> io::Result<Signature> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified().ok()))
+}
+
+struct Held {
+    operations: Vec<Operation>,
+    signature: Signature,
+    checked: Instant,
+    checks: u32,
+}
+
+/// `--stable-time`: copies of files held until their size and modification
+/// time stop changing, checked once per `stable_time`.
+pub struct StabilityCheck {
+    stable_time: Duration,
+    max_checks: u32,
+    held: BTreeMap<PathBuf, Held>,
+}
+
+impl StabilityCheck {
+    pub fn new(stable_time: Duration, max_checks: u32) -> Self {
+        StabilityCheck {
+            stable_time,
+            max_checks,
+            held: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    pub fn is_held(&self, relative: &Path) -> bool {
+        self.held.contains_key(relative)
+    }
+
+    /// Holds `operation` on `relative` along with anything already held for
+    /// it. A repeat moves to the end instead of running twice. The file's
+    /// `signature` is only taken when nothing is held for it yet.
+    pub fn hold(&mut self, relative: &Path, operation: Operation, signature: Signature, now: Instant) {
+        let held = self.held.entry(relative.to_path_buf()).or_insert_with(|| Held {
+            operations: Vec::new(),
+            signature,
+            checked: now,
+            checks: 0,
+        });
+        held.operations.retain(|other| other != &operation);
+        held.operations.push(operation);
+    }
+
+    /// Queues `operation` behind the copy held for `relative`, if there is one.
+    pub fn follow(&mut self, relative: &Path, operation: &Operation) -> bool {
+        match self.held.get_mut(relative) {
+            Some(held) => {
+                held.operations.retain(|other| other != operation);
+                held.operations.push(operation.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes out what's held for `relative` and anything under it.
+    pub fn take_under(&mut self, relative: &Path) -> Vec<Operation> {
+        let under: Vec<PathBuf> = self.held.keys().filter(|path| path.starts_with(relative)).cloned().collect();
+        under
+            .into_iter()
+            .flat_map(|path| self.held.remove(&path).unwrap().operations)
+            .collect()
+    }
+
+    /// Restats the files under `root` checked at least `stable_time` ago and
+    /// takes out the operations of those that haven't changed since, or that
+    /// are out of checks. Files that vanished are dropped, since the delete
+    /// that follows takes care of them.
+    pub fn take_stable(&mut self, root: &Path, now: Instant) -> Vec<Operation> {
+        let due: Vec<PathBuf> = self
+            .held
+            .iter()
+            .filter(|(_, held)| now.saturating_duration_since(held.checked) >= self.stable_time)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        let mut stable = Vec::new();
+        for path in due {
+            let held = self.held.get_mut(&path).unwrap();
+            match signature(&root.join(&path)) {
+                Ok(signature) if signature == held.signature => {}
+                Ok(signature) if held.checks + 1 < self.max_checks => {
+                    held.signature = signature;
+                    held.checked = now;
+                    held.checks += 1;
+                    continue;
+                }
+                Ok(_) => eprintln!(
+                    "Still changing after {} checks, copying anyway: {:?}",
+                    self.max_checks,
+                    root.join(&path)
+                ),
+                Err(_) => {
+                    self.held.remove(&path);
+                    continue;
+                }
+            }
+            stable.extend(self.held.remove(&path).unwrap().operations);
+        }
+        stable
+    }
+}
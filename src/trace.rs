@@ -0,0 +1,47 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::{fmt, path::Path};
+
+/// `--trace-events`: logs every raw watcher event, whether or not rustsync
+/// acts on it, for the paths matching any of its globs (all of them when
+/// there are none).
+pub struct EventTrace {
+    globs: Option<GlobSet>,
+}
+
+impl EventTrace {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        if patterns.is_empty() {
+            return Ok(EventTrace { globs: None });
+        }
+
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Glob::new(pattern).with_context(|| format!("Invalid glob {:?}", pattern))?);
+        }
+        Ok(EventTrace {
+            globs: Some(builder.build()?),
+        })
+    }
+
+    /// Whether an event on `paths` is traced. Globs match paths relative to
+    /// the watch root, or whole paths outside it.
+    pub fn matches(&self, watch_root: &Path, paths: &[impl AsRef<Path>]) -> bool {
+        let globs = match &self.globs {
+            Some(globs) => globs,
+            None => return true,
+        };
+        paths.iter().any(|path| {
+            let path = path.as_ref();
+            globs.is_match(path.strip_prefix(watch_root).unwrap_or(path))
+        })
+    }
+
+    /// Logs `event` to stderr with what's done about it.
+    pub fn log(&self, event: &notify::Event, verdict: impl fmt::Display) {
+        eprintln!(
+            "[trace] {} | {:?} paths={:?} attrs={:?}",
+            verdict, event.kind, event.paths, event.attrs
+        );
+    }
+}
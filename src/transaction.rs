@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{coalesce::Coalescer, mirror::Operation};
+
+/// How many settle times a group that never goes quiet is held, at most,
+/// before it's applied anyway.
+const MAX_HOLD_SETTLES: u32 = 10;
+
+/// `--transaction-glob`: paths matching the glob, relative to the watch root,
+/// form a group whose changes are applied together.
+#[derive(Clone, Debug)]
+pub struct TransactionGlob {
+    pub pattern: String,
+    matcher: GlobMatcher,
+}
+
+impl FromStr for TransactionGlob {
+    type Err = anyhow::Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        let matcher = Glob::new(pattern)
+            .with_context(|| format!("Invalid glob {:?}", pattern))?
+            .compile_matcher();
+
+        Ok(TransactionGlob {
+            pattern: pattern.to_string(),
+            matcher,
+        })
+    }
+}
+
+impl fmt::Display for TransactionGlob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// The first of `globs` matching any path `operation` touches.
+pub fn group_of(globs: &[TransactionGlob], operation: &Operation) -> Option<usize> {
+    let matches = |path: &Path| globs.iter().position(|glob| glob.matcher.is_match(path));
+    match operation {
+        Operation::Rename { path, new_path } => matches(path).or_else(|| matches(new_path)),
+        Operation::Create { path }
+        | Operation::Data { path }
+        | Operation::Metadata { path }
+        | Operation::Delete { path } => matches(path),
+    }
+}
+
+/// Operations held back per group until no new one has arrived for the
+/// settle time.
+pub struct Transactions {
+    settle: Duration,
+    quiet: Coalescer<usize>,
+    held: HashMap<usize, (Instant, Vec<Operation>)>,
+}
+
+impl Transactions {
+    pub fn new(settle: Duration) -> Self {
+        Transactions {
+            settle,
+            quiet: Coalescer::new(settle),
+            held: HashMap::new(),
+        }
+    }
+
+    /// Adds `operation` to `group`. A repeat of an operation already held
+    /// moves it to the end instead of applying it twice.
+    pub fn hold(&mut self, group: usize, operation: Operation, now: Instant) {
+        let (_, operations) = self.held.entry(group).or_insert_with(|| (now, Vec::new()));
+        operations.retain(|held| held != &operation);
+        operations.push(operation);
+        self.quiet.touch(group, now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.held.values().map(|(_, operations)| operations.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    /// Removes and returns the groups that have gone quiet, or have been held
+    /// too long to wait any more, by `now`.
+    pub fn take_settled(&mut self, now: Instant) -> Vec<(usize, Vec<Operation>)> {
+        let mut settled = self.quiet.take_settled(now);
+        let overdue: Vec<usize> = self
+            .held
+            .iter()
+            .filter(|(group, (since, _))| {
+                now.saturating_duration_since(*since) >= self.settle * MAX_HOLD_SETTLES && !settled.contains(group)
+            })
+            .map(|(group, _)| *group)
+            .collect();
+        settled.extend(overdue);
+        settled
+            .into_iter()
+            .filter_map(|group| self.held.remove(&group).map(|(_, operations)| (group, operations)))
+            .collect()
+    }
+
+    /// Removes and returns every group, settled or not, oldest first.
+    pub fn take_all(&mut self) -> Vec<(usize, Vec<Operation>)> {
+        self.quiet = Coalescer::new(self.settle);
+        let mut all: Vec<_> = self.held.drain().collect();
+        all.sort_by_key(|(_, (since, _))| *since);
+        all.into_iter().map(|(group, (_, operations))| (group, operations)).collect()
+    }
+}
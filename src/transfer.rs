@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    hash::{hash_file, ChecksumAlgorithm},
+    mirror::CONTROL_DIR,
+};
+
+/// Bytes per chunk of a file fetched from a peer.
+pub const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Where downloads in progress live, under the root's control directory so
+/// they're never listed in manifests and are on the root's filesystem.
+pub fn partial_dir(root: &Path) -> PathBuf {
+    root.join(CONTROL_DIR).join("partial")
+}
+
+/// Chunk `seq` of a file, starting at `offset`, along with the file's whole
+/// size. A chunk shorter than `CHUNK_SIZE` is the last.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chunk {
+    #[serde(with = "crate::pathbytes")]
+    pub path: PathBuf,
+    pub seq: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn is_last(&self) -> bool {
+        self.offset + self.data.len() as u64 >= self.size
+    }
+}
+
+/// Reads the chunk of `root/path` at `offset`.
+pub fn read_chunk(root: &Path, path: &Path, seq: u64, offset: u64) -> Result<Chunk> {
+    let source = root.join(path);
+    let mut file = fs::File::open(&source).with_context(|| format!("Failed to open {:?}", source))?;
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut data = Vec::new();
+    file.take(CHUNK_SIZE)
+        .read_to_end(&mut data)
+        .with_context(|| format!("Failed to read {:?}", source))?;
+    Ok(Chunk {
+        path: path.to_path_buf(),
+        seq,
+        offset,
+        size,
+        data,
+    })
+}
+
+/// What's been fetched of a file, saved after every chunk so a download
+/// interrupted by a dropped connection or a restart carries on from there.
+#[derive(Debug, Serialize, Deserialize)]
+struct Progress {
+    #[serde(with = "crate::pathbytes")]
+    path: PathBuf,
+    /// The file's hash in the peer's manifest; the finished file must match.
+    hash: String,
+    /// Everything before this has been written to the partial file.
+    offset: u64,
+}
+
+pub enum Received {
+    /// Ask for the chunk at `offset` next.
+    More { seq: u64, offset: u64 },
+    /// The file is complete, verified and in place.
+    Done { size: u64 },
+    /// Not the chunk this download is waiting for, such as a duplicate.
+    Ignored,
+}
+
+/// A file being fetched from a peer a chunk at a time. Chunks are written to
+/// a `.partial` file that's moved into place once all of it is there and
+/// its hash checks out.
+pub struct Download {
+    root: PathBuf,
+    algorithm: ChecksumAlgorithm,
+    progress: Progress,
+    partial: PathBuf,
+    saved: PathBuf,
+}
+
+impl Download {
+    /// Starts fetching `path`, or resumes a download of the same version of
+    /// it left by an earlier connection.
+    pub fn open(root: &Path, path: &Path, hash: &str, algorithm: ChecksumAlgorithm) -> Result<Self> {
+        let directory = partial_dir(root);
+        fs::create_dir_all(&directory).with_context(|| format!("Failed to create {:?}", directory))?;
+        let key = blake3::hash(path.as_os_str().as_encoded_bytes()).to_hex();
+        let partial = directory.join(format!("{}.partial", key));
+        let saved = directory.join(format!("{}.json", key));
+
+        let previous = fs::read(&saved)
+            .ok()
+            .and_then(|saved| serde_json::from_slice::<Progress>(&saved).ok())
+            .filter(|progress| progress.path == path && progress.hash == hash);
+        // Bytes past the saved offset were written but never acknowledged.
+        let written = fs::metadata(&partial).map_or(0, |metadata| metadata.len());
+        let offset = previous.map_or(0, |progress| progress.offset.min(written));
+
+        let download = Download {
+            root: root.to_path_buf(),
+            algorithm,
+            progress: Progress {
+                path: path.to_path_buf(),
+                hash: hash.to_string(),
+                offset,
+            },
+            partial,
+            saved,
+        };
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&download.partial)
+            .and_then(|file| file.set_len(offset))
+            .with_context(|| format!("Failed to open {:?}", download.partial))?;
+        download.save()?;
+        Ok(download)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.progress.path
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.progress.hash
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.progress.offset
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.progress.offset / CHUNK_SIZE
+    }
+
+    fn save(&self) -> Result<()> {
+        let temp = self.saved.with_extension("tmp");
+        fs::write(&temp, serde_json::to_vec(&self.progress)?).with_context(|| format!("Failed to write {:?}", temp))?;
+        fs::rename(&temp, &self.saved).with_context(|| format!("Failed to write {:?}", self.saved))
+    }
+
+    /// Writes `chunk` if it's the next one and, after the last, verifies the
+    /// file and moves it into place. A file that doesn't match its hash is
+    /// thrown away.
+    pub fn receive(&mut self, chunk: &Chunk) -> Result<Received> {
+        if chunk.path != self.progress.path || chunk.seq != self.seq() || chunk.offset != self.progress.offset {
+            return Ok(Received::Ignored);
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.partial)
+            .with_context(|| format!("Failed to open {:?}", self.partial))?;
+        file.set_len(chunk.offset)?;
+        file.seek(SeekFrom::Start(chunk.offset))?;
+        file.write_all(&chunk.data)
+            .and_then(|()| file.sync_data())
+            .with_context(|| format!("Failed to write {:?}", self.partial))?;
+        self.progress.offset += chunk.data.len() as u64;
+        self.save()?;
+
+        if !chunk.is_last() && !chunk.data.is_empty() {
+            return Ok(Received::More {
+                seq: self.seq(),
+                offset: self.progress.offset,
+            });
+        }
+        self.finish().map(|()| Received::Done { size: self.progress.offset })
+    }
+
+    fn finish(&self) -> Result<()> {
+        let hash = hash_file(&self.partial, self.algorithm)?;
+        if hash != self.progress.hash {
+            self.abandon();
+            anyhow::bail!(
+                "Downloaded {:?} hashes to {} instead of {}, discarding it",
+                self.progress.path,
+                hash,
+                self.progress.hash
+            );
+        }
+
+        let target = self.root.join(&self.progress.path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}", parent))?;
+        }
+        fs::rename(&self.partial, &target).with_context(|| format!("Failed to move {:?} into place", target))?;
+        let _ = fs::remove_file(&self.saved);
+        Ok(())
+    }
+
+    /// Removes the partial file and its progress.
+    pub fn abandon(&self) {
+        let _ = fs::remove_file(&self.partial);
+        let _ = fs::remove_file(&self.saved);
+    }
+}
@@ -0,0 +1,215 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use std::{
+    path::Path,
+    sync::Arc,
+};
+
+/// The file a transform is being run on.
+pub struct MirrorEvent<'a> {
+    /// Path relative to the watch root.
+    pub relative: &'a Path,
+    /// Path of the source file.
+    pub source: &'a Path,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransformOutcome {
+    /// Write the (possibly changed) content to the mirror.
+    Write,
+    /// Leave the mirror's copy as it is this time.
+    Skip,
+    /// Keep the file out of the mirror, removing any copy already there.
+    Drop,
+}
+
+/// Rewrites file contents on their way to the mirror. Transforms run in the
+/// order they were added, each seeing the previous one's output, and the
+/// first to return `Skip` or `Drop` stops the chain.
+pub trait Transform: Send + Sync {
+    fn name(&self) -> &str;
+    fn transform(&self, event: &MirrorEvent, content: &mut Vec<u8>) -> Result<TransformOutcome>;
+}
+
+/// Passes content through unchanged.
+pub struct NoOp;
+
+impl Transform for NoOp {
+    fn name(&self) -> &str {
+        "noop"
+    }
+
+    fn transform(&self, _event: &MirrorEvent, _content: &mut Vec<u8>) -> Result<TransformOutcome> {
+        Ok(TransformOutcome::Write)
+    }
+}
+
+/// Removes spaces and tabs before line endings. Files that aren't UTF-8 are
+/// written untouched.
+pub struct StripTrailingWhitespace;
+
+impl Transform for StripTrailingWhitespace {
+    fn name(&self) -> &str {
+        "strip-trailing-whitespace"
+    }
+
+    fn transform(&self, _event: &MirrorEvent, content: &mut Vec<u8>) -> Result<TransformOutcome> {
+        let text = match std::str::from_utf8(content) {
+            Ok(text) => text,
+            Err(_) => return Ok(TransformOutcome::Write),
+        };
+
+        let stripped: String = text
+            .split_inclusive('\n')
+            .map(|line| {
+                let (body, ending) = match line.strip_suffix("\r\n") {
+                    Some(body) => (body, "\r\n"),
+                    None => line.strip_suffix('\n').map_or((line, ""), |body| (body, "\n")),
+                };
+                format!("{}{}", body.trim_end_matches([' ', '\t']), ending)
+            })
+            .collect();
+        *content = stripped.into_bytes();
+        Ok(TransformOutcome::Write)
+    }
+}
+
+/// What a redacted secret is replaced with.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Key names (case-insensitive substrings) whose values are secrets.
+const SECRET_KEYS: &[&str] = &[
+    "password", "passwd", "pwd", "secret", "token", "apikey", "api_key", "api-key", "access_key", "access-key",
+    "private_key", "private-key", "credential", "authorization",
+];
+
+/// Prefixes of well-known credential formats, masked wherever they appear.
+const SECRET_PREFIXES: &[&str] = &["AKIA", "ASIA", "ghp_", "gho_", "github_pat_", "glpat-", "xoxb-", "xoxp-", "sk_live_"];
+
+/// Masks secrets: values of `KEY=value` and `key: value` lines whose key
+/// names a secret, tokens in well-known credential formats, and the bodies
+/// of PEM private keys. Files that aren't UTF-8 fail the transform, so they
+/// never reach the mirror unredacted.
+pub struct RedactSecrets;
+
+impl RedactSecrets {
+    /// The line with a secret assignment's value masked, if it is one.
+    fn redact_assignment(line: &str) -> Option<String> {
+        let separator = line.find(['=', ':'])?;
+        let key = line[..separator].trim();
+        let key = key.strip_prefix("export ").unwrap_or(key).trim_matches(['"', '\'']).to_lowercase();
+        let value = &line[separator + 1..];
+        let is_key = !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || "_-.".contains(c));
+        if !is_key || !SECRET_KEYS.iter().any(|secret| key.contains(secret)) || value.trim().is_empty() {
+            return None;
+        }
+        let indent = &value[..value.len() - value.trim_start().len()];
+        Some(format!("{}{}{}", &line[..=separator], indent, REDACTED))
+    }
+
+    /// The line with every token in a known credential format masked.
+    fn redact_tokens(line: &str) -> String {
+        let mut redacted = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(start) = rest.find(|c: char| c.is_ascii_alphanumeric()) {
+            redacted.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+                .unwrap_or(rest.len());
+            let token = &rest[..end];
+            let is_secret = token.len() >= 16 && SECRET_PREFIXES.iter().any(|prefix| token.starts_with(prefix));
+            redacted.push_str(if is_secret { REDACTED } else { token });
+            rest = &rest[end..];
+        }
+        redacted.push_str(rest);
+        redacted
+    }
+}
+
+impl Transform for RedactSecrets {
+    fn name(&self) -> &str {
+        "redact-secrets"
+    }
+
+    fn transform(&self, _event: &MirrorEvent, content: &mut Vec<u8>) -> Result<TransformOutcome> {
+        let text = std::str::from_utf8(content).context("Not UTF-8 text, so it can't be redacted")?;
+
+        let mut in_private_key = false;
+        let redacted: String = text
+            .split_inclusive('\n')
+            .map(|line| {
+                let body = line.trim_end_matches(['\r', '\n']);
+                let ending = &line[body.len()..];
+                let redacted = match body.trim() {
+                    marker if marker.starts_with("-----BEGIN") && marker.ends_with("PRIVATE KEY-----") => {
+                        in_private_key = true;
+                        body.to_string()
+                    }
+                    marker if marker.starts_with("-----END") => {
+                        in_private_key = false;
+                        body.to_string()
+                    }
+                    _ if in_private_key => REDACTED.to_string(),
+                    _ => Self::redact_assignment(body).unwrap_or_else(|| Self::redact_tokens(body)),
+                };
+                format!("{}{}", redacted, ending)
+            })
+            .collect();
+        *content = redacted.into_bytes();
+        Ok(TransformOutcome::Write)
+    }
+}
+
+/// Looks up a built-in transform by name.
+pub fn builtin(name: &str) -> Result<Arc<dyn Transform>> {
+    match name {
+        "noop" => Ok(Arc::new(NoOp)),
+        "strip-trailing-whitespace" => Ok(Arc::new(StripTrailingWhitespace)),
+        "redact-secrets" => Ok(Arc::new(RedactSecrets)),
+        _ => anyhow::bail!("Unknown transform {:?} (available: noop, strip-trailing-whitespace, redact-secrets)", name),
+    }
+}
+
+/// Transforms and the globs (relative to the watch root) they apply to.
+#[derive(Clone, Default)]
+pub struct Transforms {
+    transforms: Vec<(GlobMatcher, Arc<dyn Transform>)>,
+}
+
+impl Transforms {
+    pub fn add(&mut self, pattern: &str, transform: Arc<dyn Transform>) -> Result<()> {
+        let matcher = Glob::new(pattern)
+            .with_context(|| format!("Invalid glob {:?}", pattern))?
+            .compile_matcher();
+        self.transforms.push((matcher, transform));
+        Ok(())
+    }
+
+    /// Adds a built-in transform from a `<glob>=<name>` spec.
+    pub fn add_spec(&mut self, spec: &str) -> Result<()> {
+        let (pattern, name) = spec
+            .split_once('=')
+            .with_context(|| format!("Expected <glob>=<transform>, got {:?}", spec))?;
+        self.add(pattern, builtin(name)?)
+    }
+
+    pub fn applies_to(&self, relative: &Path) -> bool {
+        self.transforms.iter().any(|(matcher, _)| matcher.is_match(relative))
+    }
+
+    pub fn run(&self, event: &MirrorEvent, content: &mut Vec<u8>) -> Result<TransformOutcome> {
+        for (matcher, transform) in &self.transforms {
+            if !matcher.is_match(event.relative) {
+                continue;
+            }
+            let outcome = transform
+                .transform(event, content)
+                .with_context(|| format!("Transform {} failed on {:?}", transform.name(), event.relative))?;
+            if outcome != TransformOutcome::Write {
+                return Ok(outcome);
+            }
+        }
+        Ok(TransformOutcome::Write)
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    collections::VecDeque,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{mirror::Operation, units::format_duration};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// How much a `--trickle` sync may copy per minute.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrickleLimit {
+    pub files: Option<u64>,
+    pub bytes: Option<u64>,
+}
+
+/// Hands out a sync's operations a minute's worth at a time, so a big
+/// initial sync is spread over hours instead of saturating the disk. Only
+/// file copies count against the limit; directories, metadata and deletes
+/// go along with them. Operations the source has since overtaken are
+/// dropped rather than handed out.
+pub struct Trickle {
+    root: PathBuf,
+    limit: TrickleLimit,
+    pending: VecDeque<Operation>,
+    total_files: u64,
+    total_bytes: u64,
+    copied_files: u64,
+    copied_bytes: u64,
+    window_start: Option<Instant>,
+    window_files: u64,
+    window_bytes: u64,
+}
+
+fn copy_size(root: &Path, operation: &Operation) -> Option<u64> {
+    match operation {
+        Operation::Data { path } => Some(fs::metadata(root.join(path)).map_or(0, |metadata| metadata.len())),
+        _ => None,
+    }
+}
+
+/// Whether a planned operation still fits the source, which may have changed
+/// in the hours since the plan. A stale one is left to the live event for
+/// whatever overtook it: a delete of a path that's back, say, or a copy of
+/// one that's gone.
+fn is_current(root: &Path, operation: &Operation) -> bool {
+    match operation {
+        Operation::Delete { path } => fs::symlink_metadata(root.join(path)).is_err(),
+        Operation::Create { path } | Operation::Data { path } | Operation::Metadata { path } => {
+            fs::symlink_metadata(root.join(path)).is_ok()
+        }
+        Operation::Rename { new_path, .. } => fs::symlink_metadata(root.join(new_path)).is_ok(),
+    }
+}
+
+impl Trickle {
+    pub fn new(root: &Path, operations: Vec<Operation>, limit: TrickleLimit) -> Self {
+        let sizes: Vec<_> = operations.iter().filter_map(|operation| copy_size(root, operation)).collect();
+
+        Trickle {
+            root: root.to_path_buf(),
+            limit,
+            pending: operations.into(),
+            total_files: sizes.len() as u64,
+            total_bytes: sizes.iter().sum(),
+            copied_files: 0,
+            copied_bytes: 0,
+            window_start: None,
+            window_files: 0,
+            window_bytes: 0,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// The operations that fit in what's left of this minute's allowance.
+    /// A file bigger than the whole allowance goes alone in a fresh minute.
+    pub fn due(&mut self) -> Vec<Operation> {
+        if self.window_start.is_none_or(|start| start.elapsed() >= WINDOW) {
+            self.window_start = Some(Instant::now());
+            self.window_files = 0;
+            self.window_bytes = 0;
+        }
+
+        let mut due = Vec::new();
+        while let Some(operation) = self.pending.front() {
+            if !is_current(&self.root, operation) {
+                self.pending.pop_front();
+                continue;
+            }
+            if let Some(size) = copy_size(&self.root, operation) {
+                let fresh = self.window_files == 0;
+                let over_files = self.limit.files.is_some_and(|files| self.window_files >= files);
+                let over_bytes = self.limit.bytes.is_some_and(|bytes| self.window_bytes + size > bytes);
+                if over_files || (over_bytes && !fresh) {
+                    break;
+                }
+                self.window_files += 1;
+                self.window_bytes += size;
+                self.copied_files += 1;
+                self.copied_bytes += size;
+            }
+            due.extend(self.pending.pop_front());
+        }
+        due
+    }
+
+    /// Time left at the configured rate.
+    pub fn remaining(&self) -> Duration {
+        let minutes = |left: u64, per_minute: Option<u64>| match per_minute {
+            Some(per_minute) if per_minute > 0 => left as f64 / per_minute as f64,
+            _ => 0.0,
+        };
+        let files = minutes(self.total_files.saturating_sub(self.copied_files), self.limit.files);
+        let bytes = minutes(self.total_bytes.saturating_sub(self.copied_bytes), self.limit.bytes);
+        Duration::from_secs_f64(files.max(bytes) * 60.0)
+    }
+}
+
+impl fmt::Display for Trickle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} files, {}/{} bytes, about {} left",
+            self.copied_files,
+            self.total_files,
+            self.copied_bytes,
+            self.total_bytes,
+            format_duration(self.remaining())
+        )
+    }
+}
@@ -0,0 +1,59 @@
+use anyhow::Result;
+use std::time::Duration;
+
+fn split_number(value: &str) -> (&str, &str) {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    value.split_at(split)
+}
+
+pub fn parse_size(value: &str) -> Result<u64> {
+    let (number, suffix) = split_number(value);
+    let number: u64 = number.parse()?;
+
+    let multiplier: u64 = match suffix.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => anyhow::bail!("Unknown size suffix {:?}", suffix),
+    };
+
+    Ok(number * multiplier)
+}
+
+/// Parses durations such as `500ms`, `2s`, `1.5m`, `3h` or `1d`; a bare number is seconds.
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let (number, suffix) = split_number(value);
+    let number: f64 = number.parse()?;
+
+    let seconds = match suffix {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        "d" => number * 86400.0,
+        _ => anyhow::bail!("Unknown duration suffix {:?}", suffix),
+    };
+
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+/// Formats a duration as `1d2h3m4s`, leaving out zero parts.
+pub fn format_duration(duration: Duration) -> String {
+    let mut seconds = duration.as_secs();
+    let mut formatted = String::new();
+    for (unit, size) in [("d", 86400), ("h", 3600), ("m", 60)] {
+        if seconds >= size {
+            formatted.push_str(&format!("{}{}", seconds / size, unit));
+            seconds %= size;
+        }
+    }
+    if seconds > 0 || formatted.is_empty() {
+        formatted.push_str(&format!("{}s", seconds));
+    }
+    formatted
+}
@@ -0,0 +1,122 @@
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::report;
+
+/// Git's own metadata directory, which `--exclude-vcs` never mirrors.
+const GIT_DIR: &str = ".git";
+const GITIGNORE: &str = ".gitignore";
+
+/// The paths git would ignore under a watch root (`--exclude-vcs`): every
+/// `.gitignore` on the way down, then `.git/info/exclude`, then the global
+/// excludes file (`core.excludesFile`), with git's precedence. `.gitignore`
+/// files are read as directories are first checked and reread after they
+/// change.
+pub struct VcsIgnore {
+    root: PathBuf,
+    repository: Gitignore,
+    global: Gitignore,
+    directories: Mutex<HashMap<PathBuf, Gitignore>>,
+}
+
+impl VcsIgnore {
+    pub fn new(root: &Path) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        if let Some(error) = builder.add(root.join(GIT_DIR).join("info").join("exclude")) {
+            report_rule_error(&error);
+        }
+        let repository = builder.build().unwrap_or_else(|error| {
+            report_rule_error(&error);
+            Gitignore::empty()
+        });
+
+        let (global, error) = Gitignore::global();
+        if let Some(error) = error {
+            report_rule_error(&error);
+        }
+
+        VcsIgnore {
+            root: root.to_path_buf(),
+            repository,
+            global,
+            directories: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `path` or one of its directories is ignored. A directory's
+    /// children can't be re-included once it is, just as in git.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        if relative.components().any(|component| component.as_os_str() == GIT_DIR) {
+            return true;
+        }
+
+        let mut directories = self.directories.lock().unwrap();
+        path.ancestors()
+            .take_while(|ancestor| *ancestor != self.root)
+            .any(|ancestor| {
+                let is_dir = ancestor != path || path.is_dir();
+                self.matches(&mut directories, ancestor, is_dir)
+            })
+    }
+
+    /// Drops the cached rules of a `.gitignore` that changed.
+    pub fn changed(&self, path: &Path) {
+        if path.file_name().is_some_and(|name| name == GITIGNORE) {
+            if let Some(directory) = path.parent() {
+                self.directories.lock().unwrap().remove(directory);
+            }
+        }
+    }
+
+    /// The first rule that decides `path`, deepest `.gitignore` first.
+    fn matches(&self, directories: &mut HashMap<PathBuf, Gitignore>, path: &Path, is_dir: bool) -> bool {
+        for directory in path.ancestors().skip(1) {
+            let rules = directories
+                .entry(directory.to_path_buf())
+                .or_insert_with(|| load(directory));
+            match rules.matched(path, is_dir) {
+                Match::None => {}
+                decided => return decided.is_ignore(),
+            }
+            if directory == self.root {
+                break;
+            }
+        }
+
+        match self.repository.matched(path, is_dir) {
+            Match::None => self.global.matched(path, is_dir).is_ignore(),
+            decided => decided.is_ignore(),
+        }
+    }
+}
+
+fn load(directory: &Path) -> Gitignore {
+    let path = directory.join(GITIGNORE);
+    if !path.is_file() {
+        return Gitignore::empty();
+    }
+
+    let (rules, error) = Gitignore::new(&path);
+    if let Some(error) = error {
+        report_rule_error(&error);
+    }
+    rules
+}
+
+fn report_rule_error(error: &ignore::Error) {
+    if !error.is_io() {
+        eprintln!("Ignoring bad VCS ignore rule: {}", error);
+    } else {
+        report::debug(format_args!("VCS ignore rules unavailable: {}", error));
+    }
+}
@@ -0,0 +1,313 @@
+use clap::ValueEnum;
+use notify::{
+    event::{CreateKind, DataChange, MetadataKind, ModifyKind, RemoveKind},
+    Config, ErrorKind, Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::{
+    fmt,
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, SystemTime},
+};
+use walkdir::WalkDir;
+
+/// How often the tree is rescanned when native watching falls back to polling.
+pub const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Which of notify's watchers `--watcher-backend` asks for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum WatcherBackend {
+    /// The platform's native watcher, falling back to rescanning when it runs out of watches
+    #[default]
+    Auto,
+    /// Linux and Android
+    Inotify,
+    /// macOS
+    Fsevents,
+    /// FreeBSD, OpenBSD, NetBSD and DragonFly
+    Kqueue,
+    /// Windows ReadDirectoryChangesW
+    Windows,
+    /// notify's PollWatcher, comparing metadata every --poll-interval (default 10s)
+    Poll,
+}
+
+impl fmt::Display for WatcherBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            WatcherBackend::Auto => "auto",
+            WatcherBackend::Inotify => "inotify",
+            WatcherBackend::Fsevents => "FSEvents",
+            WatcherBackend::Kqueue => "kqueue",
+            WatcherBackend::Windows => "ReadDirectoryChangesW",
+            WatcherBackend::Poll => "notify's PollWatcher",
+        };
+        f.write_str(name)
+    }
+}
+
+impl WatcherBackend {
+    /// The native backend `Auto` picks on this platform.
+    pub fn native() -> Self {
+        match () {
+            _ if cfg!(any(target_os = "linux", target_os = "android")) => WatcherBackend::Inotify,
+            _ if cfg!(target_os = "macos") => WatcherBackend::Fsevents,
+            _ if cfg!(target_os = "windows") => WatcherBackend::Windows,
+            _ if cfg!(any(
+                target_os = "freebsd",
+                target_os = "openbsd",
+                target_os = "netbsd",
+                target_os = "dragonfly"
+            )) => WatcherBackend::Kqueue,
+            _ => WatcherBackend::Poll,
+        }
+    }
+
+    /// What the backend is known to miss or run out of, logged when it starts.
+    pub fn limitations(self) -> &'static str {
+        match self {
+            WatcherBackend::Auto => WatcherBackend::native().limitations(),
+            WatcherBackend::Inotify => {
+                "one watch per directory, up to fs.inotify.max_user_watches; changes made on NFS/SMB by other hosts aren't seen"
+            }
+            WatcherBackend::Fsevents => {
+                "events are batched by the OS and can arrive late or coalesced; changes on network volumes aren't seen"
+            }
+            WatcherBackend::Kqueue => {
+                "one open file per watched file and directory, up to the open files limit; new files in a directory are found by rescanning it"
+            }
+            WatcherBackend::Windows => {
+                "a fixed buffer per watch, which a burst of changes can overflow and lose events from"
+            }
+            WatcherBackend::Poll => {
+                "changes are only found each interval, by walking the whole tree; renames show up as a delete plus a create"
+            }
+        }
+    }
+
+    fn unsupported(self) -> notify::Error {
+        notify::Error::generic(&format!("The {} watcher backend isn't available on this platform", self))
+    }
+
+    /// Starts this backend's watcher sending to `sender`.
+    fn start(self, sender: Sender<notify::Result<Event>>, poll_interval: Duration) -> notify::Result<Box<dyn Watcher + Send>> {
+        let config = Config::default();
+        match self {
+            WatcherBackend::Auto => Ok(Box::new(RecommendedWatcher::new(sender, config)?)),
+            WatcherBackend::Poll => Ok(Box::new(PollWatcher::new(sender, config.with_poll_interval(poll_interval))?)),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            WatcherBackend::Inotify => Ok(Box::new(notify::INotifyWatcher::new(sender, config)?)),
+            #[cfg(target_os = "macos")]
+            WatcherBackend::Fsevents => Ok(Box::new(notify::FsEventWatcher::new(sender, config)?)),
+            #[cfg(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+            WatcherBackend::Kqueue => Ok(Box::new(notify::KqueueWatcher::new(sender, config)?)),
+            #[cfg(target_os = "windows")]
+            WatcherBackend::Windows => Ok(Box::new(notify::ReadDirectoryChangesWatcher::new(sender, config)?)),
+            #[allow(unreachable_patterns)]
+            backend => Err(backend.unsupported()),
+        }
+    }
+}
+
+/// Keeps a watch running until dropped.
+pub enum WatchHandle {
+    Native(Box<dyn Watcher + Send>),
+    Poll(Poller),
+}
+
+impl WatchHandle {
+    /// Adds a recursive watch on `path`, such as a filesystem mounted under
+    /// the root after it was first watched. Polling already rescans it.
+    pub fn add(&mut self, path: &Path) -> notify::Result<()> {
+        match self {
+            WatchHandle::Native(watcher) => watcher.watch(path, RecursiveMode::Recursive),
+            WatchHandle::Poll(_) => Ok(()),
+        }
+    }
+}
+
+/// Watches `root` with `backend`, or by rescanning it every `poll_interval`
+/// when one is given and no backend is. Native watching picked by `Auto`
+/// that runs out of watches (inotify's `max_user_watches`) falls back to
+/// rescanning; a backend asked for by name fails instead.
+pub fn watch(
+    root: &Path,
+    sender: Sender<notify::Result<Event>>,
+    backend: WatcherBackend,
+    poll_interval: Option<Duration>,
+) -> notify::Result<WatchHandle> {
+    match (backend, poll_interval) {
+        (WatcherBackend::Auto, Some(interval)) => return Ok(WatchHandle::Poll(Poller::start(root, sender, interval))),
+        (WatcherBackend::Auto | WatcherBackend::Poll, _) | (_, None) => {}
+        (_, Some(_)) => {
+            return Err(notify::Error::generic(&format!("--poll-interval doesn't apply to the {} backend", backend)))
+        }
+    }
+
+    let mut watcher = backend.start(sender.clone(), poll_interval.unwrap_or(FALLBACK_POLL_INTERVAL))?;
+    match watcher.watch(root, RecursiveMode::Recursive) {
+        Ok(()) => {
+            let active = match backend {
+                WatcherBackend::Auto => WatcherBackend::native(),
+                backend => backend,
+            };
+            println!("Watching {:?} with {}", root, active);
+            println!("Watcher limitations: {}", active.limitations());
+            Ok(WatchHandle::Native(watcher))
+        }
+        Err(error) if backend == WatcherBackend::Auto && matches!(error.kind, ErrorKind::MaxFilesWatch) => {
+            drop(watcher);
+            eprintln!("{}", watch_limit_hint(root));
+            eprintln!("Falling back to polling every {:?}", FALLBACK_POLL_INTERVAL);
+            Ok(WatchHandle::Poll(Poller::start(root, sender, FALLBACK_POLL_INTERVAL)))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+const MAX_USER_WATCHES: &str = "/proc/sys/fs/inotify/max_user_watches";
+
+/// Explains a failed recursive watch: on Linux the "No space left on device"
+/// behind it is inotify's per-user watch limit, and the tree needs about one
+/// watch per directory.
+fn watch_limit_hint(root: &Path) -> String {
+    let directories = WalkDir::new(root)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_dir())
+        .count();
+    let limit = fs::read_to_string(MAX_USER_WATCHES)
+        .ok()
+        .and_then(|limit| limit.trim().parse::<usize>().ok());
+
+    let mut hint = format!(
+        "The OS file watch limit was reached watching {:?}, which has {} directories and needs about one watch each",
+        root, directories
+    );
+    if let Some(limit) = limit {
+        // Leave room for the tree to grow and for other programs' watches.
+        let suggested = (directories * 2).max(limit * 2);
+        hint.push_str(&format!(
+            ".\nfs.inotify.max_user_watches is {} (shared by all of this user's processes). To raise it:\n\
+             \n    sudo sysctl fs.inotify.max_user_watches={}\n\
+             \nand to keep it across reboots:\n\
+             \n    echo fs.inotify.max_user_watches={} | sudo tee /etc/sysctl.d/60-rustsync.conf",
+            limit, suggested, suggested
+        ));
+    }
+    hint
+}
+
+#[derive(Clone, PartialEq)]
+struct Stamp {
+    is_dir: bool,
+    len: u64,
+    modified: Option<SystemTime>,
+    permissions: fs::Permissions,
+}
+
+fn scan(root: &Path) -> HashMap<PathBuf, Stamp> {
+    WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let stamp = Stamp {
+                is_dir: metadata.is_dir(),
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+                permissions: metadata.permissions(),
+            };
+            Some((entry.into_path(), stamp))
+        })
+        .collect()
+}
+
+/// The events that turn `old` into `new`: removals (only the topmost of a
+/// removed tree, since deleting it takes the rest), then creations parents
+/// first, then changes. Directory mtimes move with their children and are
+/// left out.
+fn changes(old: &HashMap<PathBuf, Stamp>, new: &HashMap<PathBuf, Stamp>) -> Vec<Event> {
+    let event = |kind, path: &Path| Event::new(kind).add_path(path.to_path_buf());
+
+    let removed: HashSet<&Path> = old
+        .iter()
+        .filter(|(path, stamp)| new.get(*path).is_none_or(|now| now.is_dir != stamp.is_dir))
+        .map(|(path, _)| path.as_path())
+        .collect();
+    let mut topmost: Vec<_> = removed
+        .iter()
+        .filter(|path| !path.ancestors().skip(1).any(|ancestor| removed.contains(ancestor)))
+        .collect();
+    topmost.sort();
+
+    let mut created: Vec<_> = new
+        .iter()
+        .filter(|(path, stamp)| old.get(*path).is_none_or(|before| before.is_dir != stamp.is_dir))
+        .collect();
+    created.sort_by_key(|(path, _)| *path);
+
+    let mut modified: Vec<_> = new
+        .iter()
+        .filter_map(|(path, stamp)| Some((path, old.get(path)?, stamp)))
+        .filter(|(_, before, now)| before.is_dir == now.is_dir && before != now)
+        .collect();
+    modified.sort_by_key(|(path, ..)| *path);
+
+    let mut events = Vec::new();
+    for path in topmost {
+        events.push(event(EventKind::Remove(RemoveKind::Any), path));
+    }
+    for (path, stamp) in created {
+        let kind = match stamp.is_dir {
+            true => CreateKind::Folder,
+            false => CreateKind::File,
+        };
+        events.push(event(EventKind::Create(kind), path));
+    }
+    for (path, before, now) in modified {
+        if !now.is_dir && (before.len != now.len || before.modified != now.modified) {
+            events.push(event(EventKind::Modify(ModifyKind::Data(DataChange::Any)), path));
+        }
+        if before.permissions != now.permissions {
+            events.push(event(EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any)), path));
+        }
+    }
+    events
+}
+
+/// Finds changes by rescanning the tree on an interval and diffing each scan
+/// against the last, for filesystems where native events don't arrive.
+/// Renames show up as a removal plus a creation.
+pub struct Poller {
+    _stop: Sender<()>,
+}
+
+impl Poller {
+    pub fn start(root: &Path, sender: Sender<notify::Result<Event>>, interval: Duration) -> Self {
+        let root = root.to_path_buf();
+        let (stop, stopped) = channel::<()>();
+        let mut snapshot = scan(&root);
+        println!("Polling {:?} every {:?}", root, interval);
+
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let current = scan(&root);
+                for event in changes(&snapshot, &current) {
+                    if sender.send(Ok(event)).is_err() {
+                        return;
+                    }
+                }
+                snapshot = current;
+            }
+        });
+
+        Poller { _stop: stop }
+    }
+}
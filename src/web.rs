@@ -0,0 +1,145 @@
+use anyhow::{Context, Result};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::mpsc::{channel, Receiver, Sender},
+    time::Duration,
+};
+
+use crate::{
+    control::{Command, ControlRequest},
+    report,
+};
+
+const PAGE: &str = include_str!("web.html");
+
+/// Header the page sends with every command. Browsers won't send it
+/// cross-origin without a CORS preflight, which isn't answered, so other
+/// sites can't pause or resync through a visitor's browser.
+const COMMAND_HEADER: &str = "x-rustsync";
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: String) -> Self {
+        Response { status, content_type: "application/json", body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response::json(status, serde_json::json!({ "error": message }).to_string())
+    }
+}
+
+/// Runs `command` on the event loop's thread and waits for its answer.
+fn run(sender: &Sender<ControlRequest>, command: Command) -> Response {
+    let (reply, response) = channel();
+    if sender.send(ControlRequest { command, reply, origin: "web UI" }).is_err() {
+        return Response::error(503, "shutting down");
+    }
+    match response.recv() {
+        Ok(body) => Response::json(200, body),
+        Err(_) => Response::error(503, "shutting down"),
+    }
+}
+
+/// Whether `host`, a request's Host header, names the server by the address
+/// it's bound to or as localhost. Anything else may be a DNS name rebound to
+/// it, which would make another site same-origin with the page.
+fn known_host(host: &str, bound: SocketAddr) -> bool {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().is_ok_and(|ip| ip == bound.ip() || bound.ip().is_unspecified())
+}
+
+fn respond(sender: &Sender<ControlRequest>, method: &str, path: &str, from_page: bool) -> Response {
+    match (method, path) {
+        ("GET", "/") => Response { status: 200, content_type: "text/html; charset=utf-8", body: PAGE.to_string() },
+        ("GET", "/api/status") => run(sender, Command::Status),
+        ("POST", _) if !from_page => Response::error(403, "missing X-Rustsync header"),
+        // Only what the page offers; the rest stays with the control socket.
+        ("POST", path) => match path.strip_prefix("/api/").and_then(Command::parse) {
+            Some(command @ (Command::Pause | Command::Resume | Command::Resync)) => run(sender, command),
+            _ => Response::error(404, "not found"),
+        },
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn answer(sender: &Sender<ControlRequest>, stream: TcpStream, bound: SocketAddr) -> std::io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let (mut from_page, mut host_known) = (false, false);
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            from_page |= name.trim().eq_ignore_ascii_case(COMMAND_HEADER);
+            host_known |= name.trim().eq_ignore_ascii_case("host") && known_host(value.trim(), bound);
+        }
+    }
+
+    // "POST /api/pause HTTP/1.1"; query strings are ignored.
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("GET");
+    let path = parts.next().unwrap_or("/");
+    let path = path.split('?').next().unwrap_or(path);
+    let response = match host_known {
+        true => respond(sender, method, path, from_page),
+        false => Response::error(403, "unknown Host"),
+    };
+    let reason = match response.status {
+        200 => "OK",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Service Unavailable",
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )
+}
+
+/// Serves the `--web-addr` dashboard on `address` from a background thread,
+/// one connection at a time, forwarding its status requests and commands to
+/// the returned receiver as the control socket does. Returns the address
+/// bound, for port 0.
+pub fn serve(address: SocketAddr) -> Result<(SocketAddr, Receiver<ControlRequest>)> {
+    let listener = TcpListener::bind(address).with_context(|| format!("Failed to bind --web-addr {}", address))?;
+    let bound = listener.local_addr()?;
+    let (sender, receiver) = channel();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    eprintln!("Web UI error: {}", error);
+                    continue;
+                }
+            };
+            // A client that never sends its request mustn't block others.
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            if let Err(error) = answer(&sender, stream, bound) {
+                report::debug(format_args!("Web UI request failed: {}", error));
+            }
+        }
+    });
+
+    Ok((bound, receiver))
+}
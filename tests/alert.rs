@@ -0,0 +1,53 @@
+#![cfg(unix)]
+
+use std::{
+    ffi::OsStr,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::mpsc::channel,
+    thread,
+    time::Duration,
+};
+
+use rustsync::{
+    alert::WebhookSink,
+    report::{self, ErrorKind},
+};
+
+#[test]
+fn finishing_a_webhook_sink_delivers_what_it_holds() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+        sender.send(body).unwrap();
+    });
+
+    // Far longer than the test, so only finishing can deliver it.
+    report::add_sink(Box::new(WebhookSink::new(url, Duration::from_secs(3600))));
+    let path = Path::new(OsStr::from_bytes(b"bad\xff"));
+    report::error(ErrorKind::NonUtf8, path, "Skipped non-UTF-8 path");
+    report::finish_sinks();
+
+    let body: serde_json::Value = serde_json::from_slice(&receiver.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+    assert_eq!(body["errors"][0]["kind"], "non_utf8");
+    assert_eq!(body["errors"][0]["path"], "\u{0}626164ff");
+}
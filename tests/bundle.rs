@@ -0,0 +1,39 @@
+use std::{fs, path::PathBuf};
+
+use rustsync::{bundle::apply_bundle, hash::ChecksumAlgorithm, manifest::Manifest, report};
+
+#[test]
+fn bundle_files_are_verified_against_the_manifest() {
+    let source = tempfile::tempdir().unwrap();
+    let bundle = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("dir")).unwrap();
+    for (name, contents) in [("dir/new", "new"), ("changed", "v2"), ("unchanged", "same"), ("lost", "lost"), ("bad", "good")] {
+        fs::write(source.path().join(name), contents).unwrap();
+    }
+    let manifest = Manifest::build(source.path(), ChecksumAlgorithm::Blake3).unwrap();
+
+    // The bundle carries what changed since the destination was last updated,
+    // one file of it corrupted on the way, and one left behind.
+    fs::create_dir(bundle.path().join("dir")).unwrap();
+    fs::write(bundle.path().join("dir/new"), "new").unwrap();
+    fs::write(bundle.path().join("changed"), "v2").unwrap();
+    fs::write(bundle.path().join("bad"), "corrupt").unwrap();
+    fs::write(destination.path().join("changed"), "v1").unwrap();
+    fs::write(destination.path().join("unchanged"), "same").unwrap();
+    let errors = report::error_counts().values().sum::<u64>();
+
+    let summary = apply_bundle(&manifest, bundle.path(), destination.path()).unwrap();
+    assert_eq!(fs::read_to_string(destination.path().join("dir/new")).unwrap(), "new");
+    assert_eq!(fs::read_to_string(destination.path().join("changed")).unwrap(), "v2");
+    assert!(!destination.path().join("bad").exists());
+    assert_eq!((summary.copied, summary.unchanged), (2, 1));
+    assert_eq!(summary.missing, vec![PathBuf::from("lost")]);
+    assert_eq!(summary.mismatched, vec![PathBuf::from("bad")]);
+    assert!(!summary.is_complete());
+    assert_eq!(report::error_counts().values().sum::<u64>(), errors + 1);
+
+    // Applying it again copies nothing.
+    let again = apply_bundle(&manifest, bundle.path(), destination.path()).unwrap();
+    assert_eq!((again.copied, again.unchanged), (0, 3));
+}
@@ -0,0 +1,152 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use rustsync::{
+    cas::{checkout, load_index, CasStore, Entry},
+    hash::ChecksumAlgorithm,
+    mirror::{apply_event, Mirror, Operation, Options},
+    reconcile::reconcile,
+};
+
+fn cas_mirror(source: &Path, store: &Path) -> Mirror {
+    let mut mirror = Mirror::new(fs::canonicalize(source).unwrap(), store.to_path_buf(), Options::default());
+    mirror.backend = Some(Box::new(CasStore::open(store, ChecksumAlgorithm::default()).unwrap()));
+    mirror
+}
+
+fn objects(store: &Path) -> usize {
+    walkdir::WalkDir::new(store.join("objects"))
+        .into_iter()
+        .filter(|entry| entry.as_ref().unwrap().file_type().is_file())
+        .count()
+}
+
+#[test]
+fn identical_files_are_stored_once() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("dir")).unwrap();
+    fs::write(source.path().join("a"), b"same").unwrap();
+    fs::write(source.path().join("dir/b"), b"same").unwrap();
+    fs::write(source.path().join("c"), b"other").unwrap();
+
+    reconcile(&cas_mirror(source.path(), store.path()));
+
+    assert_eq!(objects(store.path()), 2);
+    let index = load_index(store.path()).unwrap();
+    let hash = |path: &str| match &index[Path::new(path)] {
+        Entry::File { hash, .. } => hash.clone(),
+        entry => panic!("{} is {:?}", path, entry),
+    };
+    assert_eq!(hash("a"), hash("dir/b"));
+    assert_ne!(hash("a"), hash("c"));
+    assert_eq!(index[Path::new("dir")], Entry::Dir { mode: 0o755 });
+}
+
+#[test]
+fn checkout_reproduces_the_mirrored_tree() {
+    let source = tempfile::tempdir().unwrap();
+    let store = tempfile::tempdir().unwrap();
+    let tree = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("dir")).unwrap();
+    fs::write(source.path().join("dir/old"), b"renamed").unwrap();
+    fs::write(source.path().join("gone"), b"deleted").unwrap();
+    fs::write(source.path().join("script"), b"#!/bin/sh\n").unwrap();
+    fs::set_permissions(source.path().join("script"), fs::Permissions::from_mode(0o750)).unwrap();
+    std::os::unix::fs::symlink("script", source.path().join("link")).unwrap();
+
+    let mirror = cas_mirror(source.path(), store.path());
+    reconcile(&mirror);
+    fs::rename(source.path().join("dir/old"), source.path().join("dir/new")).unwrap();
+    apply_event(
+        &mirror,
+        &Operation::Rename {
+            path: PathBuf::from("dir/old"),
+            new_path: PathBuf::from("dir/new"),
+        },
+    );
+    fs::remove_file(source.path().join("gone")).unwrap();
+    drop(mirror);
+
+    // Reopening compacts the index; the deletion is found by a full sync.
+    reconcile(&cas_mirror(source.path(), store.path()));
+
+    let destination = tree.path().join("checkout");
+    assert_eq!(checkout(store.path(), &destination).unwrap(), 2);
+    assert_eq!(fs::read(destination.join("dir/new")).unwrap(), b"renamed");
+    assert!(!destination.join("dir/old").exists());
+    assert!(!destination.join("gone").exists());
+    assert_eq!(fs::read_link(destination.join("link")).unwrap(), Path::new("script"));
+
+    let script = fs::metadata(destination.join("script")).unwrap();
+    let original = fs::metadata(source.path().join("script")).unwrap();
+    assert_eq!(script.permissions().mode() & 0o7777, 0o750);
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&script).unix_seconds(),
+        filetime::FileTime::from_last_modification_time(&original).unix_seconds()
+    );
+}
+
+#[test]
+fn checkout_refuses_a_damaged_index() {
+    let tree = tempfile::tempdir().unwrap();
+    let destination = tree.path().join("checkout");
+    let file = |path: &str, hash: &str| {
+        format!(
+            r#"{{"op":"put","path":"{}","entry":{{"kind":"file","hash":"{}","size":1,"mtime":0,"mode":420}}}}"#,
+            path, hash
+        )
+    };
+
+    for line in [
+        file("../escaped", "abcdef"),
+        file("/absolute", "abcdef"),
+        file("", "abcdef"),
+        file("short", "a"),
+        file("odd", "zz/../../x"),
+    ] {
+        let store = tempfile::tempdir().unwrap();
+        fs::write(store.path().join("index"), line + "\n").unwrap();
+        assert!(checkout(store.path(), &destination).is_err());
+        assert!(!destination.exists());
+        assert!(!tree.path().join("escaped").exists());
+    }
+}
+
+#[test]
+fn checkout_never_writes_through_an_indexed_symlink() {
+    let tree = tempfile::tempdir().unwrap();
+    let store = tree.path().join("store");
+    let outside = tree.path().join("outside");
+    fs::create_dir_all(store.join("objects/ab")).unwrap();
+    fs::create_dir(&outside).unwrap();
+    fs::write(store.join("objects/ab/abcdef"), b"payload").unwrap();
+    let index = format!(
+        "{}\n{}\n",
+        format_args!(
+            r#"{{"op":"put","path":"a","entry":{{"kind":"symlink","target":{:?}}}}}"#,
+            outside.to_str().unwrap()
+        ),
+        r#"{"op":"put","path":"a/x","entry":{"kind":"file","hash":"abcdef","size":7,"mtime":0,"mode":420}}"#
+    );
+    fs::write(store.join("index"), index).unwrap();
+
+    assert!(checkout(&store, &tree.path().join("checkout")).is_err());
+    assert!(!outside.join("x").exists());
+}
+
+#[test]
+fn only_a_torn_last_index_line_is_skipped() {
+    let store = tempfile::tempdir().unwrap();
+    let good = r#"{"op":"put","path":"dir","entry":{"kind":"dir","mode":493}}"#;
+
+    fs::write(store.path().join("index"), format!("{}\n{{\"op\":\"pu", good)).unwrap();
+    assert_eq!(load_index(store.path()).unwrap().len(), 1);
+
+    fs::write(store.path().join("index"), format!("{{\"op\":\"pu\n{}\n", good)).unwrap();
+    assert!(load_index(store.path()).is_err());
+    assert!(CasStore::open(store.path(), ChecksumAlgorithm::default()).is_err());
+}
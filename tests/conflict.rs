@@ -0,0 +1,101 @@
+use std::fs;
+
+use rustsync::{
+    conflict::{ConflictLog, ConflictPolicy},
+    mirror::{apply_event, Mirror, Operation, Options},
+};
+
+fn mirror(source: &std::path::Path, destination: &std::path::Path, policy: ConflictPolicy) -> Mirror {
+    let mut mirror = Mirror::new(source.to_path_buf(), destination.to_path_buf(), Options::default());
+    mirror.conflicts = Some(ConflictLog::open(destination, policy).unwrap());
+    mirror
+}
+
+fn conflicts(destination: &std::path::Path) -> Vec<serde_json::Value> {
+    fs::read_to_string(destination.join(".rustsync/conflicts.log"))
+        .unwrap_or_default()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn changed_destination_files_are_logged_and_resolved() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    for name in ["skipped", "backed-up", "untouched"] {
+        fs::write(source.path().join(name), b"v1").unwrap();
+    }
+
+    let skipping = mirror(source.path(), destination.path(), ConflictPolicy::Skip);
+    for name in ["skipped", "backed-up", "untouched"] {
+        apply_event(&skipping, &Operation::Create { path: name.into() });
+    }
+    for name in ["skipped", "backed-up", "untouched"] {
+        fs::write(source.path().join(name), b"v2").unwrap();
+    }
+    fs::write(destination.path().join("skipped"), b"edited in the mirror").unwrap();
+    fs::write(destination.path().join("backed-up"), b"edited in the mirror").unwrap();
+
+    apply_event(&skipping, &Operation::Data { path: "skipped".into() });
+    apply_event(&skipping, &Operation::Data { path: "untouched".into() });
+    assert_eq!(fs::read(destination.path().join("skipped")).unwrap(), b"edited in the mirror");
+    assert_eq!(fs::read(destination.path().join("untouched")).unwrap(), b"v2");
+    drop(skipping);
+
+    // What was written is remembered across runs.
+    let backing_up = mirror(source.path(), destination.path(), ConflictPolicy::Backup);
+    apply_event(&backing_up, &Operation::Data { path: "backed-up".into() });
+    assert_eq!(fs::read(destination.path().join("backed-up")).unwrap(), b"v2");
+
+    let logged = conflicts(destination.path());
+    assert_eq!(logged.len(), 2);
+    assert_eq!(logged[0]["resolution"], "skip");
+    assert_eq!(logged[0]["expected"]["size"], 2);
+    assert_eq!(logged[0]["actual"]["size"], 20);
+    assert_eq!(logged[1]["resolution"], "backup");
+    let backup = logged[1]["backup"].as_str().unwrap();
+    assert_eq!(fs::read(backup).unwrap(), b"edited in the mirror");
+}
+
+#[test]
+fn conflicts_on_non_utf8_names_are_logged() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::PathBuf};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let name = PathBuf::from(OsStr::from_bytes(b"caf\xe9"));
+    fs::write(source.path().join(&name), b"v1").unwrap();
+
+    let backing_up = mirror(source.path(), destination.path(), ConflictPolicy::Backup);
+    apply_event(&backing_up, &Operation::Create { path: name.clone() });
+    fs::write(source.path().join(&name), b"v2").unwrap();
+    fs::write(destination.path().join(&name), b"edited in the mirror").unwrap();
+    apply_event(&backing_up, &Operation::Data { path: name.clone() });
+
+    let logged = conflicts(destination.path());
+    assert_eq!(logged.len(), 1);
+    let path = rustsync::pathbytes::decode(logged[0]["path"].as_str().unwrap()).unwrap();
+    assert_eq!(path, destination.path().join(&name));
+    let backup = rustsync::pathbytes::decode(logged[0]["backup"].as_str().unwrap()).unwrap();
+    assert_eq!(fs::read(backup).unwrap(), b"edited in the mirror");
+}
+
+#[test]
+fn the_written_index_reuses_copy_hashes_and_stays_compact() {
+    let destination = tempfile::tempdir().unwrap();
+    let file = destination.path().join("file");
+    fs::write(&file, b"contents").unwrap();
+    let index = || fs::read_to_string(destination.path().join(".rustsync/written")).unwrap();
+
+    let log = ConflictLog::open(destination.path(), ConflictPolicy::Skip).unwrap();
+    log.copied(&file, 8, "hashed while copying".to_string());
+    log.wrote(&file);
+    assert!(index().contains("hashed while copying"));
+
+    for _ in 0..3000 {
+        log.wrote(&file);
+    }
+    assert!(index().lines().count() <= 1025);
+    assert!(!index().contains("hashed while copying"));
+}
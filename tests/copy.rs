@@ -0,0 +1,113 @@
+use std::fs;
+
+use rustsync::copy::{copy_file, same_contents, truncate_tail, Reflink};
+
+#[test]
+fn reflinked_copy_has_identical_content() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let destination = dir.path().join("destination");
+
+    let content: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+    fs::write(&source, &content).unwrap();
+    fs::write(&destination, b"stale").unwrap();
+
+    copy_file(&source, &destination, Reflink::Auto).unwrap();
+
+    assert_eq!(fs::read(&destination).unwrap(), content);
+    assert!(!dir.path().join(".destination.rustsync-tmp").exists());
+}
+
+#[test]
+fn never_copies_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let destination = dir.path().join("destination");
+
+    fs::write(&source, b"plain copy").unwrap();
+    copy_file(&source, &destination, Reflink::Never).unwrap();
+
+    assert_eq!(fs::read(&destination).unwrap(), b"plain copy");
+}
+
+#[test]
+fn large_files_are_copied_through_a_memory_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let destination = dir.path().join("destination");
+    rustsync::hash::set_mmap_threshold(1024);
+
+    let content: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    fs::write(&source, &content).unwrap();
+    fs::write(&destination, vec![0; 128 * 1024]).unwrap();
+    copy_file(&source, &destination, Reflink::Never).unwrap();
+
+    assert_eq!(fs::read(&destination).unwrap(), content);
+}
+
+#[test]
+fn same_contents_compares_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let destination = dir.path().join("destination");
+
+    fs::write(&source, b"").unwrap();
+    fs::write(&destination, b"").unwrap();
+    assert!(same_contents(&source, &destination).unwrap());
+
+    fs::write(&source, b"saved").unwrap();
+    fs::write(&destination, b"saved").unwrap();
+    assert!(same_contents(&source, &destination).unwrap());
+
+    fs::write(&destination, b"SAVED").unwrap();
+    assert!(!same_contents(&source, &destination).unwrap());
+}
+
+#[test]
+fn copies_and_hashes_leave_the_source_atime_alone() {
+    use filetime::FileTime;
+    use rustsync::hash::{hash_file, ChecksumAlgorithm};
+
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    fs::write(&source, vec![7u8; 64 * 1024]).unwrap();
+    let old = FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_atime(&source, old).unwrap();
+
+    copy_file(&source, &dir.path().join("copy"), Reflink::Never).unwrap();
+    hash_file(&source, ChecksumAlgorithm::Blake3).unwrap();
+    hash_file(&source, ChecksumAlgorithm::Sha256).unwrap();
+
+    // Without O_NOATIME, relatime still updates an atime older than the mtime.
+    if cfg!(target_os = "linux") {
+        assert_eq!(FileTime::from_last_access_time(&fs::metadata(&source).unwrap()), old);
+    }
+}
+
+#[test]
+fn truncate_tail_rewrites_from_the_first_changed_block() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let destination = dir.path().join("destination");
+    let content: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+    fs::write(&destination, &content).unwrap();
+
+    // Cut short: nothing to rewrite.
+    fs::write(&source, &content[..300_000]).unwrap();
+    assert_eq!(truncate_tail(&source, &destination).unwrap(), Some(0));
+    assert_eq!(fs::read(&destination).unwrap(), &content[..300_000]);
+
+    // Cut shorter and changed near the end: only the last block is rewritten.
+    let mut changed = content[..200_000].to_vec();
+    changed[199_000..].fill(0);
+    fs::write(&source, &changed).unwrap();
+    assert_eq!(truncate_tail(&source, &destination).unwrap(), Some(200_000 - 3 * 64 * 1024));
+    assert_eq!(fs::read(&destination).unwrap(), changed);
+
+    // Growing, or nothing in common, is left to other copies.
+    fs::write(&source, &content[..250_000]).unwrap();
+    assert_eq!(truncate_tail(&source, &destination).unwrap(), None);
+    fs::write(&source, b"rotated").unwrap();
+    assert_eq!(truncate_tail(&source, &destination).unwrap(), None);
+    assert_eq!(fs::read(&destination).unwrap(), changed);
+}
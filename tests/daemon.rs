@@ -0,0 +1,22 @@
+use std::fs;
+
+use rustsync::daemon::PidFile;
+
+#[test]
+fn only_one_instance_gets_the_pid_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("filesync.pid");
+
+    let held = PidFile::acquire(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+    assert!(PidFile::acquire(&path).is_err());
+
+    // One created but not yet written belongs to an instance still starting.
+    drop(held);
+    fs::write(&path, b"").unwrap();
+    assert!(PidFile::acquire(&path).is_err());
+
+    fs::write(&path, b"not a pid\n").unwrap();
+    let _held = PidFile::acquire(&path).unwrap();
+    assert!(path.exists());
+}
@@ -0,0 +1,92 @@
+use std::{fs, thread, time::Duration};
+
+use rustsync::{
+    copy::Reflink,
+    deadletter::DeadLetters,
+    metrics,
+    mirror::{apply_event, flush_debounced, retry_dead_letters, Mirror, Operation, Options},
+};
+
+#[test]
+fn failing_operations_are_retried_then_dead_lettered() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("dir")).unwrap();
+    fs::write(source.path().join("dir/file"), b"contents").unwrap();
+    // A file where the mirror needs a directory fails every copy under it.
+    fs::write(destination.path().join("dir"), b"in the way").unwrap();
+
+    let options = Options {
+        reflink: Reflink::Never,
+        ..Options::default()
+    };
+    let mut mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    mirror.dead_letters = Some(DeadLetters::open(destination.path(), 1).unwrap());
+    let letters = mirror.dead_letters.as_ref().unwrap();
+    let dead_lettered = metrics::get("dead_lettered");
+    let operation = Operation::Data { path: "dir/file".into() };
+
+    apply_event(&mirror, &operation);
+    assert!(letters.is_empty());
+    // The one retry comes a second after the failure.
+    thread::sleep(Duration::from_millis(1100));
+    flush_debounced(&mirror);
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters.list()[0].operation, operation);
+    assert_eq!(letters.list()[0].attempts, 2);
+    assert_eq!(metrics::get("dead_lettered"), dead_lettered + 1);
+    assert_eq!(DeadLetters::open(destination.path(), 1).unwrap().len(), 1);
+
+    // Later changes to the path aren't applied until it's retried.
+    fs::remove_file(destination.path().join("dir")).unwrap();
+    apply_event(&mirror, &operation);
+    assert!(!destination.path().join("dir/file").exists());
+
+    assert_eq!(retry_dead_letters(&mirror), 1);
+    assert_eq!(fs::read(destination.path().join("dir/file")).unwrap(), b"contents");
+    assert!(letters.is_empty());
+    assert!(DeadLetters::open(destination.path(), 1).unwrap().is_empty());
+}
+
+#[test]
+fn warnings_after_an_applied_operation_are_not_failures() {
+    use rustsync::report::{self, ErrorKind};
+    use std::path::Path;
+
+    report::take_failure();
+    report::error(ErrorKind::Xattr, Path::new("file"), "Failed to set xattr on file");
+    report::error(ErrorKind::Times, Path::new("file"), "Failed to set timestamps for file");
+    assert_eq!(report::take_failure(), None);
+    report::error(ErrorKind::Copy, Path::new("file"), "Failed to copy file");
+    assert_eq!(report::take_failure().as_deref(), Some("Failed to copy file"));
+}
+
+#[test]
+fn a_dead_lettered_rename_holds_back_its_new_path() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("dir")).unwrap();
+    fs::write(source.path().join("dir/renamed"), b"contents").unwrap();
+    fs::write(destination.path().join("file"), b"contents").unwrap();
+    // The renamed file's new directory is a file in the mirror.
+    fs::write(destination.path().join("dir"), b"in the way").unwrap();
+
+    let mut mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), Options::default());
+    mirror.dead_letters = Some(DeadLetters::open(destination.path(), 0).unwrap());
+    let letters = mirror.dead_letters.as_ref().unwrap();
+    let rename = Operation::Rename { path: "file".into(), new_path: "dir/renamed".into() };
+    apply_event(&mirror, &rename);
+    assert_eq!(letters.len(), 1);
+
+    let data = Operation::Data { path: "dir/renamed".into() };
+    fs::write(source.path().join("dir/renamed"), b"changed").unwrap();
+    fs::remove_file(destination.path().join("dir")).unwrap();
+    apply_event(&mirror, &data);
+    assert!(!destination.path().join("dir/renamed").exists());
+    assert_eq!(letters.len(), 2);
+
+    assert_eq!(retry_dead_letters(&mirror), 2);
+    assert!(!destination.path().join("file").exists());
+    assert_eq!(fs::read(destination.path().join("dir/renamed")).unwrap(), b"changed");
+    assert!(letters.is_empty());
+}
@@ -0,0 +1,59 @@
+use std::{fs, path::Path, thread, time::Duration};
+
+use notify::{
+    event::{CreateKind, DataChange, ModifyKind},
+    Event, EventKind,
+};
+use rustsync::{
+    debounce::{window_for, DebounceRule},
+    mirror::{flush_debounced, handle_event, Mirror, Options},
+};
+
+fn create(path: &Path) -> Event {
+    Event::new(EventKind::Create(CreateKind::File)).add_path(path.to_path_buf())
+}
+
+fn data(path: &Path) -> Event {
+    Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any))).add_path(path.to_path_buf())
+}
+
+#[test]
+fn first_matching_rule_sets_the_window() {
+    let rules: Vec<DebounceRule> = ["*.mp4=2s", "*.toml=50ms", "**=1s"].iter().map(|rule| rule.parse().unwrap()).collect();
+    let default = Duration::from_millis(300);
+    assert_eq!(window_for(&rules, default, Path::new("videos/a.mp4")), Duration::from_secs(2));
+    assert_eq!(window_for(&rules, default, Path::new("config.toml")), Duration::from_millis(50));
+    assert_eq!(window_for(&rules[..2], default, Path::new("notes.txt")), default);
+    assert!("*.mp4".parse::<DebounceRule>().is_err());
+}
+
+#[test]
+fn held_copies_wait_for_their_own_window() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let options = Options {
+        debounce: vec!["*.mp4=300ms".parse().unwrap()],
+        ..Options::default()
+    };
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), options);
+
+    fs::write(watch_root.join("video.mp4"), b"part").unwrap();
+    fs::write(watch_root.join("config.toml"), b"x = 1").unwrap();
+    handle_event(&mirror, &create(&watch_root.join("video.mp4")));
+    handle_event(&mirror, &create(&watch_root.join("config.toml")));
+    assert!(destination.path().join("config.toml").exists());
+    assert!(!destination.path().join("video.mp4").exists());
+
+    // Another write restarts the window.
+    thread::sleep(Duration::from_millis(200));
+    fs::write(watch_root.join("video.mp4"), b"part and the rest").unwrap();
+    handle_event(&mirror, &data(&watch_root.join("video.mp4")));
+    thread::sleep(Duration::from_millis(200));
+    flush_debounced(&mirror);
+    assert!(!destination.path().join("video.mp4").exists());
+
+    thread::sleep(Duration::from_millis(150));
+    flush_debounced(&mirror);
+    assert_eq!(fs::read(destination.path().join("video.mp4")).unwrap(), b"part and the rest");
+}
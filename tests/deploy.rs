@@ -0,0 +1,32 @@
+use std::{fs, thread, time::Duration};
+
+use filetime::FileTime;
+use rustsync::{deploy::AtomicDeploy, mirror::Preserve};
+
+#[test]
+fn deploys_keep_the_times_of_untouched_files() {
+    let parent = tempfile::tempdir().unwrap();
+    let output = parent.path().join("site");
+    fs::create_dir_all(output.join("assets")).unwrap();
+    fs::write(output.join("assets/logo.svg"), b"<svg/>").unwrap();
+    fs::write(output.join("index.html"), b"old").unwrap();
+    let old = FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(output.join("assets/logo.svg"), old).unwrap();
+    filetime::set_file_mtime(output.join("assets"), old).unwrap();
+
+    let preserve = [Preserve::Perms, Preserve::Times];
+    let mut deploy = AtomicDeploy::new(&output, Duration::ZERO, &preserve, false).unwrap();
+    let mtime = |path: &std::path::Path| FileTime::from_last_modification_time(&fs::metadata(path).unwrap());
+    assert_eq!(mtime(&deploy.staging.join("assets/logo.svg")), old);
+    assert_eq!(mtime(&deploy.staging.join("assets")), old);
+
+    fs::write(deploy.staging.join("index.html"), b"new").unwrap();
+    deploy.changed();
+    thread::sleep(Duration::from_millis(10));
+    deploy.poll().unwrap();
+
+    assert_eq!(fs::read(output.join("index.html")).unwrap(), b"new");
+    assert_eq!(mtime(&output.join("assets/logo.svg")), old);
+    // The next deploy's staging copy keeps them too.
+    assert_eq!(mtime(&deploy.staging.join("assets/logo.svg")), old);
+}
@@ -0,0 +1,82 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use notify::{
+    event::{CreateKind, DataChange, ModifyKind},
+    Event, EventKind,
+};
+use rustsync::{
+    echo::SelfWrites,
+    mirror::{handle_event, Mirror, Options},
+};
+
+fn create(path: &Path) -> Event {
+    Event::new(EventKind::Create(CreateKind::File)).add_path(path.to_path_buf())
+}
+
+fn data(path: &Path) -> Event {
+    Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any))).add_path(path.to_path_buf())
+}
+
+fn mirror(from: &Path, to: &Path, writes: &Arc<SelfWrites>) -> Mirror {
+    let mut mirror = Mirror::new(from.to_path_buf(), to.to_path_buf(), Options::default());
+    mirror.self_writes = Some(writes.clone());
+    mirror
+}
+
+#[test]
+fn mirrors_pointed_at_each_other_drop_their_own_echoes() {
+    let (left, right) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let left: PathBuf = fs::canonicalize(left.path()).unwrap();
+    let right: PathBuf = fs::canonicalize(right.path()).unwrap();
+    let writes = Arc::new(SelfWrites::new(Duration::from_secs(60)));
+    let to_right = mirror(&left, &right, &writes);
+    let to_left = mirror(&right, &left, &writes);
+
+    fs::write(left.join("file"), b"first").unwrap();
+    handle_event(&to_right, &create(&left.join("file")));
+    assert_eq!(fs::read(right.join("file")).unwrap(), b"first");
+
+    // The file is edited again before the other side sees the first copy
+    // land. Copying that back would undo the edit.
+    fs::write(left.join("file"), b"second edit").unwrap();
+    handle_event(&to_left, &create(&right.join("file")));
+    handle_event(&to_left, &data(&right.join("file")));
+    assert_eq!(fs::read(left.join("file")).unwrap(), b"second edit");
+
+    handle_event(&to_right, &data(&left.join("file")));
+    assert_eq!(fs::read(right.join("file")).unwrap(), b"second edit");
+
+    // A real change on the other side isn't mistaken for an echo.
+    fs::write(right.join("file"), b"edited on the right").unwrap();
+    handle_event(&to_left, &data(&right.join("file")));
+    assert_eq!(fs::read(left.join("file")).unwrap(), b"edited on the right");
+}
+
+#[test]
+fn separate_processes_share_writes_through_the_control_dir() {
+    let (left, right) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+    let left: PathBuf = fs::canonicalize(left.path()).unwrap();
+    let right: PathBuf = fs::canonicalize(right.path()).unwrap();
+    // Each side's set as its own process would open it, sharing nothing in
+    // memory.
+    let window = Duration::from_secs(60);
+    let to_right = mirror(&left, &right, &Arc::new(SelfWrites::shared(window, &right, &left)));
+    let to_left = mirror(&right, &left, &Arc::new(SelfWrites::shared(window, &left, &right)));
+
+    fs::write(left.join("file"), b"first").unwrap();
+    handle_event(&to_right, &create(&left.join("file")));
+    assert!(right.join(".rustsync/self-writes").exists());
+
+    fs::write(left.join("file"), b"second edit").unwrap();
+    handle_event(&to_left, &create(&right.join("file")));
+    assert_eq!(fs::read(left.join("file")).unwrap(), b"second edit");
+
+    fs::write(right.join("file"), b"edited on the right").unwrap();
+    handle_event(&to_left, &data(&right.join("file")));
+    assert_eq!(fs::read(left.join("file")).unwrap(), b"edited on the right");
+}
@@ -0,0 +1,62 @@
+use std::{fs, io::Read};
+
+use rustsync::{
+    encrypt::{decrypt, encrypt_file, read_header, Encryption},
+    hash::{hash_file, ChecksumAlgorithm},
+};
+
+fn read_all(mut reader: impl Read) -> std::io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[test]
+fn file_round_trip_and_tampering() {
+    let dir = tempfile::tempdir().unwrap();
+    let encryption = Encryption::new(b"passphrase", dir.path(), false, ChecksumAlgorithm::Blake3).unwrap();
+
+    // Empty, partial, exactly one chunk and several chunks.
+    for size in [0, 1000, 64 * 1024, 200_000] {
+        let plain: Vec<u8> = (0..size).map(|index| (index % 251) as u8).collect();
+        let source = dir.path().join("plain");
+        let encrypted = dir.path().join("plain.enc");
+        fs::write(&source, &plain).unwrap();
+
+        encrypt_file(&source, &encrypted, &encryption).unwrap();
+        let header = read_header(&encrypted).unwrap().unwrap();
+        assert_eq!(header.size, size as u64);
+        // Only the key tells whether the hash is that of a known file.
+        let hash = hash_file(&source, ChecksumAlgorithm::Blake3).unwrap();
+        assert_ne!(header.hash, hash);
+        assert_eq!(header.hash, encryption.keyed_hash(&hash));
+        assert_eq!(read_all(decrypt(&encrypted, &encryption).unwrap()).unwrap(), plain);
+
+        let mut stored = fs::read(&encrypted).unwrap();
+        stored.truncate(stored.len() - 1);
+        fs::write(&encrypted, &stored).unwrap();
+        assert!(read_all(decrypt(&encrypted, &encryption).unwrap()).is_err());
+    }
+
+    // The salt is reused, so the same passphrase gives the same key.
+    let again = Encryption::new(b"passphrase", dir.path(), false, ChecksumAlgorithm::Blake3).unwrap();
+    let other = Encryption::new(b"other", dir.path(), false, ChecksumAlgorithm::Blake3).unwrap();
+    let source = dir.path().join("plain");
+    let encrypted = dir.path().join("plain.enc");
+    encrypt_file(&source, &encrypted, &encryption).unwrap();
+    assert!(read_all(decrypt(&encrypted, &again).unwrap()).is_ok());
+    assert!(read_all(decrypt(&encrypted, &other).unwrap()).is_err());
+}
+
+#[test]
+fn names_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let encryption = Encryption::new(b"passphrase", dir.path(), true, ChecksumAlgorithm::Blake3).unwrap();
+
+    let name = std::ffi::OsStr::new("Quarterly report.xlsx");
+    let encrypted = encryption.encrypt_name(name);
+    assert_eq!(encrypted, encryption.encrypt_name(name));
+    assert!(encrypted.to_str().unwrap().bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit()));
+    assert_eq!(encryption.decrypt_name(&encrypted).unwrap(), name);
+    assert!(encryption.decrypt_name(std::ffi::OsStr::new("plain-name")).is_none());
+}
@@ -0,0 +1,106 @@
+#![cfg(unix)]
+
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+use notify::{
+    event::{CreateKind, DataChange, ModifyKind},
+    Event, EventKind,
+};
+use rustsync::mirror::{handle_event, Mirror, Options};
+
+fn create(path: &Path) -> Event {
+    Event::new(EventKind::Create(CreateKind::File)).add_path(path.to_path_buf())
+}
+
+fn data(path: &Path) -> Event {
+    Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any))).add_path(path.to_path_buf())
+}
+
+/// Writes `file` with mode 0640 and an old modification time, sends `events`
+/// for it, and returns what the mirror ends up with.
+fn mirror_with(events: fn(&Path) -> Vec<Event>) -> (Vec<u8>, u32, filetime::FileTime) {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), Options::default());
+
+    let file = watch_root.join("dir/file");
+    fs::create_dir(watch_root.join("dir")).unwrap();
+    fs::write(&file, b"contents").unwrap();
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+    filetime::set_file_mtime(&file, filetime::FileTime::from_unix_time(1_600_000_000, 0)).unwrap();
+
+    for event in events(&file) {
+        handle_event(&mirror, &event);
+    }
+
+    let mirrored = destination.path().join("dir/file");
+    let metadata = fs::metadata(&mirrored).unwrap();
+    (
+        fs::read(&mirrored).unwrap(),
+        metadata.permissions().mode() & 0o7777,
+        filetime::FileTime::from_last_modification_time(&metadata),
+    )
+}
+
+#[test]
+fn macos_create_only_matches_linux_create_then_modify() {
+    // FSEvents reports a new, written file as a single create.
+    let macos = mirror_with(|file| vec![create(file)]);
+    // inotify reports the create, then the write.
+    let linux = mirror_with(|file| vec![create(file), data(file)]);
+    // Some backends report only the write, as for an existing file.
+    let modify_only = mirror_with(|file| vec![data(file)]);
+
+    assert_eq!(macos, (b"contents".to_vec(), 0o640, filetime::FileTime::from_unix_time(1_600_000_000, 0)));
+    assert_eq!(linux, macos);
+    assert_eq!(modify_only, macos);
+}
+
+#[test]
+fn repeated_create_is_idempotent() {
+    let once = mirror_with(|file| vec![create(file)]);
+    let twice = mirror_with(|file| vec![create(file), create(file), data(file)]);
+    assert_eq!(twice, once);
+}
+
+#[test]
+fn created_hard_link_is_copied() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), Options::default());
+
+    fs::write(watch_root.join("a"), b"linked").unwrap();
+    fs::hard_link(watch_root.join("a"), watch_root.join("b")).unwrap();
+    handle_event(&mirror, &create(&watch_root.join("b")));
+
+    assert_eq!(fs::read(destination.path().join("b")).unwrap(), b"linked");
+}
+
+#[test]
+fn metadata_only_still_fills_in_a_file_written_after_creation() {
+    use rustsync::{mirror::Changes, reconcile::reconcile};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let options = Options { changes: Changes::Metadata, ..Options::default() };
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), options);
+
+    let file = watch_root.join("file");
+    fs::write(&file, b"").unwrap();
+    handle_event(&mirror, &create(&file));
+    fs::write(&file, b"contents").unwrap();
+    handle_event(&mirror, &data(&file));
+    let mirrored = destination.path().join("file");
+    assert_eq!(fs::read(&mirrored).unwrap(), b"contents");
+
+    // Edits that keep the size are still left alone, and full syncs agree.
+    fs::write(&file, b"CONTENTS").unwrap();
+    handle_event(&mirror, &data(&file));
+    assert_eq!(fs::read(&mirrored).unwrap(), b"contents");
+    fs::write(&mirrored, b"").unwrap();
+    reconcile(&mirror);
+    assert_eq!(fs::read(&mirrored).unwrap(), b"CONTENTS");
+}
@@ -0,0 +1,120 @@
+#![cfg(target_os = "linux")]
+
+use std::{fs, path::Path};
+
+use rustsync::{
+    copy::Reflink,
+    fsflags,
+    mirror::{apply_event, Mirror, Operation, Options, Preserve},
+};
+
+/// Whether this process may set the immutable flag under `dir`, which needs
+/// CAP_LINUX_IMMUTABLE and a filesystem that keeps flags.
+fn can_set_flags(dir: &Path) -> bool {
+    let file = dir.join("probe");
+    fs::write(&file, b"").unwrap();
+    let settable = fsflags::apply(&file, fsflags::IMMUTABLE).is_ok();
+    let _ = fsflags::apply(&file, 0);
+    fs::remove_file(&file).unwrap();
+    settable
+}
+
+#[test]
+fn immutable_and_append_only_flags_are_mirrored_and_lifted_for_writes() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    if !can_set_flags(source.path()) || !can_set_flags(destination.path()) {
+        eprintln!("Skipping: can't set inode flags here");
+        return;
+    }
+
+    let options = Options {
+        preserve: vec![Preserve::Perms, Preserve::Xattrs],
+        preserve_flags: true,
+        reflink: Reflink::Never,
+        ..Options::default()
+    };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    // CAP_NET_BIND_SERVICE, permitted and effective.
+    let capability = [1, 0, 0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    for (name, flag) in [("immutable", fsflags::IMMUTABLE), ("append-only", fsflags::APPEND)] {
+        let file = source.path().join(name);
+        fs::write(&file, "old contents").unwrap();
+        let capable = xattr::set(&file, "security.capability", &capability).is_ok();
+        fsflags::apply(&file, flag).unwrap();
+        apply_event(&mirror, &Operation::Create { path: name.into() });
+
+        let mirrored = destination.path().join(name);
+        assert_eq!(fsflags::get(&mirrored).unwrap() & fsflags::MIRRORED, flag);
+        if capable {
+            assert_eq!(xattr::get(&mirrored, "security.capability").unwrap().unwrap(), capability);
+        }
+
+        fsflags::apply(&file, 0).unwrap();
+        fs::write(&file, "new").unwrap();
+        if capable {
+            xattr::set(&file, "security.capability", &capability).unwrap();
+        }
+        fsflags::apply(&file, flag).unwrap();
+        apply_event(&mirror, &Operation::Data { path: name.into() });
+
+        assert_eq!(fs::read_to_string(&mirrored).unwrap(), "new");
+        assert_eq!(fsflags::get(&mirrored).unwrap() & fsflags::MIRRORED, flag);
+        if capable {
+            assert_eq!(xattr::get(&mirrored, "security.capability").unwrap().unwrap(), capability);
+        }
+
+        // Cleared in the source, cleared in the mirror.
+        fsflags::apply(&file, 0).unwrap();
+        apply_event(&mirror, &Operation::Metadata { path: name.into() });
+        assert_eq!(fsflags::get(&mirrored).unwrap() & fsflags::MIRRORED, 0);
+    }
+}
+
+#[test]
+fn flags_are_lifted_for_deletes_renames_and_new_children() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    if !can_set_flags(source.path()) || !can_set_flags(destination.path()) {
+        eprintln!("Skipping: can't set inode flags here");
+        return;
+    }
+
+    let options = Options {
+        preserve_flags: true,
+        reflink: Reflink::Never,
+        ..Options::default()
+    };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    let dir = source.path().join("dir");
+    fs::create_dir(&dir).unwrap();
+    fs::write(dir.join("file"), "contents").unwrap();
+    fsflags::apply(&dir.join("file"), fsflags::IMMUTABLE).unwrap();
+    fsflags::apply(&dir, fsflags::IMMUTABLE).unwrap();
+    apply_event(&mirror, &Operation::Create { path: "dir".into() });
+    apply_event(&mirror, &Operation::Create { path: "dir/file".into() });
+    apply_event(&mirror, &Operation::Metadata { path: "dir".into() });
+    let mirrored = destination.path().join("dir");
+    assert_eq!(fsflags::get(&mirrored).unwrap() & fsflags::MIRRORED, fsflags::IMMUTABLE);
+
+    // A new child of the immutable directory.
+    fsflags::apply(&dir, 0).unwrap();
+    fs::write(dir.join("new"), "new").unwrap();
+    fsflags::apply(&dir, fsflags::IMMUTABLE).unwrap();
+    apply_event(&mirror, &Operation::Create { path: "dir/new".into() });
+    assert_eq!(fs::read_to_string(mirrored.join("new")).unwrap(), "new");
+
+    // The immutable file renamed within it.
+    fsflags::apply(&dir, 0).unwrap();
+    fsflags::apply(&dir.join("file"), 0).unwrap();
+    fs::rename(dir.join("file"), dir.join("renamed")).unwrap();
+    apply_event(&mirror, &Operation::Rename { path: "dir/file".into(), new_path: "dir/renamed".into() });
+    assert!(!mirrored.join("file").exists());
+    assert_eq!(fsflags::get(&mirrored.join("renamed")).unwrap() & fsflags::MIRRORED, fsflags::IMMUTABLE);
+
+    // And the whole directory deleted.
+    fs::remove_dir_all(&dir).unwrap();
+    apply_event(&mirror, &Operation::Delete { path: "dir".into() });
+    assert!(!mirrored.exists());
+}
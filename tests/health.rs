@@ -0,0 +1,35 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::Arc,
+};
+
+use rustsync::health::{serve, Health};
+
+fn get(address: std::net::SocketAddr, path: &str) -> (u16, serde_json::Value) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+#[test]
+fn readiness_waits_for_the_startup_sync() {
+    let health = Arc::new(Health::default());
+    let address = serve("127.0.0.1:0".parse().unwrap(), health.clone()).unwrap();
+
+    let (status, body) = get(address, "/healthz");
+    assert_eq!(status, 200);
+    assert_eq!(body["alive"], true);
+    assert!(body.get("last_error").is_some());
+    assert_eq!(get(address, "/readyz").0, 503);
+
+    health.set_ready();
+    let (status, body) = get(address, "/readyz?verbose");
+    assert_eq!(status, 200);
+    assert_eq!(body["ready"], true);
+    assert_eq!(get(address, "/metrics").0, 404);
+}
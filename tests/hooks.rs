@@ -0,0 +1,48 @@
+#![cfg(unix)]
+
+use std::{fs, time::Duration};
+
+use rustsync::{
+    copy::Reflink,
+    hooks::{Hook, HookRunner},
+    mirror::{apply_event, Mirror, Operation, Options},
+};
+
+#[test]
+fn finish_runs_hooks_still_waiting_out_their_debounce() {
+    let dir = tempfile::tempdir().unwrap();
+    let ran = dir.path().join("ran");
+    let hook: Hook = format!("*.txt=echo \"$1\" > {:?}", ran).parse().unwrap();
+    let runner = HookRunner::new(vec![hook], Duration::from_secs(60));
+
+    runner.changed("notes.txt".as_ref());
+    runner.changed("image.png".as_ref());
+    runner.finish();
+    assert_eq!(fs::read_to_string(&ran).unwrap(), "notes.txt\n");
+}
+
+#[test]
+fn hooks_only_run_for_operations_that_succeeded() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let ran = tempfile::tempdir().unwrap();
+    let ran = ran.path().join("ran");
+    for name in ["copied.txt", "blocked.txt"] {
+        fs::write(source.path().join(name), name).unwrap();
+    }
+    // A directory where the mirror needs a file fails its copy.
+    fs::create_dir(destination.path().join("blocked.txt")).unwrap();
+
+    let options = Options {
+        reflink: Reflink::Never,
+        ..Options::default()
+    };
+    let mut mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    let hook: Hook = format!("*.txt=echo \"$1\" >> {:?}", ran).parse().unwrap();
+    mirror.hooks = Some(HookRunner::new(vec![hook], Duration::ZERO));
+
+    apply_event(&mirror, &Operation::Data { path: "blocked.txt".into() });
+    apply_event(&mirror, &Operation::Data { path: "copied.txt".into() });
+    mirror.hooks.take().unwrap().finish();
+    assert_eq!(fs::read_to_string(&ran).unwrap(), "copied.txt\n");
+}
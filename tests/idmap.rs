@@ -0,0 +1,57 @@
+use std::{fs, os::unix::fs::MetadataExt};
+
+use rustsync::{
+    idmap::{find_range, IdMap, IdRange},
+    mirror::{apply_metadata, Mirror, Options},
+};
+
+#[test]
+fn subid_ranges_are_found_by_name_or_id() {
+    let subuid = "alice:100000:65536\n1001:165536:1000\n";
+    assert_eq!(find_range(subuid, "alice", 1000), Some(IdRange { start: 100000, count: 65536 }));
+    assert_eq!(find_range(subuid, "bob", 1001), Some(IdRange { start: 165536, count: 1000 }));
+    assert_eq!(find_range(subuid, "carol", 1002), None);
+
+    assert_eq!("300000".parse::<IdRange>().unwrap().count, 65536);
+}
+
+#[test]
+fn root_maps_to_the_user_and_the_rest_into_the_range() {
+    let range: IdRange = "200000:10".parse().unwrap();
+    let id_map = IdMap { uids: range, gids: range, user: 1000, group: 1001 };
+    assert_eq!(id_map.map(0, 0), Ok((1000, 1001)));
+    assert_eq!(id_map.map(1, 1), Ok((200000, 200000)));
+    assert_eq!(id_map.map(10, 5), Ok((200009, 200004)));
+    assert_eq!(id_map.map(11, 0), Err(("uid", 11)));
+    assert_eq!(id_map.map(0, 11), Err(("gid", 11)));
+}
+
+#[test]
+fn owners_are_shifted_and_unmappable_ones_skipped() {
+    // Setting arbitrary owners needs root.
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    for (name, owner) in [("mapped", 5), ("unmapped", 70000)] {
+        fs::write(watch_root.join(name), b"x").unwrap();
+        fs::write(destination.path().join(name), b"x").unwrap();
+        std::os::unix::fs::chown(watch_root.join(name), Some(owner), Some(owner)).unwrap();
+    }
+
+    let range = IdRange { start: 100000, count: 65536 };
+    let options = Options {
+        id_map: Some(IdMap::new(range, range)),
+        ..Options::default()
+    };
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), options);
+    apply_metadata(&mirror, &watch_root.join("mapped"));
+    apply_metadata(&mirror, &watch_root.join("unmapped"));
+
+    let mapped = fs::metadata(destination.path().join("mapped")).unwrap();
+    assert_eq!((mapped.uid(), mapped.gid()), (100004, 100004));
+    let unmapped = fs::metadata(destination.path().join("unmapped")).unwrap();
+    assert_eq!((unmapped.uid(), unmapped.gid()), (0, 0));
+}
@@ -0,0 +1,49 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Barrier,
+    },
+    thread,
+    time::Duration,
+};
+
+use rustsync::{inflight, metrics};
+
+#[test]
+fn large_reservations_wait_while_small_ones_fit() {
+    inflight::set_limit(100);
+    assert_eq!(inflight::footprint(10 << 20, false), inflight::STREAM_FOOTPRINT);
+    assert_eq!(inflight::footprint(10 << 20, true), 10 << 20);
+
+    let held = Barrier::new(2);
+    let release = Barrier::new(2);
+    let large_done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let _first = inflight::reserve(60);
+            held.wait();
+            release.wait();
+        });
+        held.wait();
+        assert_eq!(metrics::get("inflight_bytes"), 60);
+
+        let large = scope.spawn(|| {
+            // Larger than the cap: runs alone once the first is done.
+            let _large = inflight::reserve(500);
+            large_done.store(true, Ordering::SeqCst);
+        });
+        {
+            let _small = inflight::reserve(30);
+            // Nested reservations on one thread don't wait for themselves.
+            let _nested = inflight::reserve(90);
+            assert_eq!(metrics::get("inflight_bytes"), 180);
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert!(!large_done.load(Ordering::SeqCst));
+
+        release.wait();
+        large.join().unwrap();
+        assert!(large_done.load(Ordering::SeqCst));
+    });
+    assert_eq!(metrics::get("inflight_bytes"), 0);
+}
@@ -0,0 +1,76 @@
+use libp2p::identity;
+use rustsync::keys::{
+    add_peer, fingerprint, list_peers, load_keypair, load_keypair_pinned, remove_peer, save_keypair, test_rustsync_dir,
+};
+
+#[test]
+fn pinned_keypair_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let keypair = identity::Keypair::generate_ed25519();
+    let peer_id = save_keypair(dir.path(), &keypair).unwrap();
+    let expected = fingerprint(&keypair);
+
+    let loaded = load_keypair_pinned(dir.path(), &peer_id, &expected).unwrap();
+    assert_eq!(loaded.public(), keypair.public());
+    assert_eq!(fingerprint(&loaded), expected);
+
+    let other = fingerprint(&identity::Keypair::generate_ed25519());
+    let error = load_keypair_pinned(dir.path(), &peer_id, &other).unwrap_err();
+    assert!(error.to_string().contains("fingerprint mismatch"));
+}
+
+#[test]
+fn allowlist_add_list_remove() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = identity::Keypair::generate_ed25519().public().to_peer_id();
+    let second = identity::Keypair::generate_ed25519().public().to_peer_id();
+
+    assert!(list_peers(dir.path()).unwrap().is_empty());
+    assert!(add_peer(dir.path(), &first.to_string()).unwrap());
+    assert!(add_peer(dir.path(), &second.to_string()).unwrap());
+    assert!(!add_peer(dir.path(), &first.to_string()).unwrap());
+    assert_eq!(list_peers(dir.path()).unwrap(), vec![first, second]);
+
+    assert!(remove_peer(dir.path(), &first.to_string()).unwrap());
+    assert!(!remove_peer(dir.path(), &first.to_string()).unwrap());
+    assert_eq!(list_peers(dir.path()).unwrap(), vec![second]);
+
+    assert!(add_peer(dir.path(), "not-a-peer-id").is_err());
+    assert_eq!(list_peers(dir.path()).unwrap(), vec![second]);
+}
+
+#[cfg(unix)]
+#[test]
+fn keypair_lifecycle() {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    let home = tempfile::tempdir().unwrap();
+    let dir = home.path().join(".rustsync");
+    fs::create_dir(&dir).unwrap();
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+    test_rustsync_dir(&dir).unwrap();
+
+    let keypair = identity::Keypair::generate_ed25519();
+    let peer_id = save_keypair(&dir, &keypair).unwrap();
+    assert_eq!(peer_id, keypair.public().to_peer_id().to_string());
+    let mode = |extension| fs::metadata(dir.join(&peer_id).with_extension(extension)).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode("private"), 0o600);
+    assert_eq!(mode("public"), 0o644);
+
+    let loaded = load_keypair(&dir, &peer_id).unwrap();
+    assert_eq!(loaded.public().to_peer_id().to_string(), peer_id);
+
+    // A directory others can read is refused before any key is loaded.
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+    let error = test_rustsync_dir(&dir).unwrap_err();
+    assert!(error.to_string().contains("must not be accessible by group or others"));
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+
+    let missing = identity::Keypair::generate_ed25519().public().to_peer_id().to_string();
+    let error = load_keypair(&dir, &missing).unwrap_err();
+    assert!(error.to_string().starts_with("Failed to read"));
+
+    fs::write(dir.join(&peer_id).with_extension("private"), b"not a protobuf key").unwrap();
+    let error = load_keypair(&dir, &peer_id).unwrap_err();
+    assert!(error.to_string().contains("Invalid private key"));
+}
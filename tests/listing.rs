@@ -0,0 +1,38 @@
+use std::{fs, os::unix::fs::PermissionsExt};
+
+use rustsync::{
+    listing::{entries, write_list, ListFormat},
+    mirror::{Mirror, Options},
+};
+
+#[test]
+fn list_skips_ignored_paths_and_formats_entries() {
+    let source = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    fs::create_dir_all(watch_root.join(".git/info")).unwrap();
+    fs::write(watch_root.join(".gitignore"), "*.tmp\n").unwrap();
+    fs::write(watch_root.join("scratch.tmp"), b"x").unwrap();
+    fs::write(watch_root.join("a,b"), b"12345").unwrap();
+    fs::set_permissions(watch_root.join("a,b"), fs::Permissions::from_mode(0o4750)).unwrap();
+    std::os::unix::fs::symlink("a,b", watch_root.join("link")).unwrap();
+
+    let options = Options { exclude_vcs: true, ..Options::default() };
+    let mirror = Mirror::new(watch_root.clone(), watch_root.join(".rustsync"), options);
+    let listed = entries(&mirror);
+    let paths: Vec<_> = listed.iter().map(|entry| entry.path.to_str().unwrap()).collect();
+    assert_eq!(paths, [".gitignore", "a,b", "link"]);
+    assert_eq!(listed[1].mode, "-rwsr-x---");
+    assert_eq!(listed[2].mode, "lrwxrwxrwx");
+
+    let mut csv = Vec::new();
+    write_list(&listed, ListFormat::Csv, &mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let mtime = fs::metadata(watch_root.join("a,b")).unwrap().modified().unwrap();
+    let mtime = mtime.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    assert_eq!(csv.lines().nth(2).unwrap(), format!("-rwsr-x---,5,{},\"a,b\"", mtime));
+
+    let mut json = Vec::new();
+    write_list(&listed, ListFormat::Json, &mut json).unwrap();
+    let link: serde_json::Value = serde_json::from_str(String::from_utf8(json).unwrap().lines().last().unwrap()).unwrap();
+    assert_eq!(link["target"], "a,b");
+}
@@ -0,0 +1,63 @@
+#![cfg(target_os = "linux")]
+
+use std::{
+    fs,
+    path::Path,
+    process::{Child, Command},
+    thread,
+    time::Duration,
+};
+
+use rustsync::{
+    copy::Reflink,
+    metrics,
+    mirror::{apply_event, flush_debounced, LockedFiles, Mirror, Operation, Options},
+    report,
+};
+
+/// Runs a copy of `sleep` from `path`, which makes writing to it fail with
+/// ETXTBSY until it exits.
+fn run_from(path: &Path) -> Child {
+    fs::copy("/bin/sleep", path).unwrap();
+    Command::new(path).arg("30").spawn().unwrap()
+}
+
+fn mirror(source: &Path, destination: &Path, locked: LockedFiles) -> Mirror {
+    let options = Options {
+        locked,
+        reflink: Reflink::Never,
+        ..Options::default()
+    };
+    Mirror::new(source.to_path_buf(), destination.to_path_buf(), options)
+}
+
+#[test]
+fn locked_files_are_skipped_or_retried() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fs::write(source.path().join("skipped"), b"new").unwrap();
+    fs::write(source.path().join("waited"), b"new").unwrap();
+    let mut skipped = run_from(&destination.path().join("skipped"));
+    let mut waited = run_from(&destination.path().join("waited"));
+    let locked = metrics::get("locked_files");
+
+    let skipping = mirror(source.path(), destination.path(), LockedFiles::Skip);
+    apply_event(&skipping, &Operation::Data { path: "skipped".into() });
+    let waiting = mirror(source.path(), destination.path(), LockedFiles::Wait);
+    apply_event(&waiting, &Operation::Data { path: "waited".into() });
+    assert!(metrics::get("locked_files") >= locked + 2);
+    assert_ne!(fs::read(destination.path().join("waited")).unwrap(), b"new");
+
+    for child in [&mut skipped, &mut waited] {
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+    // The first retry comes 250ms after the lock.
+    thread::sleep(Duration::from_millis(300));
+    flush_debounced(&skipping);
+    flush_debounced(&waiting);
+
+    assert!(report::error_counts().is_empty());
+    assert_ne!(fs::read(destination.path().join("skipped")).unwrap(), b"new");
+    assert_eq!(fs::read(destination.path().join("waited")).unwrap(), b"new");
+}
@@ -0,0 +1,138 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use libp2p::identity::Keypair;
+use rustsync::{
+    compress::{self, Compression},
+    hash::{self, hash_file, ChecksumAlgorithm},
+    manifest::{Manifest, ManifestHistory, MAX_DELTA_VERSIONS},
+    mirror::CONTROL_DIR,
+    p2p::{SignedManifest, TransferPlan},
+};
+
+fn manifest(files: usize, changed: &str) -> Manifest {
+    let mut entries: BTreeMap<PathBuf, String> =
+        (0..files).map(|i| (PathBuf::from(format!("file{}", i)), format!("hash{}", i))).collect();
+    entries.insert(PathBuf::from("changing"), changed.to_string());
+    Manifest { algorithm: ChecksumAlgorithm::default(), entries }
+}
+
+#[test]
+fn small_change_sends_small_delta() {
+    let mut history = ManifestHistory::new(manifest(1000, "a")).unwrap();
+    let mut seen = history.current().clone();
+
+    assert_eq!(history.update(manifest(1000, "a")), seen.version);
+    let mut next = manifest(1000, "b");
+    next.entries.remove(&PathBuf::from("file7"));
+    let version = history.update(next.clone());
+
+    let delta = history.since(seen.epoch, seen.version).unwrap();
+    assert_eq!((delta.base, delta.version, delta.len()), (seen.version, version, 2));
+    assert_eq!(delta.removed, [PathBuf::from("file7")]);
+
+    seen.apply(&delta).unwrap();
+    assert_eq!(seen.version, version);
+    assert_eq!(seen.manifest.entries, next.entries);
+    assert!(seen.apply(&delta).is_err());
+    assert!(history.since(seen.epoch, version).unwrap().is_empty());
+}
+
+#[test]
+fn unknown_or_stale_versions_get_the_full_manifest() {
+    let mut history = ManifestHistory::new(manifest(10, "0")).unwrap();
+    let first = history.current().clone();
+
+    assert!(history.since(first.epoch, 0).is_none());
+    assert!(history.since(first.epoch.wrapping_add(1), first.version).is_none());
+
+    for i in 1..=MAX_DELTA_VERSIONS {
+        history.update(manifest(10, &i.to_string()));
+    }
+    assert!(history.since(first.epoch, first.version).is_some());
+    history.update(manifest(10, "last"));
+    assert!(history.since(first.epoch, first.version).is_none());
+
+    // Changing everything is no cheaper as a delta.
+    let current = history.current().version;
+    let mut rehashed = manifest(10, "other");
+    rehashed.entries.values_mut().for_each(|hash| hash.push('!'));
+    history.update(rehashed);
+    assert!(history.since(first.epoch, current).is_none());
+}
+
+#[test]
+fn manifests_only_open_with_their_signer_and_untampered() {
+    let source = Keypair::generate_ed25519();
+    let source_id = source.public().to_peer_id();
+    let signed = SignedManifest::sign(&source, &manifest(3, "a")).unwrap();
+
+    let opened: Manifest = signed.open(&source_id).unwrap();
+    assert_eq!(opened.entries, manifest(3, "a").entries);
+
+    let other = Keypair::generate_ed25519().public().to_peer_id();
+    assert!(signed.open::<Manifest>(&other).is_err());
+
+    let tampered = SignedManifest {
+        payload: signed.payload.replace("hash1", "hash9"),
+        ..signed.clone()
+    };
+    assert!(tampered.open::<Manifest>(&source_id).is_err());
+
+    let unsigned = SignedManifest { signature: Vec::new(), ..signed };
+    assert!(unsigned.open::<Manifest>(&source_id).is_err());
+}
+
+#[test]
+fn transfer_plan_lists_sends_with_sizes_and_leaves_extras() {
+    let sender = manifest(3, "new");
+    let mut receiver = manifest(2, "old");
+    receiver.entries.insert(PathBuf::from("only-on-receiver"), "hash".to_string());
+
+    let plan = TransferPlan::new(&sender, &receiver, |path| (path != std::path::Path::new("file2")).then_some(10)).unwrap();
+    let sent: Vec<_> = plan.send.iter().map(|(path, size)| (path.to_str().unwrap(), *size)).collect();
+    assert_eq!(sent, [("changing", Some(10)), ("file2", None)]);
+    assert_eq!(plan.extra, [PathBuf::from("only-on-receiver")]);
+    assert_eq!(plan.known_bytes(), 10);
+}
+
+#[test]
+fn parallel_builds_match_serial_ones() {
+    let root = tempfile::tempdir().unwrap();
+    for directory in 0..8 {
+        fs::create_dir(root.path().join(format!("dir{}", directory))).unwrap();
+        for file in 0..25 {
+            let contents = format!("{} {}", directory, file).repeat(file * 100);
+            fs::write(root.path().join(format!("dir{}/file{}", directory, file)), contents).unwrap();
+        }
+    }
+    fs::remove_file(root.path().join("dir3/file0")).unwrap();
+
+    hash::set_jobs(1);
+    let serial = Manifest::build(root.path(), ChecksumAlgorithm::Sha256).unwrap();
+    hash::set_jobs(8);
+    let parallel = Manifest::build(root.path(), ChecksumAlgorithm::Sha256).unwrap();
+    hash::set_jobs(0);
+
+    assert_eq!(parallel.entries.len(), 199);
+    assert_eq!(parallel.entries, serial.entries);
+    let relative = PathBuf::from("dir5/file7");
+    let expected = hash_file(&root.path().join(&relative), ChecksumAlgorithm::Sha256).unwrap();
+    assert_eq!(parallel.entries[&relative], expected);
+}
+
+#[test]
+fn decompressed_builds_skip_the_control_directory() {
+    let source = tempfile::tempdir().unwrap();
+    let output = tempfile::tempdir().unwrap();
+    let contents = "compressible ".repeat(1000);
+    fs::write(source.path().join("big"), &contents).unwrap();
+    fs::write(output.path().join("small"), "small").unwrap();
+    compress::compress_file(&source.path().join("big"), &output.path().join("big.zst"), &Compression::default())
+        .unwrap();
+    fs::create_dir(output.path().join(CONTROL_DIR)).unwrap();
+    fs::write(output.path().join(CONTROL_DIR).join("merkle"), "root").unwrap();
+
+    let manifest = Manifest::build_decompressed(output.path(), ChecksumAlgorithm::default(), None).unwrap();
+    let names: Vec<_> = manifest.entries.keys().cloned().collect();
+    assert_eq!(names, [PathBuf::from("big"), PathBuf::from("small")]);
+}
@@ -0,0 +1,34 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use rustsync::{hash::ChecksumAlgorithm, manifest::Manifest, merkle::MerkleTree};
+
+fn manifest(entries: &[(&str, &str)]) -> Manifest {
+    let entries: BTreeMap<PathBuf, String> =
+        entries.iter().map(|(path, hash)| (PathBuf::from(path), hash.to_string())).collect();
+    Manifest { algorithm: ChecksumAlgorithm::default(), entries }
+}
+
+#[test]
+fn incremental_updates_match_a_rebuild() {
+    let mut tree = MerkleTree::new(&manifest(&[("a", "1"), ("d/b", "2"), ("d/c", "3"), ("d-e", "4"), ("f", "5")]));
+    let original = tree.root().to_string();
+
+    tree.remove_under(&PathBuf::from("d"));
+    tree.insert(PathBuf::from("e/b"), "2");
+    tree.insert(PathBuf::from("a"), "6");
+    let expected = MerkleTree::new(&manifest(&[("a", "6"), ("d-e", "4"), ("e/b", "2"), ("f", "5")])).root().to_string();
+    assert_eq!(tree.root(), expected);
+    assert_ne!(expected, original);
+}
+
+#[test]
+fn root_covers_names_and_contents() {
+    let root = |entries: &[(&str, &str)]| MerkleTree::new(&manifest(entries)).root().to_string();
+
+    let base = root(&[("a", "1"), ("b", "2"), ("c", "3")]);
+    assert_eq!(base, root(&[("c", "3"), ("a", "1"), ("b", "2")]));
+    assert_ne!(base, root(&[("a", "1"), ("b", "2"), ("c", "4")]));
+    assert_ne!(base, root(&[("a", "1"), ("b", "2"), ("d", "3")]));
+    assert_ne!(base, root(&[("a", "1"), ("b", "2")]));
+    assert_ne!(base, root(&[("a", "2"), ("b", "1"), ("c", "3")]));
+}
@@ -0,0 +1,668 @@
+use std::fs;
+
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind,
+};
+use rustsync::mirror::{handle_event, Mirror, Options};
+
+#[test]
+fn rename_with_one_path_is_skipped() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let output_root = fs::canonicalize(destination.path()).unwrap();
+
+    fs::write(output_root.join("a"), b"a").unwrap();
+    let mirror = Mirror::new(watch_root.clone(), output_root.clone(), Options::default());
+
+    let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both))).add_path(watch_root.join("a"));
+    handle_event(&mirror, &event);
+
+    assert!(output_root.join("a").exists());
+}
+
+#[test]
+fn event_without_paths_is_skipped() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), Options::default());
+
+    handle_event(&mirror, &Event::new(EventKind::Create(notify::event::CreateKind::File)));
+}
+
+#[cfg(unix)]
+#[test]
+fn setgid_directory_keeps_its_mode() {
+    use std::os::unix::fs::PermissionsExt;
+    use rustsync::mirror::{apply_event, Operation};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let directory = source.path().join("shared");
+    fs::create_dir(&directory).unwrap();
+    fs::set_permissions(&directory, fs::Permissions::from_mode(0o2775)).unwrap();
+
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), Options::default());
+    apply_event(&mirror, &Operation::Create { path: "shared".into() });
+    apply_event(&mirror, &Operation::Metadata { path: "shared".into() });
+
+    let mode = fs::metadata(destination.path().join("shared")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o7777, 0o2775);
+}
+
+#[cfg(unix)]
+#[test]
+fn strip_setuid_drops_setuid_bit() {
+    use std::os::unix::fs::PermissionsExt;
+    use rustsync::{
+        copy::Reflink,
+        mirror::{apply_event, Operation, Preserve},
+    };
+
+    // Copies leave the bits off even when permissions aren't preserved.
+    for preserve in [Options::default().preserve, vec![Preserve::Times]] {
+        for reflink in [Reflink::Auto, Reflink::Never] {
+            let source = tempfile::tempdir().unwrap();
+            let destination = tempfile::tempdir().unwrap();
+            let file = source.path().join("tool");
+            fs::write(&file, b"#!/bin/sh\n").unwrap();
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o6755)).unwrap();
+
+            let options = Options { strip_setuid: true, preserve: preserve.clone(), reflink, ..Options::default() };
+            let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+            apply_event(&mirror, &Operation::Data { path: "tool".into() });
+            apply_event(&mirror, &Operation::Metadata { path: "tool".into() });
+
+            let mode = fs::metadata(destination.path().join("tool")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o755);
+        }
+    }
+}
+
+#[test]
+fn control_directory_events_are_dropped() {
+    use notify::event::CreateKind;
+    use rustsync::reconcile::plan;
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let output_root = fs::canonicalize(destination.path()).unwrap();
+
+    let control = watch_root.join(".rustsync");
+    fs::create_dir(&control).unwrap();
+    fs::write(control.join("journal"), b"{}").unwrap();
+    fs::create_dir(watch_root.join("nested")).unwrap();
+    fs::create_dir(watch_root.join("nested/.rustsync")).unwrap();
+    fs::write(watch_root.join("nested/.rustsync/key"), b"key").unwrap();
+    let mirror = Mirror::new(watch_root.clone(), output_root.clone(), Options::default());
+
+    for path in [control.clone(), control.join("journal"), watch_root.join("nested/.rustsync/key")] {
+        handle_event(&mirror, &Event::new(EventKind::Create(CreateKind::Any)).add_path(path));
+    }
+    assert!(!output_root.join(".rustsync").exists());
+    assert!(!output_root.join("nested").exists());
+
+    let (operations, _) = plan(&mirror);
+    assert_eq!(operations.len(), 1, "{:?}", operations);
+}
+
+#[test]
+fn output_root_inside_watch_root_is_ignored() {
+    use rustsync::mirror::is_ignored;
+
+    let source = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let options = Options {
+        atomic_deploy: true,
+        ..Options::default()
+    };
+    let mirror = Mirror::new(watch_root.clone(), watch_root.join("out.staging"), options);
+
+    assert!(is_ignored(&mirror, &watch_root.join("out.staging/a")));
+    assert!(is_ignored(&mirror, &watch_root.join("out/a")));
+    assert!(is_ignored(&mirror, &watch_root.join("out.old")));
+    assert!(is_ignored(&mirror, &watch_root.join("out.1700000000-1/a")));
+    assert!(!is_ignored(&mirror, &watch_root.join("out.1-2-3")));
+    assert!(!is_ignored(&mirror, &watch_root.join("out.txt")));
+    assert!(!is_ignored(&mirror, &watch_root.join("output")));
+
+    // Without --atomic-deploy only the output root itself is output.
+    let mirror = Mirror::new(watch_root.clone(), watch_root.join("out"), Options::default());
+    assert!(is_ignored(&mirror, &watch_root.join("out/a")));
+    assert!(!is_ignored(&mirror, &watch_root.join("out.old/a")));
+    assert!(!is_ignored(&mirror, &watch_root.join("out.staging")));
+}
+
+#[test]
+fn a_watch_root_named_like_a_deploy_sibling_is_mirrored() {
+    use rustsync::mirror::is_ignored;
+
+    let parent = tempfile::tempdir().unwrap();
+    let parent = fs::canonicalize(parent.path()).unwrap();
+    for atomic_deploy in [false, true] {
+        let options = Options {
+            atomic_deploy,
+            ..Options::default()
+        };
+        let output_root = match atomic_deploy {
+            true => parent.join("site.staging"),
+            false => parent.join("site"),
+        };
+        let mirror = Mirror::new(parent.join("site.new"), output_root, options);
+        assert!(!is_ignored(&mirror, &parent.join("site.new/index.html")));
+    }
+}
+
+#[test]
+fn concurrent_duplicate_events_copy_once() {
+    use notify::event::{DataChange, ModifyKind};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let contents: Vec<u8> = (0..4 << 20).map(|i: u32| (i % 251) as u8).collect();
+    fs::write(source.path().join("big"), &contents).unwrap();
+    // A mirrored prefix makes each duplicate append the same tail.
+    fs::write(destination.path().join("big"), &contents[..1 << 20]).unwrap();
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), Options::default());
+
+    let event = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any))).add_path(source.path().join("big"));
+    let start = std::sync::Barrier::new(16);
+    std::thread::scope(|scope| {
+        for _ in 0..16 {
+            scope.spawn(|| {
+                start.wait();
+                handle_event(&mirror, &event)
+            });
+        }
+    });
+
+    assert!(fs::read(destination.path().join("big")).unwrap() == contents);
+    let names: Vec<_> = fs::read_dir(destination.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    assert_eq!(names, ["big"]);
+}
+
+#[test]
+fn unmounted_contents_are_not_deleted() {
+    use rustsync::{mirror::unmounted, reconcile::plan};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("share")).unwrap();
+    fs::create_dir(destination.path().join("share")).unwrap();
+    fs::write(destination.path().join("share/file"), b"mounted").unwrap();
+    fs::write(destination.path().join("gone"), b"deleted").unwrap();
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), Options::default());
+
+    unmounted(&mirror, std::path::Path::new("share"));
+    let (operations, summary) = plan(&mirror);
+    assert_eq!(summary.deleted, 1, "{:?}", operations);
+
+    handle_event(&mirror, &Event::new(EventKind::Remove(notify::event::RemoveKind::File)).add_path(source.path().join("share/file")));
+    assert!(destination.path().join("share/file").exists());
+}
+
+#[test]
+fn exclude_vcs_honors_gitignore_files() {
+    use rustsync::mirror::is_ignored;
+
+    let source = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    fs::create_dir_all(watch_root.join(".git/info")).unwrap();
+    fs::create_dir(watch_root.join("sub")).unwrap();
+    fs::write(watch_root.join(".gitignore"), "build/\n*.log\n!keep.log\n").unwrap();
+    fs::write(watch_root.join(".git/info/exclude"), "*.tmp\n").unwrap();
+    fs::write(watch_root.join("sub/.gitignore"), "local.txt\n").unwrap();
+    fs::create_dir(watch_root.join("build")).unwrap();
+
+    let options = Options { exclude_vcs: true, ..Options::default() };
+    let mirror = Mirror::new(watch_root.clone(), watch_root.join("out"), options);
+
+    for ignored in [".git/HEAD", "build", "build/main.o", "a.log", "sub/b.log", "c.tmp", "sub/local.txt", "out/a"] {
+        assert!(is_ignored(&mirror, &watch_root.join(ignored)), "{}", ignored);
+    }
+    for kept in [".gitignore", "keep.log", "local.txt", "sub/other.txt"] {
+        assert!(!is_ignored(&mirror, &watch_root.join(kept)), "{}", kept);
+    }
+
+    let without = Mirror::new(watch_root.clone(), watch_root.join("out"), Options::default());
+    assert!(!is_ignored(&without, &watch_root.join("a.log")));
+}
+
+#[cfg(unix)]
+#[test]
+fn metadata_sync_leaves_contents_alone() {
+    use std::os::unix::fs::PermissionsExt;
+    use rustsync::reconcile::metadata_sync;
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    for (name, mirrored) in [("same", "same"), ("changed", "other")] {
+        fs::write(source.path().join(name), name).unwrap();
+        fs::write(destination.path().join(name), mirrored).unwrap();
+        fs::set_permissions(source.path().join(name), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::set_permissions(destination.path().join(name), fs::Permissions::from_mode(0o644)).unwrap();
+    }
+
+    let options = Options { preserve: vec![rustsync::mirror::Preserve::Perms], ..Options::default() };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    let summary = metadata_sync(&mirror);
+    assert_eq!((summary.updated, summary.skipped), (1, 1));
+
+    let mode = |name: &str| fs::metadata(destination.path().join(name)).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode("same"), 0o600);
+    assert_eq!(mode("changed"), 0o644);
+    assert_eq!(fs::read(destination.path().join("changed")).unwrap(), b"other");
+}
+
+#[test]
+fn transaction_group_waits_until_quiet() {
+    use rustsync::mirror::{dispatch, flush_transactions, Operation};
+    use std::{thread, time::Duration};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("db")).unwrap();
+    fs::write(source.path().join("db/data"), b"data").unwrap();
+    fs::write(source.path().join("db/wal"), b"wal").unwrap();
+    fs::write(source.path().join("other"), b"other").unwrap();
+    let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(source.path().join("db/data"), old).unwrap();
+
+    let options = Options {
+        transaction_globs: vec!["db/*".parse().unwrap()],
+        transaction_settle: Duration::from_millis(200),
+        ..Options::default()
+    };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    dispatch(&mirror, Operation::Create { path: "db/data".into() });
+    dispatch(&mirror, Operation::Create { path: "other".into() });
+    dispatch(&mirror, Operation::Data { path: "db/data".into() });
+    dispatch(&mirror, Operation::Create { path: "db/wal".into() });
+
+    flush_transactions(&mirror, false);
+    assert!(destination.path().join("other").exists());
+    assert!(!destination.path().join("db").exists());
+
+    thread::sleep(Duration::from_millis(250));
+    flush_transactions(&mirror, false);
+    assert_eq!(fs::read(destination.path().join("db/data")).unwrap(), b"data");
+    assert_eq!(fs::read(destination.path().join("db/wal")).unwrap(), b"wal");
+    assert_eq!(fs::read_dir(destination.path().join("db")).unwrap().count(), 2);
+    // Staged copies get their metadata as direct ones do.
+    let metadata = fs::metadata(destination.path().join("db/data")).unwrap();
+    assert_eq!(filetime::FileTime::from_last_modification_time(&metadata), old);
+}
+
+#[test]
+fn unsettled_transactions_are_applied_at_shutdown() {
+    use rustsync::mirror::{dispatch, flush_transactions, Operation};
+    use std::time::Duration;
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("db")).unwrap();
+    fs::write(source.path().join("db/data"), b"data").unwrap();
+
+    let options = Options {
+        transaction_globs: vec!["db/*".parse().unwrap()],
+        transaction_settle: Duration::from_secs(60),
+        ..Options::default()
+    };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    dispatch(&mirror, Operation::Create { path: "db/data".into() });
+    flush_transactions(&mirror, false);
+    assert!(!destination.path().join("db").exists());
+
+    flush_transactions(&mirror, true);
+    assert_eq!(fs::read(destination.path().join("db/data")).unwrap(), b"data");
+}
+
+#[cfg(unix)]
+#[test]
+fn full_sync_preserves_hardlinks() {
+    use std::os::unix::fs::MetadataExt;
+    use rustsync::reconcile::reconcile;
+
+    let source = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    fs::create_dir(watch_root.join("sub")).unwrap();
+    fs::write(watch_root.join("a"), b"linked").unwrap();
+    fs::hard_link(watch_root.join("a"), watch_root.join("b")).unwrap();
+    fs::hard_link(watch_root.join("a"), watch_root.join("sub/c")).unwrap();
+    let inode = |path: &std::path::Path| fs::metadata(path).unwrap().ino();
+
+    let destination = tempfile::tempdir().unwrap();
+    let options = Options { preserve_hardlinks: true, ..Options::default() };
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), options.clone());
+    reconcile(&mirror);
+    let first = inode(&destination.path().join("a"));
+    assert_eq!(inode(&destination.path().join("b")), first);
+    assert_eq!(inode(&destination.path().join("sub/c")), first);
+    assert_eq!(fs::metadata(destination.path().join("a")).unwrap().nlink(), 3);
+
+    // With the first link ignored, the next one is copied and the last links to it.
+    fs::write(watch_root.join(".gitignore"), "/a\n").unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let options = Options { exclude_vcs: true, ..options };
+    reconcile(&Mirror::new(watch_root, destination.path().to_path_buf(), options));
+    assert!(!destination.path().join("a").exists());
+    assert_eq!(fs::read(destination.path().join("b")).unwrap(), b"linked");
+    assert_eq!(inode(&destination.path().join("sub/c")), inode(&destination.path().join("b")));
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinked_destination_directory_is_followed() {
+    use rustsync::{
+        mirror::{apply_event, Operation},
+        reconcile::reconcile,
+    };
+
+    let source = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    fs::create_dir(watch_root.join("sub")).unwrap();
+    fs::write(watch_root.join("sub/a"), b"a").unwrap();
+
+    let disk = tempfile::tempdir().unwrap();
+    fs::write(disk.path().join("stale"), b"stale").unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    std::os::unix::fs::symlink(disk.path(), destination.path().join("sub")).unwrap();
+
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), Options::default());
+    reconcile(&mirror);
+    assert!(destination.path().join("sub").is_symlink());
+    assert_eq!(fs::read(disk.path().join("a")).unwrap(), b"a");
+    assert!(!disk.path().join("stale").exists());
+
+    // Deleting the directory removes the link, never what it points at.
+    fs::remove_dir_all(watch_root.join("sub")).unwrap();
+    apply_event(&mirror, &Operation::Delete { path: "sub".into() });
+    assert!(fs::symlink_metadata(destination.path().join("sub")).is_err());
+    assert_eq!(fs::read(disk.path().join("a")).unwrap(), b"a");
+}
+
+#[cfg(unix)]
+#[test]
+fn kept_destination_link_is_emptied_not_removed() {
+    use rustsync::{
+        mirror::{apply_event, Operation},
+        reconcile::reconcile,
+    };
+
+    let source = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    fs::create_dir_all(watch_root.join("sub/inner")).unwrap();
+    fs::write(watch_root.join("sub/a"), b"a").unwrap();
+    fs::write(watch_root.join("sub/inner/b"), b"b").unwrap();
+
+    let disk = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    std::os::unix::fs::symlink(disk.path(), destination.path().join("sub")).unwrap();
+
+    let options = Options { keep_dest_links: true, ..Options::default() };
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), options);
+    reconcile(&mirror);
+    assert_eq!(fs::read(disk.path().join("inner/b")).unwrap(), b"b");
+
+    // A rename leaves the link where it is and mirrors the directory anew.
+    fs::rename(watch_root.join("sub"), watch_root.join("moved")).unwrap();
+    apply_event(&mirror, &Operation::Rename { path: "sub".into(), new_path: "moved".into() });
+    assert!(destination.path().join("sub").is_symlink());
+    assert_eq!(fs::read_dir(disk.path()).unwrap().count(), 0);
+    assert!(!destination.path().join("moved").is_symlink());
+    assert_eq!(fs::read(destination.path().join("moved/inner/b")).unwrap(), b"b");
+
+    // So does a delete, once the link is known from a live create.
+    fs::create_dir(watch_root.join("sub")).unwrap();
+    fs::write(watch_root.join("sub/c"), b"c").unwrap();
+    apply_event(&mirror, &Operation::Create { path: "sub".into() });
+    apply_event(&mirror, &Operation::Create { path: "sub/c".into() });
+    assert_eq!(fs::read(disk.path().join("c")).unwrap(), b"c");
+    fs::remove_dir_all(watch_root.join("sub")).unwrap();
+    apply_event(&mirror, &Operation::Delete { path: "sub".into() });
+    assert!(destination.path().join("sub").is_symlink());
+    assert_eq!(fs::read_dir(disk.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn stable_time_waits_for_writes_to_stop() {
+    use rustsync::mirror::flush_stable;
+    use std::{io::Write, thread::sleep, time::Duration};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let options = Options { stable_time: Some(Duration::from_millis(100)), ..Options::default() };
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), options);
+
+    let mut file = fs::File::create(watch_root.join("slow")).unwrap();
+    file.write_all(b"first").unwrap();
+    handle_event(&mirror, &Event::new(EventKind::Create(notify::event::CreateKind::File)).add_path(watch_root.join("slow")));
+    flush_stable(&mirror);
+    assert!(!destination.path().join("slow").exists());
+
+    sleep(Duration::from_millis(150));
+    file.write_all(b" second").unwrap();
+    flush_stable(&mirror);
+    assert!(!destination.path().join("slow").exists());
+
+    sleep(Duration::from_millis(150));
+    flush_stable(&mirror);
+    assert_eq!(fs::read(destination.path().join("slow")).unwrap(), b"first second");
+}
+
+#[test]
+fn age_window_skips_old_files_without_deleting_them() {
+    use rustsync::{
+        age::{AgeFilter, TimeBound},
+        reconcile::reconcile,
+    };
+    use std::time::{Duration, SystemTime};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    fs::write(watch_root.join("old"), b"old, changed").unwrap();
+    let two_days_ago = SystemTime::now() - Duration::from_secs(2 * 86_400);
+    filetime::set_file_mtime(watch_root.join("old"), two_days_ago.into()).unwrap();
+    fs::write(watch_root.join("new"), b"new").unwrap();
+    fs::write(destination.path().join("old"), b"old").unwrap();
+
+    let age = AgeFilter { newer_than: Some("24h".parse().unwrap()), older_than: None };
+    let options = Options { age, ..Options::default() };
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), options);
+    reconcile(&mirror);
+
+    assert_eq!(fs::read(destination.path().join("new")).unwrap(), b"new");
+    assert_eq!(fs::read(destination.path().join("old")).unwrap(), b"old");
+
+    assert_eq!("@86400".parse::<TimeBound>().unwrap(), TimeBound::At(SystemTime::UNIX_EPOCH + Duration::from_secs(86_400)));
+    assert_eq!(
+        "1970-01-02T00:00Z".parse::<TimeBound>().unwrap(),
+        TimeBound::At(SystemTime::UNIX_EPOCH + Duration::from_secs(86_400))
+    );
+    assert_eq!(
+        "2024-02-29T12:30:15Z".parse::<TimeBound>().unwrap(),
+        TimeBound::At(SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_209_815))
+    );
+    assert!("2025-13-01".parse::<TimeBound>().is_err());
+}
+
+#[test]
+fn bulk_delete_is_batched_under_its_directory() {
+    use rustsync::{metrics, mirror::flush_deletes};
+    use std::{thread, time::Duration};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let options = Options {
+        delete_batch_window: Duration::from_millis(100),
+        ..Options::default()
+    };
+    let mirror = Mirror::new(watch_root.clone(), destination.path().to_path_buf(), options);
+
+    fs::create_dir_all(watch_root.join("build/objects")).unwrap();
+    fs::write(watch_root.join("kept"), b"x").unwrap();
+    for i in 0..50 {
+        fs::write(watch_root.join(format!("build/objects/{}.o", i)), b"x").unwrap();
+    }
+    rustsync::reconcile::reconcile(&mirror);
+    assert!(destination.path().join("build/objects/49.o").exists());
+
+    // The children's events arrive before the directory's, as with rm -r.
+    fs::remove_dir_all(watch_root.join("build")).unwrap();
+    let coalesced = metrics::get("deletes_coalesced");
+    let remove = |path: std::path::PathBuf| Event::new(EventKind::Remove(notify::event::RemoveKind::Any)).add_path(path);
+    for i in 0..50 {
+        handle_event(&mirror, &remove(watch_root.join(format!("build/objects/{}.o", i))));
+    }
+    handle_event(&mirror, &remove(watch_root.join("build/objects")));
+    handle_event(&mirror, &remove(watch_root.join("build")));
+    assert!(destination.path().join("build/objects/0.o").exists());
+
+    flush_deletes(&mirror, false);
+    assert!(destination.path().join("build").exists());
+    thread::sleep(Duration::from_millis(150));
+    flush_deletes(&mirror, false);
+    assert!(!destination.path().join("build").exists());
+    assert!(destination.path().join("kept").exists());
+    assert!(metrics::get("deletes_coalesced") >= coalesced + 51);
+
+    // Anything else flushes what's batched first.
+    fs::remove_file(watch_root.join("kept")).unwrap();
+    handle_event(&mirror, &remove(watch_root.join("kept")));
+    fs::write(watch_root.join("kept"), b"new").unwrap();
+    handle_event(&mirror, &Event::new(EventKind::Create(notify::event::CreateKind::File)).add_path(watch_root.join("kept")));
+    flush_deletes(&mirror, true);
+    assert_eq!(fs::read(destination.path().join("kept")).unwrap(), b"new");
+}
+
+#[test]
+fn truncated_then_rewritten_files_are_mirrored() {
+    use notify::event::{DataChange, ModifyKind};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), Options::default());
+    let log = source.path().join("app.log");
+    let written = |contents: &[u8]| {
+        fs::write(&log, contents).unwrap();
+        let event = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any))).add_path(log.clone());
+        handle_event(&mirror, &event);
+        assert_eq!(fs::read(destination.path().join("app.log")).unwrap(), contents);
+    };
+
+    let lines: Vec<u8> = (0..20_000).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+    written(&lines);
+    // Rotated in place: emptied, then written afresh.
+    written(b"");
+    written(b"line 0 after rotation\n");
+    // Cut back to a prefix, then appended to.
+    written(&lines);
+    written(&lines[..50_000]);
+    let mut appended = lines[..50_000].to_vec();
+    appended.extend_from_slice(b"new tail\n");
+    written(&appended);
+    // Cut back and changed just before the cut.
+    let mut edited = lines[..40_000].to_vec();
+    edited[39_990..].fill(b'x');
+    written(&edited);
+}
+
+#[cfg(unix)]
+#[test]
+fn read_only_files_are_updated_and_stay_read_only() {
+    use std::os::unix::fs::PermissionsExt;
+    use rustsync::{
+        copy::Reflink,
+        mirror::{apply_event, Operation},
+    };
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let options = Options {
+        reflink: Reflink::Never,
+        ..Options::default()
+    };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    let read_only = fs::Permissions::from_mode(0o444);
+
+    for (name, old, new) in [("rewritten", "old contents", "new"), ("appended", "log", "log, grown")] {
+        let file = source.path().join(name);
+        fs::write(&file, old).unwrap();
+        fs::set_permissions(&file, read_only.clone()).unwrap();
+        apply_event(&mirror, &Operation::Create { path: name.into() });
+        assert_eq!(fs::metadata(destination.path().join(name)).unwrap().permissions().mode() & 0o777, 0o444);
+
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::write(&file, new).unwrap();
+        fs::set_permissions(&file, read_only.clone()).unwrap();
+        apply_event(&mirror, &Operation::Data { path: name.into() });
+
+        let mirrored = destination.path().join(name);
+        assert_eq!(fs::read_to_string(&mirrored).unwrap(), new);
+        assert_eq!(fs::metadata(&mirrored).unwrap().permissions().mode() & 0o777, 0o444);
+    }
+}
+
+#[test]
+fn only_newer_keeps_the_times_of_a_newer_mirror_it_leaves_alone() {
+    use filetime::FileTime;
+    use rustsync::{
+        copy::Reflink,
+        mirror::{apply_event, Operation},
+    };
+    use std::time::Duration;
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let options = Options {
+        reflink: Reflink::Never,
+        only_newer: Some(Duration::from_secs(2)),
+        ..Options::default()
+    };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    let old = FileTime::from_unix_time(1_000_000_000, 0);
+    let skewed = FileTime::from_unix_time(1_000_003_600, 0);
+
+    // A mirror with the same contents keeps its times; one that's copied over
+    // takes the source's.
+    for (name, mirrored_contents, mtime) in [("same", "contents", skewed), ("changed", "older contents", old)] {
+        fs::write(source.path().join(name), "contents").unwrap();
+        filetime::set_file_mtime(source.path().join(name), old).unwrap();
+        let mirrored = destination.path().join(name);
+        fs::write(&mirrored, mirrored_contents).unwrap();
+        filetime::set_file_mtime(&mirrored, skewed).unwrap();
+
+        apply_event(&mirror, &Operation::Data { path: name.into() });
+        assert_eq!(fs::read_to_string(&mirrored).unwrap(), "contents");
+        assert_eq!(FileTime::from_last_modification_time(&fs::metadata(&mirrored).unwrap()), mtime);
+    }
+
+    // Within the tolerance, times are set as usual.
+    let mirrored = destination.path().join("same");
+    filetime::set_file_mtime(&mirrored, FileTime::from_unix_time(1_000_000_001, 0)).unwrap();
+    apply_event(&mirror, &Operation::Metadata { path: "same".into() });
+    assert_eq!(FileTime::from_last_modification_time(&fs::metadata(&mirrored).unwrap()), old);
+}
+
+#[test]
+fn compressed_names_that_clash_with_source_files_are_refused() {
+    use rustsync::{compress::Compression, reconcile::reconcile};
+
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fs::write(source.path().join("big.txt"), "compressible ".repeat(1000)).unwrap();
+    fs::write(source.path().join("big.txt.zst"), "kept as it is").unwrap();
+
+    let options = Options { compress: Some(Compression::default()), ..Options::default() };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    for _ in 0..2 {
+        reconcile(&mirror);
+        assert_eq!(fs::read_to_string(destination.path().join("big.txt.zst")).unwrap(), "kept as it is");
+        assert!(!destination.path().join("big.txt").exists());
+    }
+}
@@ -0,0 +1,109 @@
+use std::{fs, path::PathBuf, sync::mpsc::channel, time::Duration};
+
+use notify::{
+    event::{CreateKind, DataChange, ModifyKind, RenameMode},
+    Event, EventKind,
+};
+use rustsync::{
+    clock::Clock,
+    mirror::{handle_event, Mirror, Options},
+    mock::MockEventSource,
+};
+
+fn mirror(options: Options) -> (tempfile::TempDir, tempfile::TempDir, Mirror) {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let output_root = fs::canonicalize(destination.path()).unwrap();
+    let mut mirror = Mirror::new(watch_root, output_root, options);
+    mirror.clock = Clock::manual();
+    (source, destination, mirror)
+}
+
+fn data() -> EventKind {
+    EventKind::Modify(ModifyKind::Data(DataChange::Any))
+}
+
+#[test]
+fn scripted_writes_are_debounced_until_quiet() {
+    let options = Options {
+        debounce: vec!["*.log=150ms".parse().unwrap()],
+        ..Options::default()
+    };
+    let (_source, _destination, mirror) = mirror(options);
+    let log: PathBuf = mirror.watch_root.join("app.log");
+    fs::write(&log, b"line 1\nline 2\n").unwrap();
+
+    MockEventSource::new()
+        .event(EventKind::Create(CreateKind::File), vec![log.clone()])
+        .wait(Duration::from_millis(140))
+        .event(data(), vec![log.clone()])
+        .wait(Duration::from_millis(140))
+        .event(data(), vec![log.clone()])
+        .wait(Duration::from_millis(140))
+        .run(&mirror);
+    assert!(!mirror.output_root.join("app.log").exists());
+
+    MockEventSource::new().wait(Duration::from_millis(10)).run(&mirror);
+    assert_eq!(fs::read(mirror.output_root.join("app.log")).unwrap(), b"line 1\nline 2\n");
+}
+
+#[test]
+fn scripted_renames_pair_or_expire() {
+    // Deletes go straight through, so only the rename window is timed.
+    let options = Options {
+        delete_batch_window: Duration::ZERO,
+        ..Options::default()
+    };
+    let (_source, _destination, mirror) = mirror(options);
+    for name in ["old", "gone"] {
+        fs::write(mirror.watch_root.join(name), name).unwrap();
+        fs::write(mirror.output_root.join(name), name).unwrap();
+    }
+    fs::rename(mirror.watch_root.join("old"), mirror.watch_root.join("new")).unwrap();
+    fs::remove_file(mirror.watch_root.join("gone")).unwrap();
+
+    let rename = |mode, name: &str, tracker| {
+        Event::new(EventKind::Modify(ModifyKind::Name(mode)))
+            .add_path(mirror.watch_root.join(name))
+            .set_tracker(tracker)
+    };
+    MockEventSource::new()
+        .raw(rename(RenameMode::From, "old", 1))
+        .raw(rename(RenameMode::From, "gone", 2))
+        .raw(rename(RenameMode::To, "new", 1))
+        .run(&mirror);
+    assert_eq!(fs::read(mirror.output_root.join("new")).unwrap(), b"old");
+    assert!(mirror.output_root.join("gone").exists());
+
+    // A From whose To never comes was a move out of the watch root.
+    MockEventSource::new().wait(Duration::from_millis(490)).run(&mirror);
+    assert!(mirror.output_root.join("gone").exists());
+    MockEventSource::new().wait(Duration::from_millis(10)).run(&mirror);
+    assert!(!mirror.output_root.join("gone").exists());
+}
+
+#[test]
+fn spawned_script_feeds_a_channel_in_order() {
+    let (sender, receiver) = channel();
+    let paths: Vec<PathBuf> = (0..3).map(|i| PathBuf::from(format!("/watched/{}", i))).collect();
+    let script = paths
+        .iter()
+        .fold(MockEventSource::new(), |script, path| script.event(data(), vec![path.clone()]).wait(Duration::from_millis(5)));
+    script.spawn(sender).join().unwrap();
+
+    let received: Vec<PathBuf> = receiver.iter().map(|event| event.unwrap().paths[0].clone()).collect();
+    assert_eq!(received, paths);
+
+    // Events from the channel are handled as the main loop handles them.
+    let (_source, _destination, mirror) = mirror(Options::default());
+    let (sender, receiver) = channel();
+    fs::write(mirror.watch_root.join("file"), b"data").unwrap();
+    MockEventSource::new()
+        .event(EventKind::Create(CreateKind::File), vec![mirror.watch_root.join("file")])
+        .spawn(sender);
+    for event in receiver {
+        handle_event(&mirror, &event.unwrap());
+    }
+    assert_eq!(fs::read(mirror.output_root.join("file")).unwrap(), b"data");
+}
@@ -0,0 +1,80 @@
+#![cfg(unix)]
+
+use std::{env, ffi::OsStr, fs, os::unix::ffi::OsStrExt, path::PathBuf};
+
+use rustsync::{
+    copy::Reflink,
+    hash::ChecksumAlgorithm,
+    journal::{read_records, Journal},
+    keys::default_rustsync_dir,
+    manifest::Manifest,
+    mirror::{apply_event, Mirror, Operation, Options, CONTROL_DIR},
+    pathbytes, report,
+};
+
+fn non_utf8(name: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(name))
+}
+
+#[test]
+fn non_utf8_names_are_mirrored_and_serialized_exactly() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let name = non_utf8(b"caf\xe9.txt");
+    fs::write(source.path().join(&name), b"latin-1").unwrap();
+    fs::write(source.path().join("plain.txt"), b"utf-8").unwrap();
+
+    let options = Options {
+        reflink: Reflink::Never,
+        ..Options::default()
+    };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    apply_event(&mirror, &Operation::Create { path: name.clone() });
+    assert_eq!(fs::read(destination.path().join(&name)).unwrap(), b"latin-1");
+
+    // UTF-8 paths are written as they always were.
+    assert_eq!(pathbytes::encode(&PathBuf::from("plain.txt")), "plain.txt");
+    assert_eq!(pathbytes::decode(&pathbytes::encode(&name)), Some(name.clone()));
+    assert_eq!(pathbytes::decode("\0zz"), None);
+
+    let manifest = Manifest::build(source.path(), ChecksumAlgorithm::Blake3).unwrap();
+    assert!(manifest.entries.contains_key(&name));
+    let json = serde_json::to_string(&manifest).unwrap();
+    assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap().entries, manifest.entries);
+    let saved = destination.path().join("manifest");
+    manifest.save(&saved).unwrap();
+    assert_eq!(Manifest::load(&saved).unwrap().entries, manifest.entries);
+
+    let journal_path = destination.path().join("journal");
+    let journal = Journal::open(&journal_path).unwrap();
+    let rename = Operation::Rename { path: name.clone(), new_path: non_utf8(b"\xff\xfe") };
+    journal.append(&rename).unwrap();
+    let records = read_records(&journal_path).unwrap();
+    assert_eq!(records.into_iter().next().unwrap().unwrap().operation, rename);
+
+    // --require-utf8 leaves them out instead.
+    let options = Options {
+        require_utf8: true,
+        reflink: Reflink::Never,
+        ..Options::default()
+    };
+    let strict = Mirror::new(source.path().to_path_buf(), destination.path().join("strict"), options);
+    fs::create_dir(destination.path().join("strict")).unwrap();
+    apply_event(&strict, &Operation::Create { path: name.clone() });
+    apply_event(&strict, &Operation::Create { path: "plain.txt".into() });
+    assert!(!destination.path().join("strict").join(&name).exists());
+    assert!(destination.path().join("strict/plain.txt").exists());
+    assert_eq!(report::error_counts().get(&report::ErrorKind::NonUtf8), Some(&1));
+
+    // Renamed to a name it can't have, the old mirror goes.
+    let renamed = non_utf8(b"plain\xff.txt");
+    fs::rename(source.path().join("plain.txt"), source.path().join(&renamed)).unwrap();
+    apply_event(&strict, &Operation::Rename { path: "plain.txt".into(), new_path: renamed.clone() });
+    assert!(!destination.path().join("strict/plain.txt").exists());
+    assert!(!destination.path().join("strict").join(&renamed).exists());
+    assert_eq!(report::error_counts().get(&report::ErrorKind::NonUtf8), Some(&2));
+
+    let home = non_utf8(b"/home/\xe9");
+    env::set_var("HOME", &home);
+    assert_eq!(default_rustsync_dir().unwrap(), home.join(CONTROL_DIR));
+}
@@ -0,0 +1,55 @@
+use std::path::Path;
+
+use rustsync::{
+    mirror::Operation,
+    priority::{priority_of, CopyOrder, CopyQueue, PriorityRule},
+};
+
+fn data(path: &str) -> Operation {
+    Operation::Data { path: path.into() }
+}
+
+#[test]
+fn copies_run_by_priority_then_size() {
+    let rules: Vec<PriorityRule> = vec!["docs/**=10".parse().unwrap()];
+    let mut queue = CopyQueue::default();
+
+    for (path, bytes) in [("video.mkv", 1 << 30), ("a.txt", 10), ("docs/guide.md", 5000), ("b.txt", 5)] {
+        let priority = priority_of(&rules, Path::new(path));
+        assert!(queue.push(Path::new(path), data(path), priority, bytes, CopyOrder::Size));
+    }
+    assert!(!queue.push(Path::new("a.txt"), data("a.txt"), 0, 10, CopyOrder::Size));
+
+    let summary = queue.summary();
+    assert_eq!(summary.files, 4);
+    assert_eq!(summary.by_priority.get(&10), Some(&1));
+
+    let order: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+    assert_eq!(order, vec![data("docs/guide.md"), data("b.txt"), data("a.txt"), data("video.mkv")]);
+}
+
+#[test]
+fn take_under_keeps_arrival_order() {
+    let mut queue = CopyQueue::default();
+    for path in ["dir/b", "other", "dir/a"] {
+        queue.push(Path::new(path), data(path), 0, 1, CopyOrder::Arrival);
+    }
+
+    assert_eq!(queue.take_under(Path::new("dir")), vec![data("dir/b"), data("dir/a")]);
+    assert_eq!(queue.len(), 1);
+    assert!(queue.push(Path::new("dir/a"), data("dir/a"), 0, 1, CopyOrder::Arrival));
+}
+
+#[test]
+fn only_more_urgent_copies_go_ahead_of_one_in_progress() {
+    let mut queue = CopyQueue::default();
+    queue.push(Path::new("big"), data("big"), 0, 1 << 20, CopyOrder::Size);
+    assert_eq!(queue.pop_ahead_of(0, 1 << 20, CopyOrder::Size), None);
+    assert_eq!(queue.pop_ahead_of(0, 1 << 30, CopyOrder::Arrival), None);
+    assert_eq!(queue.pop_ahead_of(1, 1, CopyOrder::Size), None);
+
+    queue.push(Path::new("urgent"), data("urgent"), 5, 1 << 30, CopyOrder::Size);
+    assert_eq!(queue.pop_ahead_of(0, 1, CopyOrder::Arrival), Some(data("urgent")));
+    assert_eq!(queue.pop_ahead_of(0, 1 << 30, CopyOrder::Size), Some(data("big")));
+    assert!(queue.is_empty());
+}
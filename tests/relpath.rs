@@ -0,0 +1,62 @@
+use std::{fs, path::Path};
+
+use rustsync::relpath::{exists_exactly, is_case_insensitive, CaseIndex, Listings, RelPath};
+
+#[test]
+fn case_suffix_goes_before_the_extension() {
+    let relative = RelPath::new(Path::new("Docs/README.md")).unwrap();
+    assert_eq!(relative.with_suffix(1, "~case").to_string(), "Docs/README~case.md");
+    assert_eq!(relative.with_suffix(0, "~case").to_string(), "Docs~case/README.md");
+    assert_eq!(relative.prefix(1).to_string(), "Docs");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn case_probe_leaves_nothing_behind() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(!is_case_insensitive(dir.path()).unwrap());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    fs::write(dir.path().join("README"), b"").unwrap();
+    assert!(!is_case_insensitive(dir.path()).unwrap());
+    assert!(exists_exactly(&dir.path().join("README")));
+    assert!(!exists_exactly(&dir.path().join("readme")));
+}
+
+#[test]
+fn backslashes_are_separators_only_on_windows() {
+    let relative = RelPath::new(Path::new(r"dir/a\b.txt")).unwrap();
+    let depth = if cfg!(windows) { 3 } else { 2 };
+    assert_eq!(relative.depth(), depth);
+}
+
+#[test]
+fn case_index_forgets_paths_and_what_is_under_them() {
+    let path = |path: &str| RelPath::new(Path::new(path)).unwrap();
+    let mut index = CaseIndex::default();
+    for owner in ["Docs", "Docs/README", "Docsy", "Other"] {
+        index.insert(path(owner).case_key(), path(owner));
+    }
+
+    index.forget(&path("Docs"));
+    assert!(index.get("docs").is_none());
+    assert!(index.get("docs/readme").is_none());
+    assert_eq!(index.get("docsy"), Some(&path("Docsy")));
+    assert_eq!(index.get("other"), Some(&path("Other")));
+}
+
+#[test]
+fn listings_are_reused_until_forgotten() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut listings = Listings::default();
+    fs::write(dir.path().join("a"), b"").unwrap();
+    assert!(listings.exists_exactly(&dir.path().join("a")));
+
+    fs::write(dir.path().join("b"), b"").unwrap();
+    assert!(!listings.exists_exactly(&dir.path().join("b")));
+    listings.forget(&dir.path().join("b"));
+    assert!(listings.exists_exactly(&dir.path().join("b")));
+
+    fs::remove_file(dir.path().join("a")).unwrap();
+    assert!(!listings.exists_exactly(&dir.path().join("a")));
+}
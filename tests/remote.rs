@@ -0,0 +1,131 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use rustsync::{
+    mirror::{apply_event, Mirror, Operation, Options, Preserve},
+    reconcile::reconcile,
+    remote::{Backend, SshTarget},
+};
+
+#[test]
+fn scp_style_targets_are_parsed() {
+    assert_eq!(
+        SshTarget::parse("backup@nas:/srv/mirror"),
+        Some(SshTarget {
+            user: Some("backup".to_string()),
+            host: "nas".to_string(),
+            path: PathBuf::from("/srv/mirror"),
+        })
+    );
+    assert_eq!(
+        SshTarget::parse("nas.local:mirror"),
+        Some(SshTarget {
+            user: None,
+            host: "nas.local".to_string(),
+            path: PathBuf::from("mirror"),
+        })
+    );
+    assert_eq!(SshTarget::parse("nas:").unwrap().path, PathBuf::from("."));
+    assert_eq!(SshTarget::parse("backup@nas:/srv").unwrap().to_string(), "backup@nas:/srv");
+
+    // Local paths.
+    assert_eq!(SshTarget::parse("/srv/mirror"), None);
+    assert_eq!(SshTarget::parse("./a:b"), None);
+    assert_eq!(SshTarget::parse("C:/mirror"), None);
+    assert_eq!(SshTarget::parse("@:/mirror"), None);
+}
+
+/// Records what it's asked to do and says only `unchanged` matches.
+struct Recording {
+    calls: Arc<Mutex<Vec<String>>>,
+}
+
+impl Recording {
+    fn push(&self, call: String) -> anyhow::Result<()> {
+        self.calls.lock().unwrap().push(call);
+        Ok(())
+    }
+}
+
+impl Backend for Recording {
+    fn matches(&self, relative: &Path, _source: &fs::Metadata) -> bool {
+        relative == Path::new("unchanged")
+    }
+
+    fn write(&self, relative: &Path, _source: &Path, _metadata: &fs::Metadata) -> anyhow::Result<()> {
+        self.push(format!("write {}", relative.display()))
+    }
+
+    fn mkdir(&self, relative: &Path) -> anyhow::Result<()> {
+        self.push(format!("mkdir {}", relative.display()))
+    }
+
+    fn symlink(&self, relative: &Path, target: &Path) -> anyhow::Result<()> {
+        self.push(format!("symlink {} -> {}", relative.display(), target.display()))
+    }
+
+    fn delete(&self, relative: &Path) -> anyhow::Result<()> {
+        self.push(format!("delete {}", relative.display()))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> anyhow::Result<()> {
+        self.push(format!("rename {} {}", from.display(), to.display()))
+    }
+
+    fn set_metadata(&self, relative: &Path, _metadata: &fs::Metadata, _preserve: &[Preserve]) -> anyhow::Result<()> {
+        self.push(format!("metadata {}", relative.display()))
+    }
+}
+
+fn remote_mirror(source: &Path) -> (Mirror, Arc<Mutex<Vec<String>>>) {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let mut mirror = Mirror::new(
+        fs::canonicalize(source).unwrap(),
+        PathBuf::from("backup@nas:/srv/mirror"),
+        Options::default(),
+    );
+    mirror.backend = Some(Box::new(Recording { calls: calls.clone() }));
+    (mirror, calls)
+}
+
+#[test]
+fn operations_go_to_the_backend() {
+    let source = tempfile::tempdir().unwrap();
+    fs::create_dir(source.path().join("dir")).unwrap();
+    fs::write(source.path().join("dir/file"), b"contents").unwrap();
+    let (mirror, calls) = remote_mirror(source.path());
+
+    for operation in [
+        Operation::Create { path: PathBuf::from("dir") },
+        Operation::Data { path: PathBuf::from("dir/file") },
+        Operation::Metadata { path: PathBuf::from("dir/file") },
+        Operation::Rename {
+            path: PathBuf::from("dir/old"),
+            new_path: PathBuf::from("dir/file"),
+        },
+        Operation::Delete { path: PathBuf::from("gone") },
+    ] {
+        apply_event(&mirror, &operation);
+    }
+
+    assert_eq!(
+        *calls.lock().unwrap(),
+        ["mkdir dir", "write dir/file", "metadata dir/file", "rename dir/old dir/file", "delete gone"]
+    );
+}
+
+#[test]
+fn full_sync_uploads_only_what_differs() {
+    let source = tempfile::tempdir().unwrap();
+    fs::write(source.path().join("unchanged"), b"same").unwrap();
+    fs::write(source.path().join("changed"), b"new").unwrap();
+    let (mirror, calls) = remote_mirror(source.path());
+
+    let summary = reconcile(&mirror);
+
+    assert_eq!(summary.files_copied, 1);
+    assert_eq!(*calls.lock().unwrap(), ["write changed", "metadata changed"]);
+}
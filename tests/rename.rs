@@ -0,0 +1,137 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind,
+};
+use rustsync::{
+    clock::Clock,
+    mirror::{expire_renames, handle_event, Mirror, Options},
+    rename::{RenameTracker, Shape},
+};
+
+fn rename(mode: RenameMode) -> Event {
+    Event::new(EventKind::Modify(ModifyKind::Name(mode)))
+}
+
+fn mirror_with(file: &str) -> (tempfile::TempDir, tempfile::TempDir, Mirror) {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    let output_root = fs::canonicalize(destination.path()).unwrap();
+
+    fs::write(watch_root.join(file), b"contents").unwrap();
+    fs::write(output_root.join(file), b"contents").unwrap();
+
+    // Deletes are applied as they come rather than batched.
+    let options = Options {
+        delete_batch_window: Duration::ZERO,
+        ..Options::default()
+    };
+    let mirror = Mirror::new(watch_root, output_root, options);
+    (source, destination, mirror)
+}
+
+#[test]
+fn tracker_pairs_halves_exactly() {
+    let mut tracker = RenameTracker::new(Duration::from_secs(60));
+    let now = Instant::now();
+    tracker.moved_from(Some(1), PathBuf::from("a"), Some(Shape::File(3)), now);
+    tracker.moved_from(Some(2), PathBuf::from("b"), Some(Shape::File(3)), now);
+
+    assert_eq!(tracker.moved_to(Some(2), None, now), Some(PathBuf::from("b")));
+    assert_eq!(tracker.moved_to(Some(1), None, now), Some(PathBuf::from("a")));
+    assert_eq!(tracker.moved_to(Some(3), Some(Shape::File(3)), now), None);
+}
+
+#[test]
+fn both_after_paired_halves_is_skipped() {
+    let mut tracker = RenameTracker::new(Duration::from_secs(60));
+    let now = Instant::now();
+    tracker.moved_from(Some(7), PathBuf::from("a"), None, now);
+    tracker.moved_to(Some(7), None, now);
+
+    assert!(tracker.already_paired(Some(7)));
+    assert!(!tracker.already_paired(Some(8)));
+    assert!(!tracker.already_paired(None));
+}
+
+#[test]
+fn trackerless_halves_pair_by_shape() {
+    let mut tracker = RenameTracker::new(Duration::from_secs(60));
+    let now = Instant::now();
+    tracker.moved_from(None, PathBuf::from("dir"), Some(Shape::Dir), now);
+    tracker.moved_from(None, PathBuf::from("small"), Some(Shape::File(1)), now);
+    tracker.moved_from(None, PathBuf::from("large"), Some(Shape::File(100)), now);
+
+    assert_eq!(tracker.moved_to(None, Some(Shape::File(100)), now), Some(PathBuf::from("large")));
+    assert_eq!(tracker.moved_to(None, Some(Shape::Dir), now), Some(PathBuf::from("dir")));
+    assert_eq!(tracker.moved_to(None, Some(Shape::File(2)), now), None);
+    assert_eq!(tracker.moved_to(None, None, now), None);
+}
+
+#[test]
+fn unpaired_from_expires() {
+    let mut tracker = RenameTracker::new(Duration::from_millis(10));
+    let now = Instant::now();
+    tracker.moved_from(Some(1), PathBuf::from("a"), None, now);
+    tracker.moved_from(None, PathBuf::from("b"), Some(Shape::Dir), now);
+    assert!(tracker.expire(now + Duration::from_millis(9)).is_empty());
+
+    let mut expired = tracker.expire(now + Duration::from_millis(10));
+    expired.sort();
+    assert_eq!(expired, vec![PathBuf::from("a"), PathBuf::from("b")]);
+    assert_eq!(tracker.moved_to(Some(1), None, now), None);
+}
+
+#[test]
+fn tracked_rename_is_mirrored_once() {
+    let (_source, _destination, mirror) = mirror_with("old");
+    let old = mirror.watch_root.join("old");
+    let new = mirror.watch_root.join("new");
+    fs::rename(&old, &new).unwrap();
+
+    // inotify order: From, To, then Both for the same cookie.
+    handle_event(&mirror, &rename(RenameMode::From).add_path(old.clone()).set_tracker(42));
+    handle_event(&mirror, &rename(RenameMode::To).add_path(new.clone()).set_tracker(42));
+    handle_event(&mirror, &rename(RenameMode::Both).add_path(old).add_path(new).set_tracker(42));
+
+    assert!(!mirror.output_root.join("old").exists());
+    assert_eq!(fs::read(mirror.output_root.join("new")).unwrap(), b"contents");
+}
+
+#[test]
+fn trackerless_rename_is_mirrored() {
+    let (_source, _destination, mirror) = mirror_with("old");
+    let old = mirror.watch_root.join("old");
+    let new = mirror.watch_root.join("new");
+    fs::rename(&old, &new).unwrap();
+
+    handle_event(&mirror, &rename(RenameMode::From).add_path(old));
+    handle_event(&mirror, &rename(RenameMode::To).add_path(new));
+
+    assert!(!mirror.output_root.join("old").exists());
+    assert_eq!(fs::read(mirror.output_root.join("new")).unwrap(), b"contents");
+}
+
+#[test]
+fn move_out_of_watch_deletes_after_window() {
+    let (_source, _destination, mut mirror) = mirror_with("gone");
+    mirror.clock = Clock::manual();
+    let gone = mirror.watch_root.join("gone");
+    fs::remove_file(&gone).unwrap();
+
+    handle_event(&mirror, &rename(RenameMode::From).add_path(gone).set_tracker(1));
+    assert!(mirror.output_root.join("gone").exists());
+
+    mirror.clock.advance(Duration::from_millis(499));
+    expire_renames(&mirror);
+    assert!(mirror.output_root.join("gone").exists());
+    mirror.clock.advance(Duration::from_millis(1));
+    expire_renames(&mirror);
+    assert!(!mirror.output_root.join("gone").exists());
+}
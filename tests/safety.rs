@@ -0,0 +1,93 @@
+use std::fs;
+
+use rustsync::{
+    mirror::{blocked_deletes, confirm_deletes, Mirror, Options},
+    reconcile::reconcile,
+    safety::{check_roots, DeleteLimit},
+};
+
+#[test]
+fn sync_over_delete_limit_waits_for_confirmation() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    for name in ["a", "b", "c"] {
+        fs::write(destination.path().join(name), name).unwrap();
+    }
+    fs::write(source.path().join("c"), "c").unwrap();
+
+    let options = Options {
+        delete_limit: DeleteLimit { max_deletes: Some(1), ..DeleteLimit::default() },
+        ..Options::default()
+    };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+
+    assert_eq!(reconcile(&mirror).deleted, 0);
+    assert!(destination.path().join("a").exists());
+    assert!(destination.path().join("b").exists());
+    assert_eq!(blocked_deletes(&mirror), 2);
+
+    // A source that comes back before confirmation is kept.
+    fs::write(source.path().join("b"), "b").unwrap();
+    assert_eq!(confirm_deletes(&mirror), 1);
+    assert!(!destination.path().join("a").exists());
+    assert!(destination.path().join("b").exists());
+    assert_eq!(blocked_deletes(&mirror), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinked_roots_are_compared_resolved() {
+    use std::os::unix::fs::symlink;
+
+    let scratch = tempfile::tempdir().unwrap();
+    let source = scratch.path().join("source");
+    let destination = scratch.path().join("destination");
+    fs::create_dir_all(source.join("nested")).unwrap();
+    fs::create_dir(&destination).unwrap();
+
+    // An output root that's a symlink to the watch root's parent.
+    let output = scratch.path().join("output");
+    symlink(scratch.path(), &output).unwrap();
+    assert!(check_roots(&source, &[&output]).is_err());
+
+    // One into the watch root is skipped like any destination inside it.
+    let inside = scratch.path().join("inside");
+    symlink(source.join("nested"), &inside).unwrap();
+    check_roots(&source, &[&inside]).unwrap();
+
+    // A symlink under the watch root leading back to the destination.
+    check_roots(&source, &[&destination]).unwrap();
+    symlink(&destination, source.join("nested/back")).unwrap();
+    assert!(check_roots(&source, &[&destination]).is_err());
+}
+
+#[test]
+fn directory_deletes_count_every_entry_under_them() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fs::create_dir(destination.path().join("dir")).unwrap();
+    for i in 0..50 {
+        fs::write(destination.path().join(format!("dir/{}", i)), "x").unwrap();
+    }
+
+    let options = Options {
+        delete_limit: DeleteLimit { max_deletes: Some(10), ..DeleteLimit::default() },
+        ..Options::default()
+    };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+
+    assert_eq!(reconcile(&mirror).deleted, 0);
+    assert!(destination.path().join("dir/0").exists());
+    assert_eq!(blocked_deletes(&mirror), 1);
+}
+
+#[test]
+fn missing_destinations_resolve_parent_components() {
+    let scratch = tempfile::tempdir().unwrap();
+    let source = scratch.path().join("source");
+    fs::create_dir(&source).unwrap();
+
+    // Still to be created, but back out at the watch root's parent.
+    assert!(check_roots(&source, &[&source.join("missing/../..")]).is_err());
+    check_roots(&source, &[&source.join("missing/../inside")]).unwrap();
+}
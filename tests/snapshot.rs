@@ -0,0 +1,65 @@
+use std::{fs, os::unix::fs::MetadataExt, path::Path};
+
+use rustsync::{
+    hash::ChecksumAlgorithm,
+    mirror::{Mirror, Options},
+    snapshot::{list_snapshots, snapshots_root, take_snapshot},
+};
+
+fn inode(path: &Path) -> u64 {
+    fs::metadata(path).unwrap().ino()
+}
+
+#[test]
+fn unchanged_files_are_hard_linked_between_snapshots() {
+    let source = tempfile::tempdir().unwrap();
+    let snapshots = tempfile::tempdir().unwrap();
+    let watch_root = fs::canonicalize(source.path()).unwrap();
+    fs::create_dir(watch_root.join("dir")).unwrap();
+    fs::write(watch_root.join("dir/same"), b"unchanged").unwrap();
+    fs::write(watch_root.join("edited"), b"before").unwrap();
+    let mirror = Mirror::new(watch_root.clone(), snapshots.path().to_path_buf(), Options::default());
+    let algorithm = ChecksumAlgorithm::default();
+
+    let first = take_snapshot(&mirror, algorithm, None, 2).unwrap();
+    assert_eq!((first.linked, first.copied), (0, 2));
+    fs::write(watch_root.join("edited"), b"after!").unwrap();
+    let second = take_snapshot(&mirror, algorithm, None, 2).unwrap();
+    assert_eq!((second.linked, second.copied), (1, 1));
+
+    let root = snapshots_root(snapshots.path());
+    let taken = list_snapshots(&root).unwrap();
+    assert_eq!(taken.len(), 2);
+    let (older, newer) = (&taken[0], &taken[1]);
+    assert_eq!(inode(&older.join("dir/same")), inode(&newer.join("dir/same")));
+    assert_ne!(inode(&older.join("edited")), inode(&newer.join("edited")));
+    assert_eq!(fs::read(older.join("edited")).unwrap(), b"before");
+    assert_eq!(fs::read(newer.join("edited")).unwrap(), b"after!");
+
+    let third = take_snapshot(&mirror, algorithm, None, 2).unwrap();
+    assert_eq!((third.linked, third.copied, third.pruned), (2, 0, 1));
+    let taken = list_snapshots(&root).unwrap();
+    assert_eq!(taken.len(), 2);
+    assert!(!older.exists());
+    assert_eq!(inode(&taken[0].join("dir/same")), inode(&taken[1].join("dir/same")));
+}
+
+#[test]
+fn pruning_leaves_directories_rustsync_did_not_create() {
+    let source = tempfile::tempdir().unwrap();
+    let snapshots = tempfile::tempdir().unwrap();
+    fs::write(source.path().join("file"), b"contents").unwrap();
+    let root = snapshots_root(snapshots.path());
+    for name in ["keep-me", "2020-01-01T000000Z.old", "2020-01-01T000000Z.partial.bak"] {
+        fs::create_dir_all(root.join(name)).unwrap();
+    }
+    let mirror = Mirror::new(source.path().to_path_buf(), snapshots.path().to_path_buf(), Options::default());
+
+    take_snapshot(&mirror, ChecksumAlgorithm::default(), None, 1).unwrap();
+    let second = take_snapshot(&mirror, ChecksumAlgorithm::default(), None, 1).unwrap();
+    assert_eq!(second.pruned, 1);
+    assert_eq!(list_snapshots(&root).unwrap().len(), 1);
+    for name in ["keep-me", "2020-01-01T000000Z.old", "2020-01-01T000000Z.partial.bak"] {
+        assert!(root.join(name).is_dir(), "{} was removed", name);
+    }
+}
@@ -0,0 +1,48 @@
+use std::{fs, os::unix::fs::symlink};
+
+use rustsync::{
+    mirror::{apply_event, DanglingSymlinks, Mirror, Operation, Options},
+    report::{self, ErrorKind},
+};
+
+fn mirror_dangling(policy: DanglingSymlinks) -> (tempfile::TempDir, bool) {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    symlink("missing-target", source.path().join("broken")).unwrap();
+    fs::write(source.path().join("target"), b"x").unwrap();
+    symlink("target", source.path().join("working")).unwrap();
+
+    let options = Options {
+        dangling_symlinks: policy,
+        ..Options::default()
+    };
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), options);
+    apply_event(&mirror, &Operation::Create { path: "broken".into() });
+    apply_event(&mirror, &Operation::Create { path: "working".into() });
+
+    // Links to existing targets are mirrored whatever the policy.
+    assert_eq!(fs::read_link(destination.path().join("working")).unwrap(), fs::read_link(source.path().join("working")).unwrap());
+    let mirrored = fs::symlink_metadata(destination.path().join("broken")).is_ok();
+    (destination, mirrored)
+}
+
+#[test]
+fn keep_recreates_dangling_links() {
+    let (destination, mirrored) = mirror_dangling(DanglingSymlinks::Keep);
+    assert!(mirrored);
+    assert_eq!(fs::read_link(destination.path().join("broken")).unwrap().to_str(), Some("missing-target"));
+}
+
+#[test]
+fn skip_leaves_dangling_links_out() {
+    let (_destination, mirrored) = mirror_dangling(DanglingSymlinks::Skip);
+    assert!(!mirrored);
+}
+
+#[test]
+fn error_reports_dangling_links() {
+    let before = report::error_counts().get(&ErrorKind::Symlink).copied().unwrap_or(0);
+    let (_destination, mirrored) = mirror_dangling(DanglingSymlinks::Error);
+    assert!(!mirrored);
+    assert_eq!(report::error_counts().get(&ErrorKind::Symlink).copied().unwrap_or(0), before + 1);
+}
@@ -0,0 +1,33 @@
+use std::fs;
+
+use rustsync::copy::{clean_temp_dir, copy_file, set_temp_dir, staging_path, temp_path, Reflink};
+
+#[test]
+fn temp_files_go_to_the_temp_dir_and_stale_ones_are_removed() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fs::write(temp_dir.path().join("0123456789abcdef-file.rustsync-tmp-transform"), b"stale").unwrap();
+    fs::write(temp_dir.path().join("unrelated"), b"kept").unwrap();
+
+    set_temp_dir(temp_dir.path()).unwrap();
+    assert_eq!(clean_temp_dir(temp_dir.path()).unwrap(), 1);
+    assert!(temp_dir.path().join("unrelated").exists());
+
+    let target = destination.path().join("file");
+    let staged = staging_path(&target);
+    assert_eq!(staged.parent(), Some(temp_dir.path()));
+    assert_eq!(staging_path(&target), staged);
+    assert_ne!(staging_path(&destination.path().join("other")), staged);
+
+    // A destination without a parent on the temp dir's filesystem keeps its
+    // temp file beside it.
+    let missing = destination.path().join("missing/file");
+    assert_eq!(staging_path(&missing), temp_path(&missing));
+
+    let source = destination.path().join("source");
+    fs::write(&source, b"contents").unwrap();
+    copy_file(&source, &target, Reflink::Auto).unwrap();
+    assert_eq!(fs::read(&target).unwrap(), b"contents");
+    assert!(!staged.exists());
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+}
@@ -0,0 +1,119 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rustsync::{
+    hash::{hash_file, ChecksumAlgorithm},
+    transfer::{partial_dir, read_chunk, Download, Received, CHUNK_SIZE},
+};
+
+fn contents() -> Vec<u8> {
+    (0..CHUNK_SIZE * 3 + 1234).map(|byte| (byte % 251) as u8).collect()
+}
+
+/// Asks `source` for chunks until `download` is done or `chunks` have been
+/// received, as a peer answering `Request::Chunk` would.
+fn fetch(source: &Path, download: &mut Download, chunks: usize) -> Option<u64> {
+    let (mut seq, mut offset) = (download.seq(), download.offset());
+    for _ in 0..chunks {
+        let chunk = read_chunk(source, download.path(), seq, offset).unwrap();
+        match download.receive(&chunk).unwrap() {
+            Received::More { seq: next, offset: at } => (seq, offset) = (next, at),
+            Received::Done { size } => return Some(size),
+            Received::Ignored => panic!("chunk {} was ignored", seq),
+        }
+    }
+    None
+}
+
+#[test]
+fn dropped_transfer_resumes_from_acknowledged_offset() {
+    let source = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    let path = PathBuf::from("dir/file");
+    fs::create_dir(source.path().join("dir")).unwrap();
+    fs::write(source.path().join(&path), contents()).unwrap();
+    let hash = hash_file(&source.path().join(&path), ChecksumAlgorithm::default()).unwrap();
+
+    let mut download = Download::open(target.path(), &path, &hash, ChecksumAlgorithm::default()).unwrap();
+    assert_eq!(fetch(source.path(), &mut download, 2), None);
+    // The connection drops: everything in memory is lost.
+    drop(download);
+    assert!(!target.path().join(&path).exists());
+
+    let mut download = Download::open(target.path(), &path, &hash, ChecksumAlgorithm::default()).unwrap();
+    assert_eq!(download.offset(), CHUNK_SIZE * 2);
+    assert_eq!(download.seq(), 2);
+
+    // A chunk the peer sent before the drop arrives late.
+    let stale = read_chunk(source.path(), &path, 1, CHUNK_SIZE).unwrap();
+    assert!(matches!(download.receive(&stale).unwrap(), Received::Ignored));
+
+    assert_eq!(fetch(source.path(), &mut download, 10), Some(contents().len() as u64));
+    assert_eq!(fs::read(target.path().join(&path)).unwrap(), contents());
+    assert_eq!(fs::read_dir(partial_dir(target.path())).unwrap().count(), 0);
+}
+
+#[test]
+fn unacknowledged_bytes_are_fetched_again() {
+    let source = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    let path = PathBuf::from("file");
+    fs::write(source.path().join(&path), contents()).unwrap();
+    let hash = hash_file(&source.path().join(&path), ChecksumAlgorithm::default()).unwrap();
+
+    let mut download = Download::open(target.path(), &path, &hash, ChecksumAlgorithm::default()).unwrap();
+    fetch(source.path(), &mut download, 1);
+    drop(download);
+
+    // Bytes written past the saved offset, as if the process died mid-chunk.
+    let partial = fs::read_dir(partial_dir(target.path()))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|extension| extension == "partial"))
+        .unwrap();
+    let mut written = fs::read(&partial).unwrap();
+    written.extend_from_slice(&[0xff; 100]);
+    fs::write(&partial, written).unwrap();
+
+    let mut download = Download::open(target.path(), &path, &hash, ChecksumAlgorithm::default()).unwrap();
+    assert_eq!(download.offset(), CHUNK_SIZE);
+    assert_eq!(fs::metadata(&partial).unwrap().len(), CHUNK_SIZE);
+    fetch(source.path(), &mut download, 10).unwrap();
+    assert_eq!(fs::read(target.path().join(&path)).unwrap(), contents());
+}
+
+#[test]
+fn changed_source_restarts_and_bad_hash_is_discarded() {
+    let source = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    let path = PathBuf::from("file");
+    fs::write(source.path().join(&path), contents()).unwrap();
+    let hash = hash_file(&source.path().join(&path), ChecksumAlgorithm::default()).unwrap();
+
+    let mut download = Download::open(target.path(), &path, &hash, ChecksumAlgorithm::default()).unwrap();
+    fetch(source.path(), &mut download, 2);
+    drop(download);
+
+    // The peer now lists a different version, so nothing is resumed.
+    let download = Download::open(target.path(), &path, "other", ChecksumAlgorithm::default()).unwrap();
+    assert_eq!(download.offset(), 0);
+    drop(download);
+
+    // Fetching the file against the wrong hash never puts it in place.
+    let mut download = Download::open(target.path(), &path, "other", ChecksumAlgorithm::default()).unwrap();
+    let mut result = Ok(Received::Ignored);
+    let (mut seq, mut offset) = (0, 0);
+    for _ in 0..10 {
+        let chunk = read_chunk(source.path(), &path, seq, offset).unwrap();
+        result = download.receive(&chunk);
+        match &result {
+            Ok(Received::More { seq: next, offset: at }) => (seq, offset) = (*next, *at),
+            _ => break,
+        }
+    }
+    assert!(result.is_err());
+    assert!(!target.path().join(&path).exists());
+    assert_eq!(fs::read_dir(partial_dir(target.path())).unwrap().count(), 0);
+}
@@ -0,0 +1,54 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use rustsync::{
+    mirror::Operation,
+    trickle::{Trickle, TrickleLimit},
+};
+
+#[test]
+fn trickle_stays_within_the_minute_allowance() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir(dir.path().join("dir")).unwrap();
+    fs::write(dir.path().join("small"), vec![0; 100]).unwrap();
+    fs::write(dir.path().join("big"), vec![0; 5000]).unwrap();
+    fs::write(dir.path().join("other"), vec![0; 100]).unwrap();
+
+    let path = PathBuf::from;
+    let operations = vec![
+        Operation::Create { path: path("dir") },
+        Operation::Data { path: path("small") },
+        Operation::Metadata { path: path("small") },
+        Operation::Data { path: path("big") },
+        Operation::Data { path: path("other") },
+    ];
+    let limit = TrickleLimit {
+        files: Some(10),
+        bytes: Some(1000),
+    };
+    let mut trickle = Trickle::new(dir.path(), operations, limit);
+    assert_eq!(trickle.remaining(), Duration::from_secs(312));
+
+    // The big file waits for a minute of its own, even though it doesn't fit.
+    assert_eq!(trickle.due().len(), 3);
+    assert!(trickle.due().is_empty());
+    assert!(!trickle.is_done());
+    assert_eq!(trickle.to_string(), "1/3 files, 100/5200 bytes, about 5m6s left");
+}
+
+#[test]
+fn operations_the_source_has_overtaken_are_dropped() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("recreated"), "back").unwrap();
+
+    let path = PathBuf::from;
+    let operations = vec![
+        Operation::Delete { path: path("recreated") },
+        Operation::Data { path: path("removed") },
+        Operation::Delete { path: path("gone") },
+    ];
+    let mut trickle = Trickle::new(dir.path(), operations, TrickleLimit::default());
+
+    let due = trickle.due();
+    assert!(matches!(&due[..], [Operation::Delete { path }] if path == &PathBuf::from("gone")));
+    assert!(trickle.is_done());
+}
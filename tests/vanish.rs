@@ -0,0 +1,42 @@
+use std::fs;
+
+use rustsync::{
+    metrics,
+    mirror::{apply_event, Mirror, Operation, Options},
+    report,
+};
+
+// Each case applies an event after the file it names changed, as if the change
+// landed between the event firing and the copy.
+
+#[test]
+fn file_deleted_before_copy_is_skipped() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), Options::default());
+
+    fs::write(source.path().join("gone"), b"data").unwrap();
+    fs::remove_file(source.path().join("gone")).unwrap();
+
+    let vanished = metrics::get("vanished_files");
+    apply_event(&mirror, &Operation::Create { path: "gone".into() });
+    apply_event(&mirror, &Operation::Data { path: "gone".into() });
+    apply_event(&mirror, &Operation::Metadata { path: "gone".into() });
+
+    assert!(report::error_counts().is_empty());
+    assert!(metrics::get("vanished_files") >= vanished + 3);
+    assert!(!destination.path().join("gone").exists());
+}
+
+#[test]
+fn file_replaced_by_directory_before_copy_is_skipped() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let mirror = Mirror::new(source.path().to_path_buf(), destination.path().to_path_buf(), Options::default());
+
+    fs::create_dir(source.path().join("swapped")).unwrap();
+    apply_event(&mirror, &Operation::Data { path: "swapped".into() });
+
+    assert!(report::error_counts().is_empty());
+    assert!(!destination.path().join("swapped").exists());
+}
@@ -0,0 +1,21 @@
+use std::{fs, sync::mpsc::channel, time::Duration};
+
+use notify::EventKind;
+use rustsync::watch::{watch, WatcherBackend};
+
+#[test]
+fn watcher_backends_are_picked_by_name() {
+    let root = tempfile::tempdir().unwrap();
+    let (sender, receiver) = channel();
+    let _watcher = watch(root.path(), sender.clone(), WatcherBackend::Poll, Some(Duration::from_millis(100))).unwrap();
+
+    fs::write(root.path().join("file"), b"contents").unwrap();
+    let event = receiver.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert!(matches!(event.kind, EventKind::Create(_)));
+    assert_eq!(event.paths, vec![root.path().join("file")]);
+
+    // Only the polling backend takes an interval.
+    assert!(watch(root.path(), sender.clone(), WatcherBackend::native(), Some(Duration::from_secs(1))).is_err());
+    #[cfg(target_os = "linux")]
+    assert!(watch(root.path(), sender, WatcherBackend::Kqueue, None).is_err());
+}
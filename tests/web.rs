@@ -0,0 +1,56 @@
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+};
+
+use rustsync::{control::Command, web};
+
+fn request(address: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn web_ui_serves_the_page_and_forwards_commands() {
+    let (address, requests) = web::serve("127.0.0.1:0".parse().unwrap()).unwrap();
+    // Stands in for the event loop, answering each command with its name.
+    let event_loop = thread::spawn(move || {
+        let mut commands = Vec::new();
+        for request in requests.iter().take(3) {
+            assert_eq!(request.origin, "web UI");
+            commands.push(request.command);
+            request.reply.send(format!("{{\"command\":\"{:?}\"}}", request.command)).unwrap();
+        }
+        commands
+    });
+
+    let page = request(address, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(page.starts_with("HTTP/1.1 200 OK"));
+    assert!(page.contains("text/html") && page.contains("/api/status"));
+
+    let status = request(address, "GET /api/status HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(status.ends_with("{\"command\":\"Status\"}"));
+
+    // Commands need the page's header, which other sites can't send.
+    let forged = request(address, "POST /api/pause HTTP/1.1\r\nHost: localhost\r\nOrigin: http://example.com\r\n\r\n");
+    assert!(forged.starts_with("HTTP/1.1 403"));
+    let paused = request(address, "POST /api/pause HTTP/1.1\r\nHost: localhost\r\nX-Rustsync: 1\r\n\r\n");
+    assert!(paused.ends_with("{\"command\":\"Pause\"}"));
+    for command in ["status", "confirm-deletes", "retry-deadletter"] {
+        let refused = request(address, &format!("POST /api/{} HTTP/1.1\r\nHost: localhost\r\nX-Rustsync: 1\r\n\r\n", command));
+        assert!(refused.starts_with("HTTP/1.1 404"));
+    }
+
+    // Another name for the address may be a rebound DNS name.
+    let host = format!("Host: {}\r\n", address);
+    assert!(request(address, &format!("GET /api/status HTTP/1.1\r\n{}\r\n", host)).starts_with("HTTP/1.1 200"));
+    let rebound = request(address, "POST /api/pause HTTP/1.1\r\nHost: attacker.example:80\r\nX-Rustsync: 1\r\n\r\n");
+    assert!(rebound.starts_with("HTTP/1.1 403"));
+    assert!(request(address, "GET /api/status HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 403"));
+
+    assert_eq!(event_loop.join().unwrap(), vec![Command::Status, Command::Pause, Command::Status]);
+}